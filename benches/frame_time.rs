@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use openvr_tracker::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+use openvr_tracker::config::Config;
+use openvr_tracker::openvr_adaptor::VrDeviceManager;
+use openvr_tracker::pipeline::FrameBuilder;
+use openvr_tracker::tracking_messages::VrDeviceClass;
+use std::time::Instant;
+
+fn manager_with_devices(count: usize) -> VrDeviceManager<MockBackend> {
+    let mut backend = MockBackend::new();
+    backend.push_frame(
+        (0..count)
+            .map(|index| DeviceSample::new(index, VrDeviceClass::Tracker, IDENTITY_POSE))
+            .collect(),
+    );
+    VrDeviceManager::with_backend(backend)
+}
+
+fn frame_time(c: &mut Criterion) {
+    let mut manager = manager_with_devices(16);
+    let config = Config::default();
+    let mut builder = FrameBuilder::new();
+    let mut buffer = Vec::with_capacity(16 * 1024);
+    c.bench_function("update, build and serialize 16 devices", |b| {
+        b.iter(|| {
+            manager.update();
+            if let Some(frame) = builder.build(manager.devices(), &config, 0, Instant::now()) {
+                buffer.clear();
+                serde_json::to_writer(&mut buffer, frame).unwrap();
+            }
+        })
+    });
+}
+
+criterion_group!(benches, frame_time);
+criterion_main!(benches);
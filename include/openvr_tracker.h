@@ -0,0 +1,71 @@
+#ifndef OPENVR_TRACKER_H
+#define OPENVR_TRACKER_H
+
+/*
+ * The structs match the #[repr(C)] ones in src/ffi.rs field by field, with
+ * natural alignment: OpenVrTrackerDevice is 40 bytes, OpenVrTrackerFrame is
+ * 2584 bytes aligned to 8. The caller owns every frame, the library only
+ * writes into it. Functions return one of the codes below and can be called
+ * from any thread.
+ */
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define OPENVR_TRACKER_MAX_DEVICES 64
+
+#define OPENVR_TRACKER_OK 0
+#define OPENVR_TRACKER_ERROR_ALREADY_RUNNING -1
+#define OPENVR_TRACKER_ERROR_INIT_FAILED -2
+#define OPENVR_TRACKER_ERROR_NOT_RUNNING -3
+#define OPENVR_TRACKER_ERROR_NULL_POINTER -4
+/* a thread panicked while holding the stream, it can't be used anymore */
+#define OPENVR_TRACKER_ERROR_POISONED -5
+
+typedef enum OpenVrTrackerClass {
+    OPENVR_TRACKER_CLASS_CONTROLLER = 0,
+    OPENVR_TRACKER_CLASS_LEFT_CONTROLLER = 1,
+    OPENVR_TRACKER_CLASS_RIGHT_CONTROLLER = 2,
+    OPENVR_TRACKER_CLASS_TRACKER = 3,
+    OPENVR_TRACKER_CLASS_HMD = 4,
+    OPENVR_TRACKER_CLASS_SENSOR = 5,
+    OPENVR_TRACKER_CLASS_OTHER = 6,
+    OPENVR_TRACKER_CLASS_CAMERA = 7,
+} OpenVrTrackerClass;
+
+typedef struct OpenVrTrackerDevice {
+    uint32_t id;
+    /* one of OpenVrTrackerClass */
+    uint32_t class_;
+    bool tracked;
+    float position[3];
+    /* x, y, z, w */
+    float rotation[4];
+} OpenVrTrackerDevice;
+
+typedef struct OpenVrTrackerFrame {
+    uint64_t seq;
+    /* milliseconds since unix epoch */
+    uint64_t ts;
+    uint32_t device_count;
+    OpenVrTrackerDevice devices[OPENVR_TRACKER_MAX_DEVICES];
+} OpenVrTrackerFrame;
+
+/* Start polling OpenVR on a background thread */
+int32_t openvr_tracker_start_stream(uint32_t rate_hz);
+
+/* Returns 1 if a new frame was written, 0 if there was none, negative on error */
+int32_t openvr_tracker_poll_latest_frame(OpenVrTrackerFrame *frame);
+
+/* Stop polling and release OpenVR */
+int32_t openvr_tracker_stop(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
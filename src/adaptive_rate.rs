@@ -0,0 +1,101 @@
+//! Publish rate driven by device motion
+//!
+//! Mostly idle sessions don't need the full rate. The fastest device decides
+//! the rate, and after motion stops the rate decays over about a second
+//! instead of dropping right away, so short pauses don't cost fidelity.
+
+use crate::config::{AdaptiveRateConfig, Config};
+use crate::tracking_messages::VrDevice;
+use nalgebra as na;
+use std::time::Duration;
+
+/// Time constant of the decay after motion stops
+const MOTION_HOLD_S: f32 = 1.0;
+
+#[derive(Default)]
+pub struct AdaptiveRate {
+    /// Tracked poses of the previous sample by device id
+    previous: Vec<(usize, na::Point3<f32>, na::UnitQuaternion<f32>)>,
+    previous_ts: Option<u128>,
+    /// Motion between 0 (resting) and 1 (fast)
+    motion: f32,
+}
+
+impl AdaptiveRate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time until the next frame, given devices sampled at `ts` in milliseconds
+    pub fn period(
+        &mut self,
+        devices: &[VrDevice],
+        ts: u128,
+        config: &Config,
+        adaptive: &AdaptiveRateConfig,
+    ) -> Duration {
+        let elapsed_s = match self.previous_ts {
+            Some(previous_ts) if ts > previous_ts => (ts - previous_ts) as f32 / 1000.0,
+            _ => 0.0,
+        };
+        let mut measured: f32 = 0.0;
+        if elapsed_s > 0.0 {
+            for device in devices.iter().filter(|device| device.tracked()) {
+                let previous = self.previous.iter().find(|(id, ..)| *id == device.id());
+                if let Some((_, position, rotation)) = previous {
+                    let speed = (device.position() - position).norm() / elapsed_s;
+                    let angular_speed = device.rotation().angle_to(rotation) / elapsed_s;
+                    measured = measured
+                        .max(speed / adaptive.fast_speed_m_s.max(f32::EPSILON))
+                        .max(angular_speed / adaptive.fast_angular_speed_rad_s.max(f32::EPSILON));
+                }
+            }
+        }
+        let decayed = self.motion * (-elapsed_s / MOTION_HOLD_S).exp();
+        self.motion = measured.min(1.0).max(decayed);
+        self.previous_ts = Some(ts);
+        self.previous.clear();
+        self.previous.extend(
+            devices
+                .iter()
+                .filter(|device| device.tracked())
+                .map(|device| (device.id(), *device.position(), *device.rotation())),
+        );
+
+        let min_rate_hz = adaptive.min_rate_hz.min(config.rate_hz);
+        let rate_hz = min_rate_hz + (config.rate_hz - min_rate_hz) * self.motion;
+        Duration::from_secs_f32(1.0 / rate_hz.max(0.01))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+    use crate::openvr_adaptor::VrDeviceManager;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_full_rate_while_moving() {
+        let resting = DeviceSample::new(0, VrDeviceClass::Tracker, IDENTITY_POSE);
+        let mut moved = resting;
+        // 10 cm along x between samples 100 ms apart is 1 m/s
+        moved.pose[0][3] = 0.1;
+        let mut backend = MockBackend::new();
+        for sample in [resting, resting, moved].iter() {
+            backend.push_frame(vec![*sample]);
+        }
+        let mut manager = VrDeviceManager::with_backend(backend);
+        let config = Config::default();
+        let adaptive = AdaptiveRateConfig::default();
+        let mut rate = AdaptiveRate::new();
+
+        let mut periods = vec![];
+        for ts in [0, 100, 200].iter() {
+            manager.update();
+            periods.push(rate.period(manager.devices(), *ts, &config, &adaptive));
+        }
+        assert_eq!(periods[1], Duration::from_secs_f32(0.5));
+        assert_eq!(periods[2], config.period());
+    }
+}
@@ -0,0 +1,179 @@
+//! Merging streams of several senders into one
+//!
+//! Each source is a sender on its own multicast group, usually a separate
+//! capture volume. Devices of every source are moved into a common frame with
+//! the source's calibration, tagged with the source name and renumbered so ids
+//! stay unique.
+
+use crate::client::FrameAssembler;
+use crate::config::load_json;
+use crate::config::Config;
+use crate::error::Result;
+use crate::tracking_messages::{TrackedObjects, VrDevice};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Device ids of source `n` start at `n * SOURCE_ID_STRIDE`
+///
+/// Above every id a sender hands out with default settings: OpenVR slots, the
+/// camera, centroids, skeleton joints, anchors and mapped ids. Devices with
+/// higher ids would take the ids of the next source, so they are left out.
+pub const SOURCE_ID_STRIDE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Set as `source` on every device of this sender
+    pub name: Arc<str>,
+    pub address: SocketAddrV4,
+    /// Transform from the source's tracking space into the common one
+    #[serde(default)]
+    pub calibration: Option<na::Isometry3<f32>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregatorConfig {
+    pub sources: Vec<SourceConfig>,
+    /// Multicast group of the merged stream
+    pub output: SocketAddrV4,
+    /// Seconds without messages after which a source's devices are left out
+    pub source_timeout_s: f32,
+    /// Rate, filters and change-only settings of the merged stream
+    pub stream: Config,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self {
+            sources: vec![],
+            output: SocketAddrV4::new([239, 0, 0, 23].into(), 7070),
+            source_timeout_s: 1.0,
+            stream: Config::default(),
+        }
+    }
+}
+
+impl AggregatorConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        load_json(path, "config")
+    }
+
+    pub fn source_timeout(&self) -> Duration {
+        Duration::from_secs_f32(self.source_timeout_s.max(0.0))
+    }
+}
+
+struct Source {
+    config: SourceConfig,
+    assembler: FrameAssembler,
+    devices: Vec<VrDevice>,
+    last_seen: Option<Instant>,
+}
+
+/// Latest devices of every source, in the common tracking space
+pub struct Aggregator {
+    sources: Vec<Source>,
+    merged: Vec<VrDevice>,
+}
+
+impl Aggregator {
+    pub fn new(sources: Vec<SourceConfig>) -> Self {
+        Self {
+            sources: sources
+                .into_iter()
+                .map(|config| Source {
+                    config,
+                    assembler: FrameAssembler::new(),
+                    devices: vec![],
+                    last_seen: None,
+                })
+                .collect(),
+            merged: vec![],
+        }
+    }
+
+    /// Take in a message from source with index `source`
+    ///
+    /// Heartbeats and event messages only mark the source as alive.
+    pub fn push(&mut self, index: usize, frame: TrackedObjects, now: Instant) {
+        let source = &mut self.sources[index];
+        source.last_seen = Some(now);
+        let frame = match source.assembler.push(frame) {
+            Some(frame) => frame,
+            None => return,
+        };
+        source.devices.clear();
+        for device in frame.trackers() {
+            if device.id() >= SOURCE_ID_STRIDE {
+                continue;
+            }
+            let mut device = device.clone();
+            device.set_id(index * SOURCE_ID_STRIDE + device.id());
+            device.set_source(Some(source.config.name.clone()));
+            if let Some(calibration) = &source.config.calibration {
+                device.transform(calibration);
+            }
+            source.devices.push(device);
+        }
+    }
+
+    /// Devices of every source heard from within `timeout`
+    pub fn merged(&mut self, timeout: Duration, now: Instant) -> &[VrDevice] {
+        self.merged.clear();
+        for source in &self.sources {
+            let alive = match source.last_seen {
+                Some(last_seen) => now.duration_since(last_seen) <= timeout,
+                None => false,
+            };
+            if alive {
+                self.merged.extend(source.devices.iter().cloned());
+            }
+        }
+        &self.merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(name: &str, calibration: Option<na::Isometry3<f32>>) -> SourceConfig {
+        SourceConfig {
+            name: name.into(),
+            address: SocketAddrV4::new([239, 0, 0, 22].into(), 7070),
+            calibration,
+        }
+    }
+
+    #[test]
+    fn test_merges_sources_into_one_space() {
+        let shift = na::Isometry3::translation(2.0, 0.0, 0.0);
+        let mut aggregator =
+            Aggregator::new(vec![source("north", None), source("south", Some(shift))]);
+        let start = Instant::now();
+        let timeout = Duration::from_secs(1);
+        aggregator.push(0, TrackedObjects::new(0, 0, vec![VrDevice::new(3)]), start);
+        let south = vec![VrDevice::new(3), VrDevice::new(SOURCE_ID_STRIDE + 3)];
+        aggregator.push(1, TrackedObjects::new(0, 0, south), start);
+
+        let merged = aggregator.merged(timeout, start);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].id(), 3);
+        assert_eq!(merged[0].source(), Some("north"));
+        assert_eq!(merged[1].id(), SOURCE_ID_STRIDE + 3);
+        assert_eq!(merged[1].source(), Some("south"));
+        assert_eq!(merged[1].position().x, 2.0);
+
+        // north keeps sending heartbeats, south goes quiet
+        let later = start + Duration::from_secs(2);
+        aggregator.push(0, TrackedObjects::heartbeat(1, 0), later);
+        let merged = aggregator.merged(timeout, later);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].source(), Some("north"));
+    }
+}
@@ -0,0 +1,217 @@
+//! Named static points captured with a controller
+//!
+//! The `capture_anchor` control command arms a capture under a name. The next
+//! trigger press on any controller stores that controller's pose as the
+//! anchor, so touching a workstation corner with the controller and pulling
+//! the trigger marks it. Anchors are saved to a file and published in every
+//! frame as virtual devices of class `Other`, aliased with their name.
+//!
+//! Capturing happens on the sampling thread, which sees the trigger, while
+//! frames are built on the publishing thread. Both share one [`Anchors`].
+
+use crate::config::load_json;
+use crate::error::Result;
+use crate::tracking_messages::{VrDevice, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Most anchors kept, so their ids stay within `first_id..first_id + MAX_ANCHORS`
+pub const MAX_ANCHORS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnchorConfig {
+    /// Where anchors are saved, read once at startup
+    pub file: PathBuf,
+    /// Device id of the first anchor, the others follow in the order they were captured
+    ///
+    /// The default keeps all [`MAX_ANCHORS`] below 256, which compact frames need.
+    pub first_id: usize,
+}
+
+impl Default for AnchorConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("anchors.json"),
+            first_id: 224,
+        }
+    }
+}
+
+/// One captured point, in raw tracking space
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Anchor {
+    pub name: Arc<str>,
+    pub position: na::Point3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+}
+
+#[derive(Debug, Default)]
+struct AnchorState {
+    path: Option<PathBuf>,
+    anchors: Vec<Anchor>,
+    /// Name of the next capture
+    armed: Option<Arc<str>>,
+    /// A trigger was down in the previous sample, captures need a fresh press
+    trigger_held: bool,
+    /// Bumped on every change so the publishing side knows to rebuild its devices
+    version: u64,
+}
+
+/// Anchors shared between the sampling and the publishing thread
+#[derive(Debug, Clone, Default)]
+pub struct Anchors {
+    state: Arc<Mutex<AnchorState>>,
+}
+
+impl Anchors {
+    /// Anchors kept in memory only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load anchors from `path`, starting empty if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let anchors = if path.exists() {
+            load_json(path, "anchors")?
+        } else {
+            vec![]
+        };
+        let state = AnchorState {
+            path: Some(path.to_owned()),
+            anchors,
+            ..Default::default()
+        };
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Capture the next trigger press under `name`, replacing an anchor of that name
+    pub fn arm(&self, name: Arc<str>) {
+        self.state.lock().unwrap().armed = Some(name);
+    }
+
+    /// Feed the controller whose trigger is down in the current sample, if any
+    ///
+    /// Returns the name of the anchor captured by this press.
+    pub fn trigger(&self, pulled: Option<&VrDevice>) -> Option<Arc<str>> {
+        let mut state = self.state.lock().unwrap();
+        let pressed = !state.trigger_held && pulled.is_some();
+        state.trigger_held = pulled.is_some();
+        let device = pulled.filter(|device| pressed && device.tracked())?;
+        let name = state.armed.take()?;
+        let anchor = Anchor {
+            name: name.clone(),
+            position: *device.position(),
+            rotation: *device.rotation(),
+        };
+        let full = state.anchors.len() >= MAX_ANCHORS;
+        match state.anchors.iter_mut().find(|anchor| anchor.name == name) {
+            Some(existing) => *existing = anchor,
+            None if full => {
+                eprintln!(
+                    "Not capturing anchor {}, there are already {}",
+                    name, MAX_ANCHORS
+                );
+                return None;
+            }
+            None => state.anchors.push(anchor),
+        }
+        state.version += 1;
+        if let Err(error) = state.save() {
+            eprintln!("Failed to save anchors: {}", error.describe());
+        }
+        Some(name)
+    }
+
+    /// Forget the anchor `name`, false if there is none
+    pub fn remove(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let count = state.anchors.len();
+        state.anchors.retain(|anchor| &*anchor.name != name);
+        if state.anchors.len() == count {
+            return false;
+        }
+        state.version += 1;
+        if let Err(error) = state.save() {
+            eprintln!("Failed to save anchors: {}", error.describe());
+        }
+        true
+    }
+
+    /// Changes with every capture and removal
+    pub fn version(&self) -> u64 {
+        self.state.lock().unwrap().version
+    }
+
+    /// Anchors as tracked devices, with ids from `first_id`
+    pub fn devices(&self, first_id: usize) -> Vec<VrDevice> {
+        let state = self.state.lock().unwrap();
+        state
+            .anchors
+            .iter()
+            .enumerate()
+            .map(|(index, anchor)| {
+                let mut device = VrDevice::new(first_id + index);
+                device.set_class(VrDeviceClass::Other);
+                device.set_alias(Some(anchor.name.clone()));
+                device.set_pose(true, anchor.position, anchor.rotation);
+                device
+            })
+            .collect()
+    }
+}
+
+impl AnchorState {
+    fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // write next to the file and rename, so a crash can't leave half a file
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_string_pretty(&self.anchors)?)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_on_fresh_press() {
+        let path = std::env::temp_dir().join(format!("anchors-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let anchors = Anchors::load(&path).unwrap();
+        let mut controller = VrDevice::new(3);
+        controller.set_pose(
+            true,
+            na::Point3::new(1.0, 0.8, -0.5),
+            na::UnitQuaternion::identity(),
+        );
+
+        // unarmed presses and a trigger held down while arming don't capture
+        assert!(anchors.trigger(Some(&controller)).is_none());
+        anchors.arm("desk".into());
+        assert!(anchors.trigger(Some(&controller)).is_none());
+        assert!(anchors.trigger(None).is_none());
+        assert_eq!(anchors.trigger(Some(&controller)).as_deref(), Some("desk"));
+        assert_eq!(anchors.version(), 1);
+
+        let loaded = Anchors::load(&path).unwrap();
+        let devices = loaded.devices(224);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id(), 224);
+        assert_eq!(devices[0].alias(), Some("desk"));
+        assert_eq!(devices[0].position(), controller.position());
+        assert!(loaded.remove("desk"));
+        assert!(!loaded.remove("desk"));
+        assert!(Anchors::load(&path).unwrap().devices(300).is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,350 @@
+//! HMAC authentication of UDP and multicast payloads
+//!
+//! Lighter than [encryption](crate::encryption): payloads stay readable, but
+//! receivers with the key drop frames that were spoofed, replayed or corrupted
+//! on the way. An HMAC-SHA256 tag and a trailer are appended to each payload:
+//!
+//! ```text
+//! payload | 4 byte sender | 8 byte time | 8 byte counter | 32 byte tag | "OA" | version 2
+//! ```
+//!
+//! The tag covers everything but itself. The sender is a random id picked at
+//! startup, the time is in milliseconds since the Unix epoch and the counter
+//! counts the packets of the sender. Receivers drop packets older than
+//! `max_age_s` by their own clock, which catches packets captured in an
+//! earlier session, and packets whose counter they already saw from that
+//! sender, which catches replays within the session. Sender and receiver
+//! clocks have to agree to within `max_age_s`.
+//!
+//! With encryption enabled as well, payloads are signed first and then
+//! encrypted.
+
+use crate::config::read_file;
+use crate::error::{Error, Result};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 2] = b"OA";
+const VERSION: u8 = 2;
+const TRAILER_SIZE: usize = 3;
+const TAG_SIZE: usize = 32;
+/// Sender id, time and counter
+const STAMP_SIZE: usize = 4 + 8 + 8;
+/// Counters behind the highest one of a sender that are still accepted once
+const REPLAY_WINDOW: u64 = 64;
+/// Senders a receiver keeps replay windows for
+const MAX_SENDERS: usize = 256;
+/// Bytes signing adds to a payload
+pub const OVERHEAD: usize = STAMP_SIZE + TAG_SIZE + TRAILER_SIZE;
+
+/// Where the shared secret comes from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthenticationConfig {
+    /// Any string, longer is better
+    pub key: Option<String>,
+    /// File containing the key, keeps it out of the config
+    pub key_file: Option<PathBuf>,
+    /// Oldest packet a receiver accepts, `null` to accept any age
+    pub max_age_s: Option<f32>,
+}
+
+impl Default for AuthenticationConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            key_file: None,
+            max_age_s: Some(DEFAULT_MAX_AGE.as_secs_f32()),
+        }
+    }
+}
+
+/// Oldest packet a receiver accepts unless configured otherwise
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(5);
+
+impl AuthenticationConfig {
+    pub fn authenticator(&self) -> Result<Authenticator> {
+        if let Some(max_age_s) = self.max_age_s {
+            if !(max_age_s > 0.0 && max_age_s.is_finite()) {
+                return Err(Error::config(format!(
+                    "max_age_s must be positive, is {}",
+                    max_age_s
+                )));
+            }
+        }
+        let mut authenticator = match (&self.key, &self.key_file) {
+            (Some(key), None) => Authenticator::new(key.as_bytes())?,
+            (None, Some(path)) => Authenticator::from_file(path)?,
+            _ => return Err(Error::config("Set exactly one of key and key_file")),
+        };
+        authenticator.set_max_age(self.max_age_s.map(Duration::from_secs_f32));
+        Ok(authenticator)
+    }
+}
+
+/// Whether `bytes` end with an authentication trailer
+pub fn is_authenticated(bytes: &[u8]) -> bool {
+    bytes.len() >= OVERHEAD && bytes[bytes.len() - TRAILER_SIZE..][..2] == *MAGIC
+}
+
+/// Counters seen from one sender
+#[derive(Clone, Copy)]
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `n` is set when counter `highest - n` was seen
+    seen: u64,
+    /// Time of the newest packet, to forget the sender that was silent longest
+    last_ms: u64,
+}
+
+impl ReplayWindow {
+    /// Mark `counter` as seen, false if it was seen before or is too old to tell
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                1
+            } else {
+                self.seen << shift | 1
+            };
+            self.highest = counter;
+            return true;
+        }
+        let behind = self.highest - counter;
+        if behind >= REPLAY_WINDOW || self.seen & 1 << behind != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Signs and checks payloads with one key
+///
+/// Every sender needs its own `Authenticator`, cloning picks a new sender id
+/// and forgets the packets seen so far.
+pub struct Authenticator {
+    mac: Hmac<Sha256>,
+    sender: [u8; 4],
+    counter: u64,
+    max_age: Option<Duration>,
+    windows: HashMap<[u8; 4], ReplayWindow>,
+}
+
+impl Clone for Authenticator {
+    fn clone(&self) -> Self {
+        Self {
+            max_age: self.max_age,
+            ..Self::with_mac(self.mac.clone())
+        }
+    }
+}
+
+impl Authenticator {
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.is_empty() {
+            return Err(Error::config("Authentication key is empty"));
+        }
+        // HMAC takes keys of any length
+        let mac = Hmac::new_from_slice(key).expect("HMAC accepts any key length");
+        Ok(Self::with_mac(mac))
+    }
+
+    fn with_mac(mac: Hmac<Sha256>) -> Self {
+        let mut sender = [0; 4];
+        getrandom::getrandom(&mut sender).expect("No random numbers from the OS");
+        Self {
+            mac,
+            sender,
+            counter: 0,
+            max_age: Some(DEFAULT_MAX_AGE),
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Key is the file contents without surrounding whitespace
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let key = read_file(path, "key")?;
+        Self::new(key.trim().as_bytes()).map_err(|error| {
+            Error::config_caused(format!("Invalid key in {}", path.display()), error)
+        })
+    }
+
+    /// Oldest packet `verify` accepts, `None` to accept any age
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        self.max_age = max_age;
+    }
+
+    fn hmac(&self, parts: &[&[u8]]) -> Hmac<Sha256> {
+        let mut mac = self.mac.clone();
+        for part in parts {
+            mac.update(part);
+        }
+        mac
+    }
+
+    /// HMAC-SHA256 of `payload` on its own, without a trailer
+    pub fn tag(&self, payload: &[u8]) -> [u8; TAG_SIZE] {
+        self.hmac(&[payload]).finalize().into_bytes().into()
+    }
+
+    /// Append the sender, time, counter, tag and trailer to `payload`
+    pub fn sign(&mut self, payload: &mut Vec<u8>) {
+        payload.extend_from_slice(&self.sender);
+        payload.extend_from_slice(&now_ms().to_le_bytes());
+        payload.extend_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        let trailer = [MAGIC[0], MAGIC[1], VERSION];
+        let tag = self.hmac(&[payload, &trailer]).finalize().into_bytes();
+        payload.extend_from_slice(&tag);
+        payload.extend_from_slice(&trailer);
+    }
+
+    /// Check the tag, age and counter of `packet` and return the payload without them
+    pub fn verify<'a>(&mut self, packet: &'a [u8]) -> Result<&'a [u8]> {
+        if !is_authenticated(packet) {
+            return Err(Error::Rejected("Packet isn't authenticated".to_owned()));
+        }
+        let (rest, trailer) = packet.split_at(packet.len() - TRAILER_SIZE);
+        if trailer[2] != VERSION {
+            return Err(Error::Rejected(format!(
+                "Unsupported authentication version {}",
+                trailer[2]
+            )));
+        }
+        let (signed, received) = rest.split_at(rest.len() - TAG_SIZE);
+        // compares in constant time
+        if self.hmac(&[signed, trailer]).verify(received).is_err() {
+            return Err(Error::Rejected(
+                "Packet failed authentication, wrong key or tampered".to_owned(),
+            ));
+        }
+        let (payload, stamp) = signed.split_at(signed.len() - STAMP_SIZE);
+        let mut sender = [0; 4];
+        sender.copy_from_slice(&stamp[..4]);
+        let mut number = [0; 8];
+        number.copy_from_slice(&stamp[4..12]);
+        let sent_ms = u64::from_le_bytes(number);
+        number.copy_from_slice(&stamp[12..]);
+        let counter = u64::from_le_bytes(number);
+        let now_ms = now_ms();
+        if let Some(max_age) = self.max_age {
+            let age_ms = now_ms.saturating_sub(sent_ms);
+            if age_ms > max_age.as_millis() as u64 {
+                return Err(Error::Rejected(format!(
+                    "Packet is {} ms old, a replay or a clock off",
+                    age_ms
+                )));
+            }
+        }
+        if !self.windows.contains_key(&sender) && self.windows.len() >= MAX_SENDERS {
+            let oldest = self
+                .windows
+                .iter()
+                .min_by_key(|(_, window)| window.last_ms)
+                .map(|(sender, _)| *sender);
+            if let Some(oldest) = oldest {
+                self.windows.remove(&oldest);
+            }
+        }
+        let window = self.windows.entry(sender).or_insert(ReplayWindow {
+            highest: counter,
+            seen: 0,
+            last_ms: now_ms,
+        });
+        if !window.accept(counter) {
+            return Err(Error::Rejected(format!(
+                "Packet {} was already received, a replay",
+                counter
+            )));
+        }
+        window.last_ms = now_ms;
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_hmac_vectors_and_verify() {
+        // test case 2 and 6 of RFC 4231
+        let mut jefe = Authenticator::new(b"Jefe").unwrap();
+        assert_eq!(
+            hex(&jefe.tag(b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let mut long = Authenticator::new(&[0xaa; 131]).unwrap();
+        assert_eq!(
+            hex(&long.tag(b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+
+        let mut sender = jefe.clone();
+        let mut packet = b"{\"seq\":1}".to_vec();
+        sender.sign(&mut packet);
+        assert!(is_authenticated(&packet));
+        assert!(long.verify(&packet).is_err());
+        assert_eq!(jefe.verify(&packet).unwrap(), b"{\"seq\":1}");
+        packet[3] ^= 1;
+        assert!(jefe.verify(&packet).is_err());
+        assert!(jefe.verify(b"{\"seq\":1}").is_err());
+        assert!(Authenticator::new(b"").is_err());
+    }
+
+    #[test]
+    fn test_replayed_and_stale_packets_are_rejected() {
+        let mut sender = Authenticator::new(b"key").unwrap();
+        let mut receiver = sender.clone();
+        let packets: Vec<Vec<u8>> = (0..3)
+            .map(|_| {
+                let mut packet = b"{}".to_vec();
+                sender.sign(&mut packet);
+                packet
+            })
+            .collect();
+        // reordered within the window is fine, every packet only once
+        assert!(receiver.verify(&packets[1]).is_ok());
+        assert!(receiver.verify(&packets[0]).is_ok());
+        assert!(receiver.verify(&packets[1]).is_err());
+        assert!(receiver.verify(&packets[2]).is_ok());
+        assert!(receiver.verify(&packets[2]).is_err());
+        // another sender with the same key has its own counters
+        let mut other = sender.clone();
+        let mut packet = b"{}".to_vec();
+        other.sign(&mut packet);
+        assert!(receiver.verify(&packet).is_ok());
+
+        // re-signed with a time from an hour ago
+        let mut stale = b"{}".to_vec();
+        sender.sign(&mut stale);
+        let time = stale.len() - OVERHEAD + 4;
+        let hour_ago = now_ms() - 3_600_000;
+        stale[time..time + 8].copy_from_slice(&hour_ago.to_le_bytes());
+        let signed = stale.len() - TAG_SIZE - TRAILER_SIZE;
+        let tag = sender
+            .hmac(&[&stale[..signed], &stale[signed + TAG_SIZE..]])
+            .finalize()
+            .into_bytes();
+        stale[signed..signed + TAG_SIZE].copy_from_slice(&tag);
+        assert!(receiver.verify(&stale).is_err());
+        receiver.set_max_age(None);
+        assert!(receiver.verify(&stale).is_ok());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::tracking_messages::{DeviceRole, DisplayInfo, TrackingState, Universe, VrDeviceClass};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Controller buttons the tracker reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControllerButton {
+    Trigger,
+    /// Application menu button above the touchpad
+    Menu,
+    Grip,
+    /// Touchpad or joystick click
+    Touchpad,
+}
+
+/// Raw state of one device as reported by the tracking runtime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceSample {
+    pub index: usize,
+    pub connected: bool,
+    pub pose_valid: bool,
+    /// 3x4 device to absolute tracking matrix
+    pub pose: [[f32; 4]; 3],
+    pub class: VrDeviceClass,
+    pub state: TrackingState,
+    /// Meters per second in tracking space
+    pub velocity: [f32; 3],
+    /// Radians per second about each axis of tracking space
+    pub angular_velocity: [f32; 3],
+}
+
+impl DeviceSample {
+    pub fn new(index: usize, class: VrDeviceClass, pose: [[f32; 4]; 3]) -> Self {
+        Self {
+            index,
+            connected: true,
+            pose_valid: true,
+            pose,
+            class,
+            state: TrackingState::Ok,
+            velocity: [0.0; 3],
+            angular_velocity: [0.0; 3],
+        }
+    }
+}
+
+/// Source of device poses
+///
+/// Abstracts OpenVR so the device manager can be driven by a fake runtime in tests
+pub trait TrackingBackend {
+    /// Fill `samples` with current state of every device slot
+    ///
+    /// The buffer is reused between calls so implementations should clear it, not replace it
+    fn poll(&mut self, samples: &mut Vec<DeviceSample>);
+
+    fn serial(&self, index: usize) -> Option<String>;
+
+    /// Battery charge between 0 and 1
+    fn battery(&self, _index: usize) -> Option<f32> {
+        None
+    }
+
+    /// Id of the wireless dongle, `None` for wired devices
+    fn wireless_dongle(&self, _index: usize) -> Option<String> {
+        None
+    }
+
+    /// Role assigned to a tracker, other devices get theirs from their class
+    fn tracker_role(&self, _index: usize) -> Option<DeviceRole> {
+        None
+    }
+
+    /// Corners of the play area rectangle
+    fn play_area(&self) -> Option<[[f32; 3]; 4]> {
+        None
+    }
+
+    /// Headset display parameters, `None` without a headset
+    fn display(&self) -> Option<DisplayInfo> {
+        None
+    }
+
+    /// Short vibration on a controller, ignored by devices without haptics
+    fn haptic_pulse(&mut self, _index: usize) {}
+
+    /// Whether a button of a controller is pressed
+    fn button_pressed(&self, _index: usize, _button: ControllerButton) -> bool {
+        false
+    }
+
+    /// Fill `poses` with the pose of every device slot in another tracking universe
+    ///
+    /// Backends without other universes leave it empty
+    fn poll_universe(&mut self, _universe: Universe, poses: &mut Vec<[[f32; 4]; 3]>) {
+        poses.clear();
+    }
+
+    /// Frames the runtime has counted since it started, `None` if it doesn't count them
+    ///
+    /// Stops advancing when the runtime hangs, even while no device is tracked.
+    fn frame_count(&self) -> Option<u64> {
+        None
+    }
+
+    /// Block until the compositor's next frame
+    ///
+    /// False when the backend isn't paced by a compositor, or the wait failed, and the caller has to sleep instead
+    fn wait_for_frame(&mut self) -> bool {
+        false
+    }
+}
+
+pub const IDENTITY_POSE: [[f32; 4]; 3] = [[1., 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 0.]];
+
+/// Backend that plays back scripted samples
+///
+/// Each call to `poll` returns the next scripted frame.
+/// Once the script runs out the last frame is repeated.
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    frames: VecDeque<Vec<DeviceSample>>,
+    last_frame: Vec<DeviceSample>,
+    serials: HashMap<usize, String>,
+    batteries: HashMap<usize, f32>,
+    dongles: HashMap<usize, String>,
+    roles: HashMap<usize, DeviceRole>,
+    buttons: HashMap<(usize, ControllerButton), bool>,
+    universes: HashMap<Universe, Vec<[[f32; 4]; 3]>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_frame(&mut self, samples: Vec<DeviceSample>) {
+        self.frames.push_back(samples);
+    }
+
+    pub fn set_serial(&mut self, index: usize, serial: &str) {
+        self.serials.insert(index, serial.to_owned());
+    }
+
+    pub fn set_battery(&mut self, index: usize, battery: f32) {
+        self.batteries.insert(index, battery);
+    }
+
+    pub fn set_dongle(&mut self, index: usize, dongle: &str) {
+        self.dongles.insert(index, dongle.to_owned());
+    }
+
+    pub fn set_tracker_role(&mut self, index: usize, role: DeviceRole) {
+        self.roles.insert(index, role);
+    }
+
+    pub fn set_button(&mut self, index: usize, button: ControllerButton, pressed: bool) {
+        self.buttons.insert((index, button), pressed);
+    }
+
+    /// Poses by device index reported for `universe` from now on
+    pub fn set_universe(&mut self, universe: Universe, poses: Vec<[[f32; 4]; 3]>) {
+        self.universes.insert(universe, poses);
+    }
+}
+
+impl TrackingBackend for MockBackend {
+    fn poll(&mut self, samples: &mut Vec<DeviceSample>) {
+        if let Some(frame) = self.frames.pop_front() {
+            self.last_frame = frame;
+        }
+        samples.clone_from(&self.last_frame);
+    }
+
+    fn serial(&self, index: usize) -> Option<String> {
+        self.serials.get(&index).cloned()
+    }
+
+    fn battery(&self, index: usize) -> Option<f32> {
+        self.batteries.get(&index).copied()
+    }
+
+    fn wireless_dongle(&self, index: usize) -> Option<String> {
+        self.dongles.get(&index).cloned()
+    }
+
+    fn tracker_role(&self, index: usize) -> Option<DeviceRole> {
+        self.roles.get(&index).copied()
+    }
+
+    fn button_pressed(&self, index: usize, button: ControllerButton) -> bool {
+        self.buttons
+            .get(&(index, button))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn poll_universe(&mut self, universe: Universe, poses: &mut Vec<[[f32; 4]; 3]>) {
+        poses.clear();
+        if let Some(scripted) = self.universes.get(&universe) {
+            poses.extend_from_slice(scripted);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{bail, Result};
+use clap::Parser;
+use openvr_tracker::aggregator::{Aggregator, AggregatorConfig};
+use openvr_tracker::tracking_messages::TrackedObjects;
+use openvr_tracker::{client, identity, multicast, pipeline, signals, sink};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Merge the streams of several senders into one
+///
+/// Doesn't need OpenVR, so it can run on any host that receives all sources.
+#[derive(Parser)]
+#[clap(version = "0.0.1", author = "David M. W. <dweis7@gmail.com>")]
+struct Args {
+    /// JSON config listing the sources and the output group
+    config: PathBuf,
+}
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Hand the frames of source `index` to the main loop until shutdown
+fn receive(
+    index: usize,
+    name: &str,
+    mut receiver: multicast::MessageReceiver,
+    sender: mpsc::Sender<(usize, TrackedObjects)>,
+) -> Result<()> {
+    while !signals::shutdown_requested() {
+        if let Some(message) = receiver.recv_timeout(RECEIVE_TIMEOUT)? {
+            match client::decode_frame(message) {
+                Ok(frame) => sender.send((index, frame))?,
+                Err(error) => eprintln!("Invalid frame from {}: {}", name, error),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Args = Args::parse();
+    signals::install_shutdown_handler()?;
+    let config = AggregatorConfig::load(&args.config)?;
+    if config.sources.is_empty() {
+        bail!("No sources in {}", args.config.display());
+    }
+    if config
+        .sources
+        .iter()
+        .any(|source| source.address == config.output)
+    {
+        bail!("Output group {} is also a source", config.output);
+    }
+
+    // the keys of the stream settings are shared by the sources and the output
+    let cipher = match &config.stream.encryption {
+        Some(encryption) => Some(encryption.cipher()?),
+        None => None,
+    };
+    let authenticator = match &config.stream.authentication {
+        Some(authentication) => Some(authentication.authenticator()?),
+        None => None,
+    };
+    let (sender, messages) = mpsc::channel();
+    for (index, source) in config.sources.iter().enumerate() {
+        let mut receiver = multicast::MessageReceiver::new(source.address)?;
+        receiver.set_encryption(cipher.clone());
+        receiver.set_authentication(authenticator.clone());
+        let sender = sender.clone();
+        let name = source.name.clone();
+        eprintln!("Receiving {} on {}", name, source.address);
+        thread::spawn(move || {
+            if let Err(error) = receive(index, &name, receiver, sender) {
+                eprintln!("Stopped receiving {}: {:#}", name, error);
+            }
+        });
+    }
+    drop(sender);
+
+    let mut sinks = sink::SinkRegistry::with_queues(config.stream.sink_queues.clone());
+    let mut output = multicast::MessageSender::new(config.output)?;
+    output.set_socket_options(&config.stream.socket_options)?;
+    output.set_encryption(cipher);
+    output.set_authentication(authenticator);
+    output.set_max_packet_bytes(config.stream.max_packet_bytes);
+    sinks.register(Box::new(output));
+    eprintln!("Publishing merged stream on {}", config.output);
+    let mut aggregator = Aggregator::new(config.sources.clone());
+    let mut frame_builder = pipeline::FrameBuilder::new();
+    let sender = identity::SenderIdentity::new(config.stream.sender_name.clone());
+    eprintln!("Sender id {}", sender.id);
+    frame_builder.set_sender(Some(sender));
+    sinks.start()?;
+    while !signals::shutdown_requested() {
+        let now = Instant::now();
+        for (index, frame) in messages.try_iter() {
+            aggregator.push(index, frame, now);
+        }
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let devices = aggregator.merged(config.source_timeout(), now);
+        if let Some(frame) = frame_builder.build(devices, &config.stream, ts, now) {
+            sinks.publish(frame)?;
+        }
+        sleep(config.stream.period());
+    }
+    sinks.stop()?;
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use anyhow::Result;
+use clap::Parser;
+use openvr_tracker::tracking_messages::{Channel, TrackedObjects};
+use openvr_tracker::{
+    authentication, client, clock, encryption, mcap, multicast, reliable, signals, tui,
+};
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Listen to the tracking multicast group and print what arrives
+#[derive(Parser)]
+#[clap(version = "0.0.1", author = "David M. W. <dweis7@gmail.com>")]
+struct Args {
+    #[clap(short, long, default_value = "239.0.0.22:7070")]
+    address: SocketAddrV4,
+    /// Print each frame as an aligned device table instead of JSON
+    #[clap(short, long)]
+    pretty: bool,
+    /// Show the latest state of every device, redrawn in place
+    #[clap(short, long)]
+    refresh: bool,
+    /// Decode frames and only report the ones that fail to parse
+    #[clap(long)]
+    validate: bool,
+    /// Print receive rate, packet loss and jitter once per second
+    #[clap(short, long)]
+    stats: bool,
+    /// Don't print frames
+    #[clap(short, long)]
+    quiet: bool,
+    /// Exit after this many seconds
+    #[clap(short, long)]
+    duration: Option<f32>,
+    /// Decrypt payloads with the hex key in this file, unencrypted packets are dropped
+    #[clap(long)]
+    key_file: Option<PathBuf>,
+    /// Check the HMAC of each payload with the key in this file, unsigned packets are dropped
+    #[clap(long)]
+    auth_key_file: Option<PathBuf>,
+    /// Record decoded frames into this MCAP file
+    #[clap(long)]
+    mcap: Option<PathBuf>,
+    /// Record device poses as ROS 2 /tf messages that rosbag2 can play, instead of JSON. Requires --mcap
+    #[clap(long)]
+    mcap_ros2: bool,
+    /// Estimate the sender's clock offset from its --clock-server at this address and add it to the output
+    #[clap(long)]
+    clock: Option<SocketAddr>,
+    /// Acknowledge messages that ask for it under this receiver name, retries are dropped
+    #[clap(long)]
+    ack_as: Option<String>,
+}
+
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() -> Result<()> {
+    let args: Args = Args::parse();
+    signals::install_shutdown_handler()?;
+    let mut receiver = multicast::MessageReceiver::new(args.address)?;
+    if let Some(key_file) = &args.key_file {
+        receiver.set_encryption(Some(encryption::Cipher::from_file(key_file)?));
+    }
+    if let Some(auth_key_file) = &args.auth_key_file {
+        receiver.set_authentication(Some(authentication::Authenticator::from_file(
+            auth_key_file,
+        )?));
+    }
+    let mut recording = match &args.mcap {
+        Some(path) => {
+            let file = BufWriter::new(File::create(path)?);
+            Some(if args.mcap_ros2 {
+                mcap::McapWriter::ros2(file)?
+            } else {
+                mcap::McapWriter::new(file)?
+            })
+        }
+        None => None,
+    };
+    let clock = match args.clock {
+        Some(server) => Some(clock::ClockClient::new(server)?),
+        None => None,
+    };
+    let decode = args.pretty
+        || args.refresh
+        || args.validate
+        || args.stats
+        || recording.is_some()
+        || args.ack_as.is_some();
+    let mut deduplicator = reliable::Deduplicator::new();
+    let mut assembler = client::FrameAssembler::new();
+    let mut dashboard = if args.refresh {
+        Some(tui::Dashboard::new())
+    } else {
+        None
+    };
+    let mut interval_stats = client::StreamStats::new();
+    let mut total_stats = client::StreamStats::new();
+    let mut invalid = 0;
+    let start = Instant::now();
+    let end = args
+        .duration
+        .map(|duration| start + Duration::from_secs_f32(duration));
+    let mut last_report = Instant::now();
+    loop {
+        let timed_out = matches!(end, Some(end) if Instant::now() >= end);
+        if signals::shutdown_requested() || timed_out {
+            break;
+        }
+        let offset = clock.as_ref().and_then(|clock| clock.estimate());
+        let mut ack = None;
+        if let Some(message) = receiver.recv_timeout(STATS_INTERVAL)? {
+            if !decode {
+                if !args.quiet {
+                    print_message(message, offset);
+                }
+            } else {
+                match client::decode_frame(message) {
+                    // a retry of a message already handled only needs acknowledging again
+                    Ok(frame) if !deduplicator.first_time(&frame) => ack = Some(frame.seq()),
+                    Ok(frame) => {
+                        if frame.ack_requested() {
+                            ack = Some(frame.seq());
+                        }
+                        interval_stats.record(&frame);
+                        total_stats.record(&frame);
+                        if let Some(recording) = recording.as_mut() {
+                            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                            recording.write_frame(&frame, now.as_nanos() as u64)?;
+                        }
+                        if args.quiet || args.validate || (recording.is_some() && !args.pretty) {
+                            // nothing to print for valid frames
+                        } else if let Some(dashboard) = dashboard.as_mut() {
+                            dashboard.record(&frame);
+                        } else if args.pretty {
+                            print_table(&frame, &mut assembler, offset);
+                        } else {
+                            print_message(message, offset);
+                        }
+                    }
+                    Err(error) => {
+                        invalid += 1;
+                        eprintln!("Failed to decode frame: {}", error);
+                    }
+                }
+            }
+        }
+        if let (Some(seq), Some(name)) = (ack, &args.ack_as) {
+            receiver.acknowledge(seq, name)?;
+        }
+        if let Some(dashboard) = dashboard.as_mut() {
+            dashboard.draw_if_due()?;
+        }
+        if args.stats && last_report.elapsed() >= STATS_INTERVAL {
+            let rate = interval_stats.received() as f32 / last_report.elapsed().as_secs_f32();
+            match offset {
+                Some(offset) => eprintln!(
+                    "{:.1} frames/s, {}, clock offset {:.1} ms ±{:.1}",
+                    rate,
+                    interval_stats,
+                    offset.offset_ms,
+                    offset.delay_ms / 2.0
+                ),
+                None => eprintln!("{:.1} frames/s, {}", rate, interval_stats),
+            }
+            interval_stats = client::StreamStats::new();
+            last_report = Instant::now();
+        }
+    }
+    if let Some(recording) = recording {
+        recording.finish()?;
+    }
+    if decode {
+        let rate = total_stats.received() as f32 / start.elapsed().as_secs_f32();
+        eprintln!(
+            "Summary over {:.1} s: {:.1} frames/s, {}, {} invalid",
+            start.elapsed().as_secs_f32(),
+            rate,
+            total_stats,
+            invalid
+        );
+    }
+    if receiver.rejected() > 0 {
+        eprintln!(
+            "Rejected {} packets that failed to decrypt or authenticate",
+            receiver.rejected()
+        );
+    }
+    Ok(())
+}
+
+/// Print a message as received, with the sender's clock offset added to JSON frames when known
+fn print_message(message: &[u8], clock: Option<clock::ClockSample>) {
+    let object = clock.and_then(|clock| {
+        let mut object = serde_json::from_slice::<serde_json::Map<_, _>>(message).ok()?;
+        let offset_ms = (clock.offset_ms * 1000.0).round() / 1000.0;
+        object.insert("clock_offset_ms".to_owned(), offset_ms.into());
+        Some(serde_json::Value::Object(object))
+    });
+    match object {
+        Some(object) => println!("{}", object),
+        None => println!("{}", String::from_utf8_lossy(message)),
+    }
+}
+
+fn print_table(
+    frame: &TrackedObjects,
+    assembler: &mut client::FrameAssembler,
+    clock: Option<clock::ClockSample>,
+) {
+    if frame.channel() == Channel::Event {
+        let event = serde_json::to_string(frame).unwrap_or_default();
+        println!("event seq {}  {}", frame.seq(), event);
+        return;
+    }
+    if frame.is_heartbeat() {
+        println!("seq {}  heartbeat", frame.seq());
+        return;
+    }
+    let partial = if frame.partial() { "  partial" } else { "" };
+    let local = match clock {
+        Some(clock) => format!("  local ts {:.1}", clock.to_local_ms(frame.ts())),
+        None => String::new(),
+    };
+    println!(
+        "seq {}  ts {}{}  {} devices{}",
+        frame.seq(),
+        frame.ts(),
+        local,
+        frame.trackers().len(),
+        partial
+    );
+    // partial frames are shown with the rest of the devices filled in
+    if let Some(full) = assembler.push(frame.clone()) {
+        print!("{}", tui::device_table(full.trackers()));
+    }
+}
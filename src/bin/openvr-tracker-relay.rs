@@ -0,0 +1,118 @@
+use anyhow::{bail, Result};
+use clap::Parser;
+use openvr_tracker::socket_options::SocketOptions;
+use openvr_tracker::{authentication, client, encryption, http, multicast, signals, sink, unicast};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Receive the tracking stream from a multicast group and publish it again over unicast
+///
+/// Doesn't need OpenVR, so it can run on any host of the capture network.
+#[derive(Parser)]
+#[clap(version = "0.0.1", author = "David M. W. <dweis7@gmail.com>")]
+struct Args {
+    /// Multicast group to receive from
+    #[clap(short, long, default_value = "239.0.0.22:7070")]
+    address: SocketAddrV4,
+    /// Forward frames as UDP datagrams to this address. Can be repeated
+    #[clap(long)]
+    udp: Vec<SocketAddr>,
+    /// Stream newline-delimited JSON to TCP clients connecting to this address
+    #[clap(long)]
+    tcp: Option<SocketAddr>,
+    /// Serve web dashboard and Server-Sent Events on this address
+    #[clap(long)]
+    http: Option<SocketAddr>,
+    /// Re-publish on another multicast group
+    #[clap(long)]
+    multicast: Option<SocketAddrV4>,
+    /// Hex key shared with the sender, decrypts the input and encrypts UDP and multicast output
+    #[clap(long)]
+    key_file: Option<PathBuf>,
+    /// Key shared with the sender, checks the HMAC of the input and signs UDP and multicast output
+    #[clap(long)]
+    auth_key_file: Option<PathBuf>,
+    /// Mark UDP and multicast output with this DSCP, 46 for expedited forwarding
+    #[clap(long)]
+    dscp: Option<u8>,
+    /// Split UDP and multicast output into parts so no packet is larger than this
+    #[clap(long)]
+    max_packet_bytes: Option<usize>,
+}
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn main() -> Result<()> {
+    let args: Args = Args::parse();
+    signals::install_shutdown_handler()?;
+    let cipher = match &args.key_file {
+        Some(key_file) => Some(encryption::Cipher::from_file(key_file)?),
+        None => None,
+    };
+    let authenticator = match &args.auth_key_file {
+        Some(auth_key_file) => Some(authentication::Authenticator::from_file(auth_key_file)?),
+        None => None,
+    };
+    let socket_options = SocketOptions {
+        dscp: args.dscp,
+        ..Default::default()
+    };
+    let mut sinks = sink::SinkRegistry::new();
+    if !args.udp.is_empty() {
+        eprintln!("Relaying to UDP {:?}", args.udp);
+        let mut sender = unicast::UdpSender::new(args.udp.clone())?;
+        sender.set_socket_options(&socket_options)?;
+        sender.set_encryption(cipher.clone());
+        sender.set_authentication(authenticator.clone());
+        sender.set_max_packet_bytes(args.max_packet_bytes);
+        sinks.register(Box::new(sender));
+    }
+    if let Some(address) = args.tcp {
+        let server = unicast::TcpServer::bind(address)?;
+        eprintln!("Relaying to TCP clients on {}", server.local_addr());
+        sinks.register(Box::new(server));
+    }
+    if let Some(address) = args.http {
+        let server = http::HttpServer::bind(address)?;
+        eprintln!("Dashboard on http://{}", server.local_addr());
+        sinks.register(Box::new(server));
+    }
+    if let Some(address) = args.multicast {
+        if address == args.address {
+            bail!("Can't relay to the group it receives from");
+        }
+        let mut sender = multicast::MessageSender::new(address)?;
+        sender.set_socket_options(&socket_options)?;
+        sender.set_encryption(cipher.clone());
+        sender.set_authentication(authenticator.clone());
+        sender.set_max_packet_bytes(args.max_packet_bytes);
+        sinks.register(Box::new(sender));
+    }
+    if sinks.is_empty() {
+        bail!("Nothing to relay to, pass at least one of --udp, --tcp, --http or --multicast");
+    }
+
+    let mut receiver = multicast::MessageReceiver::new(args.address)?;
+    receiver.set_encryption(cipher);
+    receiver.set_authentication(authenticator);
+    sinks.start()?;
+    let mut invalid = 0;
+    while !signals::shutdown_requested() {
+        if let Some(message) = receiver.recv_timeout(RECEIVE_TIMEOUT)? {
+            // frames are forwarded as they are, partial frames stay partial
+            match client::decode_frame(message) {
+                Ok(frame) => sinks.publish(&frame)?,
+                Err(error) => {
+                    invalid += 1;
+                    eprintln!("Failed to decode frame: {}", error);
+                }
+            }
+        }
+    }
+    sinks.stop()?;
+    if invalid > 0 {
+        eprintln!("Skipped {} invalid frames", invalid);
+    }
+    Ok(())
+}
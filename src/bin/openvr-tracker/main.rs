@@ -0,0 +1,1333 @@
+mod servers;
+mod sinks;
+
+use anyhow::{Context, Result};
+use clap::builder::BoolishValueParser;
+use clap::{CommandFactory, Parser};
+use openvr_tracker::{
+    adaptive_rate, anchors, backend::TrackingBackend, black_box, boundary, bvh, c3d, calibration,
+    config, control, device_ids, doctor, env, events, extrapolation, http, identity, input,
+    markers, openvr_adaptor, pipeline, power, quality, recording, replay, required, sampling,
+    schema, session, signals, simulation, sink, status, summary, timestamps, tracked_camera,
+    tracking_messages, traffic, tui, watchdog,
+};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Parser)]
+#[clap(version = "0.0.1", author = "David M. W. <dweis7@gmail.com>")]
+struct Args {
+    #[clap(
+        short,
+        long,
+        default_value = "239.0.0.22:7070",
+        env = "OPENVR_TRACKER_ADDRESS"
+    )]
+    address: SocketAddrV4,
+    /// Send status, diagnostics and device events to this group instead of the pose group
+    #[clap(long, env = "OPENVR_TRACKER_EVENT_ADDRESS")]
+    event_address: Option<SocketAddrV4>,
+    /// Send from this local address, and multicast through its interface, instead of letting the OS choose
+    #[clap(long, env = "OPENVR_TRACKER_BIND")]
+    bind: Option<Ipv4Addr>,
+    /// Don't deliver sent packets to receivers on this host
+    #[clap(
+        long,
+        env = "OPENVR_TRACKER_NO_MULTICAST_LOOP",
+        value_parser = BoolishValueParser::new()
+    )]
+    no_multicast_loop: bool,
+    /// JSON config file. Changes are picked up while running
+    #[clap(short, long, env = "OPENVR_TRACKER_CONFIG")]
+    config: Option<PathBuf>,
+    /// Show live device table instead of running silently
+    #[clap(long, env = "OPENVR_TRACKER_TUI", value_parser = BoolishValueParser::new())]
+    tui: bool,
+    /// Draw 3D view of devices in a window. Requires the visualizer feature
+    #[clap(long, env = "OPENVR_TRACKER_VISUALIZE", value_parser = BoolishValueParser::new())]
+    visualize: bool,
+    /// Serve web dashboard on this address, e.g. 0.0.0.0:8080
+    #[clap(long, env = "OPENVR_TRACKER_HTTP")]
+    http: Option<SocketAddr>,
+    /// Send frames as OSC bundles to this address, e.g. 127.0.0.1:9000
+    #[clap(long, env = "OPENVR_TRACKER_OSC")]
+    osc: Option<SocketAddr>,
+    /// Accept remote control commands on the HTTP server. Requires --http
+    #[clap(long, env = "OPENVR_TRACKER_CONTROL", value_parser = BoolishValueParser::new())]
+    control: bool,
+    /// Send frames over WebRTC data channels, offers are posted to /webrtc. Requires --http and the webrtc feature
+    #[clap(long, env = "OPENVR_TRACKER_WEBRTC", value_parser = BoolishValueParser::new())]
+    webrtc: bool,
+    /// Start before SteamVR and begin streaming once it is running
+    #[clap(long, env = "OPENVR_TRACKER_WAIT_FOR_RUNTIME", value_parser = BoolishValueParser::new())]
+    wait_for_runtime: bool,
+    /// Before streaming, check that a probe sent to the multicast group comes back
+    #[clap(long, env = "OPENVR_TRACKER_SELF_TEST", value_parser = BoolishValueParser::new())]
+    self_test: bool,
+    /// Publish the pose of the headset front camera as a device of class Camera
+    #[clap(long, env = "OPENVR_TRACKER_CAMERA", value_parser = BoolishValueParser::new())]
+    camera: bool,
+    /// Also serve camera images as MJPEG on /camera. Implies --camera, requires --http
+    #[clap(long, env = "OPENVR_TRACKER_CAMERA_FRAMES", value_parser = BoolishValueParser::new())]
+    camera_frames: bool,
+    /// Sample once per headset frame, paced by the compositor. Runs as the scene application
+    #[clap(long, env = "OPENVR_TRACKER_COMPOSITOR_SYNC", value_parser = BoolishValueParser::new())]
+    compositor_sync: bool,
+    /// Publish scripted devices from a simulation file instead of OpenVR
+    #[clap(long, env = "OPENVR_TRACKER_SIMULATE")]
+    simulate: Option<PathBuf>,
+    /// Testing: fraction of multicast packets to drop
+    #[clap(long, default_value = "0", env = "OPENVR_TRACKER_IMPAIR_DROP")]
+    impair_drop: f32,
+    /// Testing: fraction of multicast packets to delay
+    #[clap(long, default_value = "0", env = "OPENVR_TRACKER_IMPAIR_DELAY")]
+    impair_delay: f32,
+    /// Testing: how long delayed packets are held back in milliseconds
+    #[clap(long, default_value = "100", env = "OPENVR_TRACKER_IMPAIR_DELAY_MS")]
+    impair_delay_ms: u64,
+    /// Testing: fraction of multicast packets to swap with the next one
+    #[clap(long, default_value = "0", env = "OPENVR_TRACKER_IMPAIR_REORDER")]
+    impair_reorder: f32,
+    /// Print a JSON status record per line on stdout, for supervisors
+    #[clap(long, env = "OPENVR_TRACKER_STATUS_JSON", value_parser = BoolishValueParser::new())]
+    status_json: bool,
+    /// Answer clock offset requests of receivers on this UDP address, e.g. 0.0.0.0:7072
+    #[clap(long, env = "OPENVR_TRACKER_CLOCK_SERVER")]
+    clock_server: Option<SocketAddr>,
+    /// Clock of frame timestamps: wall, monotonic, disciplined (wall while synchronized by NTP or PTP) or ptp
+    #[clap(long, default_value = "wall", env = "OPENVR_TRACKER_TIME_SOURCE")]
+    time_source: tracking_messages::TimeSource,
+    /// PTP hardware clock to read with --time-source ptp
+    #[clap(long, default_value = "/dev/ptp0", env = "OPENVR_TRACKER_PTP_DEVICE")]
+    ptp_device: PathBuf,
+    /// Seconds between status records
+    #[clap(long, default_value = "1", env = "OPENVR_TRACKER_STATUS_INTERVAL_S")]
+    status_interval_s: f32,
+    /// Also write the session summary printed on exit to this JSON file
+    #[clap(long, env = "OPENVR_TRACKER_SUMMARY")]
+    summary: Option<PathBuf>,
+    /// Session name written into the header of recordings
+    #[clap(long, env = "OPENVR_TRACKER_SESSION")]
+    session: Option<String>,
+    /// Subject or participant ID written into the header of recordings
+    #[clap(long, env = "OPENVR_TRACKER_SUBJECT")]
+    subject: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Parser)]
+enum Command {
+    /// Solve the transform between a tracker and what it is mounted on (AX=XB)
+    CalibrateExtrinsic(CalibrateExtrinsic),
+    /// Print the JSON Schema of the messages for generating bindings in other languages
+    ExportSchema,
+    /// Check OpenVR and the multicast group and print a pass/fail report
+    Doctor,
+    /// Send a recording from openvr-tracker-recv to the multicast group again
+    Replay(Replay),
+    /// Work with config files
+    Config(ConfigArgs),
+    /// Convert recordings from openvr-tracker-recv to other formats
+    Export(ExportArgs),
+    /// Turn off controllers and trackers through SteamVR's lighthouse_console
+    PowerOff(PowerOff),
+}
+
+#[derive(Parser)]
+struct Replay {
+    /// One JSON frame per line
+    recording: PathBuf,
+    /// Playback speed, 0.5 for slow motion, 2.0 for double speed
+    #[clap(long, default_value = "1.0")]
+    speed: f32,
+    /// Start over after the last frame
+    #[clap(long = "loop")]
+    looped: bool,
+    /// Stamp frames with the current wall clock instead of the recorded time
+    #[clap(long)]
+    restamp: bool,
+}
+
+#[derive(Parser)]
+struct ConfigArgs {
+    #[clap(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Parser)]
+enum ConfigCommand {
+    /// Check a config file without connecting to OpenVR or the network
+    Validate(ValidateConfig),
+}
+
+#[derive(Parser)]
+struct ValidateConfig {
+    config: PathBuf,
+}
+
+#[derive(Parser)]
+struct ExportArgs {
+    #[clap(subcommand)]
+    command: ExportCommand,
+}
+
+#[derive(Parser)]
+enum ExportCommand {
+    /// Write a BVH motion file, for Blender and other animation tools
+    Bvh(ExportBvh),
+    /// Write device trajectories as C3D points, for biomechanics tools
+    C3d(ExportC3d),
+}
+
+#[derive(Parser)]
+struct ExportBvh {
+    /// One JSON frame per line
+    recording: PathBuf,
+    /// JSON list of {"joint", "device", "parent"}, the first joint is the root
+    #[clap(long)]
+    mapping: PathBuf,
+    /// Where to write the BVH file
+    #[clap(long, short)]
+    output: PathBuf,
+    /// Frames per second of the BVH file
+    #[clap(long, default_value = "60")]
+    fps: f32,
+}
+
+#[derive(Parser)]
+struct ExportC3d {
+    /// One JSON frame per line
+    recording: PathBuf,
+    /// Where to write the C3D file
+    #[clap(long, short)]
+    output: PathBuf,
+    /// Frames per second of the C3D file
+    #[clap(long, default_value = "60")]
+    fps: f32,
+    /// Add points this many meters along the X, Y and Z axis of every device
+    #[clap(long, default_value = "0")]
+    axis_length_m: f32,
+}
+
+#[derive(Parser)]
+struct PowerOff {
+    /// Serials to turn off. Every connected controller and tracker if none are given
+    serials: Vec<String>,
+    /// Path of lighthouse_console, overrides the config and the SteamVR install
+    #[clap(long)]
+    lighthouse_console: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct CalibrateExtrinsic {
+    /// JSON list of {"mount": pose, "tracker": pose} captured while moving the mount
+    samples: PathBuf,
+}
+
+/// Snapshots that can queue up before sampling starts dropping them
+const SNAPSHOT_CAPACITY: usize = 16;
+const METRICS_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const CAMERA_JPEG_QUALITY: u8 = 75;
+
+type Reconnect<B> = Box<dyn Fn() -> Result<openvr_adaptor::VrDeviceManager<B>> + Send>;
+
+struct Tracker<B: TrackingBackend = openvr_adaptor::OpenVrBackend> {
+    openvr: openvr_adaptor::VrDeviceManager<B>,
+    publisher: Publisher,
+    /// Connects again after the stream went stale, if the backend can
+    reconnect: Option<Reconnect<B>>,
+}
+
+/// Everything on the publishing side of the snapshot channel
+struct Publisher {
+    sinks: sink::SinkRegistry,
+    config: config::Config,
+    watcher: Option<config::ConfigWatcher>,
+    dashboard: Option<tui::Dashboard>,
+    /// Sampling period in nanoseconds, updated on config reload
+    sample_period: Arc<AtomicU64>,
+    watchdog: watchdog::StaleWatchdog,
+    /// Set when the sampling side should reconnect to OpenVR
+    reinitialize: Arc<AtomicBool>,
+    /// Milliseconds since the epoch until which the sampling side buzzes controllers
+    buzz_until: Arc<AtomicU64>,
+    quality: quality::QualityMonitor,
+    adaptive_rate: adaptive_rate::AdaptiveRate,
+    events: events::EventDetector,
+    required: required::RequiredDeviceGuard,
+    boundary: boundary::BoundaryMonitor,
+    last_diagnostics: Instant,
+    /// Served by the HTTP server when it is enabled
+    metrics: Option<http::Metrics>,
+    last_metrics: Option<Instant>,
+    traffic: traffic::TrafficMeter,
+    sender: identity::SenderIdentity,
+    /// Commands from the HTTP control API, when enabled
+    control: Option<control::Control>,
+    /// Cleared while paused by the stop command, SIGUSR1 or the dashboard
+    streaming: bool,
+    /// Status event to announce a pause or resume with the next snapshot
+    pause_change: Option<tracking_messages::StreamStatus>,
+    started: Instant,
+    /// Headset display parameters, announced at startup and with metadata
+    display: Option<tracking_messages::DisplayInfo>,
+    play_area: Option<[[f32; 3]; 4]>,
+    last_metadata: Option<Instant>,
+    /// Serials to power off with the next snapshot, requested over the control API
+    power_off: Option<Vec<String>>,
+    /// Captured by the sampling side, when configured
+    anchors: Option<anchors::Anchors>,
+    /// Version of the anchors the frame builder has
+    anchors_version: Option<u64>,
+    /// Started and stopped by the sampling side, when configured
+    recorder: Option<recording::Recorder>,
+    /// Dumped on SIGUSR2 and by the sampling side, when configured
+    black_box: Option<black_box::BlackBox>,
+    /// Set by the control API, typed keys and the sampling side
+    markers: markers::Markers,
+    /// Header of recordings, kept current with every sample
+    session: session::Session,
+    /// Prints status records on stdout with --status-json
+    status: Option<status::StatusReporter>,
+    /// Timestamps of frames, also used by the sampling side
+    clock: timestamps::TimestampClock,
+    /// Source named in frames, changes when a disciplined clock loses sync
+    time_source: Option<tracking_messages::TimeSource>,
+    /// Pose frames published since startup, for the session summary
+    frames_published: u64,
+    /// Where to write the session summary as JSON
+    summary_path: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let variables = env::variables();
+    let args = Args::parse();
+    for variable in env::unknown(&Args::command(), config::Config::keys(), &variables) {
+        eprintln!(
+            "Ignoring {}, it is neither a flag nor a config key",
+            variable.key()
+        );
+    }
+    if let Some(Command::CalibrateExtrinsic(calibrate)) = &args.command {
+        return calibrate_extrinsic(calibrate);
+    }
+    if let Some(Command::ExportSchema) = &args.command {
+        println!("{}", serde_json::to_string_pretty(&schema::json_schema())?);
+        return Ok(());
+    }
+    if let Some(Command::Replay(replay)) = &args.command {
+        let config = config::Config::load_with_env(args.config.as_deref(), &variables)?;
+        return replay_recording(replay, args.address, args.event_address, args.bind, &config);
+    }
+    if let Some(Command::Doctor) = &args.command {
+        let report = doctor::diagnose(args.address);
+        print!("{}", report);
+        if !report.passed() {
+            anyhow::bail!("Some checks failed");
+        }
+        return Ok(());
+    }
+    if let Some(Command::PowerOff(power_off)) = &args.command {
+        return power_off_devices(power_off, args.config.as_deref());
+    }
+    if let Some(Command::Config(ConfigArgs {
+        command: ConfigCommand::Validate(validate),
+    })) = &args.command
+    {
+        return validate_config(validate);
+    }
+    if let Some(Command::Export(export)) = &args.command {
+        return export_recording(&export.command, args.config.as_deref());
+    }
+    let config = config::Config::load_checked(args.config.as_deref(), &variables)?;
+    let watcher = args.config.as_deref().map(config::ConfigWatcher::new);
+    if args.self_test {
+        let mut report = doctor::Report::new();
+        doctor::check_multicast(args.address, &mut report);
+        if let Some(failed) = report.checks.iter().find(|check| !check.passed) {
+            anyhow::bail!("Self test failed at {}: {}", failed.name, failed.detail);
+        }
+        eprintln!("Self test passed, {} is reachable", args.address);
+    }
+    let mut sinks = sink::SinkRegistry::with_queues(config.sink_queues.clone());
+    sinks.set_rates(config.sink_rates_hz.clone());
+    sinks.set_health(config.sink_health.clone());
+    sinks::register_network(&args, &config, &mut sinks)?;
+    if args.control && args.http.is_none() {
+        anyhow::bail!("--control needs --http");
+    }
+    if args.camera_frames && args.http.is_none() {
+        anyhow::bail!("--camera-frames needs --http");
+    }
+    if args.webrtc && args.http.is_none() {
+        anyhow::bail!("--webrtc needs --http");
+    }
+    if (args.camera || args.camera_frames) && args.simulate.is_some() {
+        anyhow::bail!("--camera needs OpenVR, it can't be simulated");
+    }
+    if args.compositor_sync && args.simulate.is_some() {
+        anyhow::bail!("--compositor-sync needs OpenVR, it can't be simulated");
+    }
+    if args.status_json && args.tui {
+        anyhow::bail!("--status-json can't share stdout with --tui");
+    }
+    if !args.status_interval_s.is_finite() || args.status_interval_s <= 0.0 {
+        anyhow::bail!("--status-interval-s must be positive");
+    }
+    let camera_stream = if args.camera_frames {
+        let stream = tracked_camera::CameraStream::new();
+        stream.spawn_encoder(CAMERA_JPEG_QUALITY);
+        Some(stream)
+    } else {
+        None
+    };
+    let http = match args.http {
+        Some(address) => {
+            servers::start_http(address, &args, &config, camera_stream.as_ref(), &mut sinks)?
+        }
+        None => servers::HttpServices::default(),
+    };
+    let clock = timestamps::TimestampClock::new(args.time_source, &args.ptp_device)?;
+    if args.time_source != tracking_messages::TimeSource::Wall {
+        eprintln!("Timestamps from the {:?} clock", args.time_source);
+    }
+    if let Some(address) = args.clock_server {
+        servers::start_clock(address, &clock)?;
+    }
+    signals::install_pause_handler();
+    signals::install_shutdown_handler()?;
+    let dashboard = if args.tui {
+        let mut dashboard = tui::Dashboard::new();
+        dashboard.enable_keys();
+        Some(dashboard)
+    } else {
+        None
+    };
+    let sender = identity::SenderIdentity::new(config.sender_name.clone());
+    let device_ids_file = config.device_ids_file.clone();
+    let virtual_devices = config.virtual_devices.clone();
+    let universes = config.universes.clone();
+    let input = config.input.clone();
+    let mut watchdog = watchdog::StaleWatchdog::new();
+    watchdog.set_ignored_serials(
+        virtual_devices
+            .iter()
+            .map(|device| device.serial.clone())
+            .collect(),
+    );
+    let session = session::Session::new(args.session.clone(), args.subject.clone(), sender.clone());
+    let recorder = sinks::register_recorder(&config, &session, &mut sinks);
+    let black_box = sinks::register_black_box(&config, &session, &mut sinks);
+    let markers = markers::Markers::new(config.markers.clone());
+    let anchors = match &config.anchors {
+        Some(anchor_config) => Some(anchors::Anchors::load(&anchor_config.file)?),
+        None => None,
+    };
+    eprintln!("Sender id {}", sender.id);
+    let sample_period = Arc::new(AtomicU64::new(config.sample_period().as_nanos() as u64));
+    let publisher = Publisher {
+        sinks,
+        config,
+        watcher,
+        dashboard,
+        sample_period,
+        watchdog,
+        reinitialize: Arc::default(),
+        buzz_until: Arc::default(),
+        quality: quality::QualityMonitor::new(),
+        adaptive_rate: adaptive_rate::AdaptiveRate::new(),
+        events: events::EventDetector::new(),
+        required: required::RequiredDeviceGuard::new(),
+        boundary: boundary::BoundaryMonitor::new(),
+        last_diagnostics: Instant::now(),
+        metrics: http.metrics,
+        last_metrics: None,
+        traffic: traffic::TrafficMeter::new(),
+        sender,
+        control: http.control,
+        streaming: true,
+        pause_change: None,
+        started: Instant::now(),
+        display: None,
+        play_area: None,
+        last_metadata: None,
+        power_off: None,
+        anchors,
+        anchors_version: None,
+        recorder,
+        black_box,
+        markers,
+        session,
+        status: if args.status_json {
+            Some(status::StatusReporter::new(Duration::from_secs_f32(
+                args.status_interval_s,
+            )))
+        } else {
+            None
+        },
+        clock,
+        time_source: None,
+        frames_published: 0,
+        summary_path: args.summary.clone(),
+    };
+    let device_ids = match &device_ids_file {
+        Some(path) => Some(device_ids::DeviceIdMap::load(path)?),
+        None => None,
+    };
+    let virtual_devices = if virtual_devices.is_empty() {
+        None
+    } else {
+        let directory = args
+            .config
+            .as_deref()
+            .and_then(|path| path.parent())
+            .unwrap_or_else(|| std::path::Path::new("."));
+        let simulation = simulation::SimulationConfig {
+            devices: virtual_devices,
+        };
+        Some(simulation::SimulatedBackend::new(simulation, directory)?)
+    };
+    if let Some(path) = &args.simulate {
+        let backend = simulation::SimulatedBackend::load(path)?;
+        let mut openvr = openvr_adaptor::VrDeviceManager::with_backend(backend);
+        openvr.set_device_ids(device_ids);
+        openvr.set_virtual_devices(virtual_devices);
+        openvr.set_universes(universes);
+        let tracker = Tracker {
+            openvr,
+            publisher,
+            reconnect: None,
+        };
+        return start(tracker, args.visualize);
+    }
+    // None without camera, Some(None) for the pose only
+    let camera = if args.camera || args.camera_frames {
+        Some(camera_stream)
+    } else {
+        None
+    };
+    let compositor_sync = args.compositor_sync;
+    let mut openvr = connect(args.wait_for_runtime, compositor_sync, &camera, &input)?;
+    openvr.set_device_ids(device_ids);
+    openvr.set_virtual_devices(virtual_devices);
+    openvr.set_universes(universes);
+    let tracker = Tracker {
+        openvr,
+        publisher,
+        reconnect: Some(Box::new(move || {
+            connect(true, compositor_sync, &camera, &input)
+        })),
+    };
+    start(tracker, args.visualize)
+}
+
+fn connect(
+    wait_for_runtime: bool,
+    compositor_sync: bool,
+    camera: &Option<Option<tracked_camera::CameraStream>>,
+    input: &Option<input::InputConfig>,
+) -> Result<openvr_adaptor::VrDeviceManager> {
+    let mut openvr = if wait_for_runtime {
+        openvr_adaptor::VrDeviceManager::wait_for_runtime(compositor_sync)?
+    } else {
+        openvr_adaptor::VrDeviceManager::connect(compositor_sync)?
+    };
+    if let Some(stream) = camera {
+        openvr
+            .backend_mut()
+            .enable_camera(stream.clone())
+            .context("Failed to open the headset camera")?;
+    }
+    if let Some(input) = input {
+        openvr
+            .backend_mut()
+            .enable_input(input)
+            .context("Failed to set up SteamVR input")?;
+    }
+    Ok(openvr)
+}
+
+fn start<B: TrackingBackend + Send + 'static>(tracker: Tracker<B>, visualize: bool) -> Result<()> {
+    if visualize {
+        return self::visualize(tracker);
+    }
+    run(tracker)
+}
+
+fn power_off_devices(args: &PowerOff, config: Option<&std::path::Path>) -> Result<()> {
+    let console = match (&args.lighthouse_console, config) {
+        (Some(console), _) => Some(console.clone()),
+        (None, config) => {
+            config::Config::load_with_env(config, &env::variables())?.lighthouse_console
+        }
+    };
+    let power = power::PowerControl::new(console.as_deref())?;
+    let serials = if args.serials.is_empty() {
+        let mut openvr = openvr_adaptor::VrDeviceManager::new()?;
+        openvr.update();
+        power::targets(openvr.devices(), &[])
+    } else {
+        args.serials.clone()
+    };
+    if serials.is_empty() {
+        anyhow::bail!("No controllers or trackers connected");
+    }
+    let powered_off = power.power_off_all(&serials);
+    if powered_off < serials.len() {
+        anyhow::bail!(
+            "{} of {} devices didn't power off",
+            serials.len() - powered_off,
+            serials.len()
+        );
+    }
+    Ok(())
+}
+
+fn calibrate_extrinsic(args: &CalibrateExtrinsic) -> Result<()> {
+    let text = std::fs::read_to_string(&args.samples)
+        .with_context(|| format!("Failed to read samples {}", args.samples.display()))?;
+    let pairs: Vec<calibration::PosePair> = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse samples {}", args.samples.display()))?;
+    let result = calibration::solve_ax_xb(&pairs)?;
+    eprintln!(
+        "Mean error over {} samples: {:.2} mm, {:.3} deg",
+        pairs.len(),
+        result.translation_error * 1000.0,
+        result.rotation_error.to_degrees()
+    );
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&result.mount_to_tracker)?
+    );
+    Ok(())
+}
+
+fn replay_recording(
+    args: &Replay,
+    address: SocketAddrV4,
+    event_address: Option<SocketAddrV4>,
+    bind: Option<Ipv4Addr>,
+    config: &config::Config,
+) -> Result<()> {
+    use sink::PoseSink;
+
+    let recording = replay::Recording::load(&args.recording)?;
+    let mut sender = sinks::new_sender(address, bind)?;
+    sender.set_event_address(event_address);
+    sinks::protect(&mut sender, config)?;
+    sender.set_max_packet_bytes(config.max_packet_bytes);
+    sender.set_reliable(config.reliable_events.clone())?;
+    let options = replay::ReplayOptions {
+        speed: args.speed,
+        looped: args.looped,
+        restamp: args.restamp,
+    };
+    let started = Instant::now();
+    let start_ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let mut player = replay::Player::new(&recording, options, start_ts);
+    eprintln!(
+        "Replaying {} frames at {}x",
+        recording.frames().len(),
+        args.speed
+    );
+    while let Some((offset, frame)) = player.next_frame() {
+        if let Some(wait) = offset.checked_sub(started.elapsed()) {
+            sleep(wait);
+        }
+        sender.publish(&frame)?;
+    }
+    Ok(())
+}
+
+fn export_recording(command: &ExportCommand, config: Option<&std::path::Path>) -> Result<()> {
+    let config = config::Config::load_with_env(config, &env::variables())?;
+    let (output, contents) = match command {
+        ExportCommand::Bvh(args) => {
+            let recording = replay::Recording::load(&args.recording)?;
+            let text = std::fs::read_to_string(&args.mapping)
+                .with_context(|| format!("Failed to read mapping {}", args.mapping.display()))?;
+            let joints: Vec<bvh::BvhJoint> = serde_json::from_str(&text)
+                .with_context(|| format!("Failed to parse mapping {}", args.mapping.display()))?;
+            let bvh = bvh::export(recording.frames(), &joints, &config.aliases, args.fps)?;
+            (&args.output, bvh.into_bytes())
+        }
+        ExportCommand::C3d(args) => {
+            let recording = replay::Recording::load(&args.recording)?;
+            let options = c3d::C3dOptions {
+                fps: args.fps,
+                axis_length_m: args.axis_length_m,
+            };
+            let c3d = c3d::export(recording.frames(), &config.aliases, &options)?;
+            (&args.output, c3d)
+        }
+    };
+    std::fs::write(output, contents)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    eprintln!("Wrote {}", output.display());
+    Ok(())
+}
+
+fn validate_config(args: &ValidateConfig) -> Result<()> {
+    let config = config::Config::load_with_env(Some(&args.config), &env::variables())?;
+    let mut aliases: Vec<_> = config.aliases.iter().collect();
+    aliases.sort();
+    for (serial, alias) in aliases {
+        println!("alias {} -> {}", serial, alias);
+    }
+    if !config.classes.is_empty() {
+        println!("only publishing {:?}", config.classes);
+    }
+    if let Some(calibration) = &config.calibration {
+        let (roll, pitch, yaw) = calibration.rotation.euler_angles();
+        println!(
+            "calibration translation {:?} m, roll {:.1} pitch {:.1} yaw {:.1} deg",
+            calibration.translation.vector.as_slice(),
+            roll.to_degrees(),
+            pitch.to_degrees(),
+            yaw.to_degrees()
+        );
+    }
+    if let Some(heading) = &config.heading {
+        println!(
+            "heading of -Z {:.1} deg, declination {:.1} deg",
+            heading.heading_deg, heading.declination_deg
+        );
+    }
+    for route in &config.class_routes {
+        println!("route {:?} -> {}", route.classes, route.address);
+    }
+    let problems = config.problems();
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problems in {}", problems.len(), args.config.display());
+    }
+    eprintln!("{} is valid", args.config.display());
+    Ok(())
+}
+
+#[cfg(feature = "visualizer")]
+fn visualize<B: TrackingBackend + Send + 'static>(mut tracker: Tracker<B>) -> Result<()> {
+    use openvr_tracker::visualizer;
+
+    let (channel_sink, frames) = sink::ChannelSink::new(4);
+    tracker.publisher.sinks.register(Box::new(channel_sink));
+    let play_area = tracker.openvr.backend().play_area();
+    // tracking runs in the background, windows have to stay on the main thread
+    thread::spawn(move || {
+        if let Err(error) = run(tracker) {
+            eprintln!("Tracking stopped: {}", error);
+        }
+    });
+    visualizer::run(frames, play_area)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "visualizer"))]
+fn visualize<B: TrackingBackend>(_: Tracker<B>) -> Result<()> {
+    anyhow::bail!("Built without the visualizer feature")
+}
+
+/// Sample devices on this thread and publish from another
+fn run<B: TrackingBackend>(tracker: Tracker<B>) -> Result<()> {
+    let Tracker {
+        mut openvr,
+        mut publisher,
+        reconnect,
+    } = tracker;
+    publisher.display = openvr.backend().display();
+    publisher.play_area = openvr.backend().play_area();
+    let (mut snapshots, receiver) = sampling::snapshot_channel(SNAPSHOT_CAPACITY);
+    let sample_period = publisher.sample_period.clone();
+    let reinitialize = publisher.reinitialize.clone();
+    let buzz_until = publisher.buzz_until.clone();
+    let anchors = publisher.anchors.clone();
+    let recorder = publisher.recorder.clone();
+    let black_box = publisher.black_box.clone();
+    let markers = publisher.markers.clone();
+    let clock = publisher.clock.clone();
+    let deadline = publisher.watchdog.deadline();
+    deadline.beat();
+    deadline.spawn();
+    publisher.sinks.start()?;
+    let publishing = thread::spawn(move || {
+        let result = publisher.run(receiver);
+        let stopped = publisher.sinks.stop().map_err(anyhow::Error::from);
+        let error = result.as_ref().and(stopped.as_ref()).err();
+        if let Some(error) = error {
+            publisher.report_exit(error);
+        }
+        publisher.write_summary(error);
+        stopped?;
+        result
+    });
+    loop {
+        if reinitialize.swap(false, Ordering::Relaxed) {
+            if let Some(reconnect) = &reconnect {
+                eprintln!("Reconnecting to OpenVR");
+                // only one OpenVR context can exist at a time
+                let device_ids = openvr.take_device_ids();
+                let virtual_devices = openvr.take_virtual_devices();
+                let universes = openvr.universes().to_vec();
+                drop(openvr);
+                openvr = reconnect()?;
+                openvr.set_device_ids(device_ids);
+                openvr.set_virtual_devices(virtual_devices);
+                openvr.set_universes(universes);
+            }
+        }
+        openvr.update();
+        deadline.beat();
+        let time = clock.now_ms()?;
+        if time < buzz_until.load(Ordering::Relaxed) as u128 {
+            openvr.buzz_controllers();
+        }
+        if let Some(anchors) = &anchors {
+            if let Some(name) = anchors.trigger(openvr.pulled_trigger()) {
+                eprintln!("Captured anchor {}", name);
+            }
+        }
+        if let Some(recorder) = &recorder {
+            let config = recorder.config();
+            let toggle = openvr.chord_held(config.toggle);
+            let split = matches!(config.split, Some(button) if openvr.chord_held(button));
+            if let Some(action) = recorder.update(toggle, split, Instant::now()) {
+                buzz_until.store(time as u64 + config.haptic_ms(action), Ordering::Relaxed);
+            }
+        }
+        if let Some(black_box) = &black_box {
+            let config = black_box.config();
+            let held = matches!(config.chord, Some(button) if openvr.chord_held(button));
+            if black_box.update(held, Instant::now()) {
+                buzz_until.store(time as u64 + config.haptic_ms, Ordering::Relaxed);
+            }
+        }
+        if let Some(chord) = markers.config().chord {
+            if markers.update(openvr.chord_held(chord), time, Instant::now()) {
+                buzz_until.store(time as u64 + markers.config().haptic_ms, Ordering::Relaxed);
+            }
+        }
+        if snapshots
+            .send(openvr.devices(), openvr.frame_count(), time)
+            .is_err()
+        {
+            break;
+        }
+        if !openvr.wait_for_frame() {
+            sleep(Duration::from_nanos(sample_period.load(Ordering::Relaxed)));
+        }
+    }
+    publishing
+        .join()
+        .map_err(|_| anyhow::anyhow!("Publishing thread panicked"))?
+}
+
+impl Publisher {
+    fn run(&mut self, mut snapshots: sampling::SnapshotReceiver) -> Result<()> {
+        let mut frame_builder = pipeline::FrameBuilder::new();
+        frame_builder.set_sender(Some(self.sender.clone()));
+        self.update_time_source(&mut frame_builder);
+        if self.display.is_some() || self.play_area.is_some() {
+            let time = self.clock.now_ms()?;
+            let frame = frame_builder.metadata(
+                self.display.clone(),
+                vec![],
+                self.play_area,
+                time,
+                Instant::now(),
+            );
+            self.sinks.publish(frame)?;
+        }
+        let mut period = self.config.period();
+        // devices of extrapolated frames, reused between frames
+        let mut predicted = vec![];
+        while !signals::shutdown_requested() {
+            if let Some(new_config) = self.watcher.as_mut().and_then(|watcher| watcher.poll()) {
+                self.sample_period.store(
+                    new_config.sample_period().as_nanos() as u64,
+                    Ordering::Relaxed,
+                );
+                self.sinks.set_rates(new_config.sink_rates_hz.clone());
+                self.config = new_config;
+            }
+            let rejected = self
+                .watcher
+                .as_mut()
+                .and_then(|watcher| watcher.take_error());
+            if let (Some(error), Some(status)) = (rejected, self.status.as_mut()) {
+                status.record_error(format!("Config rejected: {}", error));
+            }
+            self.apply_commands()?;
+            self.update_anchors(&mut frame_builder);
+            self.update_time_source(&mut frame_builder);
+            if let (Some(black_box), true) = (&self.black_box, signals::dump_requested()) {
+                black_box.request_dump();
+            }
+            if let Some(snapshot) = snapshots.latest()? {
+                let now = Instant::now();
+                self.quality.record(snapshot.devices(), now);
+                self.session.update(snapshot.devices(), &self.config);
+                self.traffic.update(self.sinks.traffic(), now);
+                let battery_thresholds = match &self.config.battery_alerts {
+                    Some(battery_alerts) => &battery_alerts.thresholds[..],
+                    None => &[],
+                };
+                let mut events = self
+                    .events
+                    .detect(snapshot.devices(), battery_thresholds, now);
+                if let Some(required_devices) = &self.config.required_devices {
+                    let missing = self.required.check(
+                        snapshot.devices(),
+                        required_devices,
+                        &self.config.aliases,
+                        now,
+                    );
+                    for event in &missing {
+                        let name = event.name.as_deref().unwrap_or_default();
+                        let message = format!("Required device {} is missing", name);
+                        eprintln!("{}", message);
+                        if let Some(status) = self.status.as_mut() {
+                            status.record_error(message);
+                        }
+                    }
+                    events.extend(missing);
+                }
+                if let Some(boundary) = &self.config.boundary {
+                    let near = self.boundary.check(
+                        snapshot.devices(),
+                        boundary,
+                        self.play_area,
+                        &self.config.aliases,
+                    );
+                    for event in &near {
+                        if let (events::DeviceEventKind::NearBoundary, Some(id), Some(distance)) =
+                            (event.kind, event.id, event.distance_m)
+                        {
+                            eprintln!("Device {} is {:.2} m from the boundary", id, distance);
+                        }
+                    }
+                    events.extend(near);
+                }
+                self.alert_low_battery(&events, snapshot.ts());
+                if !events.is_empty() {
+                    let frame = frame_builder.events(events, snapshot.ts(), now);
+                    self.sinks.publish(frame)?;
+                }
+                let markers = self.markers.take();
+                if !markers.is_empty() {
+                    let frame = frame_builder.markers(markers, snapshot.ts(), now);
+                    self.sinks.publish(frame)?;
+                }
+                self.publish_diagnostics(&mut frame_builder, snapshot.ts(), now)?;
+                self.publish_metadata(&mut frame_builder, snapshot, now)?;
+                self.watchdog.record(
+                    snapshot.devices(),
+                    snapshot.frame_count(),
+                    &self.config.watchdog,
+                    now,
+                );
+                self.publish_watchdog_status(&mut frame_builder, snapshot.ts(), now)?;
+                if let Some(status) = self.pause_change.take() {
+                    let frame = frame_builder.status(status, snapshot.ts(), now);
+                    self.sinks.publish(frame)?;
+                }
+                self.update_status(snapshot.devices(), snapshot.ts(), now)?;
+                if let Some(serials) = self.power_off.take() {
+                    self.power_off(snapshot.devices(), &serials);
+                }
+                period = match &self.config.adaptive_rate {
+                    Some(adaptive) => self.adaptive_rate.period(
+                        snapshot.devices(),
+                        snapshot.ts(),
+                        &self.config,
+                        adaptive,
+                    ),
+                    None => self.config.period(),
+                };
+                frame_builder.set_covariances(match &self.config.covariance {
+                    Some(covariance) => self.quality.covariances(covariance, now),
+                    None => vec![],
+                });
+                // while paused devices are still sampled, so quality and the watchdog stay current
+                let frame = if self.streaming {
+                    frame_builder.build(snapshot.devices(), &self.config, snapshot.ts(), now)
+                } else {
+                    frame_builder.paused(&self.config, snapshot.ts(), now)
+                };
+                if let Some(frame) = frame {
+                    self.sinks.publish(frame)?;
+                    if !frame.is_heartbeat() {
+                        self.frames_published += 1;
+                    }
+                    if let (false, Some(status)) = (frame.is_heartbeat(), self.status.as_mut()) {
+                        status.record_frame();
+                    }
+                    if let Some(dashboard) = self.dashboard.as_mut() {
+                        dashboard.record(frame);
+                        dashboard
+                            .set_dropped(self.sinks.dropped().map(|(_, dropped)| dropped).sum());
+                        dashboard.set_traffic(self.traffic.sinks().to_vec());
+                    }
+                }
+            } else if let (Some(extrapolation), Some(snapshot), true) = (
+                &self.config.extrapolation,
+                snapshots.current(),
+                self.streaming,
+            ) {
+                let time = self.clock.now_ms()?;
+                let elapsed_ms = time.saturating_sub(snapshot.ts());
+                if extrapolation.reaches(elapsed_ms) {
+                    let elapsed = Duration::from_millis(elapsed_ms as u64);
+                    extrapolation::extrapolate(snapshot.devices(), elapsed, &mut predicted);
+                    let frame =
+                        frame_builder.extrapolated(&predicted, &self.config, time, Instant::now());
+                    if let Some(frame) = frame {
+                        self.sinks.publish(frame)?;
+                    }
+                }
+            }
+            // a runtime hung while sampling delivers no snapshot, its missed deadline still counts
+            let time = self.clock.now_ms()?;
+            self.publish_watchdog_status(&mut frame_builder, time, Instant::now())?;
+            if let Some(dashboard) = self.dashboard.as_mut() {
+                dashboard.draw_if_due()?;
+            }
+            match &self.config.extrapolation {
+                Some(extrapolation) => sleep(period.min(extrapolation.period())),
+                None => sleep(period),
+            }
+        }
+        eprintln!("Shutting down");
+        Ok(())
+    }
+
+    /// Log and publish the watchdog's status when the stream goes stale or recovers
+    fn publish_watchdog_status(
+        &mut self,
+        frame_builder: &mut pipeline::FrameBuilder,
+        ts: u128,
+        now: Instant,
+    ) -> Result<()> {
+        let status = match self.watchdog.poll() {
+            Some(status) => status,
+            None => return Ok(()),
+        };
+        eprintln!("Tracking stream is {:?}", status);
+        if let (tracking_messages::StreamStatus::Stale, Some(reporter)) =
+            (status, self.status.as_mut())
+        {
+            reporter.record_error("Tracking stream is stale");
+        }
+        let frame = frame_builder.status(status, ts, now);
+        self.sinks.publish(frame)?;
+        if status == tracking_messages::StreamStatus::Stale && self.config.watchdog.reinitialize {
+            self.reinitialize.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn set_streaming(&mut self, streaming: bool) {
+        if streaming == self.streaming {
+            return;
+        }
+        self.streaming = streaming;
+        let status = if streaming {
+            tracking_messages::StreamStatus::Resumed
+        } else {
+            tracking_messages::StreamStatus::Paused
+        };
+        eprintln!("Publishing {:?}", status);
+        self.pause_change = Some(status);
+        if let Some(dashboard) = self.dashboard.as_mut() {
+            dashboard.set_paused(!streaming);
+        }
+    }
+
+    fn apply_commands(&mut self) -> Result<()> {
+        let typed = match &self.dashboard {
+            Some(dashboard) => dashboard.typed(),
+            None => tui::Typed::default(),
+        };
+        if signals::pause_toggled() ^ typed.pause_toggled {
+            self.set_streaming(!self.streaming);
+        }
+        for name in typed.markers {
+            self.add_marker(name.as_deref(), markers::MarkerSource::Key)?;
+        }
+        let commands = match &self.control {
+            Some(control) => control.take_commands(),
+            None => return Ok(()),
+        };
+        for command in commands {
+            match command {
+                control::ControlCommand::Start => self.set_streaming(true),
+                control::ControlCommand::Stop => self.set_streaming(false),
+                control::ControlCommand::SetRate { rate_hz } => {
+                    self.config.rate_hz = rate_hz;
+                    self.sample_period.store(
+                        self.config.sample_period().as_nanos() as u64,
+                        Ordering::Relaxed,
+                    );
+                }
+                control::ControlCommand::SetCalibration { calibration } => {
+                    self.config.calibration = calibration;
+                }
+                control::ControlCommand::Reinitialize => {
+                    self.reinitialize.store(true, Ordering::Relaxed);
+                }
+                control::ControlCommand::PowerOff { serials } => {
+                    self.power_off = Some(serials);
+                }
+                control::ControlCommand::CaptureAnchor { name } => match &self.anchors {
+                    Some(anchors) => {
+                        eprintln!("Pull a controller trigger to capture anchor {}", name);
+                        anchors.arm(name);
+                    }
+                    None => eprintln!("Can't capture anchors without anchors in the config"),
+                },
+                control::ControlCommand::RemoveAnchor { name } => {
+                    let removed = matches!(&self.anchors, Some(anchors) if anchors.remove(&name));
+                    if !removed {
+                        eprintln!("No anchor {} to remove", name);
+                    }
+                }
+                control::ControlCommand::Marker { name } => {
+                    self.add_marker(name.as_deref(), markers::MarkerSource::Control)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_marker(&mut self, name: Option<&str>, source: markers::MarkerSource) -> Result<()> {
+        let ts = self.clock.now_ms()?;
+        let name = self.markers.add(name, ts, source);
+        eprintln!("Marker {} at {}", name, ts);
+        Ok(())
+    }
+
+    /// Name the clock in frames, reporting when a disciplined clock loses or regains sync
+    fn update_time_source(&mut self, frame_builder: &mut pipeline::FrameBuilder) {
+        let time_source = self.clock.source();
+        if self.time_source == Some(time_source) {
+            return;
+        }
+        if self.time_source.is_some() || time_source != self.clock.requested() {
+            if time_source == tracking_messages::TimeSource::Wall {
+                let message = "System clock isn't synchronized, frames are marked wall clock";
+                eprintln!("{}", message);
+                if let Some(status) = self.status.as_mut() {
+                    status.record_error(message);
+                }
+            } else {
+                eprintln!("System clock is synchronized again");
+            }
+        }
+        frame_builder.set_time_source(Some(time_source));
+        self.time_source = Some(time_source);
+    }
+
+    /// Hand anchors to the frame builder after a capture or removal
+    fn update_anchors(&mut self, frame_builder: &mut pipeline::FrameBuilder) {
+        let anchors = match &self.anchors {
+            Some(anchors) => anchors,
+            None => return,
+        };
+        let version = anchors.version();
+        if self.anchors_version != Some(version) {
+            let first_id = self.config.anchors.clone().unwrap_or_default().first_id;
+            frame_builder.set_anchors(anchors.devices(first_id));
+            self.anchors_version = Some(version);
+        }
+    }
+
+    /// Power off devices in the background, lighthouse_console takes a while per device
+    fn power_off(&self, devices: &[tracking_messages::VrDevice], serials: &[String]) {
+        let serials = power::targets(devices, serials);
+        match power::PowerControl::new(self.config.lighthouse_console.as_deref()) {
+            Ok(power) => {
+                eprintln!("Powering off {} devices", serials.len());
+                thread::spawn(move || power.power_off_all(&serials));
+            }
+            Err(error) => eprintln!("Can't power off devices: {:#}", error),
+        }
+    }
+
+    fn status(
+        &self,
+        devices: &[tracking_messages::VrDevice],
+        now: Instant,
+    ) -> control::ControlStatus {
+        control::ControlStatus {
+            streaming: self.streaming,
+            rate_hz: self.config.rate_hz,
+            sender: self.sender.id.to_string(),
+            uptime_s: now.duration_since(self.started).as_secs_f32(),
+            devices: devices.iter().filter(|device| device.seen()).count(),
+            tracked: devices.iter().filter(|device| device.tracked()).count(),
+            dropped: self.sinks.dropped().map(|(_, dropped)| dropped).sum(),
+            stale: self.watchdog.is_stale(),
+            missing: match &self.config.required_devices {
+                Some(_) => self.required.missing(),
+                None => vec![],
+            },
+        }
+    }
+
+    /// Keep the control API status current and print a status record when due
+    fn update_status(
+        &mut self,
+        devices: &[tracking_messages::VrDevice],
+        ts: u128,
+        now: Instant,
+    ) -> Result<()> {
+        if let Some(control) = &self.control {
+            control.set_status(self.status(devices, now));
+        }
+        if !matches!(&self.status, Some(reporter) if reporter.is_due(now)) {
+            return Ok(());
+        }
+        let status = self.status(devices, now);
+        let sinks = status::sink_health(self.sinks.statuses(), self.traffic.sinks());
+        if let Some(reporter) = self.status.as_mut() {
+            let record = reporter.report(ts, status, sinks, now);
+            status::write_record(&mut std::io::stdout().lock(), &record)?;
+        }
+        Ok(())
+    }
+
+    /// Print a last status record for the error the publisher stopped with
+    fn report_exit(&mut self, error: &anyhow::Error) {
+        if let Some(reporter) = self.status.as_mut() {
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let record = reporter.exit_record(ts, format!("{:#}", error));
+            let _ = status::write_record(&mut std::io::stdout().lock(), &record);
+        }
+    }
+
+    /// Print the session summary on stderr and write it to the summary file, if one is set
+    fn write_summary(&self, error: Option<&anyhow::Error>) {
+        let now = Instant::now();
+        let duration = now.duration_since(self.started);
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(duration);
+        let traffic: Vec<_> = self.sinks.traffic().collect();
+        let summary = summary::SessionSummary {
+            started: started.as_millis(),
+            duration_s: duration.as_secs_f32(),
+            frames: self.frames_published,
+            devices: self.quality.report(now),
+            sinks: summary::sink_summaries(self.sinks.dropped(), &traffic),
+            error: error.map(|error| format!("{:#}", error)),
+        };
+        let _ = summary.write_text(&mut std::io::stderr().lock());
+        if let Some(path) = &self.summary_path {
+            let written = serde_json::to_vec_pretty(&summary)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(std::fs::write(path, json)?));
+            if let Err(error) = written {
+                eprintln!("Failed to write summary to {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    /// Send device details on the metadata interval, if one is set
+    fn publish_metadata(
+        &mut self,
+        frame_builder: &mut pipeline::FrameBuilder,
+        snapshot: &sampling::Snapshot,
+        now: Instant,
+    ) -> Result<()> {
+        let interval = match self.config.metadata_interval() {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        let due = match self.last_metadata {
+            Some(last_metadata) => now.duration_since(last_metadata) >= interval,
+            None => true,
+        };
+        if due {
+            self.last_metadata = Some(now);
+            let devices = self.config.metadata(snapshot.devices());
+            let frame = frame_builder.metadata(
+                self.display.clone(),
+                devices,
+                self.play_area,
+                snapshot.ts(),
+                now,
+            );
+            self.sinks.publish(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Log low battery events and buzz the controllers if configured
+    fn alert_low_battery(&self, events: &[events::DeviceEvent], ts: u128) {
+        let mut alerted = false;
+        for event in events {
+            if event.kind == events::DeviceEventKind::LowBattery {
+                let name = event.serial.as_deref().unwrap_or("unknown serial");
+                eprintln!(
+                    "Device {} ({}) battery low: {:.0}%",
+                    event.id.unwrap_or_default(),
+                    name,
+                    event.battery.unwrap_or_default() * 100.0
+                );
+                alerted = true;
+            }
+        }
+        let haptic_ms = self
+            .config
+            .battery_alerts
+            .as_ref()
+            .and_then(|battery_alerts| battery_alerts.haptic_ms);
+        if let (true, Some(haptic_ms)) = (alerted, haptic_ms) {
+            self.buzz_until
+                .store(ts as u64 + haptic_ms, Ordering::Relaxed);
+        }
+    }
+
+    /// Send device quality on its interval and keep `/metrics` current
+    fn publish_diagnostics(
+        &mut self,
+        frame_builder: &mut pipeline::FrameBuilder,
+        ts: u128,
+        now: Instant,
+    ) -> Result<()> {
+        if let Some(metrics) = &self.metrics {
+            let due = match self.last_metrics {
+                Some(last_metrics) => now.duration_since(last_metrics) >= METRICS_REFRESH_INTERVAL,
+                None => true,
+            };
+            if due {
+                let mut text = self.quality.prometheus(now);
+                text.push_str(&self.traffic.prometheus());
+                text.push_str(&self.sinks.prometheus());
+                *metrics.lock().unwrap() = text;
+                self.last_metrics = Some(now);
+            }
+        }
+        if let Some(interval) = self.config.diagnostics_interval() {
+            if now.duration_since(self.last_diagnostics) >= interval {
+                self.last_diagnostics = now;
+                for sink in self.traffic.sinks() {
+                    eprintln!("Sent {}", sink.summary());
+                }
+                let frame = frame_builder.diagnostics(self.quality.report(now), ts, now);
+                self.sinks.publish(frame)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! HTTP and clock servers, set up from flags and the config
+
+use crate::Args;
+use anyhow::Result;
+use openvr_tracker::{clock, config, control, http, sink, timestamps, tracked_camera};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// What the publisher reads from and writes to the HTTP server
+#[derive(Default)]
+pub struct HttpServices {
+    pub metrics: Option<http::Metrics>,
+    /// Commands from the control API, with `--control`
+    pub control: Option<control::Control>,
+}
+
+/// Dashboard, metrics and SSE stream, plus control API, camera frames and WebRTC if enabled
+pub fn start_http(
+    address: SocketAddr,
+    args: &Args,
+    config: &config::Config,
+    camera: Option<&tracked_camera::CameraStream>,
+    sinks: &mut sink::SinkRegistry,
+) -> Result<HttpServices> {
+    let mut server = http::HttpServer::bind(address)?;
+    if let Some(format) = config.json_formats.get("http") {
+        server.set_format(format.clone());
+    }
+    eprintln!("Dashboard on http://{}", server.local_addr());
+    let mut services = HttpServices {
+        metrics: Some(server.metrics()),
+        control: None,
+    };
+    server.set_clients(
+        config.control_clients.clone(),
+        config
+            .control_replay_window_s
+            .map(|window| Duration::from_secs_f32(window.max(0.0))),
+    );
+    if args.control {
+        eprintln!(
+            "Remote control enabled on http://{}/control",
+            server.local_addr()
+        );
+        if config.control_clients.is_empty() {
+            eprintln!("No control_clients configured, anyone on the network can send commands");
+        }
+        services.control = Some(server.enable_control());
+    }
+    if let Some(camera) = camera {
+        eprintln!("Camera frames on http://{}/camera", server.local_addr());
+        server.set_camera(camera.clone());
+    }
+    if args.webrtc {
+        start_webrtc(&server, config, sinks)?;
+    }
+    sinks.register(Box::new(server));
+    Ok(services)
+}
+
+/// Answer clock sync requests from receivers in the background
+pub fn start_clock(address: SocketAddr, clock: &timestamps::TimestampClock) -> Result<()> {
+    let server = clock::ClockServer::bind(address, clock.clone())?;
+    eprintln!("Clock server on {}", server.local_addr()?);
+    server.spawn();
+    Ok(())
+}
+
+#[cfg(feature = "webrtc")]
+fn start_webrtc(
+    server: &http::HttpServer,
+    config: &config::Config,
+    sinks: &mut sink::SinkRegistry,
+) -> Result<()> {
+    let mut data_channels =
+        openvr_tracker::data_channel::DataChannelSink::new(config.webrtc.clone())?;
+    if let Some(format) = config.json_formats.get("webrtc") {
+        data_channels.set_format(format.clone());
+    }
+    server.set_webrtc(data_channels.offer_handler());
+    eprintln!("WebRTC offers on http://{}/webrtc", server.local_addr());
+    sinks.register(Box::new(data_channels));
+    Ok(())
+}
+
+#[cfg(not(feature = "webrtc"))]
+fn start_webrtc(
+    _: &http::HttpServer,
+    _: &config::Config,
+    _: &mut sink::SinkRegistry,
+) -> Result<()> {
+    anyhow::bail!("Built without the webrtc feature")
+}
@@ -0,0 +1,106 @@
+//! Sinks frames are published to, set up from flags and the config
+
+use crate::Args;
+use anyhow::Result;
+use openvr_tracker::{
+    black_box, config, impairment, multicast, osc, recording, session, signals, sink,
+};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+pub fn new_sender(
+    address: SocketAddrV4,
+    bind: Option<Ipv4Addr>,
+) -> Result<multicast::MessageSender> {
+    let sender = match bind {
+        Some(local) => {
+            eprintln!("Sending from {}", local);
+            multicast::MessageSender::bind(address, local)?
+        }
+        None => multicast::MessageSender::new(address)?,
+    };
+    Ok(sender)
+}
+
+/// Sign and encrypt published packets as configured
+pub fn protect(sender: &mut multicast::MessageSender, config: &config::Config) -> Result<()> {
+    if let Some(authentication) = &config.authentication {
+        eprintln!("Signing UDP and multicast payloads");
+        sender.set_authentication(Some(authentication.authenticator()?));
+    }
+    if let Some(encryption) = &config.encryption {
+        eprintln!("Encrypting UDP and multicast payloads");
+        sender.set_encryption(Some(encryption.cipher()?));
+    }
+    Ok(())
+}
+
+/// Multicast sender, and OSC sender with `--osc`
+pub fn register_network(
+    args: &Args,
+    config: &config::Config,
+    sinks: &mut sink::SinkRegistry,
+) -> Result<()> {
+    let mut sender = new_sender(args.address, args.bind)?;
+    if let Some(format) = config.json_formats.get("multicast") {
+        sender.set_format(format.clone());
+    }
+    sender.set_event_address(args.event_address);
+    sender.set_multicast_loop(!args.no_multicast_loop)?;
+    sender.set_class_routes(config.class_routes.clone());
+    sender.set_compact(config.compact.clone());
+    sender.set_socket_options(&config.socket_options)?;
+    protect(&mut sender, config)?;
+    sender.set_max_packet_bytes(config.max_packet_bytes);
+    sender.set_reliable(config.reliable_events.clone())?;
+    let impair = impairment::ImpairmentConfig {
+        drop: args.impair_drop,
+        delay: args.impair_delay,
+        delay_time: Duration::from_millis(args.impair_delay_ms),
+        reorder: args.impair_reorder,
+    };
+    if impair.is_active() {
+        eprintln!("Simulating bad network: {:?}", impair);
+        sender.set_impairment(Some(impairment::Impairment::new(impair)));
+    }
+    sinks.register(Box::new(sender));
+    if let Some(address) = args.osc {
+        let mut osc = match args.bind {
+            Some(local) if address.is_ipv4() => osc::OscSender::bind(address, local.into())?,
+            _ => osc::OscSender::new(address)?,
+        };
+        osc.set_socket_options(&config.socket_options)?;
+        if let Some(addresses) = &config.osc {
+            osc.set_addresses(addresses)?;
+        }
+        sinks.register(Box::new(osc));
+    }
+    Ok(())
+}
+
+/// Recorder of the config, with its sink registered
+pub fn register_recorder(
+    config: &config::Config,
+    session: &session::Session,
+    sinks: &mut sink::SinkRegistry,
+) -> Option<recording::Recorder> {
+    let recorder = recording::Recorder::new(config.recording.clone()?);
+    let mut sink = recording::RecordingSink::new(recorder.clone());
+    sink.set_session(Some(session.clone()));
+    sinks.register(Box::new(sink));
+    Some(recorder)
+}
+
+/// Black box of the config, with its sink registered and dumped on SIGUSR2
+pub fn register_black_box(
+    config: &config::Config,
+    session: &session::Session,
+    sinks: &mut sink::SinkRegistry,
+) -> Option<black_box::BlackBox> {
+    let black_box = black_box::BlackBox::new(config.black_box.clone()?);
+    signals::install_dump_handler();
+    let mut sink = black_box::BlackBoxSink::new(black_box.clone());
+    sink.set_session(Some(session.clone()));
+    sinks.register(Box::new(sink));
+    Some(black_box)
+}
@@ -0,0 +1,270 @@
+//! Rolling buffer of the last seconds of frames, saved when asked or when
+//! something goes wrong
+//!
+//! When something weird happens nobody was recording. The black box keeps
+//! every published frame of the last `seconds` in memory and writes them to a
+//! JSONL file on SIGUSR2, a button held on both controllers or a
+//! `tracking_lost` event. It waits `after_s` before writing so the file also
+//! shows what followed, and triggers while waiting are part of the same dump.
+//!
+//! Dumps are named `blackbox-<ts>.jsonl` after the trigger time and have the
+//! format of `openvr-tracker-recv` output, after a session header.
+
+use crate::backend::ControllerButton;
+use crate::error::{Error, Result};
+use crate::events::DeviceEventKind;
+use crate::recording::Chord;
+use crate::session::Session;
+use crate::sink::PoseSink;
+use crate::tracking_messages::TrackedObjects;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlackBoxConfig {
+    /// Seconds of frames kept before a trigger
+    pub seconds: f32,
+    /// Seconds of frames after a trigger that go into the same dump
+    pub after_s: f32,
+    /// Where dumps are written, created if missing
+    pub directory: PathBuf,
+    /// Held on both controllers to dump
+    pub chord: Option<ControllerButton>,
+    /// How long the chord has to be held
+    pub hold_ms: u64,
+    /// Length of the buzz confirming a chord
+    pub haptic_ms: u64,
+    /// Dump when a device loses tracking
+    pub on_tracking_lost: bool,
+}
+
+impl Default for BlackBoxConfig {
+    fn default() -> Self {
+        Self {
+            seconds: 30.0,
+            after_s: 2.0,
+            directory: PathBuf::from("recordings"),
+            chord: None,
+            hold_ms: 1000,
+            haptic_ms: 300,
+            on_tracking_lost: true,
+        }
+    }
+}
+
+impl BlackBoxConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !(self.seconds > 0.0 && self.seconds.is_finite()) {
+            problems.push(format!(
+                "black_box seconds must be positive, is {}",
+                self.seconds
+            ));
+        }
+        if !(self.after_s >= 0.0 && self.after_s.is_finite()) {
+            problems.push(format!(
+                "black_box after_s can't be negative, is {}",
+                self.after_s
+            ));
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Default)]
+struct BlackBoxState {
+    chord: Chord,
+    requested: bool,
+}
+
+/// Dump requests shared between the sampling side, the publishing side and the sink
+#[derive(Debug, Clone)]
+pub struct BlackBox {
+    config: Arc<BlackBoxConfig>,
+    state: Arc<Mutex<BlackBoxState>>,
+}
+
+impl BlackBox {
+    pub fn new(config: BlackBoxConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            state: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &BlackBoxConfig {
+        &self.config
+    }
+
+    /// Dump with the next frame
+    pub fn request_dump(&self) {
+        self.state.lock().unwrap().requested = true;
+    }
+
+    /// Feed whether the chord is held in the current sample, true when it requested a dump
+    pub fn update(&self, held: bool, now: Instant) -> bool {
+        let hold = Duration::from_millis(self.config.hold_ms);
+        let mut state = self.state.lock().unwrap();
+        let fired = state.chord.update(held, hold, now);
+        state.requested |= fired;
+        fired
+    }
+
+    fn take_request(&self) -> bool {
+        std::mem::take(&mut self.state.lock().unwrap().requested)
+    }
+}
+
+/// Keeps the latest frames and writes them out when triggered
+pub struct BlackBoxSink {
+    black_box: BlackBox,
+    session: Option<Session>,
+    frames: VecDeque<TrackedObjects>,
+    /// Time of the trigger of the pending dump
+    triggered_at: Option<u128>,
+}
+
+impl BlackBoxSink {
+    pub fn new(black_box: BlackBox) -> Self {
+        Self {
+            black_box,
+            session: None,
+            frames: VecDeque::new(),
+            triggered_at: None,
+        }
+    }
+
+    /// Start every dump with the header of `session`
+    pub fn set_session(&mut self, session: Option<Session>) {
+        self.session = session;
+    }
+
+    fn dump(&self, triggered_at: u128) -> Result<()> {
+        let directory = &self.black_box.config.directory;
+        std::fs::create_dir_all(directory).map_err(|source| Error::Create {
+            path: directory.clone(),
+            source,
+        })?;
+        let path = directory.join(format!("blackbox-{}.jsonl", triggered_at));
+        let file = File::create(&path).map_err(|source| Error::Create {
+            path: path.clone(),
+            source,
+        })?;
+        let mut file = BufWriter::new(file);
+        if let Some(session) = &self.session {
+            session.header(triggered_at).write(&mut file)?;
+        }
+        for frame in &self.frames {
+            serde_json::to_writer(&mut file, frame)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        eprintln!(
+            "Dumped black box of {} frames to {}",
+            self.frames.len(),
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+impl PoseSink for BlackBoxSink {
+    fn name(&self) -> &str {
+        "black_box"
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        let config = &self.black_box.config;
+        let ts = frame.ts();
+        let lost = config.on_tracking_lost
+            && frame
+                .events()
+                .iter()
+                .any(|event| event.kind == DeviceEventKind::TrackingLost);
+        if (self.black_box.take_request() || lost) && self.triggered_at.is_none() {
+            self.triggered_at = Some(ts);
+        }
+        self.frames.push_back(frame.clone());
+
+        // keep `seconds` before the pending trigger, or before now
+        let window = (config.seconds * 1000.0) as u128;
+        let keep_from = self.triggered_at.unwrap_or(ts).saturating_sub(window);
+        while matches!(self.frames.front(), Some(oldest) if oldest.ts() < keep_from) {
+            self.frames.pop_front();
+        }
+
+        let after = (config.after_s * 1000.0) as u128;
+        if let Some(triggered_at) = self.triggered_at {
+            if ts >= triggered_at + after {
+                self.triggered_at = None;
+                self.dump(triggered_at)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        // don't lose a dump that was still collecting frames
+        match self.triggered_at.take() {
+            Some(triggered_at) => self.dump(triggered_at),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::DeviceEvent;
+    use crate::replay::Recording;
+
+    #[test]
+    fn test_dump_around_tracking_loss() {
+        let directory = std::env::temp_dir().join(format!("black-box-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let black_box = BlackBox::new(BlackBoxConfig {
+            seconds: 1.0,
+            after_s: 0.5,
+            directory: directory.clone(),
+            ..Default::default()
+        });
+        let mut sink = BlackBoxSink::new(black_box.clone());
+        for seq in 0..20 {
+            let mut frame = TrackedObjects::new(seq, 1000 + seq as u128 * 100, vec![]);
+            if seq == 12 {
+                frame.set_events(vec![DeviceEvent {
+                    id: Some(3),
+                    serial: None,
+                    name: None,
+                    kind: DeviceEventKind::TrackingLost,
+                    battery: None,
+                    dropout_s: None,
+                    distance_m: None,
+                }]);
+            }
+            sink.publish(&frame).unwrap();
+        }
+
+        // one second before the loss at 2200 and half a second after
+        let dump = Recording::load(&directory.join("blackbox-2200.jsonl")).unwrap();
+        let seqs: Vec<u64> = dump.frames().iter().map(|frame| frame.seq()).collect();
+        assert_eq!(seqs, (2..=17).collect::<Vec<_>>());
+
+        // a chord triggers too, stopping writes the pending dump right away
+        let start = Instant::now();
+        assert!(!black_box.update(true, start));
+        assert!(black_box.update(true, start + Duration::from_secs(1)));
+        sink.publish(&TrackedObjects::new(20, 3000, vec![]))
+            .unwrap();
+        sink.stop().unwrap();
+        let dump = Recording::load(&directory.join("blackbox-3000.jsonl")).unwrap();
+        assert_eq!(dump.frames().len(), 11);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
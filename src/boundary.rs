@@ -0,0 +1,222 @@
+//! Warnings when devices get close to the edge of the tracked volume
+//!
+//! With `boundary` set, the tracker follows how far the watched devices, the
+//! HMD unless others are listed, are from the boundary on the floor: the
+//! SteamVR play area, or a polygon from the config. A device that comes within
+//! `warn_distance_m` of the edge, or leaves the area, raises a `near_boundary`
+//! event with its `distance_m`. It raises `boundary_clear` once it is
+//! [`BOUNDARY_HYSTERESIS_M`] further in again, so an operator standing at the
+//! threshold doesn't flood the event channel.
+//!
+//! Distances are measured in the raw tracking space, before calibration, like
+//! the play area SteamVR reports.
+
+use crate::config::device_named;
+use crate::events::{DeviceEvent, DeviceEventKind};
+use crate::tracking_messages::{VrDevice, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Meters a device has to move back in past the warning distance to clear the warning
+pub const BOUNDARY_HYSTERESIS_M: f32 = 0.1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoundaryConfig {
+    /// Serials or aliases of the watched devices, the HMD if empty
+    pub devices: Vec<String>,
+    /// Meters from the edge at which a device raises a warning
+    pub warn_distance_m: f32,
+    /// Corners of the boundary on the floor as `[x, z]`, instead of the SteamVR play area
+    pub polygon: Vec<[f32; 2]>,
+}
+
+impl Default for BoundaryConfig {
+    fn default() -> Self {
+        Self {
+            devices: vec![],
+            warn_distance_m: 0.5,
+            polygon: vec![],
+        }
+    }
+}
+
+impl BoundaryConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !self.warn_distance_m.is_finite() || self.warn_distance_m < 0.0 {
+            problems.push(format!(
+                "boundary.warn_distance_m must not be negative, it is {}",
+                self.warn_distance_m
+            ));
+        }
+        if !self.polygon.is_empty() && self.polygon.len() < 3 {
+            problems.push(format!(
+                "boundary.polygon needs at least 3 corners, it has {}",
+                self.polygon.len()
+            ));
+        }
+        if self
+            .polygon
+            .iter()
+            .flatten()
+            .any(|value| !value.is_finite())
+        {
+            problems.push("boundary.polygon must only contain finite values".to_owned());
+        }
+        problems
+    }
+}
+
+/// Distance of `point` to the nearest edge of `polygon`, negative outside of it
+pub fn distance_to_edge(polygon: &[[f32; 2]], point: [f32; 2]) -> f32 {
+    let point = na::Vector2::from(point);
+    let mut distance = f32::INFINITY;
+    let mut inside = false;
+    for (index, a) in polygon.iter().enumerate() {
+        let a = na::Vector2::from(*a);
+        let b = na::Vector2::from(polygon[(index + 1) % polygon.len()]);
+        let edge = b - a;
+        let along = if edge.norm_squared() > 0.0 {
+            ((point - a).dot(&edge) / edge.norm_squared()).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        distance = distance.min((a + edge * along - point).norm());
+        // edges crossed by a ray from the point towards +x
+        if (a.y > point.y) != (b.y > point.y) {
+            let x = a.x + (point.y - a.y) / (b.y - a.y) * edge.x;
+            if point.x < x {
+                inside = !inside;
+            }
+        }
+    }
+    if inside {
+        distance
+    } else {
+        -distance
+    }
+}
+
+/// Follows which watched devices are near the boundary
+#[derive(Default)]
+pub struct BoundaryMonitor {
+    /// Ids of the devices with a warning that wasn't cleared yet
+    near: HashSet<usize>,
+}
+
+impl BoundaryMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `near_boundary` and `boundary_clear` events of the tracked devices
+    ///
+    /// Nothing without a polygon in the config or a play area from SteamVR.
+    pub fn check(
+        &mut self,
+        devices: &[VrDevice],
+        config: &BoundaryConfig,
+        play_area: Option<[[f32; 3]; 4]>,
+        aliases: &HashMap<String, Arc<str>>,
+    ) -> Vec<DeviceEvent> {
+        let polygon = match (config.polygon.is_empty(), play_area) {
+            (false, _) => config.polygon.clone(),
+            (true, Some(corners)) => corners
+                .iter()
+                .map(|corner| [corner[0], corner[2]])
+                .collect(),
+            (true, None) => return vec![],
+        };
+        let watched = |device: &VrDevice| {
+            if config.devices.is_empty() {
+                device.class() == VrDeviceClass::HMD
+            } else {
+                config
+                    .devices
+                    .iter()
+                    .any(|name| device_named(device, name, aliases))
+            }
+        };
+        let mut events = vec![];
+        for device in devices
+            .iter()
+            .filter(|device| device.tracked() && watched(device))
+        {
+            let position = device.position();
+            let distance = distance_to_edge(&polygon, [position.x, position.z]);
+            let kind = if self.near.contains(&device.id()) {
+                if distance < config.warn_distance_m + BOUNDARY_HYSTERESIS_M {
+                    continue;
+                }
+                self.near.remove(&device.id());
+                DeviceEventKind::BoundaryClear
+            } else {
+                if distance >= config.warn_distance_m {
+                    continue;
+                }
+                self.near.insert(device.id());
+                DeviceEventKind::NearBoundary
+            };
+            events.push(DeviceEvent {
+                id: Some(device.id()),
+                serial: device.serial().map(Arc::from),
+                name: None,
+                kind,
+                battery: None,
+                dropout_s: None,
+                distance_m: Some(distance),
+            });
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IDENTITY_POSE;
+
+    #[test]
+    fn test_warn_near_edge_until_back_inside() {
+        let square = [[-2.0, -2.0], [2.0, -2.0], [2.0, 2.0], [-2.0, 2.0]];
+        assert_eq!(distance_to_edge(&square, [0.0, 0.0]), 2.0);
+        assert_eq!(distance_to_edge(&square, [1.5, 0.0]), 0.5);
+        assert_eq!(distance_to_edge(&square, [3.0, 2.0]), -1.0);
+
+        let config = BoundaryConfig::default();
+        assert!(config.problems().is_empty());
+        let play_area = Some([
+            [-2.0, 0.0, -2.0],
+            [2.0, 0.0, -2.0],
+            [2.0, 0.0, 2.0],
+            [-2.0, 0.0, 2.0],
+        ]);
+        let mut tracker = VrDevice::new(3);
+        tracker.update(true, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        let mut hmd = VrDevice::new(0);
+        let at = |x: f32| {
+            let mut pose = IDENTITY_POSE;
+            pose[0][3] = x;
+            pose
+        };
+        let mut monitor = BoundaryMonitor::new();
+        let mut check = |hmd: &mut VrDevice, x| {
+            hmd.update(true, &at(x), VrDeviceClass::HMD);
+            let devices = [hmd.clone(), tracker.clone()];
+            monitor.check(&devices, &config, play_area, &HashMap::new())
+        };
+        assert!(check(&mut hmd, 1.0).is_empty());
+        let events = check(&mut hmd, 1.6);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, DeviceEventKind::NearBoundary);
+        assert_eq!(events[0].id, Some(0));
+        assert!((events[0].distance_m.unwrap() - 0.4).abs() < 1e-5);
+        assert!(check(&mut hmd, 2.5).is_empty());
+        assert!(check(&mut hmd, 1.45).is_empty());
+        let events = check(&mut hmd, 1.3);
+        assert_eq!(events[0].kind, DeviceEventKind::BoundaryClear);
+    }
+}
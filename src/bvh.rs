@@ -0,0 +1,346 @@
+//! Export of recorded sessions as BVH motion files
+//!
+//! A mapping names the device that drives each joint and the joint it hangs
+//! from. The first joint is the root and carries the position of its device,
+//! the others only rotate, relative to their parent, and are placed at a
+//! fixed offset from it: the mean of where the device was relative to its
+//! parent over the recording. Tracking space is Y up with -Z forward, which is
+//! what Blender's BVH importer expects, units are meters.
+//!
+//! Frames are resampled to a fixed rate, each sample holds the latest recorded
+//! pose. Export starts once every mapped device was tracked, devices that lose
+//! tracking afterwards keep their last tracked pose.
+
+use crate::config::device_named;
+use crate::error::{Error, Result};
+use crate::tracking_messages::TrackedObjects;
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BvhJoint {
+    /// Joint name in the BVH file, like `Hips` or `LeftFoot`
+    pub joint: String,
+    /// Serial or alias of the device that drives the joint
+    pub device: String,
+    /// Joint this one hangs from, the root if not given. Must come earlier in the mapping
+    #[serde(default)]
+    pub parent: Option<String>,
+}
+
+type Pose = (na::Point3<f32>, na::UnitQuaternion<f32>);
+
+/// Problems with a mapping, empty if it can be exported
+pub fn problems(joints: &[BvhJoint]) -> Vec<String> {
+    let mut problems = vec![];
+    if joints.is_empty() {
+        problems.push("Mapping has no joints".to_owned());
+    }
+    for (index, joint) in joints.iter().enumerate() {
+        if joint.joint.is_empty() || joint.joint.contains(char::is_whitespace) {
+            problems.push(format!("Joint name {:?} must be one word", joint.joint));
+        }
+        let earlier = &joints[..index];
+        if earlier.iter().any(|other| other.joint == joint.joint) {
+            problems.push(format!("Joint {} is mapped twice", joint.joint));
+        }
+        match &joint.parent {
+            Some(_) if index == 0 => {
+                problems.push(format!("Root joint {} can't have a parent", joint.joint))
+            }
+            Some(parent) if !earlier.iter().any(|other| &other.joint == parent) => {
+                problems.push(format!(
+                    "Parent {} of joint {} must be mapped before it",
+                    parent, joint.joint
+                ))
+            }
+            _ => (),
+        }
+    }
+    problems
+}
+
+/// BVH file of `frames` resampled to `fps`
+///
+/// Joints find their device with [`device_named`].
+pub fn export(
+    frames: &[TrackedObjects],
+    joints: &[BvhJoint],
+    aliases: &HashMap<String, Arc<str>>,
+    fps: f32,
+) -> Result<String> {
+    if let Some(problem) = problems(joints).into_iter().next() {
+        return Err(Error::InvalidInput(problem));
+    }
+    if !(fps > 0.0 && fps.is_finite()) {
+        return Err(Error::InvalidInput(format!(
+            "Frame rate must be positive, is {}",
+            fps
+        )));
+    }
+    let parents: Vec<Option<usize>> = joints
+        .iter()
+        .enumerate()
+        .map(|(index, joint)| match &joint.parent {
+            _ if index == 0 => None,
+            Some(parent) => joints.iter().position(|other| &other.joint == parent),
+            None => Some(0),
+        })
+        .collect();
+
+    let samples = resample(frames, joints, aliases, fps);
+    if samples.is_empty() {
+        let devices: Vec<_> = joints.iter().map(|joint| joint.device.as_str()).collect();
+        return Err(Error::InvalidInput(format!(
+            "{} were never all tracked at once",
+            devices.join(", ")
+        )));
+    }
+
+    // joint rotations relative to their parent, the root's in tracking space
+    let local = |sample: &[Pose], index: usize| match parents[index] {
+        Some(parent) => {
+            let inverse = sample[parent].1.inverse();
+            (
+                inverse * (sample[index].0 - sample[parent].0),
+                inverse * sample[index].1,
+            )
+        }
+        None => (sample[index].0.coords, sample[index].1),
+    };
+    let mut offsets = vec![na::Vector3::zeros(); joints.len()];
+    for sample in &samples {
+        for (index, offset) in offsets.iter_mut().enumerate().skip(1) {
+            *offset += local(sample, index).0 / samples.len() as f32;
+        }
+    }
+
+    let mut order = vec![];
+    let mut bvh = String::from("HIERARCHY\n");
+    write_joint(&mut bvh, joints, &parents, &offsets, 0, 0, &mut order)?;
+    writeln!(bvh, "MOTION")?;
+    writeln!(bvh, "Frames: {}", samples.len())?;
+    writeln!(bvh, "Frame Time: {:.6}", 1.0 / fps)?;
+    for sample in &samples {
+        let mut values = vec![];
+        for &index in &order {
+            let (position, rotation) = local(sample, index);
+            if index == 0 {
+                values.extend(position.iter().map(|value| number(*value, 5)));
+            }
+            let (z, x, y) = zxy_degrees(&rotation);
+            values.extend([z, x, y].iter().map(|value| number(*value, 4)));
+        }
+        writeln!(bvh, "{}", values.join(" "))?;
+    }
+    Ok(bvh)
+}
+
+/// Poses of every joint at `fps`, from the first frame in which all were tracked
+fn resample(
+    frames: &[TrackedObjects],
+    joints: &[BvhJoint],
+    aliases: &HashMap<String, Arc<str>>,
+    fps: f32,
+) -> Vec<Vec<Pose>> {
+    let step_ms = 1000.0 / f64::from(fps);
+    let mut latest: Vec<Option<Pose>> = vec![None; joints.len()];
+    let mut next_ms: Option<f64> = None;
+    let mut samples = vec![];
+    let mut last_ms = 0.0;
+    for frame in frames.iter().filter(|frame| !frame.is_heartbeat()) {
+        last_ms = frame.ts() as f64;
+        // samples before this frame still hold the previous one
+        while let Some(ms) = next_ms.filter(|ms| *ms < last_ms) {
+            samples.push(latest.iter().flatten().copied().collect());
+            next_ms = Some(ms + step_ms);
+        }
+        for (joint, pose) in joints.iter().zip(latest.iter_mut()) {
+            let device = frame
+                .trackers()
+                .iter()
+                .find(|device| device.tracked() && device_named(device, &joint.device, aliases));
+            if let Some(device) = device {
+                *pose = Some((*device.position(), *device.rotation()));
+            }
+        }
+        if next_ms.is_none() && latest.iter().all(Option::is_some) {
+            next_ms = Some(last_ms);
+        }
+    }
+    while let Some(ms) = next_ms.filter(|ms| *ms <= last_ms) {
+        samples.push(latest.iter().flatten().copied().collect());
+        next_ms = Some(ms + step_ms);
+    }
+    samples
+}
+
+fn write_joint(
+    bvh: &mut String,
+    joints: &[BvhJoint],
+    parents: &[Option<usize>],
+    offsets: &[na::Vector3<f32>],
+    index: usize,
+    depth: usize,
+    order: &mut Vec<usize>,
+) -> Result<()> {
+    let indent = "\t".repeat(depth);
+    order.push(index);
+    if index == 0 {
+        writeln!(bvh, "ROOT {}", joints[index].joint)?;
+    } else {
+        writeln!(bvh, "{}JOINT {}", indent, joints[index].joint)?;
+    }
+    writeln!(bvh, "{}{{", indent)?;
+    let offset = offsets[index];
+    writeln!(
+        bvh,
+        "{}\tOFFSET {} {} {}",
+        indent,
+        number(offset.x, 5),
+        number(offset.y, 5),
+        number(offset.z, 5)
+    )?;
+    if index == 0 {
+        writeln!(
+            bvh,
+            "{}\tCHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation",
+            indent
+        )?;
+    } else {
+        writeln!(bvh, "{}\tCHANNELS 3 Zrotation Xrotation Yrotation", indent)?;
+    }
+    let children: Vec<usize> = (0..joints.len())
+        .filter(|child| parents[*child] == Some(index))
+        .collect();
+    if children.is_empty() {
+        writeln!(bvh, "{}\tEnd Site", indent)?;
+        writeln!(bvh, "{}\t{{", indent)?;
+        writeln!(bvh, "{}\t\tOFFSET 0.00000 0.00000 0.00000", indent)?;
+        writeln!(bvh, "{}\t}}", indent)?;
+    }
+    for child in children {
+        write_joint(bvh, joints, parents, offsets, child, depth + 1, order)?;
+    }
+    writeln!(bvh, "{}}}", indent)?;
+    Ok(())
+}
+
+/// `value` with `precision` decimals, without the sign of values that round to zero
+fn number(value: f32, precision: usize) -> String {
+    let text = format!("{:.*}", precision, value);
+    match text.strip_prefix('-') {
+        Some(unsigned) if unsigned.chars().all(|c| c == '0' || c == '.') => unsigned.to_owned(),
+        _ => text,
+    }
+}
+
+/// Angles in degrees for the channel order Z X Y, so that R = Rz * Rx * Ry
+fn zxy_degrees(rotation: &na::UnitQuaternion<f32>) -> (f32, f32, f32) {
+    let m = rotation.to_rotation_matrix().into_inner();
+    let x = m[(2, 1)].clamp(-1.0, 1.0).asin();
+    let y = (-m[(2, 0)]).atan2(m[(2, 2)]);
+    let z = (-m[(0, 1)]).atan2(m[(1, 1)]);
+    (z.to_degrees(), x.to_degrees(), y.to_degrees())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn test_export_hierarchy_and_motion() {
+        let joints: Vec<BvhJoint> = serde_json::from_str(
+            r#"[
+                {"joint": "Hips", "device": "waist"},
+                {"joint": "Head", "device": "LHR-HEAD"},
+                {"joint": "LeftFoot", "device": "LHR-FOOT", "parent": "Hips"}
+            ]"#,
+        )
+        .unwrap();
+        let mut aliases = HashMap::new();
+        aliases.insert("LHR-WAIST".to_owned(), Arc::from("waist"));
+        let frames = vec![
+            // the foot isn't tracked yet, export starts with the next frame
+            TrackedObjects::new(
+                1,
+                1000,
+                vec![
+                    device(0).serial("LHR-WAIST").at([0.0, 1.0, 0.0]).build(),
+                    device(1).serial("LHR-HEAD").at([0.0, 1.6, 0.0]).build(),
+                ],
+            ),
+            TrackedObjects::new(
+                2,
+                1010,
+                vec![
+                    device(0).serial("LHR-WAIST").at([0.0, 1.0, 0.0]).build(),
+                    device(1).serial("LHR-HEAD").at([0.0, 1.6, 0.0]).build(),
+                    device(2).serial("LHR-FOOT").at([-0.1, 0.0, 0.0]).build(),
+                ],
+            ),
+            TrackedObjects::new(
+                3,
+                1030,
+                vec![
+                    device(0)
+                        .serial("LHR-WAIST")
+                        .at([1.0, 1.0, 0.0])
+                        .yaw(FRAC_PI_2)
+                        .build(),
+                    device(1)
+                        .serial("LHR-HEAD")
+                        .at([1.0, 1.6, 0.0])
+                        .yaw(FRAC_PI_2)
+                        .build(),
+                    device(2)
+                        .serial("LHR-FOOT")
+                        .at([1.0, 0.0, 0.1])
+                        .yaw(FRAC_PI_2)
+                        .build(),
+                ],
+            ),
+        ];
+        let bvh = export(&frames, &joints, &aliases, 100.0).unwrap();
+        let lines: Vec<&str> = bvh.lines().collect();
+        assert_eq!(lines[1], "ROOT Hips");
+        assert!(bvh.contains("\tJOINT Head\n\t{\n\t\tOFFSET 0.00000 0.60000 0.00000\n"));
+        assert!(bvh.contains("\tJOINT LeftFoot\n\t{\n\t\tOFFSET -0.10000 -1.00000 0.00000\n"));
+        assert!(bvh.contains("Frames: 3\nFrame Time: 0.010000\n"));
+        // samples at 1010 and 1020 hold the second frame, 1030 the third
+        let motion = &lines[lines.len() - 3..];
+        assert_eq!(
+            motion[1],
+            "0.00000 1.00000 0.00000 0.0000 0.0000 0.0000 0.0000 0.0000 0.0000 0.0000 0.0000 0.0000"
+        );
+        assert_eq!(
+            motion[2],
+            "1.00000 1.00000 0.00000 0.0000 0.0000 90.0000 0.0000 0.0000 0.0000 0.0000 0.0000 0.0000"
+        );
+
+        let orphan = vec![BvhJoint {
+            joint: "Hips".to_owned(),
+            device: "waist".to_owned(),
+            parent: Some("Spine".to_owned()),
+        }];
+        assert_eq!(problems(&orphan).len(), 1);
+        assert!(export(&frames[..1], &joints, &aliases, 100.0).is_err());
+    }
+
+    #[test]
+    fn test_zxy_angles() {
+        let rotation = na::UnitQuaternion::from_axis_angle(&na::Vector3::z_axis(), 0.3)
+            * na::UnitQuaternion::from_axis_angle(&na::Vector3::x_axis(), -0.4)
+            * na::UnitQuaternion::from_axis_angle(&na::Vector3::y_axis(), 1.1);
+        let (z, x, y) = zxy_degrees(&rotation);
+        assert!((z - 0.3f32.to_degrees()).abs() < 1e-3);
+        assert!((x + 0.4f32.to_degrees()).abs() < 1e-3);
+        assert!((y - 1.1f32.to_degrees()).abs() < 1e-3);
+    }
+}
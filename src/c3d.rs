@@ -0,0 +1,405 @@
+//! Export of recorded sessions as C3D files for biomechanics tools
+//!
+//! Every device in the recording becomes a point labeled with its alias or
+//! serial. Trackers have an orientation that markers lack, so optionally
+//! three more points per device sit along its X, Y and Z axes, from which
+//! tools like Visual3D or OpenSim can rebuild the segment pose.
+//!
+//! C3D files are in millimeters and Z up, tracking space is converted from its
+//! Y up by mapping (x, y, z) to (x, -z, y). Frames are resampled to a fixed
+//! rate, each sample holds the latest recorded frame. Points whose device
+//! isn't tracked in it are marked invalid, which tools show as gaps.
+//!
+//! Files use the floating point format with Intel byte order, no analog data.
+
+use crate::error::{Error, Result};
+use crate::tracking_messages::{TrackedObjects, VrDevice};
+use nalgebra as na;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const BLOCK_SIZE: usize = 512;
+/// Second byte of the header and the parameter section
+const C3D_KEY: u8 = 0x50;
+const PROCESSOR_INTEL: u8 = 84;
+/// Frame and point counts are 16 bit in the header
+const MAX_FRAMES: usize = u16::MAX as usize;
+/// Labels are dimensioned with a byte
+const MAX_POINTS: usize = u8::MAX as usize;
+
+const TYPE_CHAR: i8 = -1;
+const TYPE_INT: i8 = 2;
+const TYPE_FLOAT: i8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct C3dOptions {
+    /// Frames per second of the file
+    pub fps: f32,
+    /// Meters from the device to the points along its axes, none if 0
+    pub axis_length_m: f32,
+}
+
+impl Default for C3dOptions {
+    fn default() -> Self {
+        Self {
+            fps: 60.0,
+            axis_length_m: 0.0,
+        }
+    }
+}
+
+/// C3D file of `frames`
+///
+/// Devices without an alias of their own are labeled from `aliases` by serial.
+pub fn export(
+    frames: &[TrackedObjects],
+    aliases: &HashMap<String, Arc<str>>,
+    options: &C3dOptions,
+) -> Result<Vec<u8>> {
+    if !(options.fps > 0.0 && options.fps.is_finite()) {
+        return Err(Error::InvalidInput(format!(
+            "Frame rate must be positive, is {}",
+            options.fps
+        )));
+    }
+    let frames: Vec<&TrackedObjects> = frames
+        .iter()
+        .filter(|frame| !frame.is_heartbeat())
+        .collect();
+    let mut devices: Vec<String> = vec![];
+    for device in frames.iter().flat_map(|frame| frame.trackers()) {
+        let label = label(device, aliases);
+        if device.seen() && !devices.contains(&label) {
+            devices.push(label);
+        }
+    }
+    if devices.is_empty() {
+        return Err(Error::InvalidInput(
+            "Recording has no tracked devices".to_owned(),
+        ));
+    }
+    let axes = options.axis_length_m > 0.0;
+    let mut labels = vec![];
+    for device in &devices {
+        labels.push(device.clone());
+        if axes {
+            labels.extend(
+                ["X", "Y", "Z"]
+                    .iter()
+                    .map(|axis| format!("{}_{}", device, axis)),
+            );
+        }
+    }
+    if labels.len() > MAX_POINTS {
+        return Err(Error::InvalidInput(format!(
+            "C3D files hold at most {} points, need {}",
+            MAX_POINTS,
+            labels.len()
+        )));
+    }
+
+    // latest frame at each sample time
+    let step_ms = 1000.0 / f64::from(options.fps);
+    let first_ms = frames[0].ts() as f64;
+    let last_ms = frames[frames.len() - 1].ts() as f64;
+    let sample_count = ((last_ms - first_ms) / step_ms).floor() as usize + 1;
+    if sample_count > MAX_FRAMES {
+        return Err(Error::InvalidInput(format!(
+            "C3D files hold at most {} frames, the recording has {} at {} fps",
+            MAX_FRAMES, sample_count, options.fps
+        )));
+    }
+    let mut samples = Vec::with_capacity(sample_count);
+    let mut latest = 0;
+    for sample in 0..sample_count {
+        let ms = first_ms + sample as f64 * step_ms;
+        while latest + 1 < frames.len() && frames[latest + 1].ts() as f64 <= ms {
+            latest += 1;
+        }
+        samples.push(frames[latest]);
+    }
+
+    let points_per_device = if axes { 4 } else { 1 };
+    let parameters = parameters(&labels, samples.len(), options.fps);
+    let parameter_blocks = parameters.len() / BLOCK_SIZE;
+    let data_start = 2 + parameter_blocks;
+
+    let mut c3d =
+        Vec::with_capacity((data_start - 1) * BLOCK_SIZE + samples.len() * labels.len() * 16);
+    c3d.extend_from_slice(&header(
+        labels.len(),
+        samples.len(),
+        data_start,
+        options.fps,
+    ));
+    c3d.extend_from_slice(&parameters);
+    for frame in samples {
+        for name in &devices {
+            let device = frame
+                .trackers()
+                .iter()
+                .find(|device| device.tracked() && &label(device, aliases) == name);
+            let device = match device {
+                Some(device) => device,
+                None => {
+                    for _ in 0..points_per_device {
+                        point(&mut c3d, None);
+                    }
+                    continue;
+                }
+            };
+            let position = device.position();
+            point(&mut c3d, Some(position));
+            if axes {
+                for axis in &[na::Vector3::x(), na::Vector3::y(), na::Vector3::z()] {
+                    let tip = position + device.rotation() * axis * options.axis_length_m;
+                    point(&mut c3d, Some(&tip));
+                }
+            }
+        }
+    }
+    // the data section ends on a block boundary too
+    c3d.resize(c3d.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+    Ok(c3d)
+}
+
+/// Point label of `device`, its alias, serial or id
+fn label(device: &VrDevice, aliases: &HashMap<String, Arc<str>>) -> String {
+    let alias = device
+        .alias()
+        .or_else(|| aliases.get(device.serial()?).map(|alias| &**alias));
+    match alias.or_else(|| device.serial()) {
+        Some(name) => name.to_owned(),
+        None => format!("device_{}", device.id()),
+    }
+}
+
+/// X, Y, Z in millimeters, Z up, and the residual, -1 for invalid points
+fn point(c3d: &mut Vec<u8>, position: Option<&na::Point3<f32>>) {
+    let values = match position {
+        Some(position) => [
+            position.x * 1000.0,
+            -position.z * 1000.0,
+            position.y * 1000.0,
+            0.0,
+        ],
+        None => [0.0, 0.0, 0.0, -1.0],
+    };
+    for value in &values {
+        c3d.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn header(points: usize, frames: usize, data_start: usize, fps: f32) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    let mut word = |index: usize, value: u16| {
+        header[index * 2..index * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    };
+    word(1, points as u16);
+    // no analog measurements
+    word(2, 0);
+    word(3, 1);
+    word(4, frames as u16);
+    // maximum interpolation gap
+    word(5, 10);
+    word(8, data_start as u16);
+    word(9, 0);
+    header[0] = 2;
+    header[1] = C3D_KEY;
+    // a negative scale marks floating point data
+    header[12..16].copy_from_slice(&(-1.0f32).to_le_bytes());
+    header[20..24].copy_from_slice(&fps.to_le_bytes());
+    header
+}
+
+/// Parameter section padded to whole blocks, starting with its own header
+fn parameters(labels: &[String], frames: usize, fps: f32) -> Vec<u8> {
+    let mut parameters = Parameters::default();
+    parameters.group(1, "POINT", "3D point parameters");
+    parameters.int(1, "USED", labels.len() as i16);
+    parameters.float(1, "SCALE", -1.0);
+    parameters.float(1, "RATE", fps);
+    // unsigned, readers take counts above 32767 as such
+    parameters.int(1, "FRAMES", frames as u16 as i16);
+    parameters.strings(1, "LABELS", labels);
+    parameters.strings(1, "UNITS", &["mm".to_owned()]);
+    parameters.group(2, "ANALOG", "Analog data parameters");
+    parameters.int(2, "USED", 0);
+    parameters.float(2, "RATE", fps);
+    parameters.finish()
+}
+
+#[derive(Debug, Default)]
+struct Parameters {
+    bytes: Vec<u8>,
+    /// Where the offset to the next entry of the latest entry is
+    last_offset: Option<usize>,
+}
+
+impl Parameters {
+    fn group(&mut self, id: i8, name: &str, description: &str) {
+        self.entry(-id, name);
+        self.bytes.push(description.len() as u8);
+        self.bytes.extend_from_slice(description.as_bytes());
+    }
+
+    fn int(&mut self, group: i8, name: &str, value: i16) {
+        self.parameter(group, name, TYPE_INT, &[], &value.to_le_bytes());
+    }
+
+    fn float(&mut self, group: i8, name: &str, value: f32) {
+        self.parameter(group, name, TYPE_FLOAT, &[], &value.to_le_bytes());
+    }
+
+    /// Space padded strings of equal length
+    fn strings(&mut self, group: i8, name: &str, values: &[String]) {
+        let width = values
+            .iter()
+            .map(|value| value.len())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut data = vec![];
+        for value in values {
+            data.extend_from_slice(value.as_bytes());
+            data.resize(data.len() + width - value.len(), b' ');
+        }
+        let dimensions = [width as u8, values.len() as u8];
+        self.parameter(group, name, TYPE_CHAR, &dimensions, &data);
+    }
+
+    fn parameter(&mut self, group: i8, name: &str, kind: i8, dimensions: &[u8], data: &[u8]) {
+        self.entry(group, name);
+        self.bytes.push(kind as u8);
+        self.bytes.push(dimensions.len() as u8);
+        self.bytes.extend_from_slice(dimensions);
+        self.bytes.extend_from_slice(data);
+        // no description
+        self.bytes.push(0);
+    }
+
+    /// Name and id of a group or parameter, and the offset to the next one
+    fn entry(&mut self, id: i8, name: &str) {
+        self.link_next();
+        self.bytes.push(name.len() as u8);
+        self.bytes.push(id as u8);
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.last_offset = Some(self.bytes.len());
+        self.bytes.extend_from_slice(&[0, 0]);
+    }
+
+    /// Point the latest entry at the end of the section
+    fn link_next(&mut self) {
+        if let Some(offset) = self.last_offset {
+            let next = (self.bytes.len() - offset) as u16;
+            self.bytes[offset..offset + 2].copy_from_slice(&next.to_le_bytes());
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        // the last entry keeps an offset of 0
+        let blocks = (4 + self.bytes.len()).div_ceil(BLOCK_SIZE);
+        let mut section = vec![1, C3D_KEY, blocks as u8, PROCESSOR_INTEL];
+        section.extend_from_slice(&self.bytes);
+        section.resize(blocks * BLOCK_SIZE, 0);
+        section
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+    use std::convert::TryInto;
+
+    fn word(c3d: &[u8], index: usize) -> u16 {
+        u16::from_le_bytes(c3d[index * 2..index * 2 + 2].try_into().unwrap())
+    }
+
+    fn float(c3d: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes(c3d[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_points_and_parameters() {
+        let mut aliases = HashMap::new();
+        aliases.insert("LHR-A".to_owned(), Arc::from("knee"));
+        let frames = vec![
+            TrackedObjects::new(
+                1,
+                1000,
+                vec![
+                    device(1).serial("LHR-A").at([0.1, 0.5, -0.2]).build(),
+                    device(2).serial("LHR-B").untracked().build(),
+                ],
+            ),
+            TrackedObjects::new(
+                2,
+                1020,
+                vec![
+                    device(1).serial("LHR-A").untracked().build(),
+                    device(2).serial("LHR-B").at([1.0, 1.0, 1.0]).build(),
+                ],
+            ),
+        ];
+        let options = C3dOptions {
+            fps: 100.0,
+            axis_length_m: 0.1,
+        };
+        let c3d = export(&frames, &aliases, &options).unwrap();
+        assert_eq!(c3d.len() % BLOCK_SIZE, 0);
+        assert_eq!(&c3d[..2], &[2, C3D_KEY]);
+        // two devices with three axis points each, 1000 to 1020 at 100 fps
+        assert_eq!(word(&c3d, 1), 8);
+        assert_eq!(word(&c3d, 4), 3);
+        assert_eq!(float(&c3d, 12), -1.0);
+        assert_eq!(float(&c3d, 20), 100.0);
+        assert_eq!(&c3d[512..516], &[1, C3D_KEY, 1, PROCESSOR_INTEL]);
+        let parameters = String::from_utf8_lossy(&c3d[512..1024]);
+        assert!(parameters.contains("knee   knee_X knee_Y knee_Z LHR-B  LHR-B_X"));
+
+        let data = (word(&c3d, 8) as usize - 1) * BLOCK_SIZE;
+        assert_eq!(data, 1024);
+        // knee in the first sample, converted to Z up millimeters
+        let knee: Vec<f32> = (0..4).map(|i| float(&c3d, data + i * 4)).collect();
+        assert!((knee[0] - 100.0).abs() < 1e-3);
+        assert!((knee[1] - 200.0).abs() < 1e-3);
+        assert!((knee[2] - 500.0).abs() < 1e-3);
+        assert_eq!(knee[3], 0.0);
+        // its Y axis point is 100 mm above
+        assert!((float(&c3d, data + 2 * 16 + 8) - 600.0).abs() < 1e-3);
+        // LHR-B isn't tracked yet
+        assert_eq!(float(&c3d, data + 4 * 16 + 12), -1.0);
+        // the second sample still holds the first frame, the third the second
+        let third = data + 2 * 8 * 16;
+        assert_eq!(float(&c3d, third + 12), -1.0);
+        assert!((float(&c3d, third + 4 * 16) - 1000.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_parameter_links() {
+        let section = parameters(&["a".to_owned()], 1, 60.0);
+        // walk the entries from the first to the one with offset 0
+        let mut position = 4;
+        let mut names = vec![];
+        loop {
+            let length = section[position] as usize;
+            names.push(
+                String::from_utf8_lossy(&section[position + 2..position + 2 + length]).to_string(),
+            );
+            let offset_at = position + 2 + length;
+            let offset = u16::from_le_bytes(section[offset_at..offset_at + 2].try_into().unwrap());
+            if offset == 0 {
+                break;
+            }
+            position = offset_at + offset as usize;
+        }
+        assert_eq!(
+            names,
+            [
+                "POINT", "USED", "SCALE", "RATE", "FRAMES", "LABELS", "UNITS", "ANALOG", "USED",
+                "RATE"
+            ]
+        );
+    }
+}
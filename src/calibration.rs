@@ -0,0 +1,174 @@
+//! Hand-eye calibration of a tracker rigidly mounted on something else
+//!
+//! Solves AX = XB for the mount transform X from paired poses, where A is the
+//! motion of the mount (robot flange, camera) and B the motion of the tracker
+//! between two samples. Rotation follows Park and Martin, translation is the
+//! least squares solution given that rotation.
+
+use crate::error::{Error, Result};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+/// Smallest eigenvalue of the rotation system that still counts as constrained
+const MIN_ROTATION_SPREAD: f64 = 1e-6;
+
+/// Poses of the mount and the tracker captured at the same moment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PosePair {
+    /// Pose of the mount, for example the robot flange in the robot base frame
+    pub mount: na::Isometry3<f32>,
+    /// Pose of the tracker in tracking space
+    pub tracker: na::Isometry3<f32>,
+}
+
+/// Result of [`solve_ax_xb`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtrinsicCalibration {
+    /// Pose of the tracker relative to the mount
+    pub mount_to_tracker: na::Isometry3<f32>,
+    /// Mean rotation error over all motions in radians
+    pub rotation_error: f32,
+    /// Mean translation error over all motions in meters
+    pub translation_error: f32,
+}
+
+fn to_f64(pose: &na::Isometry3<f32>) -> na::Isometry3<f64> {
+    na::Isometry3::from_parts(
+        na::Translation3::from(pose.translation.vector.map(f64::from)),
+        na::UnitQuaternion::new_normalize(na::Quaternion::from(
+            pose.rotation.coords.map(f64::from),
+        )),
+    )
+}
+
+/// Solve for the transform from mount to tracker
+///
+/// Needs at least three pose pairs with rotations about two different axes.
+pub fn solve_ax_xb(pairs: &[PosePair]) -> Result<ExtrinsicCalibration> {
+    if pairs.len() < 3 {
+        return Err(Error::InvalidInput(format!(
+            "Need at least 3 pose pairs, got {}",
+            pairs.len()
+        )));
+    }
+    let mut motions = vec![];
+    for (i, first) in pairs.iter().enumerate() {
+        for second in &pairs[i + 1..] {
+            let a = to_f64(&first.mount).inverse() * to_f64(&second.mount);
+            let b = to_f64(&first.tracker).inverse() * to_f64(&second.tracker);
+            motions.push((a, b));
+        }
+    }
+
+    // rotation: R_X maps rotation axes of B onto those of A
+    let mut m = na::Matrix3::<f64>::zeros();
+    for (a, b) in &motions {
+        m += b.rotation.scaled_axis() * a.rotation.scaled_axis().transpose();
+    }
+    let eigen = (m.transpose() * m).symmetric_eigen();
+    if eigen.eigenvalues.min() < MIN_ROTATION_SPREAD {
+        return Err(Error::InvalidInput(
+            "Motions need to rotate about at least two different axes".to_owned(),
+        ));
+    }
+    let inverse_sqrt = eigen.eigenvectors
+        * na::Matrix3::from_diagonal(&eigen.eigenvalues.map(|value| 1.0 / value.sqrt()))
+        * eigen.eigenvectors.transpose();
+    let rotation = na::Rotation3::from_matrix(&(inverse_sqrt * m.transpose()));
+    let rotation = na::UnitQuaternion::from_rotation_matrix(&rotation);
+
+    // translation: (R_A - I) t_X = R_X t_B - t_A, stacked as normal equations
+    let mut lhs = na::Matrix3::<f64>::zeros();
+    let mut rhs = na::Vector3::<f64>::zeros();
+    for (a, b) in &motions {
+        let c = a.rotation.to_rotation_matrix().into_inner() - na::Matrix3::identity();
+        let d = rotation * b.translation.vector - a.translation.vector;
+        lhs += c.transpose() * c;
+        rhs += c.transpose() * d;
+    }
+    let translation = match lhs.try_inverse() {
+        Some(inverse) => inverse * rhs,
+        None => {
+            return Err(Error::InvalidInput(
+                "Motions don't constrain the translation".to_owned(),
+            ))
+        }
+    };
+    let x = na::Isometry3::from_parts(na::Translation3::from(translation), rotation);
+
+    let mut rotation_error = 0.0;
+    let mut translation_error = 0.0;
+    for (a, b) in &motions {
+        let ax = a * x;
+        let xb = x * b;
+        rotation_error += ax.rotation.angle_to(&xb.rotation);
+        translation_error += (ax.translation.vector - xb.translation.vector).norm();
+    }
+    let count = motions.len() as f64;
+    Ok(ExtrinsicCalibration {
+        mount_to_tracker: na::Isometry3::from_parts(
+            na::Translation3::from(x.translation.vector.map(|value| value as f32)),
+            na::UnitQuaternion::new_normalize(na::Quaternion::from(
+                x.rotation.coords.map(|value| value as f32),
+            )),
+        ),
+        rotation_error: (rotation_error / count) as f32,
+        translation_error: (translation_error / count) as f32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovers_known_mount() {
+        let x = na::Isometry3::new(
+            na::Vector3::new(0.05, -0.02, 0.1),
+            na::Vector3::new(0.3, 0.1, -0.4),
+        );
+        // arbitrary offset between robot base and tracking space
+        let base = na::Isometry3::new(
+            na::Vector3::new(1.0, 0.0, 2.0),
+            na::Vector3::new(0.0, 1.2, 0.0),
+        );
+        let mounts = [
+            na::Isometry3::new(
+                na::Vector3::new(0.3, 0.2, 0.5),
+                na::Vector3::new(0.0, 0.0, 0.0),
+            ),
+            na::Isometry3::new(
+                na::Vector3::new(0.4, 0.1, 0.5),
+                na::Vector3::new(0.5, 0.0, 0.0),
+            ),
+            na::Isometry3::new(
+                na::Vector3::new(0.2, 0.3, 0.6),
+                na::Vector3::new(0.0, 0.6, 0.2),
+            ),
+            na::Isometry3::new(
+                na::Vector3::new(0.3, 0.2, 0.4),
+                na::Vector3::new(-0.3, 0.2, 0.7),
+            ),
+        ];
+        let pairs: Vec<PosePair> = mounts
+            .iter()
+            .map(|mount| PosePair {
+                mount: *mount,
+                tracker: base * mount * x,
+            })
+            .collect();
+
+        let calibration = solve_ax_xb(&pairs).unwrap();
+        let error = calibration.mount_to_tracker.inverse() * x;
+        assert!(error.translation.vector.norm() < 1e-4);
+        assert!(error.rotation.angle() < 1e-4);
+        assert!(calibration.translation_error < 1e-4);
+
+        let parallel: Vec<PosePair> = pairs[..2]
+            .iter()
+            .cloned()
+            .chain(std::iter::once(pairs[0].clone()))
+            .collect();
+        assert!(solve_ax_xb(&parallel).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Virtual devices at the weighted centroid of other devices
+//!
+//! Crowd and locomotion studies often need one representative point per
+//! person, like the mean of the waist and chest trackers. Computing it here
+//! keeps every consumer on the same definition.
+//!
+//! The position is the weighted mean of the member positions, the rotation the
+//! normalized weighted sum of the member quaternions, which is close to the
+//! mean rotation as long as members point roughly the same way. Only tracked
+//! members count. Without tracked members the last seen poses are used and
+//! the centroid is published as not tracked.
+
+use crate::config::device_named;
+use crate::tracking_messages::{VrDevice, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CentroidConfig {
+    /// Device id of the centroid, pick one no real device uses
+    pub id: usize,
+    /// Published as the alias of the centroid
+    pub name: Arc<str>,
+    pub members: Vec<CentroidMember>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CentroidMember {
+    /// Serial or alias
+    pub device: String,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+impl CentroidConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.name.trim().is_empty() {
+            problems.push(format!("Centroid {} has no name", self.id));
+        }
+        if self.members.is_empty() {
+            problems.push(format!("Centroid {} has no members", self.name));
+        }
+        for member in &self.members {
+            if !(member.weight > 0.0 && member.weight.is_finite()) {
+                problems.push(format!(
+                    "Weight of {} in centroid {} must be positive, is {}",
+                    member.device, self.name, member.weight
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Centroid of the members in `devices`, `None` until a member was seen
+    ///
+    /// Members are found with [`device_named`].
+    pub fn locate(
+        &self,
+        devices: &[VrDevice],
+        aliases: &HashMap<String, Arc<str>>,
+    ) -> Option<VrDevice> {
+        let is_member = |device: &VrDevice| {
+            self.members
+                .iter()
+                .find(|member| device_named(device, &member.device, aliases))
+        };
+        let members = || {
+            devices
+                .iter()
+                .filter(|device| device.seen())
+                .filter_map(|device| Some((device, is_member(device)?.weight)))
+        };
+        let tracked = members().any(|(device, _)| device.tracked());
+        let mut position = na::Vector3::zeros();
+        let mut rotation = na::Vector4::zeros();
+        let mut total_weight = 0.0;
+        let mut reference: Option<na::Vector4<f32>> = None;
+        for (device, weight) in members() {
+            if tracked && !device.tracked() {
+                continue;
+            }
+            position += device.position().coords * weight;
+            // q and -q are the same rotation, keep all on the side of the first
+            let coords = device.rotation().coords;
+            let reference = *reference.get_or_insert(coords);
+            let sign = if reference.dot(&coords) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            rotation += coords * sign * weight;
+            total_weight += weight;
+        }
+        if total_weight <= 0.0 {
+            return None;
+        }
+        let mut centroid = VrDevice::new(self.id);
+        centroid.set_class(VrDeviceClass::Other);
+        centroid.set_alias(Some(self.name.clone()));
+        let rotation = na::UnitQuaternion::new_normalize(na::Quaternion::from(rotation));
+        centroid.set_pose(tracked, na::Point3::from(position / total_weight), rotation);
+        Some(centroid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+
+    #[test]
+    fn test_weighted_centroid() {
+        let centroid = CentroidConfig {
+            id: 100,
+            name: "body".into(),
+            members: vec![
+                CentroidMember {
+                    device: "waist".to_owned(),
+                    weight: 3.0,
+                },
+                CentroidMember {
+                    device: "LHR-CHEST".to_owned(),
+                    weight: 1.0,
+                },
+            ],
+        };
+        let mut aliases = HashMap::new();
+        aliases.insert("LHR-WAIST".to_owned(), Arc::from("waist"));
+        let mut devices = vec![
+            device(0)
+                .serial("LHR-WAIST")
+                .at([0.0, 1.0, 0.0])
+                .yaw(0.2)
+                .build(),
+            device(1)
+                .serial("LHR-CHEST")
+                .at([0.0, 1.4, 0.4])
+                .yaw(0.2)
+                .build(),
+            device(2).serial("LHR-FOOT").at([5.0, 0.0, 0.0]).build(),
+        ];
+        let body = centroid.locate(&devices, &aliases).unwrap();
+        assert_eq!(body.id(), 100);
+        assert_eq!(body.alias(), Some("body"));
+        assert!(body.tracked());
+        assert!((body.position() - na::Point3::new(0.0, 1.1, 0.1)).norm() < 1e-5);
+        let yaw = na::UnitQuaternion::from_axis_angle(&na::Vector3::y_axis(), 0.2);
+        assert!(body.rotation().angle_to(&yaw) < 1e-5);
+
+        devices[1] = device(1).serial("LHR-CHEST").untracked().build();
+        let body = centroid.locate(&devices, &aliases).unwrap();
+        assert!((body.position() - na::Point3::new(0.0, 1.0, 0.0)).norm() < 1e-5);
+
+        assert!(centroid.locate(&devices[2..], &aliases).is_none());
+        assert!(centroid.problems().is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::config::{seconds, ChangeOnlyConfig};
+use crate::tracking_messages::{TrackedObjects, VrDevice};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+/// Keeps track of what was last published for each device
+///
+/// Used to drop devices that didn't change from frames,
+/// with periodic keyframes that contain every device.
+#[derive(Default)]
+pub struct ChangeTracker {
+    published: HashMap<usize, VrDevice>,
+    last_keyframe: Option<Instant>,
+}
+
+impl ChangeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drops devices that didn't change from `devices`
+    ///
+    /// Returns whether this frame is a keyframe
+    pub fn filter(
+        &mut self,
+        devices: &mut Vec<VrDevice>,
+        config: &ChangeOnlyConfig,
+        now: Instant,
+    ) -> bool {
+        let keyframe_interval =
+            seconds(config.keyframe_interval_s.max(0.0)).unwrap_or(Duration::MAX);
+        let keyframe = match self.last_keyframe {
+            Some(last_keyframe) => now.duration_since(last_keyframe) >= keyframe_interval,
+            None => true,
+        };
+        if keyframe {
+            self.last_keyframe = Some(now);
+        }
+        let published = &mut self.published;
+        devices.retain(|device| {
+            let dirty = match published.get(&device.id()) {
+                Some(previous) => has_changed(previous, device, config),
+                None => true,
+            };
+            if keyframe || dirty {
+                published.insert(device.id(), device.clone());
+            }
+            keyframe || dirty
+        });
+        keyframe
+    }
+}
+
+/// Folds skipped change-only frames into the next frame that is sent
+///
+/// The receiver misses the skipped frames, so the one sent carries every
+/// device that changed since the last one. If the keyframe it builds on was
+/// skipped, the state up to it is sent as a new keyframe and later partial
+/// frames refer to that one.
+#[derive(Debug, Clone, Default)]
+pub struct SkippedChanges {
+    /// Latest state of every device since the last keyframe
+    devices: BTreeMap<usize, VrDevice>,
+    /// Devices that changed since the last frame sent
+    changed: BTreeSet<usize>,
+    /// Keyframe of the stream that `devices` build on
+    keyframe_seq: Option<u64>,
+    /// Seq the receiver got the state of that keyframe as
+    sent_keyframe_seq: Option<u64>,
+}
+
+impl SkippedChanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `frame`, false if it is partial and builds on a keyframe that wasn't added
+    pub fn add(&mut self, frame: &TrackedObjects) -> bool {
+        if frame.partial() {
+            if self.keyframe_seq.is_none() || frame.keyframe_seq() != self.keyframe_seq {
+                return false;
+            }
+            for device in frame.trackers() {
+                self.devices.insert(device.id(), device.clone());
+                self.changed.insert(device.id());
+            }
+        } else {
+            self.devices = frame
+                .trackers()
+                .iter()
+                .map(|device| (device.id(), device.clone()))
+                .collect();
+            self.changed.clear();
+            self.keyframe_seq = Some(frame.seq());
+            self.sent_keyframe_seq = None;
+        }
+        true
+    }
+
+    /// What to send instead of `frame`, the last one added
+    pub fn take(&mut self, frame: &TrackedObjects) -> TrackedObjects {
+        let mut merged = frame.clone();
+        match self.sent_keyframe_seq {
+            Some(sent_keyframe_seq) if frame.partial() => {
+                *merged.trackers_mut() = self
+                    .changed
+                    .iter()
+                    .map(|id| self.devices[id].clone())
+                    .collect();
+                merged.set_delta_of(sent_keyframe_seq);
+            }
+            _ => {
+                if frame.partial() {
+                    *merged.trackers_mut() = self.devices.values().cloned().collect();
+                    merged.set_keyframe();
+                }
+                self.sent_keyframe_seq = Some(frame.seq());
+            }
+        }
+        self.changed.clear();
+        merged
+    }
+}
+
+fn has_changed(previous: &VrDevice, current: &VrDevice, config: &ChangeOnlyConfig) -> bool {
+    previous.tracked() != current.tracked()
+        || previous.class() != current.class()
+        || previous.alias() != current.alias()
+        || (previous.position() - current.position()).norm() > config.position_threshold
+        || previous.rotation().angle_to(current.rotation()) > config.rotation_threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_changed_devices_between_keyframes() {
+        let config = ChangeOnlyConfig::default();
+        let mut tracker = ChangeTracker::new();
+        let start = Instant::now();
+        let devices = vec![VrDevice::new(0), VrDevice::new(1)];
+
+        let mut published = devices.clone();
+        assert!(tracker.filter(&mut published, &config, start));
+        assert_eq!(published.len(), 2);
+
+        let mut moved = devices;
+        moved[1].transform(&nalgebra::Isometry3::translation(0.1, 0.0, 0.0));
+        let mut published = moved.clone();
+        let keyframe = tracker.filter(&mut published, &config, start + Duration::from_millis(20));
+        assert!(!keyframe);
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].id(), 1);
+
+        let mut published = moved;
+        assert!(tracker.filter(&mut published, &config, start + Duration::from_secs(2)));
+        assert_eq!(published.len(), 2);
+    }
+}
@@ -0,0 +1,402 @@
+//! Receiving side of the tracking stream
+//!
+//! Frames are decoded into the same [`TrackedObjects`] type the sender serializes,
+//! so consumers don't need to keep their own copy of the message structs.
+
+use crate::authentication::Authenticator;
+use crate::compact;
+use crate::encryption::Cipher;
+use crate::error::{Error, Result};
+use crate::multicast;
+use crate::tracking_messages::{Channel, TrackedObjects, VrDevice};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Decode a single frame as sent over the wire, JSON or compact
+pub fn decode_frame(bytes: &[u8]) -> Result<TrackedObjects> {
+    if compact::is_compact(bytes) {
+        return compact::decode(bytes);
+    }
+    serde_json::from_slice(bytes).map_err(|error| Error::InvalidFrame(error.to_string()))
+}
+
+/// Rebuilds full frames from keyframes and partial frames
+///
+/// Partial frames only carry devices that changed, so the assembler keeps
+/// the latest state of every device since the last keyframe.
+/// If a partial frame is lost, devices it carried stay stale until they
+/// change again or the next keyframe arrives.
+#[derive(Default)]
+pub struct FrameAssembler {
+    keyframe_seq: Option<u64>,
+    devices: BTreeMap<usize, VrDevice>,
+}
+
+impl FrameAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns full frame, or `None` while waiting for the keyframe a partial frame refers to
+    pub fn push(&mut self, frame: TrackedObjects) -> Option<TrackedObjects> {
+        if frame.is_heartbeat() {
+            return None;
+        }
+        if !frame.partial() {
+            self.keyframe_seq = Some(frame.seq());
+            self.devices = frame
+                .trackers()
+                .iter()
+                .map(|device| (device.id(), device.clone()))
+                .collect();
+            return Some(frame);
+        }
+        if self.keyframe_seq.is_none() || frame.keyframe_seq() != self.keyframe_seq {
+            return None;
+        }
+        for device in frame.trackers() {
+            self.devices.insert(device.id(), device.clone());
+        }
+        Some(TrackedObjects::new(
+            frame.seq(),
+            frame.ts(),
+            self.devices.values().cloned().collect(),
+        ))
+    }
+}
+
+/// Sequence numbers this far below the highest one are forgotten, late frames
+/// older than that can't be told from duplicates
+const SEEN_WINDOW: u64 = 1024;
+
+/// Packet loss, reordering and jitter of a stream, based on sequence numbers and timestamps
+///
+/// A sender that restarts numbers its frames from 0 again. A frame numbered below the
+/// highest one but sent after it starts a new run instead of counting as reordered.
+#[derive(Debug, Default, Clone)]
+pub struct StreamStats {
+    received: u64,
+    reordered: u64,
+    duplicates: u64,
+    restarts: u64,
+    /// Frames lost in runs before the sender last restarted
+    lost_before_restart: u64,
+    first_seq: Option<u64>,
+    highest_seq: Option<u64>,
+    highest_ts: u128,
+    /// Frames of the current run, without duplicates
+    distinct: u64,
+    /// Recent sequence numbers of the current run
+    seen: BTreeSet<u64>,
+    last_transit_ms: Option<f64>,
+    jitter_ms: f64,
+}
+
+impl StreamStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, frame: &TrackedObjects) {
+        self.record_at(frame, SystemTime::now());
+    }
+
+    /// Event channel messages are ignored, they have their own sequence numbers
+    pub fn record_at(&mut self, frame: &TrackedObjects, arrival: SystemTime) {
+        if frame.channel() != Channel::Pose {
+            return;
+        }
+        let seq = frame.seq();
+        self.received += 1;
+        if matches!(self.highest_seq, Some(highest) if seq < highest && frame.ts() > self.highest_ts)
+        {
+            self.restart();
+        }
+        if !self.seen.insert(seq) {
+            self.duplicates += 1;
+        } else {
+            self.distinct += 1;
+            match self.highest_seq {
+                Some(highest) if seq < highest => self.reordered += 1,
+                _ => {
+                    self.highest_seq = Some(seq);
+                    self.highest_ts = frame.ts();
+                    self.seen = self.seen.split_off(&seq.saturating_sub(SEEN_WINDOW));
+                }
+            }
+            self.first_seq = Some(self.first_seq.map_or(seq, |first| first.min(seq)));
+        }
+        // interarrival jitter as in RFC 3550
+        // clock offset between sender and receiver cancels out in the difference
+        let arrival_ms = arrival
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0;
+        let transit_ms = arrival_ms - frame.ts() as f64;
+        if let Some(last_transit_ms) = self.last_transit_ms {
+            let difference = (transit_ms - last_transit_ms).abs();
+            self.jitter_ms += (difference - self.jitter_ms) / 16.0;
+        }
+        self.last_transit_ms = Some(transit_ms);
+    }
+
+    /// Keep the losses of the run so far and start counting the next one
+    fn restart(&mut self) {
+        self.lost_before_restart = self.lost();
+        self.restarts += 1;
+        self.first_seq = None;
+        self.highest_seq = None;
+        self.distinct = 0;
+        self.seen.clear();
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Times the sender started numbering frames over
+    pub fn restarts(&self) -> u64 {
+        self.restarts
+    }
+
+    /// Frames that never arrived, based on gaps in sequence numbers
+    pub fn lost(&self) -> u64 {
+        let lost = match (self.first_seq, self.highest_seq) {
+            (Some(first), Some(highest)) => (highest - first + 1).saturating_sub(self.distinct),
+            _ => 0,
+        };
+        self.lost_before_restart + lost
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        let expected = self.lost() + self.received - self.duplicates;
+        if expected == 0 {
+            0.0
+        } else {
+            self.lost() as f64 / expected as f64 * 100.0
+        }
+    }
+
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter_ms
+    }
+}
+
+impl fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} received, {} lost ({:.2}%), {} reordered, {} duplicates, {} restarts, jitter {:.2} ms",
+            self.received,
+            self.lost(),
+            self.loss_percent(),
+            self.reordered,
+            self.duplicates,
+            self.restarts,
+            self.jitter_ms
+        )
+    }
+}
+
+/// Blocking client for the multicast stream
+///
+/// Can be used as an iterator of frames:
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let client = openvr_tracker::client::MulticastClient::new("239.0.0.22:7070".parse()?)?;
+/// for frame in client {
+///     println!("{:?}", frame?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Partial frames are reassembled, so every frame contains all devices.
+/// Heartbeats are not returned as frames but they do update [`MulticastClient::last_seen`].
+pub struct MulticastClient {
+    receiver: multicast::MessageReceiver,
+    assembler: FrameAssembler,
+    last_seen: Option<Instant>,
+}
+
+impl MulticastClient {
+    pub fn new(multicast_address: SocketAddrV4) -> Result<Self> {
+        Ok(Self {
+            receiver: multicast::MessageReceiver::new(multicast_address).map_err(Error::Network)?,
+            assembler: FrameAssembler::new(),
+            last_seen: None,
+        })
+    }
+
+    /// Only accept frames encrypted with this key
+    pub fn set_encryption(&mut self, encryption: Option<Cipher>) {
+        self.receiver.set_encryption(encryption);
+    }
+
+    /// Only accept frames signed with this key
+    pub fn set_authentication(&mut self, authentication: Option<Authenticator>) {
+        self.receiver.set_authentication(authentication);
+    }
+
+    /// When the last message, frame or heartbeat, arrived from the sender
+    ///
+    /// Use this to tell a static scene apart from a sender that went away
+    pub fn last_seen(&self) -> Option<Instant> {
+        self.last_seen
+    }
+
+    /// Block until next frame arrives
+    pub fn next_frame(&mut self) -> Result<TrackedObjects> {
+        loop {
+            let frame = decode_frame(self.receiver.recv().map_err(Error::Network)?)?;
+            self.last_seen = Some(Instant::now());
+            if let Some(frame) = self.assembler.push(frame) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Block until next frame arrives or the timeout runs out
+    pub fn next_frame_timeout(&mut self, timeout: Duration) -> Result<Option<TrackedObjects>> {
+        match self
+            .receiver
+            .recv_timeout(timeout)
+            .map_err(Error::Network)?
+        {
+            Some(message) => {
+                let frame = decode_frame(message)?;
+                self.last_seen = Some(Instant::now());
+                Ok(self.assembler.push(frame))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Iterator for MulticastClient {
+    type Item = Result<TrackedObjects>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_frame())
+    }
+}
+
+/// Async stream of frames from the multicast group
+///
+/// Has to be called from within a tokio runtime
+#[cfg(feature = "async")]
+pub fn multicast_stream(
+    multicast_address: SocketAddrV4,
+) -> Result<impl futures::Stream<Item = Result<TrackedObjects>>> {
+    let socket = multicast::join_multicast_group(multicast_address)
+        .and_then(tokio::net::UdpSocket::from_std)
+        .map_err(Error::Network)?;
+    let buffer = vec![0; multicast::MAX_DATAGRAM_SIZE];
+    Ok(futures::stream::unfold(
+        (socket, buffer, FrameAssembler::new()),
+        |(socket, mut buffer, mut assembler)| async move {
+            loop {
+                let frame = match socket.recv(&mut buffer).await {
+                    Ok(len) => decode_frame(&buffer[..len]),
+                    Err(error) => Err(Error::Network(error)),
+                };
+                match frame {
+                    Ok(frame) => {
+                        if let Some(frame) = assembler.push(frame) {
+                            return Some((Ok(frame), (socket, buffer, assembler)));
+                        }
+                    }
+                    Err(error) => return Some((Err(error), (socket, buffer, assembler))),
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_round_trip() {
+        let frame = TrackedObjects::new(7, 1234, vec![VrDevice::new(3)]);
+        let json = serde_json::to_vec(&frame).unwrap();
+        let decoded = decode_frame(&json).unwrap();
+        assert_eq!(decoded.seq(), 7);
+        assert_eq!(decoded.ts(), 1234);
+        assert_eq!(decoded.trackers().len(), 1);
+        assert_eq!(decoded.trackers()[0].id(), 3);
+    }
+
+    #[test]
+    fn test_assembler_applies_deltas_to_keyframe() {
+        let mut assembler = FrameAssembler::new();
+        let mut orphan = TrackedObjects::new(1, 0, vec![VrDevice::new(0)]);
+        orphan.set_delta_of(0);
+        assert!(assembler.push(orphan).is_none());
+
+        let keyframe = TrackedObjects::new(2, 0, vec![VrDevice::new(0), VrDevice::new(1)]);
+        assert_eq!(assembler.push(keyframe).unwrap().trackers().len(), 2);
+        assert!(assembler.push(TrackedObjects::heartbeat(3, 0)).is_none());
+
+        let mut moved = VrDevice::new(1);
+        moved.set_alias(Some("moved".into()));
+        let mut delta = TrackedObjects::new(4, 0, vec![moved]);
+        delta.set_delta_of(2);
+        let frame = assembler.push(delta).unwrap();
+        assert!(!frame.partial());
+        assert_eq!(frame.seq(), 4);
+        assert_eq!(frame.trackers().len(), 2);
+        assert_eq!(frame.trackers()[1].alias(), Some("moved"));
+    }
+
+    #[test]
+    fn test_stream_stats_loss_and_reorder() {
+        let mut stats = StreamStats::new();
+        let arrival = UNIX_EPOCH + Duration::from_millis(1000);
+        for seq in &[0, 1, 3, 2, 5] {
+            stats.record_at(&TrackedObjects::new(*seq, 1000, vec![]), arrival);
+        }
+        assert_eq!(stats.received(), 5);
+        assert_eq!(stats.reordered(), 1);
+        assert_eq!(stats.lost(), 1);
+        assert!((stats.loss_percent() - 100.0 / 6.0).abs() < 1e-9);
+        assert!(stats.jitter_ms().abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_stream_stats_duplicates_and_restarts() {
+        let mut stats = StreamStats::new();
+        let arrival = UNIX_EPOCH + Duration::from_millis(1000);
+        // resent frames are duplicates, the sender restarting later numbers from 0 again
+        for (seq, ts) in &[
+            (0, 0),
+            (2, 20),
+            (1, 10),
+            (2, 20),
+            (0, 0),
+            (0, 500),
+            (1, 510),
+            (3, 530),
+        ] {
+            stats.record_at(&TrackedObjects::new(*seq, *ts, vec![]), arrival);
+        }
+        assert_eq!(stats.received(), 8);
+        assert_eq!(stats.reordered(), 1);
+        assert_eq!(stats.duplicates(), 2);
+        assert_eq!(stats.restarts(), 1);
+        assert_eq!(stats.lost(), 1);
+    }
+}
@@ -0,0 +1,299 @@
+//! Offset between the sender's clock and a receiver's
+//!
+//! Frame timestamps are in the sender's clock, whichever [timestamp
+//! source](crate::timestamps) it uses. Fusing them with other sensors needs to
+//! know how far that is from the receiver's own clock. With `--clock-server`
+//! the sender answers time requests on a UDP port in that clock and a
+//! [`ClockClient`] estimates the offset NTP style. A request carries the
+//! receiver's send time `t0`, the answer adds when the sender received it and
+//! answered, `t1` and `t2`, and the receiver notes its arrival `t3`:
+//!
+//! ```text
+//! offset = ((t1 - t0) + (t2 - t3)) / 2
+//! delay = (t3 - t0) - (t2 - t1)
+//! ```
+//!
+//! Queuing makes the two directions asymmetric, which the offset can't tell
+//! apart from clock difference, so of the recent samples the one with the
+//! shortest round trip is used. It is off by at most half its delay.
+
+use crate::error::{Error, Result};
+use crate::timestamps::TimestampClock;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time between requests of a [`ClockClient`]
+pub const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples the estimate picks the shortest round trip from
+const SAMPLE_WINDOW: usize = 16;
+
+/// Request, or answer when the sender filled in its times
+///
+/// Times are microseconds since the Unix epoch in the clock of whoever set them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClockMessage {
+    pub t0_us: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub t1_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub t2_us: Option<u64>,
+}
+
+/// Microseconds since the Unix epoch
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// One round trip
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    /// Sender clock minus receiver clock in milliseconds
+    pub offset_ms: f64,
+    /// Round trip without the sender's processing time in milliseconds
+    pub delay_ms: f64,
+}
+
+impl ClockSample {
+    pub fn from_times(t0_us: u64, t1_us: u64, t2_us: u64, t3_us: u64) -> Self {
+        let (t0, t1, t2, t3) = (t0_us as f64, t1_us as f64, t2_us as f64, t3_us as f64);
+        Self {
+            offset_ms: ((t1 - t0) + (t2 - t3)) / 2.0 / 1000.0,
+            delay_ms: ((t3 - t0) - (t2 - t1)).max(0.0) / 1000.0,
+        }
+    }
+
+    /// Sender timestamp `ts` in milliseconds in the receiver's clock
+    pub fn to_local_ms(&self, ts: u128) -> f64 {
+        ts as f64 - self.offset_ms
+    }
+}
+
+/// Keeps the sample with the shortest round trip of the recent ones
+#[derive(Debug, Clone, Default)]
+pub struct ClockEstimator {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, sample: ClockSample) {
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn estimate(&self) -> Option<ClockSample> {
+        self.samples
+            .iter()
+            .copied()
+            .min_by(|a, b| a.delay_ms.total_cmp(&b.delay_ms))
+    }
+}
+
+/// Answers time requests from receivers, run by the sender
+pub struct ClockServer {
+    socket: UdpSocket,
+    clock: TimestampClock,
+}
+
+impl ClockServer {
+    /// Answer with the time of `clock`, the one frame timestamps are taken from
+    pub fn bind(address: SocketAddr, clock: TimestampClock) -> Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(address).map_err(Error::Network)?,
+            clock,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().map_err(Error::Network)
+    }
+
+    /// Answer requests on a thread of its own until the socket fails
+    pub fn spawn(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buffer = [0; 256];
+            loop {
+                let (len, from) = match self.socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => {
+                        eprintln!("Clock server stopped: {}", error);
+                        return;
+                    }
+                };
+                let t1_us = self.clock.now_us();
+                let request: ClockMessage = match serde_json::from_slice(&buffer[..len]) {
+                    Ok(request) => request,
+                    // not a request, nothing to answer
+                    Err(_) => continue,
+                };
+                let (t1_us, t2_us) = match (t1_us, self.clock.now_us()) {
+                    (Ok(t1_us), Ok(t2_us)) => (t1_us as u64, t2_us as u64),
+                    (Err(error), _) | (_, Err(error)) => {
+                        eprintln!("Clock server stopped: {}", error.describe());
+                        return;
+                    }
+                };
+                let answer = ClockMessage {
+                    t0_us: request.t0_us,
+                    t1_us: Some(t1_us),
+                    t2_us: Some(t2_us),
+                };
+                if let Ok(bytes) = serde_json::to_vec(&answer) {
+                    let _ = self.socket.send_to(&bytes, from);
+                }
+            }
+        })
+    }
+}
+
+/// Asks a [`ClockServer`] for its time every [`REQUEST_INTERVAL`] on a thread of its own
+///
+/// Answers are timestamped as they arrive, not when the caller gets around to
+/// it. The thread stops when the client is dropped.
+pub struct ClockClient {
+    estimator: Arc<Mutex<ClockEstimator>>,
+    running: Arc<AtomicBool>,
+}
+
+impl ClockClient {
+    pub fn new(server: SocketAddr) -> Result<Self> {
+        let local: SocketAddr = if server.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local).map_err(Error::Network)?;
+        socket.connect(server).map_err(Error::Network)?;
+        socket
+            .set_read_timeout(Some(REQUEST_INTERVAL))
+            .map_err(Error::Network)?;
+        let estimator = Arc::new(Mutex::new(ClockEstimator::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        {
+            let estimator = estimator.clone();
+            let running = running.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    match ask(&socket) {
+                        Ok(Some(sample)) => estimator.lock().unwrap().push(sample),
+                        Ok(None) => {}
+                        Err(error) => {
+                            eprintln!("Clock offset requests stopped: {}", error);
+                            return;
+                        }
+                    }
+                    thread::sleep(REQUEST_INTERVAL);
+                }
+            });
+        }
+        Ok(Self { estimator, running })
+    }
+
+    /// Offset from the shortest of the recent round trips, `None` before the first answer
+    pub fn estimate(&self) -> Option<ClockSample> {
+        self.estimator.lock().unwrap().estimate()
+    }
+}
+
+impl Drop for ClockClient {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// One request, `None` if no answer to it came back in time
+fn ask(socket: &UdpSocket) -> io::Result<Option<ClockSample>> {
+    let t0_us = now_us();
+    let request = ClockMessage {
+        t0_us,
+        t1_us: None,
+        t2_us: None,
+    };
+    match socket.send(&serde_json::to_vec(&request)?) {
+        Ok(_) => {}
+        // the server isn't up yet
+        Err(error) if error.kind() == ErrorKind::ConnectionRefused => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let mut buffer = [0; 256];
+    loop {
+        let len = match socket.recv(&mut buffer) {
+            Ok(len) => len,
+            Err(error)
+                if error.kind() == ErrorKind::WouldBlock
+                    || error.kind() == ErrorKind::TimedOut
+                    || error.kind() == ErrorKind::ConnectionRefused =>
+            {
+                return Ok(None)
+            }
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        let t3_us = now_us();
+        let answer: ClockMessage = match serde_json::from_slice(&buffer[..len]) {
+            Ok(answer) => answer,
+            Err(_) => continue,
+        };
+        // late answers to earlier requests would count their wait as delay
+        if let (Some(t1_us), Some(t2_us), true) =
+            (answer.t1_us, answer.t2_us, answer.t0_us == t0_us)
+        {
+            return Ok(Some(ClockSample::from_times(t0_us, t1_us, t2_us, t3_us)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_offset_from_shortest_round_trip() {
+        // sender 500 ms ahead, 2 ms each way, 1 ms to answer
+        let sample = ClockSample::from_times(1_000_000, 1_502_000, 1_503_000, 1_005_000);
+        assert_eq!(sample.offset_ms, 500.0);
+        assert_eq!(sample.delay_ms, 4.0);
+        assert_eq!(sample.to_local_ms(1_600), 1_100.0);
+
+        let mut estimator = ClockEstimator::new();
+        estimator.push(sample);
+        // the answer was queued for 20 ms, which looks like the sender being 10 ms further ahead
+        estimator.push(ClockSample::from_times(
+            2_000_000, 2_502_000, 2_503_000, 2_025_000,
+        ));
+        assert_eq!(estimator.estimate(), Some(sample));
+    }
+
+    #[test]
+    fn test_client_and_server_agree_on_loopback() {
+        let address = "127.0.0.1:0".parse().unwrap();
+        let server = ClockServer::bind(address, TimestampClock::wall()).unwrap();
+        let address = server.local_addr().unwrap();
+        server.spawn();
+        let client = ClockClient::new(address).unwrap();
+        let started = Instant::now();
+        while client.estimate().is_none() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let estimate = client.estimate().unwrap();
+        // same clock on both ends
+        assert!(estimate.offset_ms.abs() <= estimate.delay_ms / 2.0 + 1.0);
+    }
+}
@@ -0,0 +1,273 @@
+//! Compact binary encoding of pose frames
+//!
+//! For links where JSON doesn't fit. Positions are quantized to 16 bits per
+//! axis within declared bounds and rotations are packed as the smallest three
+//! quaternion components, so a device takes 16 bytes. Serials, aliases and
+//! other details are left out, send them on the metadata channel instead.
+//!
+//! All numbers are little endian. A frame is a 45 byte header followed by the devices:
+//!
+//! | bytes | header field |
+//! |-------|--------------|
+//! | 2 | magic `OT` |
+//! | 1 | version, currently 1 |
+//! | 1 | flags: bit 0 partial, bit 1 heartbeat, bit 2 extrapolated |
+//! | 4 | seq, lowest 32 bits |
+//! | 8 | ts, milliseconds since the Unix epoch |
+//! | 4 | keyframe seq of partial frames, lowest 32 bits |
+//! | 24 | bounds min x, y, z and max x, y, z as f32 |
+//! | 1 | device count |
+//!
+//! | bytes | device field |
+//! |-------|--------------|
+//! | 1 | id |
+//! | 1 | bit 0 tracked, bits 1-3 class, bits 4-6 tracking state, in declaration order |
+//! | 6 | position x, y, z as u16, 0 at bounds min and 65535 at bounds max |
+//! | 8 | rotation: bits 60-61 index of the dropped largest component, then three 20 bit components |
+
+use crate::error::{Error, Result};
+use crate::tracking_messages::{TrackedObjects, TrackingState, VrDevice, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::convert::TryInto;
+
+const MAGIC: &[u8; 2] = b"OT";
+const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 45;
+pub const DEVICE_SIZE: usize = 16;
+
+const CLASSES: [VrDeviceClass; 8] = [
+    VrDeviceClass::Controller,
+    VrDeviceClass::LeftController,
+    VrDeviceClass::RightController,
+    VrDeviceClass::Tracker,
+    VrDeviceClass::HMD,
+    VrDeviceClass::Sensor,
+    VrDeviceClass::Other,
+    VrDeviceClass::Camera,
+];
+
+const STATES: [TrackingState; 6] = [
+    TrackingState::Ok,
+    TrackingState::OutOfRange,
+    TrackingState::Calibrating,
+    TrackingState::RotationOnly,
+    TrackingState::Disconnected,
+    TrackingState::Uninitialized,
+];
+
+const COMPONENT_BITS: u32 = 20;
+const COMPONENT_MAX: f32 = ((1 << COMPONENT_BITS) - 1) as f32;
+
+/// Space positions are quantized in, in meters
+///
+/// Positions outside are clamped to the bounds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactConfig {
+    pub bounds_min: [f32; 3],
+    pub bounds_max: [f32; 3],
+}
+
+impl Default for CompactConfig {
+    fn default() -> Self {
+        Self {
+            bounds_min: [-10.0, -2.0, -10.0],
+            bounds_max: [10.0, 4.0, 10.0],
+        }
+    }
+}
+
+/// Whether `bytes` look like a compact frame rather than JSON
+pub fn is_compact(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Append compact encoding of frame to `buffer`
+pub fn encode(frame: &TrackedObjects, config: &CompactConfig, buffer: &mut Vec<u8>) -> Result<()> {
+    if frame.trackers().len() > u8::MAX as usize {
+        return Err(Error::InvalidInput(
+            "Compact frames hold at most 255 devices".to_owned(),
+        ));
+    }
+    buffer.extend_from_slice(MAGIC);
+    buffer.push(VERSION);
+    buffer.push(
+        frame.partial() as u8
+            | (frame.is_heartbeat() as u8) << 1
+            | (frame.extrapolated() as u8) << 2,
+    );
+    buffer.extend_from_slice(&(frame.seq() as u32).to_le_bytes());
+    buffer.extend_from_slice(&(frame.ts() as u64).to_le_bytes());
+    buffer.extend_from_slice(&(frame.keyframe_seq().unwrap_or_default() as u32).to_le_bytes());
+    for value in config.bounds_min.iter().chain(config.bounds_max.iter()) {
+        buffer.extend_from_slice(&value.to_le_bytes());
+    }
+    buffer.push(frame.trackers().len() as u8);
+    for device in frame.trackers() {
+        if device.id() > u8::MAX as usize {
+            return Err(Error::InvalidInput(format!(
+                "Device id {} doesn't fit a compact frame",
+                device.id()
+            )));
+        }
+        buffer.push(device.id() as u8);
+        let class = CLASSES.iter().position(|class| *class == device.class());
+        let state = STATES.iter().position(|state| *state == device.state());
+        let flags = device.tracked() as usize
+            | class.unwrap_or_default() << 1
+            | state.unwrap_or_default() << 4;
+        buffer.push(flags as u8);
+        for axis in 0..3 {
+            let (min, max) = (config.bounds_min[axis], config.bounds_max[axis]);
+            let scaled = (device.position()[axis] - min) / (max - min).max(f32::EPSILON);
+            let quantized = (scaled.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16;
+            buffer.extend_from_slice(&quantized.to_le_bytes());
+        }
+        buffer.extend_from_slice(&pack_rotation(device.rotation()).to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Decode a compact frame, devices only carry id, class, state and pose
+pub fn decode(bytes: &[u8]) -> Result<TrackedObjects> {
+    if bytes.len() < HEADER_SIZE || !is_compact(bytes) {
+        return Err(Error::InvalidFrame("Not a compact frame".to_owned()));
+    }
+    if bytes[2] != VERSION {
+        return Err(Error::InvalidFrame(format!(
+            "Unsupported compact frame version {}",
+            bytes[2]
+        )));
+    }
+    let truncated = |_| Error::InvalidFrame("Compact frame is truncated".to_owned());
+    let flags = bytes[3];
+    let seq = u32::from_le_bytes(bytes[4..8].try_into().map_err(truncated)?) as u64;
+    let ts = u64::from_le_bytes(bytes[8..16].try_into().map_err(truncated)?) as u128;
+    let keyframe_seq = u32::from_le_bytes(bytes[16..20].try_into().map_err(truncated)?) as u64;
+    let mut bounds = [0.0; 6];
+    for (index, value) in bounds.iter_mut().enumerate() {
+        let start = 20 + index * 4;
+        *value = f32::from_le_bytes(bytes[start..start + 4].try_into().map_err(truncated)?);
+    }
+    let count = bytes[44] as usize;
+    if bytes.len() != HEADER_SIZE + count * DEVICE_SIZE {
+        return Err(Error::InvalidFrame(format!(
+            "Compact frame with {} devices has wrong length",
+            count
+        )));
+    }
+
+    let mut frame = if flags & 0b10 != 0 {
+        TrackedObjects::heartbeat(seq, ts)
+    } else {
+        TrackedObjects::new(seq, ts, Vec::with_capacity(count))
+    };
+    if flags & 0b1 != 0 {
+        frame.set_delta_of(keyframe_seq);
+    }
+    frame.set_extrapolated(flags & 0b100 != 0);
+    for chunk in bytes[HEADER_SIZE..].chunks_exact(DEVICE_SIZE) {
+        let mut device = VrDevice::new(chunk[0] as usize);
+        let flags = chunk[1] as usize;
+        device.set_class(
+            CLASSES
+                .get(flags >> 1 & 0b111)
+                .copied()
+                .unwrap_or(VrDeviceClass::Other),
+        );
+        device.set_state(STATES.get(flags >> 4 & 0b111).copied().unwrap_or_default());
+        let mut position = na::Point3::origin();
+        for axis in 0..3 {
+            let start = 2 + axis * 2;
+            let quantized =
+                u16::from_le_bytes(chunk[start..start + 2].try_into().map_err(truncated)?);
+            let (min, max) = (bounds[axis], bounds[axis + 3]);
+            position[axis] = min + quantized as f32 / u16::MAX as f32 * (max - min);
+        }
+        let rotation = unpack_rotation(u64::from_le_bytes(
+            chunk[8..16].try_into().map_err(truncated)?,
+        ));
+        device.set_pose(flags & 1 != 0, position, rotation);
+        frame.trackers_mut().push(device);
+    }
+    Ok(frame)
+}
+
+/// Smallest three: drop the largest component, it follows from the unit length
+fn pack_rotation(rotation: &na::UnitQuaternion<f32>) -> u64 {
+    let coords = rotation.coords;
+    let largest = (0..4)
+        .max_by(|a, b| {
+            coords[*a]
+                .abs()
+                .partial_cmp(&coords[*b].abs())
+                .unwrap_or(Ordering::Equal)
+        })
+        .unwrap_or(3);
+    // q and -q are the same rotation, make the dropped component positive
+    let sign = if coords[largest] < 0.0 { -1.0 } else { 1.0 };
+    let mut packed = (largest as u64) << (3 * COMPONENT_BITS);
+    let mut shift = 2 * COMPONENT_BITS;
+    for index in (0..4).filter(|index| *index != largest) {
+        let scaled = (coords[index] * sign * std::f32::consts::SQRT_2 + 1.0) / 2.0;
+        let quantized = (scaled.clamp(0.0, 1.0) * COMPONENT_MAX).round() as u64;
+        packed |= quantized << shift;
+        shift = shift.saturating_sub(COMPONENT_BITS);
+    }
+    packed
+}
+
+fn unpack_rotation(packed: u64) -> na::UnitQuaternion<f32> {
+    let largest = (packed >> (3 * COMPONENT_BITS) & 0b11) as usize;
+    let mut coords = na::Vector4::zeros();
+    let mut shift = 2 * COMPONENT_BITS;
+    let mut sum = 0.0;
+    for index in (0..4).filter(|index| *index != largest) {
+        let quantized = (packed >> shift & ((1 << COMPONENT_BITS) - 1)) as f32;
+        let value = (quantized / COMPONENT_MAX * 2.0 - 1.0) / std::f32::consts::SQRT_2;
+        coords[index] = value;
+        sum += value * value;
+        shift = shift.saturating_sub(COMPONENT_BITS);
+    }
+    coords[largest] = (1.0 - sum).max(0.0).sqrt();
+    na::UnitQuaternion::new_normalize(na::Quaternion::from(coords))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_within_quantization() {
+        let mut device = VrDevice::new(5);
+        let rotation = na::UnitQuaternion::from_euler_angles(0.3, -2.0, 1.1);
+        device.set_pose(true, na::Point3::new(1.234, 0.5, -3.21), rotation);
+        device.set_class(VrDeviceClass::Tracker);
+        device.set_state(TrackingState::RotationOnly);
+        let mut frame = TrackedObjects::new(70_000, 1_600_000_000_123, vec![device]);
+        frame.set_delta_of(69_990);
+        frame.set_extrapolated(true);
+
+        let mut buffer = vec![];
+        encode(&frame, &CompactConfig::default(), &mut buffer).unwrap();
+        assert_eq!(buffer.len(), HEADER_SIZE + DEVICE_SIZE);
+        assert!(is_compact(&buffer));
+
+        let decoded = decode(&buffer).unwrap();
+        assert_eq!(decoded.seq(), 70_000);
+        assert_eq!(decoded.ts(), 1_600_000_000_123);
+        assert_eq!(decoded.keyframe_seq(), Some(69_990));
+        assert!(decoded.extrapolated());
+        let device = &decoded.trackers()[0];
+        assert_eq!(device.id(), 5);
+        assert!(device.tracked());
+        assert_eq!(device.class(), VrDeviceClass::Tracker);
+        assert_eq!(device.state(), TrackingState::RotationOnly);
+        assert!((device.position() - na::Point3::new(1.234, 0.5, -3.21)).norm() < 0.001);
+        assert!(device.rotation().angle_to(&rotation) < 0.001);
+
+        assert!(decode(&buffer[..buffer.len() - 1]).is_err());
+    }
+}
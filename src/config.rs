@@ -0,0 +1,1233 @@
+use crate::anchors::{self, AnchorConfig};
+use crate::authentication::AuthenticationConfig;
+use crate::black_box::BlackBoxConfig;
+use crate::boundary::BoundaryConfig;
+use crate::centroid::CentroidConfig;
+use crate::compact::CompactConfig;
+use crate::control::{ControlClient, ControlCommand};
+use crate::data_channel::WebRtcConfig;
+use crate::device_ids;
+use crate::encryption::EncryptionConfig;
+use crate::env::{self, Variable};
+use crate::error::{Error, Result};
+use crate::extrapolation::ExtrapolationConfig;
+use crate::fragment;
+use crate::input::InputConfig;
+use crate::json_format::JsonFormat;
+use crate::markers::MarkerConfig;
+use crate::osc::OscConfig;
+use crate::pairs::{self, PairConfig};
+use crate::quality::CovarianceConfig;
+use crate::recording::RecordingConfig;
+use crate::reliable::ReliableConfig;
+use crate::required::RequiredDevicesConfig;
+use crate::simulation::SimulatedDevice;
+use crate::sink::{QueueConfig, SinkHealthConfig};
+use crate::skeleton::{self, SkeletonConfig};
+use crate::socket_options::SocketOptions;
+use crate::tracking_messages::{DeviceMetadata, DeviceRole, Universe, VrDevice, VrDeviceClass};
+use crate::transform_tree::TransformTreeConfig;
+use crate::workspace::WorkspaceLimit;
+use nalgebra as na;
+use serde::de::{self, DeserializeOwned, Visitor};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Runtime configuration loaded from a JSON file
+///
+/// Every field is optional, missing fields use the defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How many frames are published per second
+    pub rate_hz: f32,
+    /// How many times per second devices are polled. Defaults to `rate_hz`
+    pub sample_rate_hz: Option<f32>,
+    /// Device serial number to alias
+    pub aliases: HashMap<String, Arc<str>>,
+    /// Only publish devices of these classes. Publish everything if empty
+    pub classes: Vec<VrDeviceClass>,
+    /// Order of devices in frames
+    pub device_order: DeviceOrder,
+    /// Roles by serial or alias, overriding what OpenVR assigned. Hand roles also set the controller class
+    pub roles: HashMap<String, DeviceRole>,
+    /// Publish left controllers as right ones and the other way around
+    pub swap_hands: bool,
+    /// Transform applied to every pose before publishing
+    pub calibration: Option<na::Isometry3<f32>>,
+    /// Rotate poses about the vertical axis after calibration, so -Z points north
+    pub heading: Option<HeadingConfig>,
+    /// Only publish devices that changed since the last frame
+    pub change_only: Option<ChangeOnlyConfig>,
+    /// Lower the publish rate while nothing moves
+    pub adaptive_rate: Option<AdaptiveRateConfig>,
+    /// Seconds without frames after which a heartbeat is sent
+    pub heartbeat_interval_s: f32,
+    pub watchdog: WatchdogConfig,
+    /// Seconds between heartbeats carrying device tracking quality. 0 disables them
+    pub diagnostics_interval_s: f32,
+    /// Seconds between metadata messages. 0 sends serials, battery and dongles in every frame instead
+    pub metadata_interval_s: f32,
+    /// Queue settings by sink name. Only read at startup
+    pub sink_queues: HashMap<String, QueueConfig>,
+    /// JSON layout by sink name. Only read at startup
+    pub json_formats: HashMap<String, JsonFormat>,
+    /// Highest rate of pose frames in Hz by sink name, sinks not listed get every frame
+    pub sink_rates_hz: HashMap<String, f32>,
+    /// Disable sinks that keep failing for a while instead of stopping, `null` stops on the first error. Only read at startup
+    pub sink_health: Option<SinkHealthConfig>,
+    /// Multicast groups for specific device classes. Only read at startup
+    pub class_routes: Vec<ClassRoute>,
+    /// Send pose frames in the compact binary encoding on multicast. Only read at startup
+    pub compact: Option<CompactConfig>,
+    /// File keeping device ids stable per serial across restarts. Only read at startup
+    pub device_ids_file: Option<PathBuf>,
+    /// Sender id in every frame, a random UUID if not set. Only read at startup
+    pub sender_name: Option<Arc<str>>,
+    /// SteamVR tool used to power off devices, searched in the SteamVR install if not set
+    pub lighthouse_console: Option<PathBuf>,
+    /// Encrypt UDP and multicast payloads with a pre-shared key. Only read at startup
+    pub encryption: Option<EncryptionConfig>,
+    /// Append an HMAC to UDP and multicast payloads. Only read at startup
+    pub authentication: Option<AuthenticationConfig>,
+    /// Clients allowed to use the HTTP server beyond the dashboard, open to anyone if empty. Only read at startup
+    pub control_clients: Vec<ControlClient>,
+    /// Control commands need a `nonce` and a `ts` within this many seconds. Only read at startup
+    pub control_replay_window_s: Option<f32>,
+    /// Send buffer and QoS marking of outgoing UDP sockets. Only read at startup
+    pub socket_options: SocketOptions,
+    /// Split UDP and multicast payloads into parts so no packet is larger. Only read at startup
+    pub max_packet_bytes: Option<usize>,
+    /// Send `low_battery` events when a device's charge drops below a threshold
+    pub battery_alerts: Option<BatteryAlertConfig>,
+    /// Virtual devices at the weighted centroid of other devices, published in every frame
+    pub centroids: Vec<CentroidConfig>,
+    /// Head, hip, knee and foot joints solved from the headset, waist and foot trackers
+    pub skeleton: Option<SkeletonConfig>,
+    /// Named points captured with a controller trigger, published in every frame. Only read at startup
+    pub anchors: Option<AnchorConfig>,
+    /// Scripted devices merged into the live stream, as in a simulation file. Only read at startup
+    ///
+    /// Waypoint files are relative to the config file.
+    pub virtual_devices: Vec<SimulatedDevice>,
+    /// Record to JSONL files, started and stopped by holding buttons on both controllers. Only read at startup
+    pub recording: Option<RecordingConfig>,
+    /// Keep the last seconds of frames in memory and write them out when something goes wrong. Only read at startup
+    pub black_box: Option<BlackBoxConfig>,
+    /// Controller chord that sets numbered markers. Only read at startup
+    pub markers: MarkerConfig,
+    /// STUN servers and peer limit of `--webrtc`. Only read at startup
+    pub webrtc: WebRtcConfig,
+    /// Publish frames predicted from device velocities between samples, at a higher rate
+    pub extrapolation: Option<ExtrapolationConfig>,
+    /// Also publish every device's pose in these tracking universes, before calibration. Only read at startup
+    pub universes: Vec<Universe>,
+    /// Read controller buttons through SteamVR input actions instead of the legacy controller state. Only read at startup
+    pub input: Option<InputConfig>,
+    /// Add forward and up unit vectors of the rotation to every device
+    pub directions: bool,
+    /// Also publish poses as a tree of transforms, with static transforms below devices
+    pub transform_tree: Option<TransformTreeConfig>,
+    /// Also publish the pose of devices relative to other devices
+    pub pairs: Vec<PairConfig>,
+    /// Flag, or clamp, device poses outside of these limits, in order
+    pub workspace: Vec<WorkspaceLimit>,
+    /// Attach a pose covariance estimated from tracking state, noise and dropouts to every device
+    pub covariance: Option<CovarianceConfig>,
+    /// Addresses of OSC messages, by default one `/openvr/{name}/pose` per device. Only read at startup
+    pub osc: Option<OscConfig>,
+    /// Devices that must be tracked, each raises a `missing` event and shows in the status when it isn't
+    pub required_devices: Option<RequiredDevicesConfig>,
+    /// Raise events when watched devices get close to the edge of the play area
+    pub boundary: Option<BoundaryConfig>,
+    /// Retry event channel messages until receivers acknowledge them. Only read at startup
+    pub reliable_events: Option<ReliableConfig>,
+}
+
+/// Send devices of some classes to their own multicast group
+///
+/// Devices not covered by any route stay on the main group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassRoute {
+    pub classes: Vec<VrDeviceClass>,
+    pub address: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChangeOnlyConfig {
+    /// Seconds between frames that contain every device
+    pub keyframe_interval_s: f32,
+    /// Meters a device has to move to count as changed
+    pub position_threshold: f32,
+    /// Radians a device has to rotate to count as changed
+    pub rotation_threshold: f32,
+}
+
+impl Default for ChangeOnlyConfig {
+    fn default() -> Self {
+        Self {
+            keyframe_interval_s: 1.0,
+            position_threshold: 0.001,
+            rotation_threshold: 0.002,
+        }
+    }
+}
+
+/// Publish rate that follows how fast devices move
+///
+/// Scales linearly from `min_rate_hz` when everything rests up to `rate_hz`
+/// at `fast_speed_m_s` or `fast_angular_speed_rad_s`, whichever is reached first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveRateConfig {
+    pub min_rate_hz: f32,
+    pub fast_speed_m_s: f32,
+    pub fast_angular_speed_rad_s: f32,
+}
+
+impl Default for AdaptiveRateConfig {
+    fn default() -> Self {
+        Self {
+            min_rate_hz: 2.0,
+            fast_speed_m_s: 0.5,
+            fast_angular_speed_rad_s: 3.0,
+        }
+    }
+}
+
+/// Alignment of the tracking space with a heading reference, like a compass or a building grid
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadingConfig {
+    /// Heading of the -Z axis of the calibrated tracking space in degrees, clockwise from north
+    pub heading_deg: f32,
+    /// Magnetic declination in degrees, east positive, added when `heading_deg` was read off a compass
+    pub declination_deg: f32,
+}
+
+impl HeadingConfig {
+    /// Rotation that turns -Z to north and +X to east, Y staying up
+    pub fn rotation(&self) -> na::Isometry3<f32> {
+        let true_heading = self.heading_deg + self.declination_deg;
+        na::Isometry3::rotation(na::Vector3::y() * -true_heading.to_radians())
+    }
+}
+
+/// Order of devices in frames
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceOrder {
+    /// OpenVR index, then virtual and derived devices
+    #[default]
+    Index,
+    /// Headset, left and right hand, then trackers by role, then the rest in index order
+    Role,
+}
+
+/// Detection of poses that stopped updating
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Seconds of identical poses after which the stream counts as stale
+    pub stale_after_s: f32,
+    /// Reconnect to OpenVR when the stream goes stale
+    pub reinitialize: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            stale_after_s: 2.0,
+            reinitialize: false,
+        }
+    }
+}
+
+/// Warnings for devices running out of charge
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatteryAlertConfig {
+    /// Charge levels between 0 and 1, each raises one event when crossed downward
+    pub thresholds: Vec<f32>,
+    /// Milliseconds to buzz every controller with each alert, no buzz if not set
+    pub haptic_ms: Option<u64>,
+}
+
+impl Default for BatteryAlertConfig {
+    fn default() -> Self {
+        Self {
+            thresholds: vec![0.2, 0.1],
+            haptic_ms: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rate_hz: 50.0,
+            sample_rate_hz: None,
+            aliases: HashMap::new(),
+            classes: vec![],
+            device_order: DeviceOrder::Index,
+            roles: HashMap::new(),
+            swap_hands: false,
+            calibration: None,
+            heading: None,
+            change_only: None,
+            adaptive_rate: None,
+            heartbeat_interval_s: 1.0,
+            watchdog: WatchdogConfig::default(),
+            diagnostics_interval_s: 10.0,
+            metadata_interval_s: 0.0,
+            sink_queues: HashMap::new(),
+            json_formats: HashMap::new(),
+            sink_rates_hz: HashMap::new(),
+            sink_health: Some(SinkHealthConfig::default()),
+            class_routes: vec![],
+            compact: None,
+            device_ids_file: None,
+            sender_name: None,
+            lighthouse_console: None,
+            encryption: None,
+            authentication: None,
+            control_clients: vec![],
+            control_replay_window_s: None,
+            socket_options: SocketOptions::default(),
+            battery_alerts: None,
+            centroids: vec![],
+            skeleton: None,
+            anchors: None,
+            virtual_devices: vec![],
+            recording: None,
+            black_box: None,
+            markers: MarkerConfig::default(),
+            webrtc: WebRtcConfig::default(),
+            extrapolation: None,
+            universes: vec![],
+            input: None,
+            directions: false,
+            transform_tree: None,
+            pairs: vec![],
+            workspace: vec![],
+            covariance: None,
+            osc: None,
+            required_devices: None,
+            boundary: None,
+            reliable_events: None,
+            max_packet_bytes: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        load_json(path, "config")
+    }
+
+    /// Config from `path`, or the defaults, with top-level keys set by `variables`
+    ///
+    /// Values are JSON, or taken as a string if they aren't, so
+    /// `OPENVR_TRACKER_RATE_HZ=90` and `OPENVR_TRACKER_SENDER_NAME=stage` both
+    /// work. Variables that aren't config keys are skipped.
+    pub fn load_with_env(path: Option<&Path>, variables: &[Variable]) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::load(path)?,
+            None => Self::default(),
+        };
+        let keys = Self::keys();
+        for variable in variables.iter().filter(|v| keys.contains(&v.name.as_str())) {
+            let as_string = serde_json::Value::String(variable.value.clone());
+            let parsed =
+                serde_json::from_str(&variable.value).unwrap_or_else(|_| as_string.clone());
+            config = config
+                .with_key(&variable.name, parsed)
+                .or_else(|error| {
+                    config
+                        .with_key(&variable.name, as_string)
+                        .map_err(|_| error)
+                })
+                .map_err(|error| {
+                    Error::config_caused(format!("Invalid {}", variable.key()), error)
+                })?;
+        }
+        Ok(config)
+    }
+
+    /// [`load_with_env`](Self::load_with_env), failing if the config has any [`problems`](Self::problems)
+    pub fn load_checked(path: Option<&Path>, variables: &[Variable]) -> Result<Self> {
+        let config = Self::load_with_env(path, variables)?;
+        let problems = config.problems();
+        if !problems.is_empty() {
+            return Err(Error::config(format!(
+                "{} problems: {}",
+                problems.len(),
+                problems.join(", ")
+            )));
+        }
+        Ok(config)
+    }
+
+    fn with_key(&self, key: &str, value: serde_json::Value) -> serde_json::Result<Self> {
+        let mut config = serde_json::to_value(self)?;
+        config[key] = value;
+        serde_json::from_value(config)
+    }
+
+    /// Top-level keys of config files, as serde names the fields
+    pub fn keys() -> &'static [&'static str] {
+        let mut keys: &'static [&'static str] = &[];
+        // only reaches deserialize_struct, which records the fields and stops
+        let _ = Self::deserialize(FieldNames(&mut keys));
+        keys
+    }
+
+    /// Settings that parse but can't work as intended, like typos in sink names
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !positive(self.rate_hz) {
+            problems.push(format!("rate_hz must be positive, is {}", self.rate_hz));
+        }
+        if let Some(sample_rate_hz) = self.sample_rate_hz {
+            if !positive(sample_rate_hz) {
+                problems.push(format!(
+                    "sample_rate_hz must be positive, is {}",
+                    sample_rate_hz
+                ));
+            }
+        }
+        let mut aliases: Vec<(&String, &Arc<str>)> = self.aliases.iter().collect();
+        aliases.sort();
+        for (index, (serial, alias)) in aliases.iter().enumerate() {
+            if serial.is_empty() || serial.trim() != serial.as_str() {
+                problems.push(format!("Alias serial {:?} is empty or has spaces", serial));
+            }
+            if alias.trim().is_empty() {
+                problems.push(format!("Alias of {} is empty", serial));
+            }
+            if let Some((other, _)) = aliases[..index].iter().find(|(_, other)| other == alias) {
+                problems.push(format!(
+                    "{} and {} are both aliased {}",
+                    other, serial, alias
+                ));
+            }
+        }
+        if let Some(calibration) = &self.calibration {
+            let finite = calibration
+                .translation
+                .vector
+                .iter()
+                .all(|value| value.is_finite())
+                && calibration
+                    .rotation
+                    .coords
+                    .iter()
+                    .all(|value| value.is_finite());
+            if !finite {
+                problems.push("calibration is not finite".to_owned());
+            }
+        }
+        if let Some(heading) = &self.heading {
+            if !heading.heading_deg.is_finite() || !heading.declination_deg.is_finite() {
+                problems.push("heading is not finite".to_owned());
+            }
+        }
+        if seconds(self.heartbeat_interval_s).is_none() {
+            problems.push(format!(
+                "heartbeat_interval_s must be a number of seconds, is {}",
+                self.heartbeat_interval_s
+            ));
+        }
+        for (name, interval) in [
+            ("diagnostics_interval_s", self.diagnostics_interval_s),
+            ("metadata_interval_s", self.metadata_interval_s),
+        ] {
+            if (interval.is_nan() || interval > 0.0) && seconds(interval).is_none() {
+                problems.push(format!(
+                    "{} must be a number of seconds or 0, is {}",
+                    name, interval
+                ));
+            }
+        }
+        if let Some(change_only) = &self.change_only {
+            let interval = change_only.keyframe_interval_s;
+            if !positive(interval) || seconds(interval).is_none() {
+                problems.push(format!(
+                    "change_only.keyframe_interval_s must be a positive number of seconds, is {}",
+                    interval
+                ));
+            }
+            if change_only.position_threshold < 0.0 || change_only.rotation_threshold < 0.0 {
+                problems.push("change_only thresholds can't be negative".to_owned());
+            }
+        }
+        if let Some(battery_alerts) = &self.battery_alerts {
+            for threshold in &battery_alerts.thresholds {
+                if !(*threshold > 0.0 && *threshold <= 1.0) {
+                    problems.push(format!(
+                        "battery_alerts thresholds must be between 0 and 1, one is {}",
+                        threshold
+                    ));
+                }
+            }
+        }
+        for (index, centroid) in self.centroids.iter().enumerate() {
+            problems.extend(centroid.problems());
+            if self.centroids[..index]
+                .iter()
+                .any(|other| other.id == centroid.id)
+            {
+                problems.push(format!("Two centroids have id {}", centroid.id));
+            }
+        }
+        if let Some(recording) = &self.recording {
+            problems.extend(recording.problems());
+        }
+        if let Some(black_box) = &self.black_box {
+            problems.extend(black_box.problems());
+        }
+        if let Some(extrapolation) = &self.extrapolation {
+            problems.extend(extrapolation.problems());
+        }
+        if let Some(input) = &self.input {
+            problems.extend(input.problems());
+        }
+        if let Some(tree) = &self.transform_tree {
+            problems.extend(tree.problems());
+        }
+        problems.extend(pairs::problems(&self.pairs));
+        problems.extend(self.webrtc.problems());
+        for limit in &self.workspace {
+            problems.extend(limit.problems());
+        }
+        if let Some(covariance) = &self.covariance {
+            problems.extend(covariance.problems());
+        }
+        if let Some(osc) = &self.osc {
+            problems.extend(osc.problems());
+        }
+        for hand in [DeviceRole::LeftHand, DeviceRole::RightHand] {
+            let mut names: Vec<&str> = self
+                .roles
+                .iter()
+                .filter(|(_, role)| **role == hand)
+                .map(|(name, _)| name.as_str())
+                .collect();
+            if names.len() > 1 {
+                names.sort_unstable();
+                problems.push(format!(
+                    "roles assign {} to several devices: {}",
+                    hand.name(),
+                    names.join(", ")
+                ));
+            }
+        }
+        if let Some(required_devices) = &self.required_devices {
+            problems.extend(required_devices.problems());
+        }
+        if let Some(boundary) = &self.boundary {
+            problems.extend(boundary.problems());
+        }
+        if let Some(reliable_events) = &self.reliable_events {
+            problems.extend(reliable_events.problems());
+        }
+        if let Some(max_packet_bytes) = self.max_packet_bytes {
+            if max_packet_bytes < fragment::MIN_PACKET_BYTES {
+                problems.push(format!(
+                    "max_packet_bytes {} is too small, use at least {}",
+                    max_packet_bytes,
+                    fragment::MIN_PACKET_BYTES
+                ));
+            }
+        }
+        for (index, universe) in self.universes.iter().enumerate() {
+            if self.universes[..index].contains(universe) {
+                problems.push(format!("Universe {:?} is listed twice", universe));
+            }
+        }
+        if let Some(skeleton) = &self.skeleton {
+            problems.extend(skeleton.problems());
+        }
+        problems.extend(self.virtual_id_problems());
+        if let Some(adaptive) = &self.adaptive_rate {
+            if !positive(adaptive.min_rate_hz) || adaptive.min_rate_hz > self.rate_hz {
+                problems.push(format!(
+                    "adaptive_rate.min_rate_hz must be between 0 and rate_hz, is {}",
+                    adaptive.min_rate_hz
+                ));
+            }
+            if !positive(adaptive.fast_speed_m_s) || !positive(adaptive.fast_angular_speed_rad_s) {
+                problems.push("adaptive_rate speeds must be positive".to_owned());
+            }
+        }
+        if !positive(self.watchdog.stale_after_s) {
+            problems.push("watchdog.stale_after_s must be positive".to_owned());
+        }
+        for (sink, rate) in &self.sink_rates_hz {
+            if !positive(*rate) {
+                problems.push(format!(
+                    "Rate of sink {} must be positive, is {}",
+                    sink, rate
+                ));
+            }
+        }
+        if let Some(sink_health) = &self.sink_health {
+            problems.extend(sink_health.problems());
+        }
+        let sinks = self.sink_queues.keys().chain(self.json_formats.keys());
+        for sink in sinks.chain(self.sink_rates_hz.keys()) {
+            if !SINK_NAMES.contains(&sink.as_str()) {
+                problems.push(format!(
+                    "Unknown sink {:?}, expected one of {}",
+                    sink,
+                    SINK_NAMES.join(", ")
+                ));
+            }
+        }
+        for (index, route) in self.class_routes.iter().enumerate() {
+            if !route.address.ip().is_multicast() {
+                problems.push(format!("Class route {} is not multicast", route.address));
+            }
+            for class in &route.classes {
+                if !self.classes.is_empty() && !self.classes.contains(class) {
+                    problems.push(format!(
+                        "Class route {} covers {:?}, which the class filter drops",
+                        route.address, class
+                    ));
+                }
+                if self.class_routes[..index]
+                    .iter()
+                    .any(|other| other.classes.contains(class))
+                {
+                    problems.push(format!("{:?} is in more than one class route", class));
+                }
+            }
+        }
+        if let Some(compact) = &self.compact {
+            let ordered = compact
+                .bounds_min
+                .iter()
+                .zip(compact.bounds_max.iter())
+                .all(|(min, max)| min < max);
+            if !ordered {
+                problems.push("compact.bounds_min must be below bounds_max".to_owned());
+            }
+        }
+        if let Some(encryption) = &self.encryption {
+            if let Err(error) = encryption.cipher() {
+                problems.push(format!("encryption: {}", error.describe()));
+            }
+        }
+        if let Some(authentication) = &self.authentication {
+            if let Err(error) = authentication.authenticator() {
+                problems.push(format!("authentication: {}", error.describe()));
+            }
+        }
+        problems.extend(self.socket_options.problems());
+        if let Some(window) = self.control_replay_window_s {
+            if !positive(window) {
+                problems.push(format!(
+                    "control_replay_window_s must be positive, is {}",
+                    window
+                ));
+            }
+        }
+        for (index, client) in self.control_clients.iter().enumerate() {
+            if client.token.is_empty() {
+                problems.push(format!("Control client {} has an empty token", client.name));
+            }
+            if self.control_clients[..index]
+                .iter()
+                .any(|other| other.token == client.token)
+            {
+                problems.push(format!(
+                    "Control client {} shares its token with another client",
+                    client.name
+                ));
+            }
+            for command in &client.commands {
+                if !ControlCommand::NAMES.contains(&command.as_str()) {
+                    problems.push(format!(
+                        "Control client {} allows unknown command {:?}, expected one of {}",
+                        client.name,
+                        command,
+                        ControlCommand::NAMES.join(", ")
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Skeleton joints, anchors, mapped devices and centroids sharing ids, or with ids compact frames can't carry
+    fn virtual_id_problems(&self) -> Vec<String> {
+        let mut ranges: Vec<(String, Range<usize>)> = vec![];
+        if let Some(skeleton) = &self.skeleton {
+            let ids = skeleton.first_id..skeleton.first_id + skeleton::JOINTS.len();
+            ranges.push(("skeleton joints".to_owned(), ids));
+        }
+        if let Some(anchors) = &self.anchors {
+            let ids = anchors.first_id..anchors.first_id + anchors::MAX_ANCHORS;
+            ranges.push(("anchors".to_owned(), ids));
+        }
+        if self.device_ids_file.is_some() {
+            let ids =
+                device_ids::FIRST_MAPPED_ID..device_ids::FIRST_MAPPED_ID + device_ids::MAPPED_IDS;
+            ranges.push(("mapped devices".to_owned(), ids));
+        }
+        let mut problems = vec![];
+        let overlap = |a: &Range<usize>, b: &Range<usize>| a.start < b.end && b.start < a.end;
+        for (index, (name, ids)) in ranges.iter().enumerate() {
+            for (other, other_ids) in &ranges[..index] {
+                if overlap(ids, other_ids) {
+                    problems.push(format!("Ids of {} and {} overlap", other, name));
+                }
+            }
+        }
+        for centroid in &self.centroids {
+            for (name, ids) in &ranges {
+                if ids.contains(&centroid.id) {
+                    problems.push(format!(
+                        "Centroid {} has the id of one of the {}",
+                        centroid.name, name
+                    ));
+                }
+            }
+        }
+        // centroids sharing an id are reported with the other centroid problems
+        ranges.extend(self.centroids.iter().map(|centroid| {
+            let ids = centroid.id..centroid.id + 1;
+            (format!("centroid {}", centroid.name), ids)
+        }));
+        if self.compact.is_some() {
+            for (name, ids) in &ranges {
+                if ids.end > usize::from(u8::MAX) + 1 {
+                    problems.push(format!(
+                        "Ids of {} go above {}, compact frames can't carry them",
+                        name,
+                        u8::MAX
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    pub fn period(&self) -> Duration {
+        Duration::from_secs_f32(1.0 / self.rate_hz.max(0.01))
+    }
+
+    pub fn sample_period(&self) -> Duration {
+        let rate_hz = self.sample_rate_hz.unwrap_or(self.rate_hz);
+        Duration::from_secs_f32(1.0 / rate_hz.max(0.01))
+    }
+
+    /// `None` when diagnostics are disabled
+    pub fn diagnostics_interval(&self) -> Option<Duration> {
+        seconds(self.diagnostics_interval_s).filter(|interval| !interval.is_zero())
+    }
+
+    /// `None` when metadata is sent in every frame
+    pub fn metadata_interval(&self) -> Option<Duration> {
+        seconds(self.metadata_interval_s).filter(|interval| !interval.is_zero())
+    }
+
+    /// Never for intervals too long for a [`Duration`]
+    pub fn heartbeat_interval(&self) -> Duration {
+        seconds(self.heartbeat_interval_s.max(0.0)).unwrap_or(Duration::MAX)
+    }
+
+    /// Whether device passes the class filter
+    pub fn accepts(&self, device: &VrDevice) -> bool {
+        self.classes.is_empty() || self.classes.contains(&device.class())
+    }
+
+    /// Swap hands and apply role overrides, before the class filter
+    ///
+    /// A controller OpenVR gave a hand that an override assigns to another
+    /// controller gets the other hand, or none if that is taken too.
+    pub fn assign_roles(&self, devices: &mut [VrDevice]) {
+        if !self.swap_hands && self.roles.is_empty() {
+            return;
+        }
+        let mut overridden = vec![false; devices.len()];
+        for (device, overridden) in devices.iter_mut().zip(&mut overridden) {
+            if self.swap_hands {
+                match device.class() {
+                    VrDeviceClass::LeftController => set_hand(device, Some(DeviceRole::RightHand)),
+                    VrDeviceClass::RightController => set_hand(device, Some(DeviceRole::LeftHand)),
+                    _ => {}
+                }
+            }
+            let role = self
+                .roles
+                .iter()
+                .find(|(name, _)| device_named(device, name, &self.aliases))
+                .map(|(_, role)| *role);
+            if let Some(role) = role {
+                *overridden = true;
+                match role {
+                    DeviceRole::LeftHand | DeviceRole::RightHand => set_hand(device, Some(role)),
+                    _ => device.set_role(Some(role)),
+                }
+            }
+        }
+        let claimed = |role: DeviceRole, devices: &[VrDevice]| {
+            devices
+                .iter()
+                .zip(&overridden)
+                .any(|(device, overridden)| *overridden && device.role() == Some(role))
+        };
+        for index in 0..devices.len() {
+            let hand = match devices[index].role() {
+                Some(hand @ DeviceRole::LeftHand) | Some(hand @ DeviceRole::RightHand) => hand,
+                _ => continue,
+            };
+            if overridden[index] || !claimed(hand, devices) {
+                continue;
+            }
+            let other = match hand {
+                DeviceRole::LeftHand => DeviceRole::RightHand,
+                _ => DeviceRole::LeftHand,
+            };
+            let taken = claimed(other, devices)
+                || devices.iter().any(|device| device.role() == Some(other));
+            set_hand(&mut devices[index], if taken { None } else { Some(other) });
+        }
+    }
+
+    /// Details of every device that passes the filter, for the metadata message
+    pub fn metadata(&self, devices: &[VrDevice]) -> Vec<DeviceMetadata> {
+        let mut devices = devices.to_vec();
+        self.assign_roles(&mut devices);
+        devices
+            .iter()
+            .filter(|device| device.seen() && self.accepts(device))
+            .map(|device| {
+                let mut metadata = DeviceMetadata::from(device);
+                if let Some(alias) = device.serial().and_then(|serial| self.aliases.get(serial)) {
+                    metadata.alias = Some(alias.clone());
+                }
+                metadata
+            })
+            .collect()
+    }
+
+    /// Apply alias and calibration to a device, dropping details sent with metadata
+    pub fn apply(&self, device: &mut VrDevice) {
+        // devices that already have an alias, e.g. from an aggregated source, keep it
+        let alias = device
+            .serial()
+            .and_then(|serial| self.aliases.get(serial))
+            .cloned();
+        if alias.is_some() {
+            device.set_alias(alias);
+        }
+        // matched before the serial is stripped, limited in the published frame
+        let limits: Vec<&WorkspaceLimit> = self
+            .workspace
+            .iter()
+            .filter(|limit| limit.applies_to(device, &self.aliases))
+            .collect();
+        if self.metadata_interval().is_some() {
+            device.strip_metadata();
+        }
+        if let Some(calibration) = &self.calibration {
+            device.transform(calibration);
+        }
+        if let Some(heading) = &self.heading {
+            device.transform(&heading.rotation());
+        }
+        let mut outside = false;
+        for limit in limits {
+            outside |= limit.limit(device);
+        }
+        device.set_outside_workspace(outside);
+        if self.directions {
+            device.set_directions();
+        }
+    }
+}
+
+/// Whether `name` is the serial or alias of `device`
+///
+/// `aliases` is [`Config::aliases`]. An alias the device already carries counts too.
+pub fn device_named(device: &VrDevice, name: &str, aliases: &HashMap<String, Arc<str>>) -> bool {
+    let alias = device
+        .alias()
+        .or_else(|| aliases.get(device.serial()?).map(|alias| &**alias));
+    device.serial() == Some(name) || alias == Some(name)
+}
+
+/// Make `device` the controller of `hand`, or a controller without a hand
+fn set_hand(device: &mut VrDevice, hand: Option<DeviceRole>) {
+    device.set_class(match hand {
+        Some(DeviceRole::LeftHand) => VrDeviceClass::LeftController,
+        Some(DeviceRole::RightHand) => VrDeviceClass::RightController,
+        _ => VrDeviceClass::Controller,
+    });
+    device.set_role(hand);
+}
+
+/// Deserializer that records the field names of the struct it is asked for
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> serde::Deserializer<'de> for FieldNames<'a> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("only structs have field names"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("field names recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// False for NaN as well
+fn positive(value: f32) -> bool {
+    value > 0.0 && value.is_finite()
+}
+
+/// `value` seconds, `None` if it is negative, not finite or too long for a [`Duration`]
+pub(crate) fn seconds(value: f32) -> Option<Duration> {
+    Duration::try_from_secs_f32(value).ok()
+}
+
+/// Names of the built-in sinks, as used in `sink_queues`, `json_formats` and `sink_rates_hz`
+pub const SINK_NAMES: [&str; 8] = [
+    "multicast",
+    "osc",
+    "http",
+    "webrtc",
+    "udp",
+    "tcp",
+    "recording",
+    "black_box",
+];
+
+/// Text of a file the config points to, `what` names it in errors
+pub(crate) fn read_file(path: &Path, what: &str) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|error| {
+        Error::config_caused(format!("Failed to read {} {}", what, path.display()), error)
+    })
+}
+
+/// JSON file the config points to, parse errors show the offending line
+pub(crate) fn load_json<T: DeserializeOwned>(path: &Path, what: &str) -> Result<T> {
+    let text = read_file(path, what)?;
+    serde_json::from_str(&text).map_err(|error| {
+        let mut message = format!("Failed to parse {} {}", what, path.display());
+        if let Some(context) = line_context(&text, &error) {
+            message.push('\n');
+            message.push_str(&context);
+        }
+        Error::config_caused(message, error)
+    })
+}
+
+/// Line of a parse error with a marker under the column
+fn line_context(text: &str, error: &serde_json::Error) -> Option<String> {
+    let line = match text.lines().nth(error.line().saturating_sub(1)) {
+        Some(line) if error.line() > 0 => line,
+        _ => return None,
+    };
+    let number = error.line().to_string();
+    Some(format!(
+        "{} | {}\n{} | {}^",
+        number,
+        line,
+        " ".repeat(number.len()),
+        " ".repeat(error.column().saturating_sub(1))
+    ))
+}
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reloads config when the file changes on disk
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    error: Option<String>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            modified: modified_time(path),
+            last_check: Instant::now(),
+            error: None,
+        }
+    }
+
+    /// Returns the new config if the file changed since the last call
+    ///
+    /// Invalid configs, and ones with [`problems`](Config::problems), are
+    /// reported and ignored so that the stream keeps running.
+    pub fn poll(&mut self) -> Option<Config> {
+        if self.last_check.elapsed() < WATCH_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        match Config::load_checked(Some(&self.path), &env::variables()) {
+            Ok(config) => {
+                eprintln!("Reloaded config {}", self.path.display());
+                Some(config)
+            }
+            Err(error) => {
+                let error = error.describe();
+                eprintln!("Keeping previous config: {}", error);
+                self.error = Some(error);
+                None
+            }
+        }
+    }
+
+    /// Why the last change was rejected, once
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+
+    #[test]
+    fn test_apply_filters_and_aliases() {
+        let config: Config =
+            serde_json::from_str(r#"{"aliases": {"LHR-1234": "waist"}, "classes": ["Tracker"]}"#)
+                .unwrap();
+        let mut tracker = VrDevice::new(1);
+        tracker.set_class(VrDeviceClass::Tracker);
+        tracker.set_serial(Some("LHR-1234".into()));
+        let mut hmd = VrDevice::new(0);
+        hmd.set_class(VrDeviceClass::HMD);
+        assert!(!config.accepts(&hmd));
+        assert!(config.accepts(&tracker));
+        let mut separate = config.clone();
+        separate.metadata_interval_s = 5.0;
+        config.apply(&mut tracker);
+        assert_eq!(tracker.alias(), Some("waist"));
+        assert_eq!(tracker.serial(), Some("LHR-1234"));
+        separate.apply(&mut tracker);
+        assert_eq!(tracker.serial(), None);
+        assert_eq!(tracker.forward(), None);
+    }
+
+    #[test]
+    fn test_directions_follow_calibration() {
+        let config = Config {
+            directions: true,
+            calibration: Some(na::Isometry3::rotation(
+                na::Vector3::y() * std::f32::consts::FRAC_PI_2,
+            )),
+            ..Default::default()
+        };
+        let mut tracker = device(1).class(VrDeviceClass::Tracker).build();
+        config.apply(&mut tracker);
+        let forward = tracker.forward().unwrap();
+        assert!((forward - -na::Vector3::x()).norm() < 1e-6, "{}", forward);
+        assert!((tracker.up().unwrap() - na::Vector3::y()).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_heading_turns_minus_z_north() {
+        let config: Config =
+            serde_json::from_str(r#"{"heading": {"heading_deg": 80, "declination_deg": 10}}"#)
+                .unwrap();
+        assert!(config.problems().is_empty());
+        // -Z points east, so a device one meter along it ends up along +X
+        let mut device = VrDevice::new(0);
+        device.set_pose(
+            true,
+            na::Point3::new(0.0, 1.5, -1.0),
+            na::UnitQuaternion::identity(),
+        );
+        config.apply(&mut device);
+        assert!((device.position() - na::Point3::new(1.0, 1.5, 0.0)).norm() < 1e-5);
+        assert!((device.rotation() * -na::Vector3::z() - na::Vector3::x()).norm() < 1e-5);
+    }
+
+    #[test]
+    fn test_role_overrides() {
+        let controller = |id, serial, class| device(id).serial(serial).class(class).build();
+        let mut devices = vec![
+            controller(1, "LHR-A", VrDeviceClass::LeftController),
+            controller(2, "LHR-B", VrDeviceClass::RightController),
+            controller(3, "LHR-C", VrDeviceClass::Tracker),
+        ];
+        let config: Config = serde_json::from_str(
+            r#"{"aliases": {"LHR-C": "belt"}, "roles": {"LHR-B": "left_hand", "belt": "waist"}}"#,
+        )
+        .unwrap();
+        config.assign_roles(&mut devices);
+        let roles: Vec<_> = devices
+            .iter()
+            .map(|device| (device.class(), device.role()))
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                (VrDeviceClass::RightController, Some(DeviceRole::RightHand)),
+                (VrDeviceClass::LeftController, Some(DeviceRole::LeftHand)),
+                (VrDeviceClass::Tracker, Some(DeviceRole::Waist)),
+            ]
+        );
+
+        let swap = Config {
+            swap_hands: true,
+            ..Default::default()
+        };
+        swap.assign_roles(&mut devices);
+        assert_eq!(devices[0].class(), VrDeviceClass::LeftController);
+        assert_eq!(devices[1].role(), Some(DeviceRole::RightHand));
+    }
+
+    #[test]
+    fn test_problems_and_line_context() {
+        let config: Config = serde_json::from_str(
+            r#"{"aliases": {"LHR-1": "waist", "LHR-2": "waist"}, "json_formats": {"multicats": {}}}"#,
+        )
+        .unwrap();
+        let problems = config.problems();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("both aliased waist"));
+        assert!(problems[1].contains("multicats"));
+        assert!(Config::default().problems().is_empty());
+
+        let text = "{\n  \"rate_hz\": \"fast\"\n}";
+        let error = serde_json::from_str::<Config>(text).unwrap_err();
+        let message = line_context(text, &error).unwrap();
+        assert!(
+            message.starts_with("2 |   \"rate_hz\": \"fast\"\n"),
+            "{}",
+            message
+        );
+        assert!(message.ends_with(&format!("  | {}^", " ".repeat(18))));
+    }
+
+    #[test]
+    fn test_intervals_fit_a_duration() {
+        let config = Config {
+            heartbeat_interval_s: f32::INFINITY,
+            diagnostics_interval_s: f32::NAN,
+            metadata_interval_s: 1e30,
+            change_only: Some(ChangeOnlyConfig {
+                keyframe_interval_s: f32::INFINITY,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let problems = config.problems();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].starts_with("heartbeat_interval_s"));
+        assert!(problems[1].starts_with("diagnostics_interval_s"));
+        assert!(problems[2].starts_with("metadata_interval_s"));
+        assert!(problems[3].starts_with("change_only.keyframe_interval_s"));
+        assert_eq!(config.heartbeat_interval(), Duration::MAX);
+        assert_eq!(config.diagnostics_interval(), None);
+        assert_eq!(config.metadata_interval(), None);
+    }
+
+    #[test]
+    fn test_virtual_device_ids() {
+        let mut config = Config {
+            skeleton: Some(SkeletonConfig::default()),
+            anchors: Some(AnchorConfig::default()),
+            compact: Some(CompactConfig::default()),
+            centroids: vec![CentroidConfig {
+                id: 100,
+                name: "body".into(),
+                members: vec![],
+            }],
+            ..Default::default()
+        };
+        assert!(config.virtual_id_problems().is_empty());
+
+        config.centroids[0].id = 203;
+        config.anchors.as_mut().unwrap().first_id = 300;
+        config.skeleton.as_mut().unwrap().first_id = 320;
+        assert_eq!(
+            config.virtual_id_problems(),
+            vec![
+                "Ids of skeleton joints and anchors overlap",
+                "Ids of skeleton joints go above 255, compact frames can't carry them",
+                "Ids of anchors go above 255, compact frames can't carry them",
+            ]
+        );
+        config.skeleton.as_mut().unwrap().first_id = 200;
+        config.compact = None;
+        assert_eq!(
+            config.virtual_id_problems(),
+            vec!["Centroid body has the id of one of the skeleton joints"]
+        );
+    }
+
+    #[test]
+    fn test_environment_overrides() {
+        let variables = [
+            Variable::new("rate_hz", "90"),
+            Variable::new("sender_name", "1234"),
+            Variable::new("classes", r#"["Tracker"]"#),
+            Variable::new("http", "0.0.0.0:8080"),
+        ];
+        let config = Config::load_with_env(None, &variables).unwrap();
+        assert_eq!(config.rate_hz, 90.0);
+        // not valid for the key as JSON, so taken as a string
+        assert_eq!(config.sender_name.as_deref(), Some("1234"));
+        assert_eq!(config.classes, vec![VrDeviceClass::Tracker]);
+        assert!(Config::keys().contains(&"universes"));
+        match serde_json::to_value(Config::default()).unwrap() {
+            serde_json::Value::Object(defaults) => assert_eq!(Config::keys().len(), defaults.len()),
+            _ => unreachable!(),
+        }
+
+        let error = Config::load_with_env(None, &[Variable::new("rate_hz", "fast")]).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Invalid OPENVR_TRACKER_RATE_HZ"));
+    }
+
+    #[test]
+    fn test_load_checked_rejects_problems() {
+        assert!(Config::load_checked(None, &[Variable::new("rate_hz", "90")]).is_ok());
+        let error = Config::load_checked(None, &[Variable::new("rate_hz", "-1")]).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("rate_hz must be positive, is -1"));
+    }
+}
@@ -0,0 +1,386 @@
+//! Remote control of a running sender
+//!
+//! The HTTP server queues [`ControlCommand`]s and serves the latest
+//! [`ControlStatus`]. The publisher applies the commands between frames and
+//! keeps the status current.
+//!
+//! With [`ControlClient`]s configured, requests need a client's token and
+//! commands are limited to what that client is allowed to send.
+//! With a replay window set, commands carry a `ts` and a single use `nonce`,
+//! and stale or repeated commands are rejected by the [`ReplayGuard`].
+//! Signed requests always need them, checked against [`DEFAULT_REPLAY_WINDOW`]
+//! if no window is set, or a captured signature could be sent again forever.
+
+use crate::authentication::Authenticator;
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ControlCommand {
+    /// Resume publishing frames
+    Start,
+    /// Stop publishing frames. Devices are still sampled
+    Stop,
+    /// Change the publish rate until the config file changes
+    SetRate { rate_hz: f32 },
+    /// Replace the calibration transform until the config file changes
+    SetCalibration {
+        calibration: Option<na::Isometry3<f32>>,
+    },
+    /// Reconnect to OpenVR
+    Reinitialize,
+    /// Turn off these controllers and trackers, all connected ones if empty
+    PowerOff {
+        #[serde(default)]
+        serials: Vec<String>,
+    },
+    /// Store the pose of the next controller trigger press as anchor `name`
+    CaptureAnchor { name: Arc<str> },
+    /// Forget anchor `name`
+    RemoveAnchor { name: String },
+    /// Publish a marker, numbered if it has no name
+    Marker {
+        #[serde(default)]
+        name: Option<String>,
+    },
+}
+
+impl ControlCommand {
+    /// Every command name, as used in JSON and in client allowlists
+    pub const NAMES: &'static [&'static str] = &[
+        "start",
+        "stop",
+        "set_rate",
+        "set_calibration",
+        "reinitialize",
+        "power_off",
+        "capture_anchor",
+        "remove_anchor",
+        "marker",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlCommand::Start => "start",
+            ControlCommand::Stop => "stop",
+            ControlCommand::SetRate { .. } => "set_rate",
+            ControlCommand::SetCalibration { .. } => "set_calibration",
+            ControlCommand::Reinitialize => "reinitialize",
+            ControlCommand::PowerOff { .. } => "power_off",
+            ControlCommand::CaptureAnchor { .. } => "capture_anchor",
+            ControlCommand::RemoveAnchor { .. } => "remove_anchor",
+            ControlCommand::Marker { .. } => "marker",
+        }
+    }
+}
+
+/// A client allowed to use the control API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlClient {
+    /// Shown in the log with each command
+    pub name: String,
+    /// Sent as `Authorization: Bearer <token>`
+    pub token: String,
+    /// Command names this client may send, all of them if empty
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+/// How a request proves which client sent it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    /// `Authorization: Bearer <token>`
+    Token(String),
+    /// `Authorization: HMAC <client>:<hex HMAC-SHA256 of the body keyed with the token>`
+    ///
+    /// The token never goes over the network and the body can't be changed.
+    Signature { client: String, signature: String },
+}
+
+impl Credentials {
+    /// Parse the value of an `Authorization` header
+    pub fn parse(header: &str) -> Option<Self> {
+        let header = header.trim();
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            return Some(Credentials::Token(token.trim().to_owned()));
+        }
+        let (client, signature) = header.strip_prefix("HMAC ")?.trim().rsplit_once(':')?;
+        Some(Credentials::Signature {
+            client: client.to_owned(),
+            signature: signature.to_ascii_lowercase(),
+        })
+    }
+
+    fn identifies(&self, client: &ControlClient, body: &[u8]) -> bool {
+        match self {
+            Credentials::Token(token) => same_token(client.token.as_bytes(), token.as_bytes()),
+            Credentials::Signature {
+                client: name,
+                signature,
+            } => {
+                if name != &client.name {
+                    return false;
+                }
+                let expected = match Authenticator::new(client.token.as_bytes()) {
+                    Ok(authenticator) => authenticator.tag(body),
+                    Err(_) => return false,
+                };
+                let expected: String = expected
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                same_token(expected.as_bytes(), signature.as_bytes())
+            }
+        }
+    }
+}
+
+/// Replay window of signed requests when none is configured
+pub const DEFAULT_REPLAY_WINDOW: Duration = Duration::from_secs(30);
+
+/// Rejects commands that are too old or were seen before
+#[derive(Debug, Clone)]
+pub struct ReplayGuard {
+    window_ms: u128,
+    /// Nonces within the window with their `ts`
+    seen: HashMap<String, u128>,
+}
+
+impl ReplayGuard {
+    /// Accept commands whose `ts` is within `window` of the local clock
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_ms: window.as_millis(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Check `nonce` and `ts` (milliseconds since unix epoch) against the clock `now`
+    pub fn check(&mut self, nonce: Option<&str>, ts: Option<u64>, now: u128) -> Result<(), String> {
+        let (nonce, ts) = match (nonce, ts) {
+            (Some(nonce), Some(ts)) if !nonce.is_empty() => (nonce, ts as u128),
+            _ => return Err("Commands need a nonce and a ts".to_owned()),
+        };
+        if ts + self.window_ms < now || ts > now + self.window_ms {
+            return Err(format!("Command ts {} is outside the replay window", ts));
+        }
+        let window_ms = self.window_ms;
+        // nonces older than the window can go, their ts would be rejected anyway
+        self.seen.retain(|_, seen| *seen + window_ms >= now);
+        if self.seen.insert(nonce.to_owned(), ts).is_some() {
+            return Err(format!("Nonce {:?} was already used", nonce));
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of checking a request against the configured clients
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    /// Allowed, with the client name if clients are configured
+    Allowed(Option<String>),
+    /// Missing or unknown token
+    Unauthorized,
+    /// Valid token, but the client may not send this command
+    Forbidden(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ControlStatus {
+    pub streaming: bool,
+    pub rate_hz: f32,
+    pub sender: String,
+    pub uptime_s: f32,
+    /// Devices seen since startup
+    pub devices: usize,
+    /// Devices tracked in the latest sample
+    pub tracked: usize,
+    /// Frames dropped by slow sinks
+    pub dropped: u64,
+    pub stale: bool,
+    /// Required devices that aren't tracked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+}
+
+/// Handle shared by the HTTP server and the publisher
+#[derive(Clone, Default)]
+pub struct Control {
+    commands: Arc<Mutex<Vec<ControlCommand>>>,
+    status: Arc<Mutex<ControlStatus>>,
+    clients: Arc<Mutex<Vec<ControlClient>>>,
+    replay_guard: Arc<Mutex<Option<ReplayGuard>>>,
+    /// Used for signed requests while no replay window is set
+    signed_guard: Arc<Mutex<Option<ReplayGuard>>>,
+}
+
+impl Control {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&self, command: ControlCommand) {
+        self.commands.lock().unwrap().push(command);
+    }
+
+    /// Commands received since the last call, oldest first
+    pub fn take_commands(&self) -> Vec<ControlCommand> {
+        std::mem::take(&mut *self.commands.lock().unwrap())
+    }
+
+    pub fn status(&self) -> ControlStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn set_status(&self, status: ControlStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+
+    /// Require a token from one of `clients`, anyone can send commands if empty
+    pub fn set_clients(&self, clients: Vec<ControlClient>) {
+        *self.clients.lock().unwrap() = clients;
+    }
+
+    /// Require a fresh `ts` and an unused `nonce` on every command
+    pub fn set_replay_window(&self, window: Option<Duration>) {
+        *self.replay_guard.lock().unwrap() = window.map(ReplayGuard::new);
+    }
+
+    /// Check the `nonce` and `ts` of a request
+    ///
+    /// With a replay window every request needs them. `signed` requests need them
+    /// without one too, checked against [`DEFAULT_REPLAY_WINDOW`]. Other requests
+    /// pass without a replay window.
+    pub fn check_fresh(
+        &self,
+        signed: bool,
+        nonce: Option<&str>,
+        ts: Option<u64>,
+        now: u128,
+    ) -> Result<(), String> {
+        if let Some(guard) = self.replay_guard.lock().unwrap().as_mut() {
+            return guard.check(nonce, ts, now);
+        }
+        if !signed {
+            return Ok(());
+        }
+        self.signed_guard
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| ReplayGuard::new(DEFAULT_REPLAY_WINDOW))
+            .check(nonce, ts, now)
+    }
+
+    /// Check `credentials` for the request `body` and, for commands, whether the client may send `command`
+    pub fn authorize(
+        &self,
+        credentials: Option<&Credentials>,
+        body: &[u8],
+        command: Option<&ControlCommand>,
+    ) -> Access {
+        let clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Access::Allowed(None);
+        }
+        let client = match credentials.and_then(|credentials| {
+            clients
+                .iter()
+                .find(|client| credentials.identifies(client, body))
+        }) {
+            Some(client) => client,
+            None => return Access::Unauthorized,
+        };
+        match command {
+            Some(command)
+                if !client.commands.is_empty()
+                    && !client.commands.iter().any(|name| name == command.name()) =>
+            {
+                Access::Forbidden(client.name.clone())
+            }
+            _ => Access::Allowed(Some(client.name.clone())),
+        }
+    }
+}
+
+/// Compare without stopping at the first difference
+fn same_token(expected: &[u8], received: &[u8]) -> bool {
+    expected.len() == received.len()
+        && expected
+            .iter()
+            .zip(received)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_commands_and_replays() {
+        let control = Control::new();
+        control.set_clients(vec![ControlClient {
+            name: "robot".into(),
+            token: "secret".into(),
+            commands: vec![],
+        }]);
+        let body = br#"{"command": "stop", "nonce": "1", "ts": 1000}"#;
+        // HMAC-SHA256 of the body keyed with "secret"
+        let signature: String = Authenticator::new(b"secret")
+            .unwrap()
+            .tag(body)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let signed = Credentials::parse(&format!("HMAC robot:{}", signature)).unwrap();
+        assert_eq!(
+            control.authorize(Some(&signed), body, Some(&ControlCommand::Stop)),
+            Access::Allowed(Some("robot".into()))
+        );
+        assert_eq!(
+            control.authorize(Some(&signed), b"{}", Some(&ControlCommand::Stop)),
+            Access::Unauthorized
+        );
+        assert_eq!(
+            Credentials::parse("Bearer secret"),
+            Some(Credentials::Token("secret".into()))
+        );
+
+        assert!(control.check_fresh(false, None, None, 1000).is_ok());
+        // signed commands can't be replayed without a configured window either
+        assert!(control.check_fresh(true, None, None, 1000).is_err());
+        assert!(control
+            .check_fresh(true, Some("1"), Some(1000), 1500)
+            .is_ok());
+        assert!(control
+            .check_fresh(true, Some("1"), Some(1000), 1600)
+            .is_err());
+        assert!(control
+            .check_fresh(true, Some("2"), Some(1000), 40_000)
+            .is_err());
+
+        control.set_replay_window(Some(Duration::from_secs(5)));
+        assert!(control
+            .check_fresh(false, Some("1"), Some(1000), 1500)
+            .is_ok());
+        assert!(control
+            .check_fresh(false, Some("1"), Some(1000), 1600)
+            .is_err());
+        assert!(control
+            .check_fresh(false, Some("2"), Some(1000), 7000)
+            .is_err());
+        assert!(control
+            .check_fresh(false, Some("3"), Some(9000), 1000)
+            .is_err());
+        assert!(control.check_fresh(false, None, Some(1000), 1000).is_err());
+        // the nonce is forgotten once its ts is out of the window
+        assert!(control
+            .check_fresh(false, Some("1"), Some(5000), 8000)
+            .is_ok());
+    }
+}
@@ -0,0 +1,297 @@
+//! Frames over WebRTC data channels, for browsers on other networks
+//!
+//! Server-Sent Events ride on TCP, so one lost packet holds up every frame
+//! behind it. A data channel carries frames over SCTP without retransmission:
+//! late frames are dropped and the rest keep their order. Browsers post their
+//! SDP offer to `/webrtc` on the HTTP server and get the answer back with all
+//! ICE candidates, no trickling. STUN servers let the sender find its public
+//! address, so browsers behind NAT connect directly without a relay.
+//!
+//! Frames go on the pre-negotiated channel with id 0, which the browser
+//! creates with `negotiated: true, id: 0`. The sender sets the channel up as
+//! ordered without retransmits whatever the browser asks for.
+//!
+//! Peers are only handled with the `webrtc` feature, the config is always
+//! there so configs stay valid across builds.
+
+use serde::{Deserialize, Serialize};
+
+/// Id of the negotiated channel frames are sent on
+pub const CHANNEL_ID: u16 = 0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebRtcConfig {
+    /// `stun:` URLs of servers that tell the sender its public address, none by default
+    pub stun_servers: Vec<String>,
+    /// Browsers connected at once, offers beyond are refused
+    pub max_peers: usize,
+}
+
+impl Default for WebRtcConfig {
+    fn default() -> Self {
+        Self {
+            stun_servers: vec![],
+            max_peers: 8,
+        }
+    }
+}
+
+impl WebRtcConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.max_peers == 0 {
+            problems.push("webrtc.max_peers must be at least 1".to_owned());
+        }
+        for server in &self.stun_servers {
+            if !server.starts_with("stun:") && !server.starts_with("stuns:") {
+                problems.push(format!("webrtc STUN server {} is not a stun: URL", server));
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(feature = "webrtc")]
+pub use peers::DataChannelSink;
+
+#[cfg(feature = "webrtc")]
+mod peers {
+    use super::{WebRtcConfig, CHANNEL_ID};
+    use crate::error::{Error, Result};
+    use crate::http::OfferHandler;
+    use crate::json_format::JsonFormat;
+    use crate::sink::PoseSink;
+    use crate::tracking_messages::TrackedObjects;
+    use crate::traffic::Traffic;
+    use std::sync::{Arc, Mutex};
+    use tokio::runtime::Runtime;
+    use webrtc::api::{APIBuilder, API};
+    use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+    use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+    use webrtc::data_channel::RTCDataChannel;
+    use webrtc::ice_transport::ice_server::RTCIceServer;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+
+    struct Peer {
+        connection: Arc<RTCPeerConnection>,
+        channel: Arc<RTCDataChannel>,
+    }
+
+    /// State shared between the sink and the signaling requests
+    struct Shared {
+        runtime: Runtime,
+        api: API,
+        config: WebRtcConfig,
+        peers: Mutex<Vec<Peer>>,
+    }
+
+    impl Shared {
+        /// Answer `offer`, a JSON session description, and keep the peer
+        fn answer(&self, offer: &str) -> Result<String> {
+            let offer: RTCSessionDescription = serde_json::from_str(offer).map_err(|error| {
+                Error::InvalidInput(format!("Offer isn't a session description: {}", error))
+            })?;
+            if self.peers.lock().unwrap().len() >= self.config.max_peers {
+                return Err(Error::InvalidInput(format!(
+                    "Already {} browsers connected",
+                    self.config.max_peers
+                )));
+            }
+            let mut configuration = RTCConfiguration::default();
+            if !self.config.stun_servers.is_empty() {
+                configuration.ice_servers.push(RTCIceServer {
+                    urls: self.config.stun_servers.clone(),
+                    ..Default::default()
+                });
+            }
+            let (connection, channel, answer) = self
+                .runtime
+                .block_on(async {
+                    let connection = Arc::new(self.api.new_peer_connection(configuration).await?);
+                    let init = RTCDataChannelInit {
+                        ordered: Some(true),
+                        max_retransmits: Some(0),
+                        negotiated: Some(CHANNEL_ID),
+                        ..Default::default()
+                    };
+                    let channel = connection.create_data_channel("frames", Some(init)).await?;
+                    connection.set_remote_description(offer).await?;
+                    let answer = connection.create_answer(None).await?;
+                    let mut gathered = connection.gathering_complete_promise().await;
+                    connection.set_local_description(answer).await?;
+                    let _ = gathered.recv().await;
+                    let answer = connection.local_description().await;
+                    Ok::<_, webrtc::Error>((connection, channel, answer))
+                })
+                .map_err(|error| Error::external_caused("WebRTC negotiation failed", error))?;
+            let answer =
+                answer.ok_or_else(|| Error::external("No answer after gathering candidates"))?;
+            self.peers.lock().unwrap().push(Peer {
+                connection,
+                channel,
+            });
+            Ok(serde_json::to_string(&answer)?)
+        }
+    }
+
+    /// Sends frames to every browser with an open data channel
+    pub struct DataChannelSink {
+        shared: Arc<Shared>,
+        format: JsonFormat,
+        buffer: Vec<u8>,
+        traffic: Traffic,
+    }
+
+    impl DataChannelSink {
+        pub fn new(config: WebRtcConfig) -> Result<Self> {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(2)
+                .thread_name("webrtc")
+                .enable_all()
+                .build()?;
+            Ok(Self {
+                shared: Arc::new(Shared {
+                    runtime,
+                    api: APIBuilder::new().build(),
+                    config,
+                    peers: Mutex::default(),
+                }),
+                format: JsonFormat::default(),
+                buffer: vec![],
+                traffic: Traffic::new(),
+            })
+        }
+
+        pub fn set_format(&mut self, format: JsonFormat) {
+            self.format = format;
+        }
+
+        /// Answers offers posted to `/webrtc`
+        pub fn offer_handler(&self) -> OfferHandler {
+            let shared = self.shared.clone();
+            Arc::new(move |offer: &str| shared.answer(offer))
+        }
+
+        /// Browsers with an open channel
+        pub fn connected(&self) -> usize {
+            let peers = self.shared.peers.lock().unwrap();
+            peers
+                .iter()
+                .filter(|peer| peer.channel.ready_state() == RTCDataChannelState::Open)
+                .count()
+        }
+    }
+
+    impl PoseSink for DataChannelSink {
+        fn name(&self) -> &str {
+            "webrtc"
+        }
+
+        fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+            self.buffer.clear();
+            self.format.write(frame, &mut self.buffer)?;
+            let text = String::from_utf8_lossy(&self.buffer).into_owned();
+            let shared = &self.shared;
+            let mut peers = shared.peers.lock().unwrap();
+            peers.retain(|peer| {
+                let state = peer.connection.connection_state();
+                if matches!(
+                    state,
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+                ) {
+                    let connection = peer.connection.clone();
+                    shared
+                        .runtime
+                        .spawn(async move { connection.close().await });
+                    return false;
+                }
+                true
+            });
+            for peer in peers
+                .iter()
+                .filter(|peer| peer.channel.ready_state() == RTCDataChannelState::Open)
+            {
+                // a browser that went away fails its connection and is dropped above
+                if let Ok(sent) = shared
+                    .runtime
+                    .block_on(peer.channel.send_text(text.clone()))
+                {
+                    self.traffic.record(sent);
+                }
+            }
+            Ok(())
+        }
+
+        fn traffic(&self) -> Option<&Traffic> {
+            Some(&self.traffic)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::time::{Duration, Instant};
+
+        #[test]
+        fn test_browser_receives_frames() {
+            let mut sink = DataChannelSink::new(WebRtcConfig {
+                stun_servers: vec![],
+                ..Default::default()
+            })
+            .unwrap();
+            let handler = sink.offer_handler();
+            let runtime = Runtime::new().unwrap();
+            // plays the browser
+            let (browser, channel, received) = runtime.block_on(async {
+                let browser = APIBuilder::new()
+                    .build()
+                    .new_peer_connection(RTCConfiguration::default())
+                    .await
+                    .unwrap();
+                let init = RTCDataChannelInit {
+                    negotiated: Some(CHANNEL_ID),
+                    ..Default::default()
+                };
+                let channel = browser
+                    .create_data_channel("frames", Some(init))
+                    .await
+                    .unwrap();
+                let received = Arc::new(Mutex::new(vec![]));
+                let messages = received.clone();
+                channel.on_message(Box::new(move |message| {
+                    messages.lock().unwrap().push(message.data.to_vec());
+                    Box::pin(async {})
+                }));
+                let offer = browser.create_offer(None).await.unwrap();
+                let mut gathered = browser.gathering_complete_promise().await;
+                browser.set_local_description(offer).await.unwrap();
+                let _ = gathered.recv().await;
+                (browser, channel, received)
+            });
+            let offer = runtime.block_on(browser.local_description()).unwrap();
+            let answer = handler(&serde_json::to_string(&offer).unwrap()).unwrap();
+            let answer: RTCSessionDescription = serde_json::from_str(&answer).unwrap();
+            runtime
+                .block_on(browser.set_remote_description(answer))
+                .unwrap();
+
+            let start = Instant::now();
+            while received.lock().unwrap().is_empty() {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                sink.publish(&TrackedObjects::new(1, 1000, vec![])).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            assert_eq!(sink.connected(), 1);
+            let frame: TrackedObjects =
+                serde_json::from_slice(&received.lock().unwrap()[0]).unwrap();
+            assert_eq!(frame.seq(), 1);
+            assert!(handler("{}").is_err());
+            runtime.block_on(channel.close()).unwrap();
+            runtime.block_on(browser.close()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Published device ids that survive restarts
+//!
+//! OpenVR hands out device indices in connection order, so the same tracker
+//! can come back under a different index after SteamVR restarts. With a map
+//! file every serial keeps the id it got the first time it was seen.
+//!
+//! Mapped ids start at [`FIRST_MAPPED_ID`], so they can't take the id of a
+//! device without a serial, which keeps its OpenVR index.
+
+use crate::config::load_json;
+use crate::error::Result;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Id of the first serial, above the OpenVR slots, the camera and virtual devices
+pub const FIRST_MAPPED_ID: usize = 128;
+
+/// Ids from [`FIRST_MAPPED_ID`] taken by a full set of OpenVR devices
+pub const MAPPED_IDS: usize = openvr::MAX_TRACKED_DEVICE_COUNT;
+
+/// Serial to id map, saved to its file whenever a new serial shows up
+#[derive(Debug, Clone, Default)]
+pub struct DeviceIdMap {
+    path: Option<PathBuf>,
+    ids: BTreeMap<String, usize>,
+}
+
+impl DeviceIdMap {
+    /// Map kept in memory only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load map from `path`, starting empty if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let ids = if path.exists() {
+            load_json(path, "device ids")?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            path: Some(path.to_owned()),
+            ids,
+        })
+    }
+
+    /// Id of `serial`, assigning the lowest free id from [`FIRST_MAPPED_ID`] to new serials
+    pub fn id(&mut self, serial: &str) -> usize {
+        if let Some(id) = self.ids.get(serial) {
+            return *id;
+        }
+        let id = (FIRST_MAPPED_ID..)
+            .find(|id| !self.ids.values().any(|used| used == id))
+            .unwrap_or_default();
+        self.ids.insert(serial.to_owned(), id);
+        if let Err(error) = self.save() {
+            eprintln!("Failed to save device ids: {}", error.describe());
+        }
+        id
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // write next to the file and rename, so a crash can't leave half a map
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_string_pretty(&self.ids)?)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_persist() {
+        let path = std::env::temp_dir().join(format!("device-ids-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut ids = DeviceIdMap::load(&path).unwrap();
+        assert_eq!(ids.id("LHR-B"), FIRST_MAPPED_ID);
+        assert_eq!(ids.id("LHR-A"), FIRST_MAPPED_ID + 1);
+        assert_eq!(ids.id("LHR-B"), FIRST_MAPPED_ID);
+
+        let mut reloaded = DeviceIdMap::load(&path).unwrap();
+        assert_eq!(reloaded.id("LHR-A"), FIRST_MAPPED_ID + 1);
+        assert_eq!(reloaded.id("LHR-C"), FIRST_MAPPED_ID + 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
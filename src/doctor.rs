@@ -0,0 +1,190 @@
+//! Environment checks behind `openvr-tracker doctor`
+//!
+//! Runs the steps a stream needs one at a time, so a broken setup shows
+//! which step fails instead of just producing no data.
+
+use crate::error::{Error, Result};
+use crate::identity::SenderIdentity;
+use crate::multicast::{MessageReceiver, MessageSender};
+use crate::openvr_adaptor::{self, VrDeviceManager};
+use crate::tracking_messages::TrackedObjects;
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to wait for the probe to come back
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    /// Add a check, with the detail of why it failed as the error
+    fn push(&mut self, name: &'static str, result: Result<String, String>) -> bool {
+        let passed = result.is_ok();
+        let detail = match result {
+            Ok(detail) => detail,
+            Err(detail) => detail,
+        };
+        self.checks.push(Check {
+            name,
+            passed,
+            detail,
+        });
+        passed
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            let result = if check.passed { "PASS" } else { "FAIL" };
+            writeln!(f, "[{}] {}", result, check.name)?;
+            for line in check.detail.lines() {
+                writeln!(f, "       {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check OpenVR and the multicast group `address`
+pub fn diagnose(address: SocketAddrV4) -> Report {
+    let mut report = Report::new();
+    check_openvr(&mut report);
+    check_multicast(address, &mut report);
+    report
+}
+
+fn check_openvr(report: &mut Report) {
+    let running = if openvr_adaptor::runtime_running() {
+        Ok("SteamVR accepts connections".to_owned())
+    } else {
+        Err("SteamVR is not running or refused the connection".to_owned())
+    };
+    if !report.push("OpenVR runtime", running) {
+        return;
+    }
+    let devices = VrDeviceManager::new()
+        .map_err(|error| error.describe())
+        .map(|mut manager| {
+            manager.update();
+            manager.device_list()
+        });
+    report.push(
+        "Devices",
+        devices.and_then(|devices| {
+            let lines: Vec<String> = devices
+                .iter()
+                .filter(|device| device.seen())
+                .map(|device| {
+                    format!(
+                        "{} {:?} {} {}",
+                        device.id(),
+                        device.class(),
+                        device.serial().unwrap_or("-"),
+                        if device.tracked() {
+                            "tracked"
+                        } else {
+                            "not tracked"
+                        }
+                    )
+                })
+                .collect();
+            if lines.is_empty() {
+                return Err("No devices connected".to_owned());
+            }
+            Ok(lines.join("\n"))
+        }),
+    );
+}
+
+/// Join the group, send a heartbeat and wait for it to loop back
+pub fn check_multicast(address: SocketAddrV4, report: &mut Report) {
+    let receiver = MessageReceiver::new(address);
+    let joined = receiver
+        .as_ref()
+        .map(|_| format!("Joined {} on the default interface", address))
+        .map_err(|error| format!("Can't join {}: {}", address, error));
+    let mut receiver = match (report.push("Join multicast group", joined), receiver) {
+        (true, Ok(receiver)) => receiver,
+        _ => return,
+    };
+
+    let probe = SenderIdentity::new(Some("openvr-tracker-doctor".into()));
+    let sent = send_probe(address, &probe).map_err(|error| error.describe());
+    if !report.push("Send probe", sent) {
+        return;
+    }
+
+    let started = Instant::now();
+    let mut received = Err(format!(
+        "Probe didn't come back within {}s, multicast loopback or the firewall may block it",
+        PROBE_TIMEOUT.as_secs()
+    ));
+    while started.elapsed() < PROBE_TIMEOUT {
+        let bytes = match receiver.recv_timeout(PROBE_TIMEOUT - started.elapsed()) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => break,
+            Err(error) => {
+                received = Err(Error::Network(error).describe());
+                break;
+            }
+        };
+        let frame: Option<TrackedObjects> = serde_json::from_slice(bytes).ok();
+        if frame.and_then(|frame| frame.sender().cloned()).as_ref() == Some(&probe) {
+            received = Ok(format!(
+                "Received after {:.1} ms",
+                started.elapsed().as_secs_f64() * 1000.0
+            ));
+            break;
+        }
+    }
+    report.push("Loopback receive", received);
+}
+
+/// Heartbeat frame, which every receiver accepts and ignores
+fn send_probe(address: SocketAddrV4, probe: &SenderIdentity) -> Result<String> {
+    let mut sender = MessageSender::new(address)?;
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let mut frame = TrackedObjects::heartbeat(0, ts);
+    frame.set_sender(Some(probe.clone()));
+    sender.send(&serde_json::to_string(&frame)?)?;
+    Ok(format!("Sent heartbeat to {}", address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multicast_loopback() {
+        let mut report = Report::new();
+        check_multicast("239.0.0.22:7391".parse().unwrap(), &mut report);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.checks.len(), 3);
+        assert!(report
+            .to_string()
+            .starts_with("[PASS] Join multicast group\n"));
+    }
+}
@@ -0,0 +1,216 @@
+//! Pre-shared key encryption of UDP and multicast payloads
+//!
+//! Payloads are sealed with ChaCha20-Poly1305 (RFC 8439) from the
+//! `chacha20poly1305` crate under a 256 bit key shared by sender and
+//! receivers. A packet is the header, the nonce, the ciphertext and the tag,
+//! with the header authenticated as associated data:
+//!
+//! ```text
+//! "OE" | version 1 | 12 byte nonce | ciphertext | 16 byte tag
+//! ```
+//!
+//! The nonce is a prefix from the OS random number generator, chosen at
+//! startup, followed by a counter that starts at the current time in
+//! microseconds, so restarts don't reuse nonces. Receivers with a key drop
+//! every packet that doesn't authenticate, including plaintext ones.
+
+use crate::config::read_file;
+use crate::error::{Error, Result};
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 2] = b"OE";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 3;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Bytes encryption adds to a payload
+pub const OVERHEAD: usize = HEADER_SIZE + NONCE_SIZE + TAG_SIZE;
+
+/// Where the key comes from, hex encoded either way
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// 64 hex digits
+    pub key: Option<String>,
+    /// File containing the 64 hex digits, keeps the key out of the config
+    pub key_file: Option<PathBuf>,
+}
+
+impl EncryptionConfig {
+    pub fn cipher(&self) -> Result<Cipher> {
+        match (&self.key, &self.key_file) {
+            (Some(key), None) => Cipher::from_hex(key),
+            (None, Some(path)) => Cipher::from_file(path),
+            _ => Err(Error::config("Set exactly one of key and key_file")),
+        }
+    }
+}
+
+/// Whether `bytes` look like an encrypted packet
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_SIZE && &bytes[..2] == MAGIC
+}
+
+/// Seals and opens packets with one key
+///
+/// Every sender needs its own `Cipher`, cloning picks a new nonce prefix.
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    nonce_prefix: [u8; 4],
+    counter: u64,
+}
+
+impl Clone for Cipher {
+    fn clone(&self) -> Self {
+        // sharing the nonce sequence with another sender would reuse nonces
+        Self::with_aead(self.aead.clone())
+    }
+}
+
+impl Cipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self::with_aead(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    fn with_aead(aead: ChaCha20Poly1305) -> Self {
+        let counter = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut nonce_prefix = [0; 4];
+        getrandom::getrandom(&mut nonce_prefix).expect("No random numbers from the OS");
+        Self {
+            aead,
+            nonce_prefix,
+            counter,
+        }
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(Error::config("Key has to be 64 hex digits"));
+        }
+        let mut key = [0; 32];
+        for (index, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16)
+                .map_err(|error| Error::config_caused("Key has to be 64 hex digits", error))?;
+        }
+        Ok(Self::new(key))
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self> {
+        let hex = read_file(path, "key")?;
+        Self::from_hex(&hex).map_err(|error| {
+            Error::config_caused(format!("Invalid key in {}", path.display()), error)
+        })
+    }
+
+    /// Encrypt `plaintext` into a packet in `out`, replacing its contents
+    pub fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>) {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[..4].copy_from_slice(&self.nonce_prefix);
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter = self.counter.wrapping_add(1);
+        out.clear();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&nonce);
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+        let (header, ciphertext) = out.split_at_mut(start);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                &header[..HEADER_SIZE],
+                ciphertext,
+            )
+            .expect("Payload too long to encrypt");
+        out.extend_from_slice(&tag);
+    }
+
+    /// Check and decrypt `packet` into `out`, replacing its contents
+    pub fn open(&self, packet: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        if !is_encrypted(packet) {
+            return Err(Error::Rejected("Packet isn't encrypted".to_owned()));
+        }
+        if packet[2] != VERSION {
+            return Err(Error::Rejected(format!(
+                "Unsupported encryption version {}",
+                packet[2]
+            )));
+        }
+        if packet.len() < OVERHEAD {
+            return Err(Error::Rejected("Encrypted packet too short".to_owned()));
+        }
+        let nonce = Nonce::from_slice(&packet[HEADER_SIZE..HEADER_SIZE + NONCE_SIZE]);
+        let (ciphertext, tag) =
+            packet[HEADER_SIZE + NONCE_SIZE..].split_at(packet.len() - OVERHEAD);
+        out.clear();
+        out.extend_from_slice(ciphertext);
+        let opened = self.aead.decrypt_in_place_detached(
+            nonce,
+            &packet[..HEADER_SIZE],
+            out,
+            Tag::from_slice(tag),
+        );
+        if opened.is_err() {
+            out.clear();
+            return Err(Error::Rejected(
+                "Packet failed authentication, wrong key or tampered".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::{Aead, Payload};
+
+    #[test]
+    fn test_round_trip_and_tampering() {
+        let key = [7; 32];
+        let mut sender = Cipher::new(key);
+        let receiver = Cipher::new(key);
+        let (mut packet, mut plaintext) = (vec![], vec![]);
+        sender.seal(b"{\"seq\":1}", &mut packet);
+        assert!(is_encrypted(&packet));
+        assert_eq!(packet.len(), OVERHEAD + 9);
+        receiver.open(&packet, &mut plaintext).unwrap();
+        assert_eq!(plaintext, b"{\"seq\":1}");
+        // plain RFC 8439 with the header as associated data, readable by any implementation
+        let opened = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(
+                Nonce::from_slice(&packet[HEADER_SIZE..HEADER_SIZE + NONCE_SIZE]),
+                Payload {
+                    msg: &packet[HEADER_SIZE + NONCE_SIZE..],
+                    aad: &packet[..HEADER_SIZE],
+                },
+            )
+            .unwrap();
+        assert_eq!(opened, b"{\"seq\":1}");
+        let mut second = vec![];
+        sender.seal(b"{\"seq\":1}", &mut second);
+        assert_ne!(packet, second);
+        // a clone is another sender with its own nonces
+        let mut clone = sender.clone();
+        clone.seal(b"{\"seq\":1}", &mut second);
+        assert_ne!(
+            packet[HEADER_SIZE..HEADER_SIZE + 4],
+            second[HEADER_SIZE..HEADER_SIZE + 4]
+        );
+
+        packet[HEADER_SIZE + NONCE_SIZE] ^= 1;
+        assert!(receiver.open(&packet, &mut plaintext).is_err());
+        assert!(receiver.open(b"{\"seq\":1}", &mut plaintext).is_err());
+        assert!(Cipher::new([8; 32]).open(&second, &mut plaintext).is_err());
+        assert!(Cipher::from_hex("00").is_err());
+    }
+}
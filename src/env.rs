@@ -0,0 +1,129 @@
+//! Settings from `OPENVR_TRACKER_*` environment variables
+//!
+//! Services and containers are easier to reconfigure through their environment
+//! than by editing the command line in a unit file. A variable named after a
+//! top-level flag, like `OPENVR_TRACKER_HTTP` for `--http`, acts as if the
+//! flag was passed. One named after a top-level config key, like
+//! `OPENVR_TRACKER_RATE_HZ`, replaces that key of the config file.
+//!
+//! Flags read their variable through clap's `env`, so switches take `1`,
+//! `true`, `yes` or `on`, and `0`, `false`, `no` or `off`. Flags on the command
+//! line win over the environment, which wins over the config file, which wins
+//! over the defaults.
+
+use clap::Command;
+use std::ffi::OsStr;
+
+pub const PREFIX: &str = "OPENVR_TRACKER_";
+
+/// A setting from the environment, `name` is lowercase without the prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub value: String,
+}
+
+impl Variable {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    /// Name of the environment variable
+    pub fn key(&self) -> String {
+        format!("{}{}", PREFIX, self.name.to_uppercase())
+    }
+}
+
+/// `OPENVR_TRACKER_*` variables of this process
+pub fn variables() -> Vec<Variable> {
+    let mut variables: Vec<Variable> = std::env::vars()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(PREFIX)?;
+            Some(Variable {
+                name: name.to_lowercase(),
+                value,
+            })
+        })
+        .collect();
+    variables.sort_by(|a, b| a.name.cmp(&b.name));
+    variables
+}
+
+/// Variables that are neither the `env` of an argument of `app` nor one of `config_keys`
+pub fn unknown<'a>(
+    app: &Command,
+    config_keys: &[&str],
+    variables: &'a [Variable],
+) -> Vec<&'a Variable> {
+    let flags: Vec<&OsStr> = app
+        .get_arguments()
+        .filter_map(|arg| arg.get_env())
+        .collect();
+    variables
+        .iter()
+        .filter(|variable| {
+            !flags.contains(&OsStr::new(&variable.key()))
+                && !config_keys.contains(&variable.name.as_str())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::builder::BoolishValueParser;
+    use clap::Arg;
+
+    #[test]
+    fn test_unknown_variables() {
+        let app = Command::new("test")
+            .arg(
+                Arg::new("http")
+                    .long("http")
+                    .takes_value(true)
+                    .env("OPENVR_TRACKER_HTTP"),
+            )
+            .arg(Arg::new("tui").long("tui").env("OPENVR_TRACKER_TUI"));
+        let variables = [
+            Variable::new("http", "0.0.0.0:8080"),
+            Variable::new("tui", "yes"),
+            Variable::new("rate_hz", "90"),
+        ];
+        assert!(unknown(&app, &["rate_hz"], &variables).is_empty());
+        let typo = [Variable::new("tiu", "1")];
+        assert_eq!(unknown(&app, &[], &typo)[0].key(), "OPENVR_TRACKER_TIU");
+    }
+
+    #[test]
+    fn test_switches_from_environment() {
+        let app = || {
+            Command::new("test").arg(
+                Arg::new("tui")
+                    .long("tui")
+                    .env("OPENVR_TRACKER_TEST_TUI")
+                    .action(clap::ArgAction::SetTrue)
+                    .value_parser(BoolishValueParser::new()),
+            )
+        };
+        std::env::set_var("OPENVR_TRACKER_TEST_TUI", "yes");
+        assert!(*app()
+            .get_matches_from(["test"])
+            .get_one::<bool>("tui")
+            .unwrap());
+        std::env::set_var("OPENVR_TRACKER_TEST_TUI", "off");
+        assert!(!*app()
+            .get_matches_from(["test"])
+            .get_one::<bool>("tui")
+            .unwrap());
+        assert!(*app()
+            .get_matches_from(["test", "--tui"])
+            .get_one::<bool>("tui")
+            .unwrap());
+        std::env::set_var("OPENVR_TRACKER_TEST_TUI", "maybe");
+        assert!(app().try_get_matches_from(["test"]).is_err());
+        std::env::remove_var("OPENVR_TRACKER_TEST_TUI");
+    }
+}
@@ -0,0 +1,225 @@
+//! Errors of the library API
+//!
+//! Everything in the library returns [`Error`], so callers can tell a runtime
+//! that isn't up yet, and is worth retrying, from a config that will never
+//! work. Causes are kept as [`source`](std::error::Error::source), the
+//! binaries print the whole chain through `anyhow`.
+
+use std::error::Error as StdError;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// SteamVR isn't installed, or its installation is broken
+    #[error("SteamVR is not installed")]
+    RuntimeNotInstalled(#[source] openvr::InitError),
+    /// SteamVR isn't running or has no headset yet, connecting later may work
+    #[error("SteamVR is not ready")]
+    RuntimeNotReady(#[source] openvr::InitError),
+    /// Any other failure to connect to OpenVR
+    #[error("Failed to connect to OpenVR")]
+    Init(#[source] openvr::InitError),
+    /// An OpenVR interface, like the tracked camera, is missing or failed
+    #[error("{0}")]
+    Interface(String),
+    /// Binding, joining, sending to or receiving from the network failed
+    #[error("Network error")]
+    Network(#[source] io::Error),
+    /// Reading or writing a stream failed
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+    /// A file or directory for output can't be created
+    #[error("Failed to create {}", path.display())]
+    Create {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Config, or a file it points to, can't be read, parsed or used
+    #[error("{message}")]
+    Config {
+        message: String,
+        #[source]
+        source: Option<Box<dyn StdError + Send + Sync>>,
+    },
+    /// Frame or message can't be serialized or deserialized as JSON
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+    /// Writing text output, like a BVH or CSV export, failed
+    #[error("Formatting error")]
+    Format(#[from] std::fmt::Error),
+    /// Received bytes aren't a frame
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+    /// Packet failed authentication or decryption, or is a replay
+    #[error("{0}")]
+    Rejected(String),
+    /// Arguments the operation can't work with, like too few samples
+    #[error("{0}")]
+    InvalidInput(String),
+    /// Not available on this platform
+    #[error("{0}")]
+    Unsupported(String),
+    /// The thread or peer on the other end went away
+    #[error("{0}")]
+    Closed(String),
+    /// An external program or service failed
+    #[error("{message}")]
+    External {
+        message: String,
+        #[source]
+        source: Option<Box<dyn StdError + Send + Sync>>,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Whether the cause can go away without changes, like SteamVR starting up
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::RuntimeNotReady(_) | Error::Network(_))
+    }
+
+    /// Config error without an underlying cause
+    pub(crate) fn config(message: impl Into<String>) -> Self {
+        Error::Config {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Config error caused by `source`, like a file that failed to parse
+    pub(crate) fn config_caused(
+        message: impl Into<String>,
+        source: impl Into<Box<dyn StdError + Send + Sync>>,
+    ) -> Self {
+        Error::Config {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// External failure without an underlying cause
+    pub(crate) fn external(message: impl Into<String>) -> Self {
+        Error::External {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// External failure caused by `source`
+    pub(crate) fn external_caused(
+        message: impl Into<String>,
+        source: impl Into<Box<dyn StdError + Send + Sync>>,
+    ) -> Self {
+        Error::External {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// Message with its causes on one line, for logs and status reports
+    pub fn describe(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = self.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        message
+    }
+}
+
+impl From<openvr::InitError> for Error {
+    fn from(error: openvr::InitError) -> Self {
+        match init_error_kind(init_error_code(error)) {
+            InitErrorKind::NotInstalled => Error::RuntimeNotInstalled(error),
+            InitErrorKind::NotReady => Error::RuntimeNotReady(error),
+            InitErrorKind::Other => Error::Init(error),
+        }
+    }
+}
+
+/// Raw code of `error`, which the bindings keep private
+fn init_error_code(error: openvr::InitError) -> openvr_sys::EVRInitError {
+    // safe because InitError is a newtype around the raw code
+    unsafe { std::mem::transmute(error) }
+}
+
+#[derive(Debug, PartialEq)]
+enum InitErrorKind {
+    NotInstalled,
+    NotReady,
+    Other,
+}
+
+fn init_error_kind(code: openvr_sys::EVRInitError) -> InitErrorKind {
+    match code {
+        openvr_sys::EVRInitError_VRInitError_Init_InstallationNotFound
+        | openvr_sys::EVRInitError_VRInitError_Init_InstallationCorrupt
+        | openvr_sys::EVRInitError_VRInitError_Init_VRClientDLLNotFound
+        | openvr_sys::EVRInitError_VRInitError_Init_PathRegistryNotFound => {
+            InitErrorKind::NotInstalled
+        }
+        openvr_sys::EVRInitError_VRInitError_Init_HmdNotFound
+        | openvr_sys::EVRInitError_VRInitError_Init_HmdNotFoundPresenceFailed
+        | openvr_sys::EVRInitError_VRInitError_Init_NoServerForBackgroundApp
+        | openvr_sys::EVRInitError_VRInitError_Init_Retry
+        | openvr_sys::EVRInitError_VRInitError_Init_AnotherAppLaunching
+        | openvr_sys::EVRInitError_VRInitError_Init_ShuttingDown
+        | openvr_sys::EVRInitError_VRInitError_Init_RebootingBusy
+        | openvr_sys::EVRInitError_VRInitError_Init_FirmwareUpdateBusy
+        | openvr_sys::EVRInitError_VRInitError_Init_FirmwareRecoveryBusy
+        | openvr_sys::EVRInitError_VRInitError_Init_USBServiceBusy => InitErrorKind::NotReady,
+        // the server process isn't reachable yet
+        code if (openvr_sys::EVRInitError_VRInitError_IPC_ServerInitFailed
+            ..openvr_sys::EVRInitError_VRInitError_Compositor_Failed)
+            .contains(&code) =>
+        {
+            InitErrorKind::NotReady
+        }
+        _ => InitErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_init_errors_by_cause() {
+        assert_eq!(
+            init_error_kind(openvr_sys::EVRInitError_VRInitError_Init_InstallationNotFound),
+            InitErrorKind::NotInstalled
+        );
+        assert_eq!(
+            init_error_kind(openvr_sys::EVRInitError_VRInitError_Init_NoServerForBackgroundApp),
+            InitErrorKind::NotReady
+        );
+        assert_eq!(
+            init_error_kind(openvr_sys::EVRInitError_VRInitError_IPC_ConnectFailed),
+            InitErrorKind::NotReady
+        );
+        assert_eq!(
+            init_error_kind(openvr_sys::EVRInitError_VRInitError_Init_InvalidApplicationType),
+            InitErrorKind::Other
+        );
+        let error = Error::Network(io::Error::from(io::ErrorKind::AddrInUse));
+        assert!(error.is_retryable());
+        assert!(!Error::from(io::Error::from(io::ErrorKind::NotFound)).is_retryable());
+        assert!(!Error::config("bad").is_retryable());
+    }
+
+    #[test]
+    fn test_describe_includes_causes() {
+        let cause = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        let error = Error::config_caused("Failed to read config a.json", cause);
+        assert_eq!(
+            error.describe(),
+            "Failed to read config a.json: no such file"
+        );
+    }
+}
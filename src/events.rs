@@ -0,0 +1,252 @@
+//! Device events for the low-rate event channel
+//!
+//! Compares consecutive samples and reports when something about a device
+//! changed, so consumers that only care about changes don't have to diff
+//! every pose frame themselves.
+//!
+//! `tracking_lost` and `tracking_recovered` follow whether the pose is valid,
+//! whatever the reason, so safety stops can key off them alone. Recovery
+//! carries how long the device was lost.
+//!
+//! With battery thresholds set, a device whose charge drops below one raises a
+//! `low_battery` event once. The threshold is armed again after the device
+//! charged [`BATTERY_HYSTERESIS`] above it, so a reading that wobbles around a
+//! threshold doesn't repeat the alert.
+
+use crate::tracking_messages::{TrackingState, VrDevice};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Charge a device has to regain above an alerted threshold before it can alert again
+pub const BATTERY_HYSTERESIS: f32 = 0.05;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceEventKind {
+    /// Device appeared for the first time or got its radio link back
+    Connected,
+    /// Radio link to the device was lost
+    Disconnected,
+    /// Charge dropped below one of the battery alert thresholds
+    LowBattery,
+    /// Pose stopped being valid
+    TrackingLost,
+    /// Pose is valid again after being lost
+    TrackingRecovered,
+    /// A required device wasn't tracked for too long
+    Missing,
+    /// A watched device came close to the boundary or left the tracked area
+    NearBoundary,
+    /// A device that was near the boundary is well inside again
+    BoundaryClear,
+}
+
+/// Something that happened to one device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceEvent {
+    /// Not set on `missing` events for devices that never appeared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<Arc<str>>,
+    /// Serial or alias the device is required by, set on `missing` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<Arc<str>>,
+    pub kind: DeviceEventKind,
+    /// Charge between 0 and 1, set on `low_battery` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery: Option<f32>,
+    /// Seconds the device was lost, set on `tracking_recovered` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropout_s: Option<f32>,
+    /// Meters from the boundary, negative outside, set on boundary events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance_m: Option<f32>,
+}
+
+impl DeviceEvent {
+    fn new(device: &VrDevice, kind: DeviceEventKind) -> Self {
+        Self {
+            id: Some(device.id()),
+            serial: device.serial().map(Arc::from),
+            name: None,
+            kind,
+            battery: None,
+            dropout_s: None,
+            distance_m: None,
+        }
+    }
+}
+
+/// Turns sampled device state into [`DeviceEvent`]s
+#[derive(Default)]
+pub struct EventDetector {
+    /// Whether each device was connected in the previous sample, indexed by device id
+    connected: Vec<Option<bool>>,
+    /// Lowest battery threshold each device alerted for, indexed by device id
+    battery_alerted: Vec<Option<f32>>,
+    /// When each lost device stopped tracking, indexed by device id
+    lost_at: Vec<Option<Instant>>,
+}
+
+impl EventDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Events since the previous sample
+    ///
+    /// `battery_thresholds` are charge levels that raise a `low_battery` event, none if empty.
+    pub fn detect(
+        &mut self,
+        devices: &[VrDevice],
+        battery_thresholds: &[f32],
+        now: Instant,
+    ) -> Vec<DeviceEvent> {
+        let mut events = vec![];
+        for device in devices.iter().filter(|device| device.seen()) {
+            if self.connected.len() <= device.id() {
+                self.connected.resize(device.id() + 1, None);
+                self.battery_alerted.resize(device.id() + 1, None);
+                self.lost_at.resize(device.id() + 1, None);
+            }
+            if let Some(battery) = device.battery() {
+                if self.battery_dropped(device.id(), battery, battery_thresholds) {
+                    let mut event = DeviceEvent::new(device, DeviceEventKind::LowBattery);
+                    event.battery = Some(battery);
+                    events.push(event);
+                }
+            }
+            let connected = device.state() != TrackingState::Disconnected;
+            let previous = self.connected[device.id()].replace(connected);
+            let kind = match (previous, connected) {
+                (None, true) | (Some(false), true) => Some(DeviceEventKind::Connected),
+                (Some(true), false) => Some(DeviceEventKind::Disconnected),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                events.push(DeviceEvent::new(device, kind));
+            }
+            let lost_at = &mut self.lost_at[device.id()];
+            match (*lost_at, device.tracked()) {
+                (None, false) => {
+                    *lost_at = Some(now);
+                    events.push(DeviceEvent::new(device, DeviceEventKind::TrackingLost));
+                }
+                (Some(since), true) => {
+                    *lost_at = None;
+                    let mut event = DeviceEvent::new(device, DeviceEventKind::TrackingRecovered);
+                    event.dropout_s = Some(now.duration_since(since).as_secs_f32());
+                    events.push(event);
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Whether `battery` crossed a threshold the device didn't alert for yet
+    fn battery_dropped(&mut self, id: usize, battery: f32, thresholds: &[f32]) -> bool {
+        let crossed = thresholds
+            .iter()
+            .copied()
+            .filter(|threshold| battery < *threshold)
+            .reduce(f32::min);
+        let alerted = &mut self.battery_alerted[id];
+        match (crossed, *alerted) {
+            (Some(crossed), None) => {
+                *alerted = Some(crossed);
+                true
+            }
+            (Some(crossed), Some(previous)) if crossed < previous => {
+                *alerted = Some(crossed);
+                true
+            }
+            (_, Some(previous)) if battery >= previous + BATTERY_HYSTERESIS => {
+                // charged, thresholds it is still below stay quiet
+                *alerted = crossed;
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+    use crate::openvr_adaptor::VrDeviceManager;
+    use crate::tracking_messages::VrDeviceClass;
+    use std::time::Duration;
+
+    #[test]
+    fn test_connect_and_disconnect() {
+        let connected = DeviceSample::new(0, VrDeviceClass::Tracker, IDENTITY_POSE);
+        let mut lost = connected;
+        lost.pose_valid = false;
+        lost.connected = false;
+        lost.state = TrackingState::Disconnected;
+        let mut backend = MockBackend::new();
+        backend.set_serial(0, "LHR-1");
+        for sample in [connected, connected, lost, lost, connected].iter() {
+            backend.push_frame(vec![*sample]);
+        }
+        let mut manager = VrDeviceManager::with_backend(backend);
+        let mut detector = EventDetector::new();
+        let mut kinds = vec![];
+        let mut dropouts = vec![];
+        let start = Instant::now();
+        for step in 0..5 {
+            manager.update();
+            for event in detector.detect(
+                manager.devices(),
+                &[],
+                start + Duration::from_millis(step * 10),
+            ) {
+                assert_eq!(event.serial.as_deref(), Some("LHR-1"));
+                kinds.push(event.kind);
+                dropouts.extend(event.dropout_s);
+            }
+        }
+        assert_eq!(
+            kinds,
+            vec![
+                DeviceEventKind::Connected,
+                DeviceEventKind::Disconnected,
+                DeviceEventKind::TrackingLost,
+                DeviceEventKind::Connected,
+                DeviceEventKind::TrackingRecovered
+            ]
+        );
+        assert_eq!(dropouts.len(), 1);
+        assert!((dropouts[0] - 0.02).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_low_battery_alerts_once_per_threshold() {
+        let mut backend = MockBackend::new();
+        backend.push_frame(vec![DeviceSample::new(
+            0,
+            VrDeviceClass::Tracker,
+            IDENTITY_POSE,
+        )]);
+        let mut manager = VrDeviceManager::with_backend(backend);
+        manager.update();
+        let mut devices = manager.device_list();
+        let mut detector = EventDetector::new();
+        let thresholds = [0.2, 0.1];
+        let mut alerts = vec![];
+        for battery in [0.5, 0.19, 0.21, 0.18, 0.09, 0.12, 0.3, 0.15] {
+            devices[0].set_battery(Some(battery));
+            for event in detector.detect(&devices, &thresholds, Instant::now()) {
+                if event.kind == DeviceEventKind::LowBattery {
+                    alerts.push(event.battery.unwrap());
+                }
+            }
+        }
+        // 0.21 and 0.18 are within the hysteresis of 0.2, 0.12 of 0.1, 0.3 arms both again
+        assert_eq!(alerts, vec![0.19, 0.09, 0.15]);
+    }
+}
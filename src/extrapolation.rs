@@ -0,0 +1,113 @@
+//! Frames between samples, predicted from the velocities the runtime reports
+//!
+//! Some consumers, like haptic renderers, need poses faster than OpenVR can be
+//! sampled and can't afford the latency of interpolating on their side. With
+//! extrapolation the publisher sends frames at `rate_hz`, moving every tracked
+//! device of the latest sample along its linear and angular velocity for the
+//! time since it was sampled. Those frames are marked `extrapolated`.
+//!
+//! Predictions get worse the further they reach, so nothing is extrapolated
+//! more than `max_ahead_ms` past a sample. When sampling stalls the stream
+//! falls back to the sampled frames.
+
+use crate::tracking_messages::VrDevice;
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtrapolationConfig {
+    /// Frames per second, sampled and extrapolated together
+    pub rate_hz: f32,
+    /// Longest time past a sample that poses are predicted for
+    pub max_ahead_ms: f32,
+}
+
+impl Default for ExtrapolationConfig {
+    fn default() -> Self {
+        Self {
+            rate_hz: 1000.0,
+            max_ahead_ms: 50.0,
+        }
+    }
+}
+
+impl ExtrapolationConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !(self.rate_hz > 0.0 && self.rate_hz.is_finite()) {
+            problems.push(format!(
+                "extrapolation rate_hz must be positive, is {}",
+                self.rate_hz
+            ));
+        }
+        if !(self.max_ahead_ms > 0.0 && self.max_ahead_ms.is_finite()) {
+            problems.push(format!(
+                "extrapolation max_ahead_ms must be positive, is {}",
+                self.max_ahead_ms
+            ));
+        }
+        problems
+    }
+
+    pub fn period(&self) -> Duration {
+        Duration::from_secs_f32(1.0 / self.rate_hz)
+    }
+
+    /// Whether a sample taken `elapsed_ms` ago may still be extrapolated
+    pub fn reaches(&self, elapsed_ms: u128) -> bool {
+        elapsed_ms > 0 && elapsed_ms as f32 <= self.max_ahead_ms
+    }
+}
+
+/// Move tracked devices along their velocities for `elapsed`
+///
+/// Devices that aren't tracked keep their last pose.
+pub fn extrapolate(sampled: &[VrDevice], elapsed: Duration, devices: &mut Vec<VrDevice>) {
+    let seconds = elapsed.as_secs_f32();
+    devices.clear();
+    devices.extend(sampled.iter().map(|device| {
+        let mut device = device.clone();
+        if device.tracked() {
+            let position = device.position() + device.velocity() * seconds;
+            let turn = na::UnitQuaternion::from_scaled_axis(device.angular_velocity() * seconds);
+            device.set_pose(true, position, turn * device.rotation());
+        }
+        device
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moves_along_velocities() {
+        let mut moving = VrDevice::new(1);
+        moving.set_pose(
+            true,
+            na::Point3::new(1.0, 1.0, 0.0),
+            na::UnitQuaternion::identity(),
+        );
+        moving.set_velocity(
+            na::Vector3::new(2.0, 0.0, -1.0),
+            na::Vector3::new(0.0, std::f32::consts::PI, 0.0),
+        );
+        let mut lost = moving.clone();
+        lost.set_pose(false, na::Point3::origin(), na::UnitQuaternion::identity());
+        let mut devices = vec![];
+        extrapolate(&[moving, lost], Duration::from_millis(10), &mut devices);
+
+        let predicted = &devices[0];
+        assert!((predicted.position() - na::Point3::new(1.02, 1.0, -0.01)).norm() < 1e-5);
+        let (_, yaw, _) = predicted.rotation().euler_angles();
+        assert!((yaw - std::f32::consts::PI / 100.0).abs() < 1e-5);
+        assert_eq!(devices[1].position(), &na::Point3::origin());
+
+        let config = ExtrapolationConfig::default();
+        assert!(config.reaches(50));
+        assert!(!config.reaches(51));
+        assert!(!config.reaches(0));
+    }
+}
@@ -0,0 +1,238 @@
+//! C interface for linking the tracker pipeline into native applications
+//!
+//! See `include/openvr_tracker.h` for the matching header.
+//! Polling runs on a background thread and only the latest frame is kept.
+//!
+//! # ABI
+//!
+//! The structs are `#[repr(C)]` and their fields match the header one to one,
+//! in the same order, with C's `bool` as one byte. `class` is a C++ keyword,
+//! so the field is `class_` on both sides. The caller owns the frame and this
+//! library only writes into it. Every function returns one of the
+//! `OPENVR_TRACKER_*` codes, a poisoned lock included, instead of panicking
+//! across the boundary.
+
+use crate::openvr_adaptor::VrDeviceManager;
+use crate::tracking_messages::{TrackedObjects, VrDeviceClass};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_DEVICES: usize = 64;
+
+pub const OPENVR_TRACKER_OK: i32 = 0;
+pub const OPENVR_TRACKER_ERROR_ALREADY_RUNNING: i32 = -1;
+pub const OPENVR_TRACKER_ERROR_INIT_FAILED: i32 = -2;
+pub const OPENVR_TRACKER_ERROR_NOT_RUNNING: i32 = -3;
+pub const OPENVR_TRACKER_ERROR_NULL_POINTER: i32 = -4;
+/// A thread panicked while holding the stream, it can't be used anymore
+pub const OPENVR_TRACKER_ERROR_POISONED: i32 = -5;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct OpenVrTrackerDevice {
+    pub id: u32,
+    /// One of the `OPENVR_TRACKER_CLASS_*` values
+    pub class_: u32,
+    pub tracked: bool,
+    pub position: [f32; 3],
+    /// x, y, z, w
+    pub rotation: [f32; 4],
+}
+
+#[repr(C)]
+pub struct OpenVrTrackerFrame {
+    pub seq: u64,
+    /// Milliseconds since unix epoch
+    pub ts: u64,
+    pub device_count: u32,
+    pub devices: [OpenVrTrackerDevice; MAX_DEVICES],
+}
+
+fn class_to_c(class: VrDeviceClass) -> u32 {
+    match class {
+        VrDeviceClass::Controller => 0,
+        VrDeviceClass::LeftController => 1,
+        VrDeviceClass::RightController => 2,
+        VrDeviceClass::Tracker => 3,
+        VrDeviceClass::HMD => 4,
+        VrDeviceClass::Sensor => 5,
+        VrDeviceClass::Other => 6,
+        VrDeviceClass::Camera => 7,
+    }
+}
+
+struct Stream {
+    running: Arc<AtomicBool>,
+    latest: Arc<Mutex<Option<TrackedObjects>>>,
+    thread: JoinHandle<()>,
+}
+
+static STREAM: Mutex<Option<Stream>> = Mutex::new(None);
+
+fn poll_loop(
+    mut openvr: VrDeviceManager,
+    period: Duration,
+    running: Arc<AtomicBool>,
+    latest: Arc<Mutex<Option<TrackedObjects>>>,
+) {
+    let mut seq = 0;
+    while running.load(Ordering::Relaxed) {
+        openvr.update();
+        let devices = openvr
+            .device_list()
+            .into_iter()
+            .filter(|object| object.seen())
+            .collect();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        match latest.lock() {
+            Ok(mut latest) => *latest = Some(TrackedObjects::new(seq, time, devices)),
+            // the reader panicked, nobody is left to take frames
+            Err(_) => return,
+        }
+        seq += 1;
+        thread::sleep(period);
+    }
+}
+
+/// Start polling OpenVR on a background thread at `rate_hz`
+#[no_mangle]
+pub extern "C" fn openvr_tracker_start_stream(rate_hz: u32) -> i32 {
+    let mut stream = match STREAM.lock() {
+        Ok(stream) => stream,
+        Err(_) => return OPENVR_TRACKER_ERROR_POISONED,
+    };
+    if stream.is_some() {
+        return OPENVR_TRACKER_ERROR_ALREADY_RUNNING;
+    }
+    let period = Duration::from_secs_f32(1.0 / rate_hz.max(1) as f32);
+    let running = Arc::new(AtomicBool::new(true));
+    let latest = Arc::new(Mutex::new(None));
+    let (init_tx, init_rx) = mpsc::channel();
+    let thread = {
+        let running = running.clone();
+        let latest = latest.clone();
+        // OpenVR context is created on the polling thread and never leaves it
+        thread::spawn(move || match VrDeviceManager::new() {
+            Ok(openvr) => {
+                let _ = init_tx.send(true);
+                poll_loop(openvr, period, running, latest);
+            }
+            Err(_) => {
+                let _ = init_tx.send(false);
+            }
+        })
+    };
+    if !init_rx.recv().unwrap_or(false) {
+        let _ = thread.join();
+        return OPENVR_TRACKER_ERROR_INIT_FAILED;
+    }
+    *stream = Some(Stream {
+        running,
+        latest,
+        thread,
+    });
+    OPENVR_TRACKER_OK
+}
+
+/// Copy the newest frame into `frame`
+///
+/// Returns 1 if a new frame was written, 0 if there was no new frame since the last call
+///
+/// # Safety
+///
+/// `frame` has to be null or point to a valid `OpenVrTrackerFrame`
+#[no_mangle]
+pub unsafe extern "C" fn openvr_tracker_poll_latest_frame(frame: *mut OpenVrTrackerFrame) -> i32 {
+    if frame.is_null() {
+        return OPENVR_TRACKER_ERROR_NULL_POINTER;
+    }
+    let stream = match STREAM.lock() {
+        Ok(stream) => stream,
+        Err(_) => return OPENVR_TRACKER_ERROR_POISONED,
+    };
+    let stream = match stream.as_ref() {
+        Some(stream) => stream,
+        None => return OPENVR_TRACKER_ERROR_NOT_RUNNING,
+    };
+    let latest = match stream.latest.lock() {
+        Ok(mut latest) => latest.take(),
+        Err(_) => return OPENVR_TRACKER_ERROR_POISONED,
+    };
+    let latest = match latest {
+        Some(latest) => latest,
+        None => return 0,
+    };
+    let frame = &mut *frame;
+    frame.seq = latest.seq();
+    frame.ts = latest.ts() as u64;
+    let mut count = 0;
+    for (device, out) in latest.trackers().iter().zip(frame.devices.iter_mut()) {
+        let position = device.position();
+        let rotation = device.rotation();
+        *out = OpenVrTrackerDevice {
+            id: device.id() as u32,
+            class_: class_to_c(device.class()),
+            tracked: device.tracked(),
+            position: [position.x, position.y, position.z],
+            rotation: [rotation.i, rotation.j, rotation.k, rotation.w],
+        };
+        count += 1;
+    }
+    frame.device_count = count;
+    1
+}
+
+/// Stop the background thread and release OpenVR
+///
+/// Also stops a stream whose lock is poisoned, but still reports that.
+#[no_mangle]
+pub extern "C" fn openvr_tracker_stop() -> i32 {
+    let (stream, code) = match STREAM.lock() {
+        Ok(mut stream) => (stream.take(), OPENVR_TRACKER_OK),
+        Err(poisoned) => (poisoned.into_inner().take(), OPENVR_TRACKER_ERROR_POISONED),
+    };
+    let stream = match stream {
+        Some(stream) => stream,
+        None => return OPENVR_TRACKER_ERROR_NOT_RUNNING,
+    };
+    stream.running.store(false, Ordering::Relaxed);
+    let _ = stream.thread.join();
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::{align_of, size_of, MaybeUninit};
+    use std::ptr::addr_of;
+
+    #[test]
+    fn test_layout_matches_header() {
+        let device = MaybeUninit::<OpenVrTrackerDevice>::uninit();
+        let base = device.as_ptr() as usize;
+        // safe because only field addresses are taken, nothing is read
+        unsafe {
+            let device = device.as_ptr();
+            assert_eq!(addr_of!((*device).class_) as usize - base, 4);
+            assert_eq!(addr_of!((*device).tracked) as usize - base, 8);
+            assert_eq!(addr_of!((*device).position) as usize - base, 12);
+            assert_eq!(addr_of!((*device).rotation) as usize - base, 24);
+        }
+        assert_eq!(size_of::<OpenVrTrackerDevice>(), 40);
+
+        let frame = MaybeUninit::<OpenVrTrackerFrame>::uninit();
+        let base = frame.as_ptr() as usize;
+        unsafe {
+            let frame = frame.as_ptr();
+            assert_eq!(addr_of!((*frame).device_count) as usize - base, 16);
+            assert_eq!(addr_of!((*frame).devices) as usize - base, 20);
+        }
+        assert_eq!(size_of::<OpenVrTrackerFrame>(), 2584);
+        assert_eq!(align_of::<OpenVrTrackerFrame>(), 8);
+    }
+}
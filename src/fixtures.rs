@@ -0,0 +1,66 @@
+//! Devices for tests
+//!
+//! Poses use OpenVR's axes: +Y is up, -Z is forward and +X is to the right.
+//! Yaw turns about +Y, counter-clockwise seen from above, so a quarter turn
+//! points -Z forward along -X.
+
+use crate::backend::IDENTITY_POSE;
+use crate::tracking_messages::{TrackingState, VrDevice, VrDeviceClass};
+use nalgebra as na;
+
+/// Tracked device `id` at the origin, facing -Z
+pub fn device(id: usize) -> DeviceBuilder {
+    let mut device = VrDevice::new(id);
+    device.set_pose(true, na::Point3::origin(), na::UnitQuaternion::identity());
+    DeviceBuilder(device)
+}
+
+pub struct DeviceBuilder(VrDevice);
+
+impl DeviceBuilder {
+    /// Class as the runtime reports it, so hand controllers get their role
+    pub fn class(mut self, class: VrDeviceClass) -> Self {
+        let (position, rotation) = (*self.0.position(), *self.0.rotation());
+        let tracked = self.0.tracked();
+        self.0.update(tracked, &IDENTITY_POSE, class);
+        self.0.set_pose(tracked, position, rotation);
+        self
+    }
+
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.0.set_serial(Some(serial.into()));
+        self
+    }
+
+    pub fn state(mut self, state: TrackingState) -> Self {
+        self.0.set_state(state);
+        self
+    }
+
+    /// Position in meters
+    pub fn at(mut self, position: [f32; 3]) -> Self {
+        let rotation = *self.0.rotation();
+        self.0
+            .set_pose(self.0.tracked(), na::Point3::from(position), rotation);
+        self
+    }
+
+    /// Turn `radians` about +Y
+    pub fn yaw(mut self, radians: f32) -> Self {
+        let rotation = na::UnitQuaternion::from_axis_angle(&na::Vector3::y_axis(), radians);
+        self.0
+            .set_pose(self.0.tracked(), *self.0.position(), rotation);
+        self
+    }
+
+    /// Seen before, but its pose isn't valid now
+    pub fn untracked(mut self) -> Self {
+        self.0
+            .set_pose(false, *self.0.position(), *self.0.rotation());
+        self
+    }
+
+    pub fn build(self) -> VrDevice {
+        self.0
+    }
+}
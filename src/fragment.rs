@@ -0,0 +1,217 @@
+//! Splitting payloads that don't fit one packet
+//!
+//! Wi-Fi networks often drop IP fragments, so a frame larger than the path MTU,
+//! like JSON with a few dozen devices, would be lost as a whole. With
+//! `max_packet_bytes` set, UDP and multicast senders split such payloads into
+//! parts that fit and [`MessageReceiver`](crate::multicast::MessageReceiver)
+//! joins them again. A part is a header followed by a slice of the payload:
+//!
+//! ```text
+//! "OF" | version 1 | message id u32 | part index u16 | part count u16 | bytes
+//! ```
+//!
+//! Numbers are little endian. Parts are signed and encrypted one by one, like
+//! whole payloads. Losing any part loses the message. Receivers keep the parts
+//! of the latest few messages, so parts arriving out of order still join.
+//! Message ids are only unique per sender, so parts are kept by the address
+//! they came from as well.
+
+use crate::error::{Error, Result};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 2] = b"OF";
+const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 11;
+/// Smallest `max_packet_bytes` that leaves room for payload after all headers
+pub const MIN_PACKET_BYTES: usize = 256;
+/// Messages whose parts are kept while waiting for the rest
+const PENDING_MESSAGES: usize = 8;
+
+/// Whether `packet` is part of a split payload
+pub fn is_part(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE && packet.starts_with(MAGIC)
+}
+
+/// Numbers messages and cuts them into parts
+pub struct Splitter {
+    next_id: u32,
+}
+
+impl Default for Splitter {
+    fn default() -> Self {
+        // a restarted sender doesn't continue where receivers still have parts of
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            next_id: now.as_micros() as u32,
+        }
+    }
+}
+
+impl Splitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parts of at most `part_bytes` payload bytes each, headers included
+    pub fn split(&mut self, payload: &[u8], part_bytes: usize) -> Result<Vec<Vec<u8>>> {
+        if part_bytes == 0 {
+            return Err(Error::InvalidInput(
+                "No room for payload in a part".to_owned(),
+            ));
+        }
+        let count = payload.len().div_ceil(part_bytes);
+        if count > u16::MAX as usize {
+            return Err(Error::InvalidInput(format!(
+                "Payload of {} bytes needs more than {} parts",
+                payload.len(),
+                u16::MAX
+            )));
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(payload
+            .chunks(part_bytes)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut part = Vec::with_capacity(HEADER_SIZE + chunk.len());
+                part.extend_from_slice(MAGIC);
+                part.push(VERSION);
+                part.extend_from_slice(&id.to_le_bytes());
+                part.extend_from_slice(&(index as u16).to_le_bytes());
+                part.extend_from_slice(&(count as u16).to_le_bytes());
+                part.extend_from_slice(chunk);
+                part
+            })
+            .collect())
+    }
+}
+
+struct Pending {
+    source: SocketAddr,
+    id: u32,
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+/// Joins parts back into payloads
+#[derive(Default)]
+pub struct Reassembler {
+    pending: VecDeque<Pending>,
+    /// Messages given up on because newer ones pushed them out
+    incomplete: u64,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a part sent from `source`, returns the whole payload once its last part arrived
+    ///
+    /// Malformed parts are ignored.
+    pub fn add(&mut self, source: SocketAddr, packet: &[u8]) -> Option<Vec<u8>> {
+        if !is_part(packet) || packet[2] != VERSION {
+            return None;
+        }
+        let id = u32::from_le_bytes(packet[3..7].try_into().ok()?);
+        let index = u16::from_le_bytes(packet[7..9].try_into().ok()?) as usize;
+        let count = u16::from_le_bytes(packet[9..11].try_into().ok()?) as usize;
+        if index >= count {
+            return None;
+        }
+        let position = match self.pending.iter().position(|pending| {
+            pending.source == source && pending.id == id && pending.parts.len() == count
+        }) {
+            Some(position) => position,
+            None => {
+                if self.pending.len() == PENDING_MESSAGES {
+                    self.pending.pop_front();
+                    self.incomplete += 1;
+                }
+                self.pending.push_back(Pending {
+                    source,
+                    id,
+                    parts: vec![None; count],
+                    received: 0,
+                });
+                self.pending.len() - 1
+            }
+        };
+        let pending = &mut self.pending[position];
+        if pending.parts[index].is_none() {
+            pending.parts[index] = Some(packet[HEADER_SIZE..].to_vec());
+            pending.received += 1;
+        }
+        if pending.received < count {
+            return None;
+        }
+        let pending = self.pending.remove(position)?;
+        Some(pending.parts.into_iter().flatten().flatten().collect())
+    }
+
+    /// Messages dropped before all their parts arrived
+    pub fn incomplete(&self) -> u64 {
+        self.incomplete
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_join_out_of_order() {
+        let payload: Vec<u8> = (0..250).collect();
+        let mut splitter = Splitter::new();
+        let first = splitter.split(&payload, 100).unwrap();
+        let second = splitter.split(&payload[..50], 100).unwrap();
+        assert_eq!(first.len(), 3);
+        assert_eq!(second.len(), 1);
+        assert!(first.iter().all(|part| is_part(part)));
+        assert_eq!(first[2].len(), HEADER_SIZE + 50);
+
+        let sender: SocketAddr = "192.168.0.2:7070".parse().unwrap();
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.add(sender, &first[2]), None);
+        assert_eq!(
+            reassembler.add(sender, &second[0]),
+            Some(payload[..50].to_vec())
+        );
+        assert_eq!(reassembler.add(sender, &first[0]), None);
+        // duplicates don't count twice
+        assert_eq!(reassembler.add(sender, &first[0]), None);
+        assert_eq!(reassembler.add(sender, &first[1]), Some(payload.clone()));
+        assert_eq!(reassembler.add(sender, b"{\"ts\": 1}"), None);
+
+        for _ in 0..PENDING_MESSAGES + 1 {
+            let parts = splitter.split(&payload, 100).unwrap();
+            reassembler.add(sender, &parts[0]);
+        }
+        assert_eq!(reassembler.incomplete(), 1);
+    }
+
+    #[test]
+    fn test_same_message_id_from_two_senders() {
+        let payload: Vec<u8> = (0..150).collect();
+        let mut splitter = Splitter::new();
+        let parts = splitter.split(&payload, 100).unwrap();
+        // another sender that happened to start counting at the same id
+        let mut other = parts.clone();
+        for part in &mut other {
+            part[HEADER_SIZE..].iter_mut().for_each(|byte| *byte = 0);
+        }
+
+        let first: SocketAddr = "192.168.0.2:7070".parse().unwrap();
+        let second: SocketAddr = "192.168.0.3:7070".parse().unwrap();
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.add(first, &parts[0]), None);
+        assert_eq!(reassembler.add(second, &other[1]), None);
+        assert_eq!(reassembler.add(first, &parts[1]), Some(payload));
+        assert_eq!(reassembler.add(second, &other[0]), Some(vec![0; 150]));
+    }
+}
@@ -0,0 +1,309 @@
+//! Per connection stream settings for TCP clients
+//!
+//! A client may send one JSON line right after connecting to ask for its own
+//! layout, device classes, device fields and rate:
+//!
+//! ```text
+//! {"hello": {"format": {"case": "camel_case"}, "classes": ["Tracker"], "fields": ["position"], "rate_hz": 30}}
+//! ```
+//!
+//! The server answers with a `welcome` line holding the settings it applied,
+//! or an `error` line and closes the connection. Clients that don't send a
+//! hello within [`HANDSHAKE_TIMEOUT`] get the default stream without a reply,
+//! so existing clients keep working.
+
+use crate::change_tracker::SkippedChanges;
+use crate::error::Result;
+use crate::json_format::JsonFormat;
+use crate::tracking_messages::{Channel, TrackedObjects, VrDeviceClass};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// How long a new connection has to send its hello
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Protocol version in the welcome line
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a client asks for
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamRequest {
+    /// JSON layout, the sink's layout if not set
+    pub format: Option<JsonFormat>,
+    /// Only devices of these classes, all if empty
+    pub classes: Vec<VrDeviceClass>,
+    /// Only these device fields, all if empty. `id` is always kept
+    pub fields: Vec<String>,
+    /// Most pose frames per second, every frame if not set
+    ///
+    /// In change-only mode, skipped partial frames are merged into the next one
+    /// sent, and a skipped keyframe is replaced by one with every device.
+    pub rate_hz: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientMessage {
+    Hello(StreamRequest),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome {
+        version: u32,
+        #[serde(flatten)]
+        stream: StreamRequest,
+    },
+    Error(String),
+}
+
+impl StreamRequest {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(rate_hz) = self.rate_hz {
+            if !(rate_hz > 0.0 && rate_hz.is_finite()) {
+                problems.push(format!("rate_hz must be positive, is {}", rate_hz));
+            }
+        }
+        problems
+    }
+}
+
+/// A connection's stream, tailored by its request
+#[derive(Debug, Clone)]
+pub struct TailoredStream {
+    request: StreamRequest,
+    period: Option<Duration>,
+    last_sent: Option<Instant>,
+    /// Changes in skipped frames, kept while rate limited
+    skipped: SkippedChanges,
+}
+
+impl TailoredStream {
+    pub fn new(request: StreamRequest) -> Self {
+        let period = request
+            .rate_hz
+            .map(|rate_hz| Duration::from_secs_f32(1.0 / rate_hz));
+        Self {
+            request,
+            period,
+            last_sent: None,
+            skipped: SkippedChanges::new(),
+        }
+    }
+
+    /// Serialize `frame` for this connection, false if it is skipped
+    pub fn write(
+        &mut self,
+        frame: &TrackedObjects,
+        default_format: &JsonFormat,
+        now: Instant,
+        buffer: &mut Vec<u8>,
+    ) -> Result<bool> {
+        let pose = frame.channel() == Channel::Pose && !frame.is_heartbeat();
+        let limited = match (self.period, pose) {
+            (Some(period), true) => match self.limit(frame, period, now) {
+                Some(limited) => Some(limited),
+                None => return Ok(false),
+            },
+            _ => None,
+        };
+        let frame = limited.as_ref().unwrap_or(frame);
+        let format = self.request.format.as_ref().unwrap_or(default_format);
+        if self.request.classes.is_empty() {
+            format.write(frame, buffer)?;
+        } else {
+            let mut filtered = frame.clone();
+            filtered
+                .trackers_mut()
+                .retain(|device| self.request.classes.contains(&device.class()));
+            // keyframes go out even when empty, later partial frames build on them
+            if pose && frame.partial() && filtered.trackers().is_empty() {
+                return Ok(false);
+            }
+            format.write(&filtered, buffer)?;
+        }
+        if !self.request.fields.is_empty() {
+            self.keep_fields(format, buffer)?;
+        }
+        Ok(true)
+    }
+
+    /// What to send instead of `frame` at the limited rate, `None` if it is skipped
+    ///
+    /// Skipped frames are folded into the next one sent by [`SkippedChanges`].
+    fn limit(
+        &mut self,
+        frame: &TrackedObjects,
+        period: Duration,
+        now: Instant,
+    ) -> Option<TrackedObjects> {
+        // joined after the keyframe, nothing to build on until the next one
+        if !self.skipped.add(frame) {
+            return None;
+        }
+        if let Some(last_sent) = self.last_sent {
+            if now.duration_since(last_sent) < period {
+                return None;
+            }
+        }
+        self.last_sent = Some(now);
+        Some(self.skipped.take(frame))
+    }
+
+    fn keep_fields(&self, format: &JsonFormat, buffer: &mut Vec<u8>) -> Result<()> {
+        let mut value: Value = serde_json::from_slice(buffer)?;
+        // names in the request are snake case, compare them in the output layout
+        let mut keep = vec![format.field_name("id")];
+        keep.extend(
+            self.request
+                .fields
+                .iter()
+                .map(|field| format.field_name(field)),
+        );
+        let trackers = value
+            .get_mut("trackers")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten();
+        for device in trackers.filter_map(Value::as_object_mut) {
+            let dropped: Vec<String> = device
+                .keys()
+                .filter(|key| !keep.contains(key))
+                .cloned()
+                .collect();
+            for key in dropped {
+                device.remove(&key);
+            }
+        }
+        buffer.clear();
+        serde_json::to_writer(&mut *buffer, &value)?;
+        Ok(())
+    }
+}
+
+/// Parse a hello line into the reply to send and the stream to serve
+pub fn answer(line: &str) -> (ServerMessage, Option<TailoredStream>) {
+    let request = match serde_json::from_str::<ClientMessage>(line) {
+        Ok(ClientMessage::Hello(request)) => request,
+        Err(error) => {
+            return (
+                ServerMessage::Error(format!("Invalid hello: {}", error)),
+                None,
+            )
+        }
+    };
+    let problems = request.problems();
+    if !problems.is_empty() {
+        return (ServerMessage::Error(problems.join(", ")), None);
+    }
+    let welcome = ServerMessage::Welcome {
+        version: PROTOCOL_VERSION,
+        stream: request.clone(),
+    };
+    (welcome, Some(TailoredStream::new(request)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FrameAssembler;
+    use crate::fixtures::device;
+    use crate::tracking_messages::VrDevice;
+    use nalgebra::Isometry3;
+
+    #[test]
+    fn test_tailored_stream() {
+        let hello = r#"{"hello": {"format": {"case": "camel_case"}, "classes": ["Tracker"], "fields": ["position"], "rate_hz": 10}}"#;
+        let (welcome, stream) = answer(hello);
+        assert!(matches!(welcome, ServerMessage::Welcome { version: 1, .. }));
+        let mut stream = stream.unwrap();
+
+        let frame = TrackedObjects::new(
+            1,
+            0,
+            vec![
+                device(0).class(VrDeviceClass::HMD).build(),
+                device(1).class(VrDeviceClass::Tracker).build(),
+            ],
+        );
+        let start = Instant::now();
+        let mut buffer = vec![];
+        let default = JsonFormat::default();
+        assert!(stream.write(&frame, &default, start, &mut buffer).unwrap());
+        let value: Value = serde_json::from_slice(&buffer).unwrap();
+        let trackers = value["trackers"].as_array().unwrap();
+        assert_eq!(trackers.len(), 1);
+        assert_eq!(trackers[0]["id"], 1);
+        assert!(trackers[0]["position"].is_array());
+        assert!(trackers[0].get("rotation").is_none());
+
+        buffer.clear();
+        let soon = start + Duration::from_millis(50);
+        assert!(!stream.write(&frame, &default, soon, &mut buffer).unwrap());
+        let later = start + Duration::from_millis(150);
+        assert!(stream.write(&frame, &default, later, &mut buffer).unwrap());
+
+        assert!(matches!(answer("{}").0, ServerMessage::Error(_)));
+        assert!(matches!(
+            answer(r#"{"hello": {"rate_hz": 0}}"#).0,
+            ServerMessage::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_rate_limited_change_only_stream_reassembles() {
+        let (_, stream) = answer(r#"{"hello": {"rate_hz": 10}}"#);
+        let mut stream = stream.unwrap();
+        let default = JsonFormat::default();
+        let positions = |frame: &TrackedObjects| {
+            frame
+                .trackers()
+                .iter()
+                .map(|device| (device.id(), *device.position()))
+                .collect::<Vec<_>>()
+        };
+
+        let start = Instant::now();
+        let mut devices = vec![VrDevice::new(0), VrDevice::new(1)];
+        let mut keyframe_seq = 0;
+        let mut upstream = FrameAssembler::new();
+        let mut client = FrameAssembler::new();
+        let mut buffer = vec![];
+        let mut sent = vec![];
+        for seq in 0..50 {
+            // every 4th frame is sent, the keyframes at 15, 30 and 45 are skipped
+            devices[0].transform(&Isometry3::translation(0.01, 0.0, 0.0));
+            let frame = if seq % 15 == 0 {
+                keyframe_seq = seq;
+                TrackedObjects::new(seq, 0, devices.clone())
+            } else {
+                let mut changed = vec![devices[0].clone()];
+                // only moves in a skipped frame
+                if seq == 2 {
+                    devices[1].transform(&Isometry3::translation(0.0, 0.5, 0.0));
+                    changed.push(devices[1].clone());
+                }
+                let mut frame = TrackedObjects::new(seq, 0, changed);
+                frame.set_delta_of(keyframe_seq);
+                frame
+            };
+            let full = upstream.push(frame.clone()).unwrap();
+
+            buffer.clear();
+            let now = start + Duration::from_millis(30 * seq);
+            if stream.write(&frame, &default, now, &mut buffer).unwrap() {
+                let received: TrackedObjects = serde_json::from_slice(&buffer).unwrap();
+                let rebuilt = client
+                    .push(received)
+                    .expect("partial frame refers to a keyframe the client got");
+                assert_eq!(positions(&rebuilt), positions(&full));
+                sent.push(seq);
+            }
+        }
+        assert_eq!(sent, (0..50).step_by(4).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,152 @@
+//! Past poses of every device, for lookups at any time
+//!
+//! Sensor fusion needs the pose of a device at the moment another sensor took
+//! its measurement, not the latest one. [`PoseHistory`] keeps the samples of
+//! the last `window` per device and interpolates between the two samples
+//! around a timestamp, linearly for positions and spherically for rotations.
+//!
+//! Push full frames. Partial frames have to go through a
+//! [`FrameAssembler`](crate::client::FrameAssembler) first.
+
+use crate::tracking_messages::TrackedObjects;
+use nalgebra as na;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// Pose of a device at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedPose {
+    /// Milliseconds since the Unix epoch, as in frames
+    pub ts: u128,
+    pub position: na::Point3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+}
+
+#[derive(Debug, Clone)]
+struct Sample {
+    tracked: bool,
+    pose: TimedPose,
+}
+
+/// Ring buffer of recent poses per device id
+#[derive(Debug, Clone)]
+pub struct PoseHistory {
+    window_ms: u128,
+    devices: BTreeMap<usize, VecDeque<Sample>>,
+}
+
+impl PoseHistory {
+    /// Keep samples up to `window` older than the newest frame
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_ms: window.as_millis(),
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Add the devices of a frame
+    ///
+    /// Heartbeats, event channel messages and extrapolated frames are ignored,
+    /// as are samples older than the newest one of a device.
+    pub fn push(&mut self, frame: &TrackedObjects) {
+        if frame.is_heartbeat() || !frame.channel().is_pose() || frame.extrapolated() {
+            return;
+        }
+        let ts = frame.ts();
+        for device in frame.trackers() {
+            let samples = self.devices.entry(device.id()).or_default();
+            if matches!(samples.back(), Some(newest) if newest.pose.ts >= ts) {
+                continue;
+            }
+            samples.push_back(Sample {
+                tracked: device.tracked(),
+                pose: TimedPose {
+                    ts,
+                    position: *device.position(),
+                    rotation: *device.rotation(),
+                },
+            });
+        }
+        let keep_from = ts.saturating_sub(self.window_ms);
+        for samples in self.devices.values_mut() {
+            while matches!(samples.front(), Some(oldest) if oldest.pose.ts < keep_from) {
+                samples.pop_front();
+            }
+        }
+        self.devices.retain(|_, samples| !samples.is_empty());
+    }
+
+    /// Pose of device `id` at `ts`, interpolated between the samples around it
+    ///
+    /// `None` outside of the buffered time span, or when the device wasn't
+    /// tracked in one of the samples around `ts`.
+    pub fn pose_at(&self, id: usize, ts: u128) -> Option<TimedPose> {
+        let samples = self.devices.get(&id)?;
+        let after = samples.partition_point(|sample| sample.pose.ts < ts);
+        let next = samples.get(after)?;
+        if next.pose.ts == ts {
+            return Some(next.pose).filter(|_| next.tracked);
+        }
+        let previous = samples.get(after.checked_sub(1)?)?;
+        if !(previous.tracked && next.tracked) {
+            return None;
+        }
+        let (previous, next) = (&previous.pose, &next.pose);
+        let t = (ts - previous.ts) as f32 / (next.ts - previous.ts) as f32;
+        Some(TimedPose {
+            ts,
+            position: previous.position + (next.position - previous.position) * t,
+            rotation: previous.rotation.slerp(&next.rotation, t),
+        })
+    }
+
+    /// Newest tracked pose of device `id`
+    pub fn latest(&self, id: usize) -> Option<TimedPose> {
+        let newest = self.devices.get(&id)?.back()?;
+        Some(newest.pose).filter(|_| newest.tracked)
+    }
+
+    /// Oldest and newest timestamp buffered for device `id`
+    pub fn span(&self, id: usize) -> Option<(u128, u128)> {
+        let samples = self.devices.get(&id)?;
+        Some((samples.front()?.pose.ts, samples.back()?.pose.ts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracking_messages::VrDevice;
+
+    fn frame(ts: u128, tracked: bool, x: f32, yaw: f32) -> TrackedObjects {
+        let mut device = VrDevice::new(2);
+        device.set_pose(
+            tracked,
+            na::Point3::new(x, 1.0, 0.0),
+            na::UnitQuaternion::from_euler_angles(0.0, yaw, 0.0),
+        );
+        TrackedObjects::new(ts as u64, ts, vec![device])
+    }
+
+    #[test]
+    fn test_interpolates_inside_window() {
+        let mut history = PoseHistory::new(Duration::from_millis(100));
+        history.push(&frame(1000, true, 0.0, 0.0));
+        history.push(&frame(1010, true, 1.0, 1.0));
+        history.push(&frame(1020, false, 5.0, 0.0));
+
+        let pose = history.pose_at(2, 1004).unwrap();
+        assert!((pose.position - na::Point3::new(0.4, 1.0, 0.0)).norm() < 1e-5);
+        let (_, yaw, _) = pose.rotation.euler_angles();
+        assert!((yaw - 0.4).abs() < 1e-5);
+        assert_eq!(history.pose_at(2, 1010).unwrap().position.x, 1.0);
+        // no interpolation into lost tracking, out of the span or for unknown devices
+        assert_eq!(history.pose_at(2, 1015), None);
+        assert_eq!(history.pose_at(2, 999), None);
+        assert_eq!(history.pose_at(3, 1004), None);
+        assert_eq!(history.latest(2), None);
+
+        history.push(&frame(1105, true, 0.0, 0.0));
+        assert_eq!(history.span(2), Some((1010, 1105)));
+    }
+}
@@ -0,0 +1,750 @@
+//! Small built-in HTTP server
+//!
+//! Serves the web dashboard on `/`, frames as Server-Sent Events on `/events`
+//! and Prometheus metrics on `/metrics`. With control enabled, `GET /status`
+//! reports the sender state, with 503 while required devices are missing, and
+//! `POST /control` takes a [`ControlCommand`].
+//! With camera frames enabled, `/camera` streams them as MJPEG. With WebRTC
+//! enabled, `POST /webrtc` takes a browser's SDP offer and returns the answer.
+//!
+//! With clients set, every route but the dashboard needs a client's token.
+//! Signed `GET` requests sign the path with its query, which carries a `ts`
+//! and a single use `nonce` so they can't be replayed.
+
+use crate::control::{Access, Control, ControlClient, ControlCommand, Credentials};
+use crate::error::{Error, Result};
+use crate::json_format::JsonFormat;
+use crate::sink::PoseSink;
+use crate::tracked_camera::CameraStream;
+use crate::tracking_messages::TrackedObjects;
+use crate::traffic::Traffic;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// Slow clients get dropped instead of stalling the stream
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(50);
+
+type Clients = Arc<Mutex<Vec<TcpStream>>>;
+
+/// Text served on `/metrics`, replaced by whoever collects the metrics
+pub type Metrics = Arc<Mutex<String>>;
+
+/// Set once control is enabled
+type ControlSlot = Arc<Mutex<Option<Control>>>;
+
+/// Set once camera frames are enabled
+type CameraSlot = Arc<Mutex<Option<CameraStream>>>;
+
+/// Answers a JSON SDP offer with a JSON SDP answer
+pub type OfferHandler = Arc<dyn Fn(&str) -> Result<String> + Send + Sync>;
+
+/// Set once WebRTC is enabled
+type WebRtcSlot = Arc<Mutex<Option<OfferHandler>>>;
+
+/// Camera clients wait this long for a frame before checking again
+const CAMERA_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Larger request bodies are rejected
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Larger request lines and headers are rejected
+const MAX_HEADER_SIZE: u64 = 8 * 1024;
+
+/// Requests have this long to arrive in full, so slow clients don't hold a thread
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct HttpServer {
+    address: SocketAddr,
+    clients: Clients,
+    metrics: Metrics,
+    /// Clients and replay guard every route but the dashboard is checked against
+    access: Control,
+    control: ControlSlot,
+    camera: CameraSlot,
+    webrtc: WebRtcSlot,
+    format: JsonFormat,
+    buffer: Vec<u8>,
+    traffic: Traffic,
+}
+
+impl HttpServer {
+    /// Start listening and accepting connections on a background thread
+    pub fn bind(address: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(address).map_err(Error::Network)?;
+        let address = listener.local_addr().map_err(Error::Network)?;
+        let clients: Clients = Arc::default();
+        let metrics: Metrics = Arc::default();
+        let access = Control::new();
+        let control: ControlSlot = Arc::default();
+        let camera: CameraSlot = Arc::default();
+        let webrtc: WebRtcSlot = Arc::default();
+        let accept_clients = clients.clone();
+        let accept_metrics = metrics.clone();
+        let accept_access = access.clone();
+        let accept_control = control.clone();
+        let accept_camera = camera.clone();
+        let accept_webrtc = webrtc.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let clients = accept_clients.clone();
+                let metrics = accept_metrics.clone();
+                let access = accept_access.clone();
+                let control = accept_control.clone();
+                let camera = accept_camera.clone();
+                let webrtc = accept_webrtc.clone();
+                thread::spawn(move || {
+                    let slots = Slots {
+                        clients: &clients,
+                        metrics: &metrics,
+                        access: &access,
+                        control: &control,
+                        camera: &camera,
+                        webrtc: &webrtc,
+                    };
+                    let result = handle_connection(stream, slots);
+                    if let Err(error) = result {
+                        eprintln!("HTTP connection failed: {}", error);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            address,
+            clients,
+            metrics,
+            access,
+            control,
+            camera,
+            webrtc,
+            format: JsonFormat::default(),
+            buffer: vec![],
+            traffic: Traffic::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Format of frames on `/events`. The built-in dashboard expects the default
+    pub fn set_format(&mut self, format: JsonFormat) {
+        self.format = format;
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Serve `/status` and accept commands on `/control`
+    ///
+    /// Anyone who can reach the server can control the sender,
+    /// unless clients are set with [`HttpServer::set_clients`].
+    pub fn enable_control(&self) -> Control {
+        self.control
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| self.access.clone())
+            .clone()
+    }
+
+    /// Require a token from one of `clients` on every route but the dashboard, open to anyone if empty
+    ///
+    /// With a replay window, commands need a fresh `ts` and an unused `nonce`.
+    pub fn set_clients(&self, clients: Vec<ControlClient>, replay_window: Option<Duration>) {
+        self.access.set_clients(clients);
+        self.access.set_replay_window(replay_window);
+    }
+
+    /// Stream frames encoded by `camera` on `/camera`
+    pub fn set_camera(&self, camera: CameraStream) {
+        *self.camera.lock().unwrap() = Some(camera);
+    }
+
+    /// Answer WebRTC offers posted to `/webrtc` with `handler`
+    pub fn set_webrtc(&self, handler: OfferHandler) {
+        *self.webrtc.lock().unwrap() = Some(handler);
+    }
+}
+
+/// What connections are served from
+struct Slots<'a> {
+    clients: &'a Clients,
+    metrics: &'a Metrics,
+    access: &'a Control,
+    control: &'a ControlSlot,
+    camera: &'a CameraSlot,
+    webrtc: &'a WebRtcSlot,
+}
+
+fn handle_connection(stream: TcpStream, slots: Slots) -> Result<()> {
+    let Slots {
+        clients,
+        metrics,
+        access,
+        control,
+        camera,
+        webrtc,
+    } = slots;
+    let mut reader = BufReader::new(DeadlineReader {
+        stream: stream.try_clone()?,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    });
+    let mut header = (&mut reader).take(MAX_HEADER_SIZE);
+    let mut request_line = String::new();
+    header.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    let mut credentials = None;
+    let mut line = String::new();
+    while header.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                credentials = Credentials::parse(value);
+            }
+        }
+        line.clear();
+    }
+    let mut stream = stream;
+    if header.limit() == 0 {
+        write_response(
+            &mut stream,
+            "431 Request Header Fields Too Large",
+            "text/plain",
+            "",
+        )?;
+        return Ok(());
+    }
+    let mut request = request_line.split_whitespace();
+    let method = request.next().unwrap_or("GET");
+    let target = request.next().unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    // EventSource and img can't set headers
+    if let (None, Some(token)) = (&credentials, query_param(query, "access_token")) {
+        credentials = Some(Credentials::Token(token.to_owned()));
+    }
+    match (method, path) {
+        (_, "/" | "/index.html") => {
+            write_response(
+                &mut stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                DASHBOARD_HTML,
+            )?;
+            return Ok(());
+        }
+        // browsers on another origin ask before posting JSON, without credentials
+        ("OPTIONS", "/webrtc") => {
+            stream.write_all(
+                b"HTTP/1.1 204 No Content\r\n\
+                Access-Control-Allow-Origin: *\r\n\
+                Access-Control-Allow-Methods: POST\r\n\
+                Access-Control-Allow-Headers: Content-Type, Authorization\r\n\
+                Connection: close\r\n\r\n",
+            )?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    let mut body = vec![];
+    if method == "POST" {
+        if content_length > MAX_BODY_SIZE {
+            write_response(&mut stream, "413 Payload Too Large", "text/plain", "")?;
+            return Ok(());
+        }
+        body.resize(content_length, 0);
+        reader.read_exact(&mut body)?;
+    }
+    let control = control.lock().unwrap().clone();
+    if let ("POST", "/control", Some(control)) = (method, path, &control) {
+        return handle_command(&mut stream, control, credentials.as_ref(), &body);
+    }
+    // signatures of requests without a body cover the path and query
+    let signed = if method == "POST" {
+        &body[..]
+    } else {
+        target.as_bytes()
+    };
+    if access.authorize(credentials.as_ref(), signed, None) == Access::Unauthorized {
+        write_response(&mut stream, "401 Unauthorized", "text/plain", "")?;
+        return Ok(());
+    }
+    if method != "POST" && matches!(credentials, Some(Credentials::Signature { .. })) {
+        let nonce = query_param(query, "nonce");
+        let ts = query_param(query, "ts").and_then(|ts| ts.parse().ok());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        if let Err(message) = access.check_fresh(true, nonce, ts, now) {
+            eprintln!("Rejected signed request for {}: {}", path, message);
+            write_response(&mut stream, "409 Conflict", "text/plain", &message)?;
+            return Ok(());
+        }
+    }
+    if let ("GET", "/camera", Some(camera)) = (method, path, camera.lock().unwrap().clone()) {
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+            Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\
+            Cache-Control: no-cache\r\n\r\n",
+        )?;
+        stream_camera(stream, camera);
+        return Ok(());
+    }
+    if let ("POST", "/webrtc", Some(webrtc)) = (method, path, webrtc.lock().unwrap().clone()) {
+        let (status, content_type, body) = match webrtc(&String::from_utf8_lossy(&body)) {
+            Ok(answer) => ("200 OK", "application/json", answer),
+            Err(error) => ("400 Bad Request", "text/plain", error.describe()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+            Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )?;
+        return Ok(());
+    }
+    match (method, path, control) {
+        (_, "/metrics", _) => {
+            let metrics = metrics.lock().unwrap().clone();
+            write_response(&mut stream, "200 OK", "text/plain; version=0.0.4", &metrics)?;
+        }
+        (_, "/events", _) => {
+            stream.write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                Content-Type: text/event-stream\r\n\
+                Cache-Control: no-cache\r\n\
+                Access-Control-Allow-Origin: *\r\n\r\n",
+            )?;
+            stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))?;
+            clients.lock().unwrap().push(stream);
+        }
+        ("GET", "/status", Some(control)) => {
+            let status = control.status();
+            // supervisors polling for health see missing required devices in the code alone
+            let code = if status.missing.is_empty() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            write_response(
+                &mut stream,
+                code,
+                "application/json",
+                &serde_json::to_string(&status)?,
+            )?;
+        }
+        _ => {
+            write_response(&mut stream, "404 Not Found", "text/plain", "Not found")?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads from a connection until a deadline, after which reads time out
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buffer)
+    }
+}
+
+/// Authorize and queue a command posted to `/control`
+fn handle_command(
+    stream: &mut TcpStream,
+    control: &Control,
+    credentials: Option<&Credentials>,
+    body: &[u8],
+) -> Result<()> {
+    let (command, nonce, ts) = match parse_command(body) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            let message = format!("Invalid command: {}", error);
+            write_response(stream, "400 Bad Request", "text/plain", &message)?;
+            return Ok(());
+        }
+    };
+    match control.authorize(credentials, body, Some(&command)) {
+        Access::Allowed(client) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let signed = matches!(credentials, Some(Credentials::Signature { .. }));
+            if let Err(message) = control.check_fresh(signed, nonce.as_deref(), ts, now) {
+                eprintln!("Rejected control command {}: {}", command.name(), message);
+                write_response(stream, "409 Conflict", "text/plain", &message)?;
+                return Ok(());
+            }
+            match client {
+                Some(client) => eprintln!("Control command {:?} from {}", command, client),
+                None => eprintln!("Control command {:?}", command),
+            }
+            control.send(command);
+            write_response(stream, "202 Accepted", "text/plain", "")?;
+        }
+        Access::Unauthorized => {
+            eprintln!(
+                "Rejected control command {} without a valid token",
+                command.name()
+            );
+            write_response(stream, "401 Unauthorized", "text/plain", "")?;
+        }
+        Access::Forbidden(client) => {
+            eprintln!(
+                "Rejected control command {} from {}",
+                command.name(),
+                client
+            );
+            let message = format!("{} may not send {}", client, command.name());
+            write_response(stream, "403 Forbidden", "text/plain", &message)?;
+        }
+    }
+    Ok(())
+}
+
+/// Command with the `nonce` and `ts` that sit next to its fields
+fn parse_command(body: &[u8]) -> serde_json::Result<(ControlCommand, Option<String>, Option<u64>)> {
+    let mut value: serde_json::Value = serde_json::from_slice(body)?;
+    let (nonce, ts) = match value.as_object_mut() {
+        Some(object) => (object.remove("nonce"), object.remove("ts")),
+        None => (None, None),
+    };
+    let command = serde_json::from_value(value)?;
+    let nonce = nonce.and_then(|nonce| nonce.as_str().map(str::to_owned));
+    Ok((command, nonce, ts.and_then(|ts| ts.as_u64())))
+}
+
+/// Value of `name` in a query string, as sent
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Send every new frame as a part until the client goes away
+fn stream_camera(mut stream: TcpStream, camera: CameraStream) {
+    let mut last = 0;
+    loop {
+        let (number, jpeg) = match camera.wait_jpeg(last, CAMERA_FRAME_TIMEOUT) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        last = number;
+        let written = write!(
+            stream,
+            "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg.len()
+        )
+        .and_then(|_| stream.write_all(&jpeg))
+        .and_then(|_| stream.write_all(b"\r\n"));
+        if written.is_err() {
+            return;
+        }
+    }
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+impl PoseSink for HttpServer {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(b"data: ");
+        self.format.write(frame, &mut self.buffer)?;
+        self.buffer.extend_from_slice(b"\n\n");
+        let (buffer, traffic) = (&self.buffer, &self.traffic);
+        self.clients.lock().unwrap().retain(|mut client| {
+            let written = client.write_all(buffer).is_ok();
+            if written {
+                traffic.record(buffer.len());
+            }
+            written
+        });
+        Ok(())
+    }
+
+    fn traffic(&self) -> Option<&Traffic> {
+        Some(&self.traffic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::Authenticator;
+    use crate::control::ControlStatus;
+    use crate::tracked_camera::RawFrame;
+
+    fn request(address: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        stream
+    }
+
+    #[test]
+    fn test_serves_dashboard_and_events() {
+        let mut server = HttpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr();
+
+        let mut page = String::new();
+        request(address, "/").read_to_string(&mut page).unwrap();
+        assert!(page.starts_with("HTTP/1.1 200 OK"));
+        assert!(page.contains("EventSource"));
+
+        *server.metrics().lock().unwrap() = "metric 1\n".to_owned();
+        let mut metrics = String::new();
+        request(address, "/metrics")
+            .read_to_string(&mut metrics)
+            .unwrap();
+        assert!(metrics.ends_with("\r\n\r\nmetric 1\n"));
+
+        let mut missing = String::new();
+        request(address, "/nope")
+            .read_to_string(&mut missing)
+            .unwrap();
+        assert!(missing.starts_with("HTTP/1.1 404"));
+
+        let mut events = BufReader::new(request(address, "/events"));
+        let mut line = String::new();
+        while events.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        while server.clients.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        server.publish(&TrackedObjects::new(7, 0, vec![])).unwrap();
+        let mut event = String::new();
+        events.read_line(&mut event).unwrap();
+        assert!(event.starts_with("data: {"));
+        assert!(event.contains("\"seq\":7"));
+    }
+
+    #[test]
+    fn test_rejects_oversized_headers() {
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut header = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        header.resize(MAX_HEADER_SIZE as usize, b'a');
+        stream.write_all(&header).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431"));
+    }
+
+    #[test]
+    fn test_streams_camera_frames() {
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let camera = CameraStream::new();
+        camera.spawn_encoder(75);
+        server.set_camera(camera.clone());
+        let mut frames = BufReader::new(request(server.local_addr(), "/camera"));
+        let mut line = String::new();
+        frames.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 200 OK"));
+        camera.push_raw(RawFrame {
+            width: 8,
+            height: 8,
+            bytes_per_pixel: 3,
+            pixels: vec![0; 8 * 8 * 3],
+        });
+        let mut length = 0;
+        // skip the response headers, then read the headers of the first part
+        while length == 0 || line != "\r\n" {
+            line.clear();
+            frames.read_line(&mut line).unwrap();
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut jpeg = vec![0; length];
+        frames.read_exact(&mut jpeg).unwrap();
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+        assert_eq!(&jpeg[length - 2..], &[0xff, 0xd9]);
+    }
+
+    #[test]
+    fn test_accepts_control_commands() {
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr();
+        let mut missing = String::new();
+        request(address, "/status")
+            .read_to_string(&mut missing)
+            .unwrap();
+        assert!(missing.starts_with("HTTP/1.1 404"));
+
+        let control = server.enable_control();
+        let post = |body: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "POST /control HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(post(r#"{"command": "set_rate", "rate_hz": 30.0}"#).starts_with("HTTP/1.1 202"));
+        assert!(post(r#"{"command": "stop"}"#).starts_with("HTTP/1.1 202"));
+        assert!(post(r#"{"command": "explode"}"#).starts_with("HTTP/1.1 400"));
+        assert_eq!(
+            control.take_commands(),
+            vec![
+                ControlCommand::SetRate { rate_hz: 30.0 },
+                ControlCommand::Stop
+            ]
+        );
+
+        control.set_status(ControlStatus {
+            rate_hz: 30.0,
+            ..Default::default()
+        });
+        let mut status = String::new();
+        request(address, "/status")
+            .read_to_string(&mut status)
+            .unwrap();
+        assert!(status.contains("\"rate_hz\":30.0"));
+        control.set_status(ControlStatus {
+            missing: vec!["chest".into()],
+            ..Default::default()
+        });
+        let mut status = String::new();
+        request(address, "/status")
+            .read_to_string(&mut status)
+            .unwrap();
+        assert!(status.starts_with("HTTP/1.1 503"));
+
+        control.set_clients(vec![ControlClient {
+            name: "robot".into(),
+            token: "secret".into(),
+            commands: vec!["stop".into()],
+        }]);
+        let post_as = |token: &str, body: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "POST /control HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+                token,
+                body.len(),
+                body
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(post(r#"{"command": "stop"}"#).starts_with("HTTP/1.1 401"));
+        assert!(post_as("wrong", r#"{"command": "stop"}"#).starts_with("HTTP/1.1 401"));
+        assert!(post_as("secret", r#"{"command": "start"}"#).starts_with("HTTP/1.1 403"));
+        assert!(post_as("secret", r#"{"command": "stop"}"#).starts_with("HTTP/1.1 202"));
+        assert_eq!(control.take_commands(), vec![ControlCommand::Stop]);
+        let mut status = String::new();
+        request(address, "/status")
+            .read_to_string(&mut status)
+            .unwrap();
+        assert!(status.starts_with("HTTP/1.1 401"));
+    }
+
+    #[test]
+    fn test_routes_need_a_token() {
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr();
+        server.enable_control();
+        server.set_webrtc(Arc::new(|_: &str| Ok("{}".to_owned())));
+        server.set_clients(
+            vec![ControlClient {
+                name: "robot".into(),
+                token: "secret".into(),
+                commands: vec![],
+            }],
+            None,
+        );
+        let get = |path: &str, authorization: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+                path, authorization
+            )
+            .unwrap();
+            let mut status = String::new();
+            BufReader::new(stream).read_line(&mut status).unwrap();
+            status
+        };
+        assert!(get("/", "").starts_with("HTTP/1.1 200"));
+        assert!(get("/metrics", "").starts_with("HTTP/1.1 401"));
+        assert!(get("/events", "").starts_with("HTTP/1.1 401"));
+        assert!(get("/events?access_token=secret", "").starts_with("HTTP/1.1 200"));
+        assert!(get("/status", "Bearer secret").starts_with("HTTP/1.1 200"));
+
+        let mut offer = TcpStream::connect(address).unwrap();
+        write!(
+            offer,
+            "POST /webrtc HTTP/1.1\r\nContent-Length: 2\r\n\r\n{{}}"
+        )
+        .unwrap();
+        let mut response = String::new();
+        offer.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        // signed requests sign the path and query, and can't be sent twice
+        let signed = |path: &str| {
+            let signature: String = Authenticator::new(b"secret")
+                .unwrap()
+                .tag(path.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            format!("HMAC robot:{}", signature)
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let path = format!("/status?nonce=1&ts={}", now);
+        assert!(get(&path, &signed(&path)).starts_with("HTTP/1.1 200"));
+        assert!(get(&path, &signed(&path)).starts_with("HTTP/1.1 409"));
+        assert!(get(&path, &signed("/status")).starts_with("HTTP/1.1 401"));
+        assert!(get("/status", &signed("/status")).starts_with("HTTP/1.1 409"));
+    }
+}
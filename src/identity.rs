@@ -0,0 +1,120 @@
+//! Which sender a frame came from
+//!
+//! Several rigs can share a network, so every frame names its sender.
+//! Devices get a GUID derived from their serial, the same on every machine.
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SenderIdentity {
+    /// Configured sender name, or a UUID generated at startup
+    pub id: Arc<str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<Arc<str>>,
+}
+
+impl SenderIdentity {
+    /// Identity of this process, named `name` if given
+    pub fn new(name: Option<Arc<str>>) -> Self {
+        Self {
+            id: name.unwrap_or_else(|| random_uuid().into()),
+            hostname: hostname().map(Arc::from),
+        }
+    }
+}
+
+/// Deterministic UUID of a device serial
+///
+/// 128 bit FNV-1a of the serial, formatted as a version 8 (custom) UUID.
+/// The algorithm is fixed, so the same serial gets the same GUID across versions and machines.
+pub fn device_guid(serial: &str) -> String {
+    const FNV_OFFSET: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    const FNV_PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+    let mut bits = FNV_OFFSET;
+    for byte in b"openvr-tracker device ".iter().chain(serial.as_bytes()) {
+        bits ^= *byte as u128;
+        bits = bits.wrapping_mul(FNV_PRIME);
+    }
+    format_uuid(bits, 0x8)
+}
+
+/// Random version 4 UUID
+fn random_uuid() -> String {
+    format_uuid((random_u64(0) as u128) << 64 | random_u64(1) as u128, 0x4)
+}
+
+/// Random number, not for key material
+fn random_u64(salt: u64) -> u64 {
+    // every RandomState is seeded with fresh randomness from the OS
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(salt);
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+/// Set version and RFC 4122 variant bits and format as hex groups
+fn format_uuid(mut bits: u128, version: u128) -> String {
+    bits = (bits & !(0xf << 76)) | (version << 76);
+    bits = (bits & !(0x3 << 62)) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buffer
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(buffer.len());
+    Some(String::from_utf8_lossy(&buffer[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_ids_are_uuids() {
+        let first = SenderIdentity::new(None);
+        let second = SenderIdentity::new(None);
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.id.len(), 36);
+        assert_eq!(&first.id[14..15], "4");
+        assert!(matches!(&first.id[19..20], "8" | "9" | "a" | "b"));
+
+        let named = SenderIdentity::new(Some("rig-a".into()));
+        assert_eq!(&*named.id, "rig-a");
+
+        let guid = device_guid("LHR-1234ABCD");
+        assert_eq!(guid, device_guid("LHR-1234ABCD"));
+        assert_ne!(guid, device_guid("LHR-1234ABCE"));
+        assert_eq!(&guid[14..15], "8");
+    }
+}
@@ -0,0 +1,189 @@
+//! Deliberately bad network for testing receivers
+//!
+//! Drops, delays or reorders a fraction of outgoing packets so receiver
+//! robustness can be checked on a clean network.
+
+use crate::error::Result;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Fractions of packets to mess with, each between 0 and 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImpairmentConfig {
+    pub drop: f32,
+    pub delay: f32,
+    /// How long delayed packets are held back
+    pub delay_time: Duration,
+    /// Packets swapped with the one sent after them
+    pub reorder: f32,
+}
+
+impl Default for ImpairmentConfig {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            delay: 0.0,
+            delay_time: Duration::from_millis(100),
+            reorder: 0.0,
+        }
+    }
+}
+
+impl ImpairmentConfig {
+    pub fn is_active(&self) -> bool {
+        self.drop > 0.0 || self.delay > 0.0 || self.reorder > 0.0
+    }
+}
+
+/// xorshift64*, plenty for picking packets
+struct Rng(u64);
+
+impl Rng {
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let value = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (value >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+pub struct Impairment {
+    config: ImpairmentConfig,
+    rng: Rng,
+    delayed: Vec<(Instant, Vec<u8>)>,
+    held: Option<Vec<u8>>,
+    dropped: u64,
+    delayed_count: u64,
+    reordered: u64,
+}
+
+impl Impairment {
+    pub fn new(config: ImpairmentConfig) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self::with_seed(config, seed)
+    }
+
+    pub fn with_seed(config: ImpairmentConfig, seed: u64) -> Self {
+        Self {
+            config,
+            // xorshift gets stuck on zero
+            rng: Rng(seed | 1),
+            delayed: vec![],
+            held: None,
+            dropped: 0,
+            delayed_count: 0,
+            reordered: 0,
+        }
+    }
+
+    /// Pass an outgoing packet through, calling `send` for everything due now
+    ///
+    /// Delayed packets only go out on a later call, so they are late by at least one frame period.
+    pub fn process(
+        &mut self,
+        packet: &[u8],
+        now: Instant,
+        mut send: impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let mut index = 0;
+        while index < self.delayed.len() {
+            if self.delayed[index].0 <= now {
+                let (_, delayed) = self.delayed.remove(index);
+                send(&delayed)?;
+            } else {
+                index += 1;
+            }
+        }
+        let roll = self.rng.next_f32();
+        let config = &self.config;
+        if roll < config.drop {
+            self.dropped += 1;
+        } else if roll < config.drop + config.delay {
+            self.delayed_count += 1;
+            self.delayed
+                .push((now + config.delay_time, packet.to_vec()));
+        } else if roll < config.drop + config.delay + config.reorder && self.held.is_none() {
+            self.reordered += 1;
+            self.held = Some(packet.to_vec());
+        } else {
+            send(packet)?;
+            if let Some(held) = self.held.take() {
+                send(&held)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} dropped, {} delayed, {} reordered",
+            self.dropped, self.delayed_count, self.reordered
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(config: ImpairmentConfig, packets: u8) -> Vec<u8> {
+        let mut impairment = Impairment::with_seed(config, 7);
+        let mut sent = vec![];
+        let start = Instant::now();
+        for packet in 0..packets {
+            let now = start + Duration::from_millis(packet as u64 * 20);
+            impairment
+                .process(&[packet], now, |bytes| {
+                    sent.push(bytes[0]);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        sent
+    }
+
+    #[test]
+    fn test_passes_everything_when_inactive() {
+        let sent = run(ImpairmentConfig::default(), 100);
+        assert_eq!(sent, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_drop_delay_and_reorder() {
+        let dropped = run(
+            ImpairmentConfig {
+                drop: 0.5,
+                ..Default::default()
+            },
+            200,
+        );
+        assert!(dropped.len() > 50 && dropped.len() < 150);
+        assert!(dropped.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let delayed = run(
+            ImpairmentConfig {
+                delay: 1.0,
+                delay_time: Duration::from_millis(50),
+                ..Default::default()
+            },
+            10,
+        );
+        // each packet goes out three frames later
+        assert_eq!(delayed, (0..7).collect::<Vec<_>>());
+
+        let reordered = run(
+            ImpairmentConfig {
+                reorder: 0.3,
+                ..Default::default()
+            },
+            100,
+        );
+        assert!(reordered.windows(2).any(|pair| pair[0] > pair[1]));
+        let mut sorted = reordered.clone();
+        sorted.sort_unstable();
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
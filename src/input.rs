@@ -0,0 +1,291 @@
+//! Controller buttons through the SteamVR input system
+//!
+//! Newer runtimes stop reporting buttons through the legacy controller state,
+//! `GetControllerState` returns nothing there. The input system instead maps
+//! physical buttons to named actions declared in an action manifest, bound per
+//! controller type in a way users can change in SteamVR. With `input` in the
+//! config the tracker loads a manifest when connecting, updates its action sets
+//! with every sample and reads the buttons it reacts to from boolean actions.
+//!
+//! `input/actions.json` declares one action per [`ControllerButton`] and binds
+//! them for Vive wands, other controllers are bound in the SteamVR binding UI.
+
+use crate::backend::ControllerButton;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem::size_of;
+use std::path::PathBuf;
+
+/// Input sources buttons are read from, one controller each
+const HANDS: [&str; 2] = ["/user/hand/left", "/user/hand/right"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    /// Action manifest, relative to the working directory
+    pub manifest: PathBuf,
+    /// Action sets updated with every sample
+    pub action_sets: Vec<String>,
+    /// Boolean action of each button, buttons without one are never pressed
+    pub buttons: HashMap<ControllerButton, String>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        let action = |name: &str| format!("/actions/openvr_tracker/in/{}", name);
+        Self {
+            manifest: PathBuf::from("input/actions.json"),
+            action_sets: vec!["/actions/openvr_tracker".to_owned()],
+            buttons: vec![
+                (ControllerButton::Trigger, action("trigger")),
+                (ControllerButton::Menu, action("menu")),
+                (ControllerButton::Grip, action("grip")),
+                (ControllerButton::Touchpad, action("touchpad")),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+}
+
+impl InputConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.action_sets.is_empty() {
+            problems.push("input.action_sets is empty, actions are never updated".to_owned());
+        }
+        let mut buttons: Vec<_> = self.buttons.iter().collect();
+        buttons.sort_by_key(|(button, _)| format!("{:?}", button));
+        for (button, action) in buttons {
+            let in_set = self
+                .action_sets
+                .iter()
+                .any(|set| action.starts_with(&format!("{}/", set)));
+            if !in_set {
+                problems.push(format!(
+                    "input action {} of {:?} isn't in any of input.action_sets",
+                    action, button
+                ));
+            }
+        }
+        problems
+    }
+}
+
+/// Input system of a connected OpenVR context
+pub struct ActionInput {
+    table: &'static openvr_sys::VR_IVRInput_FnTable,
+    sets: Vec<openvr_sys::VRActiveActionSet_t>,
+    buttons: Vec<(ControllerButton, openvr_sys::VRActionHandle_t)>,
+    hands: Vec<openvr_sys::VRInputValueHandle_t>,
+    /// Device index and button of every press in the latest update
+    pressed: Vec<(usize, ControllerButton)>,
+}
+
+impl ActionInput {
+    /// Load the manifest and look up actions, needs an initialized OpenVR context
+    pub fn open(config: &InputConfig) -> Result<Self> {
+        // the table layout openvr_sys was generated for
+        let mut name = b"FnTable:".to_vec();
+        name.extend_from_slice(openvr_sys::IVRInput_Version);
+        let mut error = openvr_sys::EVRInitError_VRInitError_None;
+        let table = unsafe {
+            openvr_sys::VR_GetGenericInterface(name.as_ptr() as *const _, &mut error)
+                as *const openvr_sys::VR_IVRInput_FnTable
+        };
+        if error != openvr_sys::EVRInitError_VRInitError_None || table.is_null() {
+            return Err(Error::Interface(format!(
+                "Input interface not available, error {}",
+                error
+            )));
+        }
+        // the function table lives as long as the OpenVR context
+        let table = unsafe { &*table };
+        let (set_manifest, get_set, get_action, get_source) = match (
+            table.SetActionManifestPath,
+            table.GetActionSetHandle,
+            table.GetActionHandle,
+            table.GetInputSourceHandle,
+        ) {
+            (Some(set_manifest), Some(get_set), Some(get_action), Some(get_source))
+                if table.UpdateActionState.is_some()
+                    && table.GetDigitalActionData.is_some()
+                    && table.GetOriginTrackedDeviceInfo.is_some() =>
+            {
+                (set_manifest, get_set, get_action, get_source)
+            }
+            _ => {
+                return Err(Error::Interface(
+                    "Input interface of the runtime is missing functions".to_owned(),
+                ))
+            }
+        };
+        // SteamVR resolves the manifest from its own working directory
+        let manifest = config.manifest.canonicalize().map_err(|error| {
+            Error::config_caused(
+                format!(
+                    "Failed to find action manifest {}",
+                    config.manifest.display()
+                ),
+                error,
+            )
+        })?;
+        let manifest = c_string(&manifest.to_string_lossy())?;
+        check(
+            unsafe { set_manifest(manifest.as_ptr() as *mut _) },
+            "load the action manifest",
+        )?;
+        let mut sets = vec![];
+        for name in &config.action_sets {
+            let mut handle = 0;
+            let path = c_string(name)?;
+            check(
+                unsafe { get_set(path.as_ptr() as *mut _, &mut handle) },
+                name,
+            )?;
+            sets.push(openvr_sys::VRActiveActionSet_t {
+                ulActionSet: handle,
+                ulRestrictedToDevice: openvr_sys::k_ulInvalidInputValueHandle,
+                ulSecondaryActionSet: 0,
+                unPadding: 0,
+                nPriority: 0,
+            });
+        }
+        let mut buttons = vec![];
+        for (button, name) in &config.buttons {
+            let mut handle = 0;
+            let path = c_string(name)?;
+            check(
+                unsafe { get_action(path.as_ptr() as *mut _, &mut handle) },
+                name,
+            )?;
+            buttons.push((*button, handle));
+        }
+        let mut hands = vec![];
+        for name in HANDS.iter() {
+            let mut handle = 0;
+            let path = c_string(name)?;
+            check(
+                unsafe { get_source(path.as_ptr() as *mut _, &mut handle) },
+                name,
+            )?;
+            hands.push(handle);
+        }
+        Ok(Self {
+            table,
+            sets,
+            buttons,
+            hands,
+            pressed: vec![],
+        })
+    }
+
+    /// Read the buttons of both hands, once per sample
+    pub fn update(&mut self) {
+        self.pressed.clear();
+        // checked when opening
+        let update_state = match self.table.UpdateActionState {
+            Some(update_state) => update_state,
+            None => return,
+        };
+        let updated = unsafe {
+            update_state(
+                self.sets.as_mut_ptr(),
+                size_of::<openvr_sys::VRActiveActionSet_t>() as u32,
+                self.sets.len() as u32,
+            )
+        };
+        if updated != openvr_sys::EVRInputError_VRInputError_None {
+            return;
+        }
+        for &(button, action) in &self.buttons {
+            for &hand in &self.hands {
+                if let Some(index) = self.pressing_device(action, hand) {
+                    self.pressed.push((index, button));
+                }
+            }
+        }
+    }
+
+    /// Index of the device of `hand` pressing `action`
+    fn pressing_device(
+        &self,
+        action: openvr_sys::VRActionHandle_t,
+        hand: openvr_sys::VRInputValueHandle_t,
+    ) -> Option<usize> {
+        let get_data = self.table.GetDigitalActionData?;
+        let get_origin = self.table.GetOriginTrackedDeviceInfo?;
+        let mut data: openvr_sys::InputDigitalActionData_t = unsafe { std::mem::zeroed() };
+        let error = unsafe {
+            get_data(
+                action,
+                &mut data,
+                size_of::<openvr_sys::InputDigitalActionData_t>() as u32,
+                hand,
+            )
+        };
+        if error != openvr_sys::EVRInputError_VRInputError_None || !data.bActive || !data.bState {
+            return None;
+        }
+        let mut origin: openvr_sys::InputOriginInfo_t = unsafe { std::mem::zeroed() };
+        let error = unsafe {
+            get_origin(
+                data.activeOrigin,
+                &mut origin,
+                size_of::<openvr_sys::InputOriginInfo_t>() as u32,
+            )
+        };
+        if error != openvr_sys::EVRInputError_VRInputError_None {
+            return None;
+        }
+        Some(origin.trackedDeviceIndex as usize)
+    }
+
+    /// Whether device `index` pressed `button` in the latest update
+    pub fn pressed(&self, index: usize, button: ControllerButton) -> bool {
+        self.pressed.contains(&(index, button))
+    }
+}
+
+fn c_string(text: &str) -> Result<CString> {
+    CString::new(text)
+        .map_err(|error| Error::config_caused(format!("{:?} contains a null byte", text), error))
+}
+
+fn check(error: openvr_sys::EVRInputError, what: &str) -> Result<()> {
+    if error != openvr_sys::EVRInputError_VRInputError_None {
+        return Err(Error::Interface(format!(
+            "Input error {} for {}",
+            error, what
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_actions_are_in_manifest() {
+        let config = InputConfig::default();
+        assert!(config.problems().is_empty());
+        let text = std::fs::read_to_string(&config.manifest).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&text).unwrap();
+        let declared: Vec<_> = manifest["actions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|action| action["name"].as_str().unwrap())
+            .collect();
+        for action in config.buttons.values() {
+            assert!(declared.contains(&action.as_str()), "{}", action);
+        }
+
+        let config: InputConfig =
+            serde_json::from_str(r#"{"buttons": {"grip": "/actions/other/in/grip"}}"#).unwrap();
+        assert_eq!(config.problems().len(), 1);
+    }
+}
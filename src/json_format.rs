@@ -0,0 +1,510 @@
+//! Alternative JSON layouts for consumers that can't read the default one
+//!
+//! The default layout is what serde derives and what [`crate::client`] decodes.
+//! Other layouts are written in the same pass by a serializer that renames struct
+//! fields and lays out rotations on the way through. Map keys, like aliases, are
+//! data and stay as they are.
+
+use crate::error::Result;
+use crate::tracking_messages::TrackedObjects;
+use serde::ser::{
+    self, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple,
+    SerializeTupleStruct, SerializeTupleVariant, Serializer,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Names of quaternion components in the order nalgebra writes them
+const COMPONENTS: [&str; 4] = ["x", "y", "z", "w"];
+
+/// camelCase field names handed out so far, serde wants them `'static`
+static CAMEL_CASE_FIELDS: Mutex<BTreeMap<&'static str, &'static str>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldCase {
+    SnakeCase,
+    CamelCase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuaternionFormat {
+    /// `[x, y, z, w]` as nalgebra writes it
+    Array,
+    /// `{"x": .., "y": .., "z": .., "w": ..}`
+    Object,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JsonFormat {
+    pub case: FieldCase,
+    pub quaternion: QuaternionFormat,
+}
+
+impl Default for JsonFormat {
+    fn default() -> Self {
+        Self {
+            case: FieldCase::SnakeCase,
+            quaternion: QuaternionFormat::Array,
+        }
+    }
+}
+
+impl JsonFormat {
+    /// Serialize frame into `buffer` in this format
+    pub fn write(&self, frame: &TrackedObjects, buffer: &mut Vec<u8>) -> Result<()> {
+        if *self == Self::default() {
+            serde_json::to_writer(buffer, frame)?;
+        } else {
+            let mut serializer = serde_json::Serializer::new(buffer);
+            Formatted::new(frame, self, false).serialize(&mut serializer)?;
+        }
+        Ok(())
+    }
+
+    /// Name of a serde field in this layout
+    pub fn field_name(&self, field: &str) -> String {
+        match self.case {
+            FieldCase::SnakeCase => field.to_owned(),
+            FieldCase::CamelCase => camel_case(field),
+        }
+    }
+
+    fn static_field_name(&self, field: &'static str) -> &'static str {
+        match self.case {
+            FieldCase::SnakeCase => field,
+            FieldCase::CamelCase => {
+                // field names come from derives, so only a fixed set is ever leaked
+                let mut fields = CAMEL_CASE_FIELDS
+                    .lock()
+                    .unwrap_or_else(|poison| poison.into_inner());
+                fields
+                    .entry(field)
+                    .or_insert_with(|| Box::leak(camel_case(field).into_boxed_str()))
+            }
+        }
+    }
+}
+
+/// `value` serialized in `format`
+struct Formatted<'a, T: ?Sized> {
+    value: &'a T,
+    format: &'a JsonFormat,
+    /// Value of a `rotation` field, written as an object if it is a sequence of four
+    rotation: bool,
+}
+
+impl<'a, T: ?Sized> Formatted<'a, T> {
+    fn new(value: &'a T, format: &'a JsonFormat, rotation: bool) -> Self {
+        Self {
+            value,
+            format,
+            rotation,
+        }
+    }
+}
+
+impl<T: Serialize + ?Sized> Serialize for Formatted<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(Layout {
+            inner: serializer,
+            format: self.format,
+            rotation: self.rotation,
+        })
+    }
+}
+
+/// Serializer that passes everything to `inner`, with struct fields and rotations in `format`
+struct Layout<'a, S> {
+    inner: S,
+    format: &'a JsonFormat,
+    rotation: bool,
+}
+
+/// Compound value whose members are written in `format` as well
+struct Members<'a, C> {
+    inner: C,
+    format: &'a JsonFormat,
+}
+
+impl<'a, C> Members<'a, C> {
+    fn new(inner: C, format: &'a JsonFormat) -> Self {
+        Self { inner, format }
+    }
+}
+
+/// Elements of a sequence, or the components of a rotation as an object
+enum Sequence<'a, S: Serializer> {
+    Elements(Members<'a, S::SerializeSeq>),
+    Components(S::SerializeMap, usize),
+}
+
+macro_rules! forward {
+    ($($method:ident($type:ty)),*) => {
+        $(fn $method(self, value: $type) -> Result<S::Ok, S::Error> {
+            self.inner.$method(value)
+        })*
+    };
+}
+
+impl<'a, S: Serializer> Serializer for Layout<'a, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Sequence<'a, S>;
+    type SerializeTuple = Members<'a, S::SerializeTuple>;
+    type SerializeTupleStruct = Members<'a, S::SerializeTupleStruct>;
+    type SerializeTupleVariant = Members<'a, S::SerializeTupleVariant>;
+    type SerializeMap = Members<'a, S::SerializeMap>;
+    type SerializeStruct = Members<'a, S::SerializeStruct>;
+    type SerializeStructVariant = Members<'a, S::SerializeStructVariant>;
+
+    forward!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8])
+    );
+
+    fn serialize_none(self) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_none()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+        self.inner
+            .serialize_some(&Formatted::new(value, self.format, self.rotation))
+    }
+
+    fn serialize_unit(self) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit()
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit_struct(name)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    ) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit_variant(name, index, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        self.inner
+            .serialize_newtype_struct(name, &Formatted::new(value, self.format, self.rotation))
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_newtype_variant(
+            name,
+            index,
+            variant,
+            &Formatted::new(value, self.format, false),
+        )
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        if self.rotation && self.format.quaternion == QuaternionFormat::Object && len == Some(4) {
+            return Ok(Sequence::Components(self.inner.serialize_map(len)?, 0));
+        }
+        Ok(Sequence::Elements(Members::new(
+            self.inner.serialize_seq(len)?,
+            self.format,
+        )))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+        Ok(Members::new(self.inner.serialize_tuple(len)?, self.format))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, S::Error> {
+        Ok(Members::new(
+            self.inner.serialize_tuple_struct(name, len)?,
+            self.format,
+        ))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, S::Error> {
+        Ok(Members::new(
+            self.inner
+                .serialize_tuple_variant(name, index, variant, len)?,
+            self.format,
+        ))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        Ok(Members::new(self.inner.serialize_map(len)?, self.format))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, S::Error> {
+        Ok(Members::new(
+            self.inner.serialize_struct(name, len)?,
+            self.format,
+        ))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, S::Error> {
+        Ok(Members::new(
+            self.inner
+                .serialize_struct_variant(name, index, variant, len)?,
+            self.format,
+        ))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+impl<S: Serializer> SerializeSeq for Sequence<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        match self {
+            Sequence::Elements(elements) => {
+                elements
+                    .inner
+                    .serialize_element(&Formatted::new(value, elements.format, false))
+            }
+            Sequence::Components(components, index) => {
+                let name = COMPONENTS
+                    .get(*index)
+                    .ok_or_else(|| ser::Error::custom("rotation has more than four components"))?;
+                *index += 1;
+                components.serialize_entry(name, value)
+            }
+        }
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        match self {
+            Sequence::Elements(elements) => elements.inner.end(),
+            Sequence::Components(components, _) => components.end(),
+        }
+    }
+}
+
+impl<C: SerializeTuple> SerializeTuple for Members<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.inner
+            .serialize_element(&Formatted::new(value, self.format, false))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.inner.end()
+    }
+}
+
+impl<C: SerializeTupleStruct> SerializeTupleStruct for Members<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.inner
+            .serialize_field(&Formatted::new(value, self.format, false))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.inner.end()
+    }
+}
+
+impl<C: SerializeTupleVariant> SerializeTupleVariant for Members<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.inner
+            .serialize_field(&Formatted::new(value, self.format, false))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.inner.end()
+    }
+}
+
+impl<C: SerializeMap> SerializeMap for Members<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), C::Error> {
+        self.inner.serialize_key(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.inner
+            .serialize_value(&Formatted::new(value, self.format, false))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.inner.end()
+    }
+}
+
+impl<C: SerializeStruct> SerializeStruct for Members<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), C::Error> {
+        self.inner.serialize_field(
+            self.format.static_field_name(key),
+            &Formatted::new(value, self.format, key == "rotation"),
+        )
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), C::Error> {
+        self.inner.skip_field(self.format.static_field_name(key))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.inner.end()
+    }
+}
+
+impl<C: SerializeStructVariant> SerializeStructVariant for Members<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), C::Error> {
+        self.inner.serialize_field(
+            self.format.static_field_name(key),
+            &Formatted::new(value, self.format, key == "rotation"),
+        )
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), C::Error> {
+        self.inner.skip_field(self.format.static_field_name(key))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.inner.end()
+    }
+}
+
+fn camel_case(snake: &str) -> String {
+    let mut out = String::with_capacity(snake.len());
+    let mut upper = false;
+    for c in snake.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracking_messages::VrDevice;
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_camel_case_and_quaternion_object() {
+        let mut frame = TrackedObjects::new(1, 2, vec![VrDevice::new(0)]);
+        frame.set_delta_of(0);
+        let format = JsonFormat {
+            case: FieldCase::CamelCase,
+            quaternion: QuaternionFormat::Object,
+        };
+        let mut buffer = vec![];
+        format.write(&frame, &mut buffer).unwrap();
+        let value: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(value["keyframeSeq"], 0);
+        assert_eq!(value["trackers"][0]["rotation"]["w"], 1.0);
+        assert_eq!(value["trackers"][0]["rotation"]["x"], 0.0);
+
+        let mut buffer = vec![];
+        JsonFormat::default().write(&frame, &mut buffer).unwrap();
+        let value: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(value["keyframe_seq"], 0);
+        assert_eq!(value["trackers"][0]["rotation"][3], 1.0);
+    }
+
+    #[test]
+    fn test_camel_case_leaves_map_keys() {
+        let format = JsonFormat {
+            case: FieldCase::CamelCase,
+            quaternion: QuaternionFormat::Array,
+        };
+        let aliases: HashMap<_, _> = vec![("LHR-1", "left_foot")].into_iter().collect();
+        let mut buffer = vec![];
+        let mut serializer = serde_json::Serializer::new(&mut buffer);
+        Formatted::new(&aliases, &format, false)
+            .serialize(&mut serializer)
+            .unwrap();
+        assert_eq!(buffer, br#"{"LHR-1":"left_foot"}"#);
+
+        let mut frame = TrackedObjects::new(1, u128::from(u64::MAX) + 1, vec![]);
+        frame.set_delta_of(0);
+        let mut buffer = vec![];
+        format.write(&frame, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains(r#""ts":18446744073709551616"#));
+        assert!(text.contains(r#""keyframeSeq":0"#));
+    }
+}
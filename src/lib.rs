@@ -0,0 +1,79 @@
+pub mod adaptive_rate;
+pub mod aggregator;
+pub mod anchors;
+pub mod authentication;
+pub mod backend;
+pub mod black_box;
+pub mod boundary;
+pub mod bvh;
+pub mod c3d;
+pub mod calibration;
+pub mod centroid;
+pub mod change_tracker;
+pub mod client;
+pub mod clock;
+pub mod compact;
+pub mod config;
+pub mod control;
+pub mod data_channel;
+pub mod device_ids;
+pub mod doctor;
+pub mod encryption;
+pub mod env;
+pub mod error;
+pub mod events;
+pub mod extrapolation;
+pub mod fragment;
+pub mod handshake;
+pub mod history;
+pub mod http;
+pub mod identity;
+pub mod impairment;
+pub mod input;
+pub mod json_format;
+pub mod markers;
+pub mod mcap;
+pub mod multicast;
+pub mod openvr_adaptor;
+pub mod osc;
+pub mod pairs;
+pub mod pipeline;
+pub mod power;
+pub mod quality;
+pub mod recording;
+pub mod reliable;
+pub mod replay;
+pub mod required;
+pub mod sampling;
+pub mod schema;
+pub mod session;
+pub mod signals;
+pub mod simulation;
+pub mod sink;
+pub mod skeleton;
+pub mod socket_options;
+pub mod status;
+pub mod subscription;
+pub mod summary;
+pub mod timestamps;
+pub mod topic;
+pub mod tracked_camera;
+pub mod tracking_messages;
+pub mod traffic;
+pub mod transform_tree;
+pub mod tui;
+pub mod unicast;
+pub mod watchdog;
+pub mod workspace;
+
+#[cfg(feature = "visualizer")]
+pub mod visualizer;
+
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(test)]
+mod fixtures;
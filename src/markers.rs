@@ -0,0 +1,140 @@
+//! Named markers in the stream, for aligning experiments with the data
+//!
+//! A marker is published on the event channel as soon as it is set, with the
+//! time it was set, and so ends up in every recording next to the poses. Set
+//! one with the `marker` control command, by typing `m` and a name in `--tui`,
+//! or by holding the marker chord on both controllers. Markers without a name
+//! are numbered, `marker 1`, `marker 2` and so on.
+//!
+//! Chords are read on the sampling thread while markers are published from the
+//! publishing thread. Both share one [`Markers`].
+
+use crate::backend::ControllerButton;
+use crate::recording::Chord;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkerConfig {
+    /// Held on both controllers to set a numbered marker
+    pub chord: Option<ControllerButton>,
+    /// How long the chord has to be held
+    pub hold_ms: u64,
+    /// Length of the buzz confirming a chord
+    pub haptic_ms: u64,
+}
+
+impl Default for MarkerConfig {
+    fn default() -> Self {
+        Self {
+            chord: None,
+            hold_ms: 500,
+            haptic_ms: 100,
+        }
+    }
+}
+
+/// Where a marker was set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkerSource {
+    Control,
+    Key,
+    Chord,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Marker {
+    pub name: Arc<str>,
+    /// Milliseconds since the Unix epoch when the marker was set, on the clock of the frames
+    pub ts: u128,
+    pub source: MarkerSource,
+}
+
+#[derive(Debug, Default)]
+struct MarkerState {
+    pending: Vec<Marker>,
+    /// Markers set since startup
+    count: u64,
+    chord: Chord,
+}
+
+/// Markers shared between the sampling and the publishing thread
+#[derive(Debug, Clone, Default)]
+pub struct Markers {
+    config: MarkerConfig,
+    state: Arc<Mutex<MarkerState>>,
+}
+
+impl Markers {
+    pub fn new(config: MarkerConfig) -> Self {
+        Self {
+            config,
+            state: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &MarkerConfig {
+        &self.config
+    }
+
+    /// Set a marker at `ts`, numbered if it has no name
+    pub fn add(&self, name: Option<&str>, ts: u128, source: MarkerSource) -> Arc<str> {
+        let mut state = self.state.lock().unwrap();
+        state.count += 1;
+        let name: Arc<str> = match name.map(str::trim).filter(|name| !name.is_empty()) {
+            Some(name) => name.into(),
+            None => format!("marker {}", state.count).into(),
+        };
+        state.pending.push(Marker {
+            name: name.clone(),
+            ts,
+            source,
+        });
+        name
+    }
+
+    /// Feed whether the chord is held in the current sample, true when it set a marker
+    pub fn update(&self, held: bool, ts: u128, now: Instant) -> bool {
+        let hold = Duration::from_millis(self.config.hold_ms);
+        let fired = self.state.lock().unwrap().chord.update(held, hold, now);
+        if fired {
+            self.add(None, ts, MarkerSource::Chord);
+        }
+        fired
+    }
+
+    /// Markers set since the last call, in order
+    pub fn take(&self) -> Vec<Marker> {
+        std::mem::take(&mut self.state.lock().unwrap().pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_and_numbered_markers() {
+        let markers = Markers::new(MarkerConfig::default());
+        let start = Instant::now();
+        assert!(!markers.update(true, 1000, start));
+        assert!(markers.update(true, 1500, start + Duration::from_millis(500)));
+        // once per hold
+        assert!(!markers.update(true, 2000, start + Duration::from_secs(1)));
+        markers.add(Some(" trial 3 start "), 2100, MarkerSource::Control);
+        markers.add(Some(""), 2200, MarkerSource::Key);
+        let taken = markers.take();
+        let names: Vec<&str> = taken.iter().map(|marker| &*marker.name).collect();
+        assert_eq!(names, ["marker 1", "trial 3 start", "marker 3"]);
+        assert_eq!(taken[0].ts, 1500);
+        assert_eq!(taken[0].source, MarkerSource::Chord);
+        assert!(markers.take().is_empty());
+        assert_eq!(
+            serde_json::to_string(&taken[1]).unwrap(),
+            r#"{"name":"trial 3 start","ts":2100,"source":"control"}"#
+        );
+    }
+}
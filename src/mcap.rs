@@ -0,0 +1,538 @@
+//! Recording into MCAP files, for Foxglove and rosbag2 tooling
+//!
+//! Files are written unchunked and without a summary section, both optional
+//! in the format, so frames go to disk as they arrive and a crash only loses
+//! the footer. Readers fall back to scanning the data section.
+//!
+//! The default layout keeps every message as the JSON of the stream, with the
+//! [message schema](crate::schema) attached, on `/openvr_tracker/pose` and
+//! `/openvr_tracker/event`. The ROS 2 layout uses the `ros2` profile that
+//! `ros2 bag` reads and only records device poses, as CDR encoded
+//! `tf2_msgs/msg/TFMessage` on `/tf`, one transform per device from
+//! [`TF_PARENT_FRAME`] to its alias, serial or `device_<id>`. Devices that
+//! carry a covariance are also written as `PoseWithCovarianceStamped` on
+//! `/openvr_tracker/pose/<frame>`, a topic per device created when it first
+//! shows up, as sensor fusion nodes expect.
+
+use crate::error::Result;
+use crate::schema;
+use crate::tracking_messages::{Channel, TrackedObjects, VrDevice};
+use nalgebra as na;
+use std::collections::HashMap;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"\x89MCAP0\r\n";
+
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_DATA_END: u8 = 0x0f;
+
+/// Frame every device transform is relative to in the ROS 2 layout
+pub const TF_PARENT_FRAME: &str = "openvr";
+
+/// `tf2_msgs/msg/TFMessage` with its dependencies, as `ros2 bag` stores it
+const TF_MESSAGE_DEFINITION: &str = "geometry_msgs/TransformStamped[] transforms
+================================================================================
+MSG: geometry_msgs/TransformStamped
+std_msgs/Header header
+string child_frame_id
+Transform transform
+================================================================================
+MSG: std_msgs/Header
+builtin_interfaces/Time stamp
+string frame_id
+================================================================================
+MSG: builtin_interfaces/Time
+int32 sec
+uint32 nanosec
+================================================================================
+MSG: geometry_msgs/Transform
+Vector3 translation
+Quaternion rotation
+================================================================================
+MSG: geometry_msgs/Vector3
+float64 x
+float64 y
+float64 z
+================================================================================
+MSG: geometry_msgs/Quaternion
+float64 x
+float64 y
+float64 z
+float64 w
+";
+
+/// `geometry_msgs/msg/PoseWithCovarianceStamped` with its dependencies
+const POSE_MESSAGE_DEFINITION: &str = "std_msgs/Header header
+PoseWithCovariance pose
+================================================================================
+MSG: std_msgs/Header
+builtin_interfaces/Time stamp
+string frame_id
+================================================================================
+MSG: builtin_interfaces/Time
+int32 sec
+uint32 nanosec
+================================================================================
+MSG: geometry_msgs/PoseWithCovariance
+Pose pose
+float64[36] covariance
+================================================================================
+MSG: geometry_msgs/Pose
+Point position
+Quaternion orientation
+================================================================================
+MSG: geometry_msgs/Point
+float64 x
+float64 y
+float64 z
+================================================================================
+MSG: geometry_msgs/Quaternion
+float64 x
+float64 y
+float64 z
+float64 w
+";
+
+/// Little endian CDR with no options
+const CDR_HEADER: [u8; 4] = [0x00, 0x01, 0x00, 0x00];
+
+const POSE_CHANNEL: u16 = 1;
+const EVENT_CHANNEL: u16 = 2;
+const TF_CHANNEL: u16 = 3;
+/// Per device pose channels are numbered from here
+const FIRST_POSE_CHANNEL: u16 = 4;
+
+/// Writes frames into an MCAP file
+pub struct McapWriter<W: Write> {
+    writer: W,
+    ros2: bool,
+    /// Per channel message counters
+    sequences: Vec<u32>,
+    /// Channel of every device pose topic in the ROS 2 layout
+    pose_channels: HashMap<String, u16>,
+    record: Vec<u8>,
+    message: Vec<u8>,
+}
+
+impl<W: Write> McapWriter<W> {
+    /// Start a file with JSON messages
+    pub fn new(writer: W) -> Result<Self> {
+        Self::start(writer, false)
+    }
+
+    /// Start a file in the ROS 2 layout
+    pub fn ros2(writer: W) -> Result<Self> {
+        Self::start(writer, true)
+    }
+
+    fn start(writer: W, ros2: bool) -> Result<Self> {
+        let mut mcap = Self {
+            writer,
+            ros2,
+            sequences: vec![0; FIRST_POSE_CHANNEL as usize],
+            pose_channels: HashMap::new(),
+            record: vec![],
+            message: vec![],
+        };
+        mcap.writer.write_all(MAGIC)?;
+        let profile = if ros2 { "ros2" } else { "" };
+        mcap.write_record(OP_HEADER, |record| {
+            put_string(record, profile);
+            put_string(
+                record,
+                concat!("openvr-tracker ", env!("CARGO_PKG_VERSION")),
+            );
+        })?;
+        if ros2 {
+            mcap.write_schema(
+                1,
+                "tf2_msgs/msg/TFMessage",
+                "ros2msg",
+                TF_MESSAGE_DEFINITION.as_bytes(),
+            )?;
+            mcap.write_channel(TF_CHANNEL, 1, "/tf", "cdr")?;
+            mcap.write_schema(
+                2,
+                "geometry_msgs/msg/PoseWithCovarianceStamped",
+                "ros2msg",
+                POSE_MESSAGE_DEFINITION.as_bytes(),
+            )?;
+        } else {
+            let schema = serde_json::to_vec(&schema::json_schema())?;
+            mcap.write_schema(1, "openvr_tracker.TrackedObjects", "jsonschema", &schema)?;
+            mcap.write_channel(POSE_CHANNEL, 1, "/openvr_tracker/pose", "json")?;
+            mcap.write_channel(EVENT_CHANNEL, 1, "/openvr_tracker/event", "json")?;
+        }
+        Ok(mcap)
+    }
+
+    /// Record a frame received at `log_time_ns` nanoseconds since the epoch
+    ///
+    /// The ROS 2 layout skips heartbeats and event channel messages.
+    pub fn write_frame(&mut self, frame: &TrackedObjects, log_time_ns: u64) -> Result<()> {
+        let publish_time_ns = frame.ts() as u64 * 1_000_000;
+        let mut message = std::mem::take(&mut self.message);
+        message.clear();
+        let result = if self.ros2 {
+            if frame.channel() == Channel::Event || frame.trackers().is_empty() {
+                self.message = message;
+                return Ok(());
+            }
+            encode_tf(frame, &mut message);
+            self.write_message(TF_CHANNEL, log_time_ns, publish_time_ns, &message)
+                .and_then(|_| self.write_poses(frame, log_time_ns, publish_time_ns, &mut message))
+        } else {
+            serde_json::to_writer(&mut message, frame)?;
+            let channel = match frame.channel() {
+                Channel::Pose => POSE_CHANNEL,
+                Channel::Event => EVENT_CHANNEL,
+            };
+            self.write_message(channel, log_time_ns, publish_time_ns, &message)
+        };
+        self.message = message;
+        result
+    }
+
+    /// Write every tracked device with a covariance on its own pose topic
+    fn write_poses(
+        &mut self,
+        frame: &TrackedObjects,
+        log_time_ns: u64,
+        publish_time_ns: u64,
+        message: &mut Vec<u8>,
+    ) -> Result<()> {
+        for device in frame.trackers().iter().filter(|device| device.tracked()) {
+            let covariance = match device.covariance() {
+                Some(covariance) => covariance,
+                None => continue,
+            };
+            let name = tf_frame_id(device);
+            let channel = match self.pose_channels.get(&name) {
+                Some(channel) => *channel,
+                None => {
+                    let channel = FIRST_POSE_CHANNEL + self.pose_channels.len() as u16;
+                    let topic = format!("/openvr_tracker/pose/{}", topic_name(&name));
+                    self.write_channel(channel, 2, &topic, "cdr")?;
+                    self.pose_channels.insert(name, channel);
+                    self.sequences.push(0);
+                    channel
+                }
+            };
+            // the device's edge from the root when the frame has a transform tree
+            let parent = frame
+                .transforms()
+                .iter()
+                .find(|edge| edge.id == device.id())
+                .map(|edge| &*edge.parent)
+                .unwrap_or(TF_PARENT_FRAME);
+            message.clear();
+            encode_pose(frame.ts(), parent, device, covariance, message);
+            self.write_message(channel, log_time_ns, publish_time_ns, message)?;
+        }
+        Ok(())
+    }
+
+    fn write_message(
+        &mut self,
+        channel: u16,
+        log_time_ns: u64,
+        publish_time_ns: u64,
+        message: &[u8],
+    ) -> Result<()> {
+        let sequence = self.sequences[channel as usize];
+        self.sequences[channel as usize] = sequence.wrapping_add(1);
+        self.write_record(OP_MESSAGE, |record| {
+            record.extend_from_slice(&channel.to_le_bytes());
+            record.extend_from_slice(&sequence.to_le_bytes());
+            record.extend_from_slice(&log_time_ns.to_le_bytes());
+            record.extend_from_slice(&publish_time_ns.to_le_bytes());
+            record.extend_from_slice(message);
+        })
+    }
+
+    /// Write the closing records and hand back the writer
+    pub fn finish(mut self) -> Result<W> {
+        // a CRC of 0 tells readers none was computed
+        self.write_record(OP_DATA_END, |record| {
+            record.extend_from_slice(&0u32.to_le_bytes())
+        })?;
+        self.write_record(OP_FOOTER, |record| {
+            record.extend_from_slice(&0u64.to_le_bytes());
+            record.extend_from_slice(&0u64.to_le_bytes());
+            record.extend_from_slice(&0u32.to_le_bytes());
+        })?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_schema(&mut self, id: u16, name: &str, encoding: &str, data: &[u8]) -> Result<()> {
+        self.write_record(OP_SCHEMA, |record| {
+            record.extend_from_slice(&id.to_le_bytes());
+            put_string(record, name);
+            put_string(record, encoding);
+            put_bytes(record, data);
+        })
+    }
+
+    fn write_channel(
+        &mut self,
+        id: u16,
+        schema_id: u16,
+        topic: &str,
+        encoding: &str,
+    ) -> Result<()> {
+        self.write_record(OP_CHANNEL, |record| {
+            record.extend_from_slice(&id.to_le_bytes());
+            record.extend_from_slice(&schema_id.to_le_bytes());
+            put_string(record, topic);
+            put_string(record, encoding);
+            // no metadata
+            record.extend_from_slice(&0u32.to_le_bytes());
+        })
+    }
+
+    fn write_record(&mut self, opcode: u8, content: impl FnOnce(&mut Vec<u8>)) -> Result<()> {
+        self.record.clear();
+        content(&mut self.record);
+        self.writer.write_all(&[opcode])?;
+        self.writer
+            .write_all(&(self.record.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&self.record)?;
+        Ok(())
+    }
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    put_bytes(buffer, value.as_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, value: &[u8]) {
+    buffer.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(value);
+}
+
+/// Child frame of a device in `/tf`
+pub fn tf_frame_id(device: &VrDevice) -> String {
+    device.frame_id()
+}
+
+/// Serialize the tracked devices of `frame` as a CDR `TFMessage`
+///
+/// Frames that carry a transform tree are written as that tree.
+fn encode_tf(frame: &TrackedObjects, buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&CDR_HEADER);
+    let mut cdr = Cdr { buffer, start: 4 };
+    let sec = (frame.ts() / 1000) as i32;
+    let nanosec = (frame.ts() % 1000) as u32 * 1_000_000;
+    if !frame.transforms().is_empty() {
+        cdr.u32(frame.transforms().len() as u32);
+        for edge in frame.transforms() {
+            cdr.u32(sec as u32);
+            cdr.u32(nanosec);
+            cdr.string(&edge.parent);
+            cdr.string(&edge.child);
+            cdr.transform(&edge.translation, &edge.rotation);
+        }
+        return;
+    }
+    let devices = frame.trackers().iter().filter(|device| device.tracked());
+    cdr.u32(devices.clone().count() as u32);
+    for device in devices {
+        cdr.u32(sec as u32);
+        cdr.u32(nanosec);
+        cdr.string(TF_PARENT_FRAME);
+        cdr.string(&tf_frame_id(device));
+        cdr.transform(&device.position().coords, device.rotation());
+    }
+}
+
+/// Serialize the pose of `device` in the `parent` frame as a CDR `PoseWithCovarianceStamped`
+fn encode_pose(
+    ts: u128,
+    parent: &str,
+    device: &VrDevice,
+    covariance: &na::Matrix6<f32>,
+    buffer: &mut Vec<u8>,
+) {
+    buffer.extend_from_slice(&CDR_HEADER);
+    let mut cdr = Cdr { buffer, start: 4 };
+    cdr.u32((ts / 1000) as u32);
+    cdr.u32((ts % 1000) as u32 * 1_000_000);
+    cdr.string(parent);
+    cdr.transform(&device.position().coords, device.rotation());
+    // symmetric, so column major order is the row major order ROS expects
+    for value in covariance.iter() {
+        cdr.f64(*value as f64);
+    }
+}
+
+/// ROS topic names only allow letters, digits and underscores between slashes
+fn topic_name(frame: &str) -> String {
+    let name: String = frame
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match name.chars().next() {
+        Some(first) if first.is_ascii_digit() => format!("_{}", name),
+        _ => name,
+    }
+}
+
+/// CDR writer, values are aligned to their size from the end of the encapsulation header
+struct Cdr<'a> {
+    buffer: &'a mut Vec<u8>,
+    start: usize,
+}
+
+impl Cdr<'_> {
+    fn align(&mut self, size: usize) {
+        while !(self.buffer.len() - self.start).is_multiple_of(size) {
+            self.buffer.push(0);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.align(8);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn transform(&mut self, translation: &na::Vector3<f32>, rotation: &na::UnitQuaternion<f32>) {
+        for value in translation.iter().chain(rotation.coords.iter()) {
+            self.f64(*value as f64);
+        }
+    }
+
+    /// Length includes the terminating NUL
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32 + 1);
+        self.buffer.extend_from_slice(value.as_bytes());
+        self.buffer.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IDENTITY_POSE;
+    use crate::tracking_messages::VrDeviceClass;
+    use std::convert::TryInto;
+
+    /// Opcodes and contents of every record between the magic bytes
+    fn records(bytes: &[u8]) -> Vec<(u8, &[u8])> {
+        assert_eq!(&bytes[..8], MAGIC);
+        assert_eq!(&bytes[bytes.len() - 8..], MAGIC);
+        let mut records = vec![];
+        let mut rest = &bytes[8..bytes.len() - 8];
+        while !rest.is_empty() {
+            let length = u64::from_le_bytes(rest[1..9].try_into().unwrap()) as usize;
+            records.push((rest[0], &rest[9..9 + length]));
+            rest = &rest[9 + length..];
+        }
+        records
+    }
+
+    fn frame() -> TrackedObjects {
+        let mut device = VrDevice::new(2);
+        device.update(true, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        device.set_serial(Some("LHR-1".into()));
+        TrackedObjects::new(7, 1_500, vec![device])
+    }
+
+    #[test]
+    fn test_json_layout() {
+        let mut mcap = McapWriter::new(vec![]).unwrap();
+        mcap.write_frame(&frame(), 42).unwrap();
+        let bytes = mcap.finish().unwrap();
+        let records = records(&bytes);
+        let opcodes: Vec<u8> = records.iter().map(|(opcode, _)| *opcode).collect();
+        assert_eq!(
+            opcodes,
+            vec![
+                OP_HEADER,
+                OP_SCHEMA,
+                OP_CHANNEL,
+                OP_CHANNEL,
+                OP_MESSAGE,
+                OP_DATA_END,
+                OP_FOOTER
+            ]
+        );
+        let message = records[4].1;
+        assert_eq!(u16::from_le_bytes([message[0], message[1]]), POSE_CHANNEL);
+        assert_eq!(u64::from_le_bytes(message[6..14].try_into().unwrap()), 42);
+        assert_eq!(
+            u64::from_le_bytes(message[14..22].try_into().unwrap()),
+            1_500_000_000
+        );
+        let decoded: TrackedObjects = serde_json::from_slice(&message[22..]).unwrap();
+        assert_eq!(decoded.seq(), 7);
+    }
+
+    #[test]
+    fn test_ros2_tf_layout() {
+        let mut mcap = McapWriter::ros2(vec![]).unwrap();
+        mcap.write_frame(&frame(), 0).unwrap();
+        mcap.write_frame(&TrackedObjects::heartbeat(8, 0), 0)
+            .unwrap();
+        let bytes = mcap.finish().unwrap();
+        let records = records(&bytes);
+        let messages: Vec<&[u8]> = records
+            .iter()
+            .filter(|(opcode, _)| *opcode == OP_MESSAGE)
+            .map(|(_, content)| *content)
+            .collect();
+        assert_eq!(messages.len(), 1);
+        let cdr = &messages[0][22..];
+        assert_eq!(&cdr[..4], &CDR_HEADER);
+        // one transform, stamped 1.5 s
+        assert_eq!(u32::from_le_bytes(cdr[4..8].try_into().unwrap()), 1);
+        assert_eq!(i32::from_le_bytes(cdr[8..12].try_into().unwrap()), 1);
+        assert_eq!(
+            u32::from_le_bytes(cdr[12..16].try_into().unwrap()),
+            500_000_000
+        );
+        assert_eq!(&cdr[20..27], b"openvr\0");
+        // the child frame length is padded to offset 24 after the encapsulation header
+        assert_eq!(&cdr[32..38], b"LHR-1\0");
+        // translation padded to offset 40, then seven doubles, identity rotation ends with w = 1
+        assert_eq!(cdr.len(), 4 + 40 + 7 * 8);
+        assert_eq!(
+            f64::from_le_bytes(cdr[cdr.len() - 8..].try_into().unwrap()),
+            1.0
+        );
+
+        // with a covariance every frame adds a pose on the device's own topic
+        let mut frame = frame();
+        frame.trackers_mut()[0].set_covariance(Some(na::Matrix6::identity()));
+        let mut mcap = McapWriter::ros2(vec![]).unwrap();
+        mcap.write_frame(&frame, 0).unwrap();
+        mcap.write_frame(&frame, 0).unwrap();
+        let bytes = mcap.finish().unwrap();
+        let records = self::records(&bytes);
+        let channels: Vec<&[u8]> = records
+            .iter()
+            .filter(|(opcode, _)| *opcode == OP_CHANNEL)
+            .map(|(_, content)| *content)
+            .collect();
+        assert_eq!(channels.len(), 2);
+        assert_eq!(&channels[1][8..32], b"/openvr_tracker/pose/LHR");
+        let poses: Vec<&[u8]> = records
+            .iter()
+            .filter(|(opcode, content)| *opcode == OP_MESSAGE && content[0] == 4)
+            .map(|(_, content)| *content)
+            .collect();
+        assert_eq!(poses.len(), 2);
+        // header with "openvr", pose and 36 covariance values
+        assert_eq!(poses[0][22..].len(), 4 + 20 + 4 + 7 * 8 + 36 * 8);
+    }
+}
@@ -1,9 +1,23 @@
-use anyhow::Result;
+use crate::authentication::{self, Authenticator};
+use crate::compact::{self, CompactConfig};
+use crate::config::ClassRoute;
+use crate::encryption::{self, Cipher};
+use crate::error::{Error, Result};
+use crate::fragment::{self, Reassembler, Splitter};
+use crate::impairment::Impairment;
+use crate::json_format::JsonFormat;
+use crate::reliable::{Ack, ReliableConfig, Retransmitter};
+use crate::sink::PoseSink;
+use crate::socket_options::SocketOptions;
+use crate::tracking_messages::{Channel, TrackedObjects};
+use crate::traffic::Traffic;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::net::SocketAddrV4;
+use std::io::{self, ErrorKind};
 use std::net::UdpSocket;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
 
-fn bind_multicast(addr: &SocketAddrV4, multi_addr: &SocketAddrV4) -> Result<UdpSocket> {
+fn bind_multicast(addr: &SocketAddrV4, multi_addr: &SocketAddrV4) -> io::Result<UdpSocket> {
     // this code was inspired by https://github.com/henninglive/tokio-udp-multicast-chat
     assert!(multi_addr.ip().is_multicast(), "Address must be multicast");
     let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
@@ -16,25 +30,658 @@ fn bind_multicast(addr: &SocketAddrV4, multi_addr: &SocketAddrV4) -> Result<UdpS
 }
 
 const ALL_INTERFACES: [u8; 4] = [0, 0, 0, 0];
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Join multicast group on all interfaces
+///
+/// Returned socket is non-blocking
+pub(crate) fn join_multicast_group(multicast_address: SocketAddrV4) -> io::Result<UdpSocket> {
+    let addr = SocketAddrV4::new(ALL_INTERFACES.into(), multicast_address.port());
+    bind_multicast(&addr, &multicast_address)
+}
 
 pub struct MessageSender {
     socket: UdpSocket,
     multicast_address: SocketAddrV4,
+    /// Group for event channel messages, same as poses if not set
+    event_address: Option<SocketAddrV4>,
+    class_routes: Vec<ClassRoute>,
+    /// Frame with only the devices of one route
+    routed: TrackedObjects,
+    /// Serialization buffer reused between frames
+    buffer: Vec<u8>,
+    format: JsonFormat,
+    /// Binary encoding for pose frames, JSON if not set
+    compact: Option<CompactConfig>,
+    impairment: Option<Impairment>,
+    authentication: Option<Authenticator>,
+    encryption: Option<Cipher>,
+    /// Encrypted packet, swapped with the serialization buffer
+    sealed: Vec<u8>,
+    /// Payloads that don't fit are split into parts when set
+    max_packet_bytes: Option<usize>,
+    splitter: Splitter,
+    /// Retries of event messages receivers have to acknowledge
+    reliable: Option<Reliable>,
+    traffic: Traffic,
+}
+
+/// Socket acknowledged messages are sent from, so acks come back to it alone
+struct Reliable {
+    socket: UdpSocket,
+    retransmitter: Retransmitter,
+    /// Packets of the message being sent, kept for retries
+    captured: Option<Vec<Vec<u8>>>,
+    buffer: Vec<u8>,
+    plaintext: Vec<u8>,
 }
 
 impl MessageSender {
     pub fn new(multicast_address: SocketAddrV4) -> Result<Self> {
         let addr = SocketAddrV4::new(ALL_INTERFACES.into(), multicast_address.port());
-        let socket = bind_multicast(&addr, &multicast_address)?;
+        Self::with_socket(
+            bind_multicast(&addr, &multicast_address).map_err(Error::Network)?,
+            multicast_address,
+        )
+    }
+
+    /// Send from `local` instead of whichever address the OS picks
+    ///
+    /// Multicast also leaves through the interface that owns `local`.
+    pub fn bind(multicast_address: SocketAddrV4, local: Ipv4Addr) -> Result<Self> {
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))
+            .map_err(Error::Network)?;
+        socket
+            .bind(&socket2::SockAddr::from(SocketAddrV4::new(local, 0)))
+            .map_err(Error::Network)?;
+        socket.set_multicast_if_v4(&local).map_err(Error::Network)?;
+        socket.set_multicast_loop_v4(true).map_err(Error::Network)?;
+        Self::with_socket(socket.into_udp_socket(), multicast_address)
+    }
+
+    fn with_socket(socket: UdpSocket, multicast_address: SocketAddrV4) -> Result<Self> {
         Ok(Self {
             socket,
             multicast_address,
+            event_address: None,
+            class_routes: vec![],
+            routed: TrackedObjects::new(0, 0, vec![]),
+            buffer: Vec::with_capacity(MAX_DATAGRAM_SIZE),
+            format: JsonFormat::default(),
+            compact: None,
+            impairment: None,
+            authentication: None,
+            encryption: None,
+            sealed: Vec::with_capacity(MAX_DATAGRAM_SIZE),
+            max_packet_bytes: None,
+            splitter: Splitter::new(),
+            reliable: None,
+            traffic: Traffic::new(),
         })
     }
 
-    pub fn send(&self, message: &str) -> Result<()> {
+    pub fn set_format(&mut self, format: JsonFormat) {
+        self.format = format;
+    }
+
+    /// Apply `options` to the socket multicast is sent from
+    pub fn set_socket_options(&self, options: &SocketOptions) -> Result<()> {
+        options.apply(&self.socket)
+    }
+
+    /// Send pose frames in the compact binary encoding. Event channel messages stay JSON
+    pub fn set_compact(&mut self, compact: Option<CompactConfig>) {
+        self.compact = compact;
+    }
+
+    /// Whether sent packets are also delivered to receivers on this host
+    ///
+    /// On by default. Turn off when this host runs a receiver for a different sender on the same group.
+    pub fn set_multicast_loop(&mut self, enabled: bool) -> Result<()> {
         self.socket
-            .send_to(message.as_bytes(), self.multicast_address)?;
+            .set_multicast_loop_v4(enabled)
+            .map_err(Error::Network)?;
         Ok(())
     }
+
+    /// Send event channel messages to a separate group or port
+    pub fn set_event_address(&mut self, event_address: Option<SocketAddrV4>) {
+        self.event_address = event_address;
+    }
+
+    /// Send devices of some classes to other groups
+    pub fn set_class_routes(&mut self, class_routes: Vec<ClassRoute>) {
+        self.class_routes = class_routes;
+    }
+
+    /// Encrypt every packet, receivers need the same key
+    pub fn set_encryption(&mut self, encryption: Option<Cipher>) {
+        self.encryption = encryption;
+    }
+
+    /// Append an HMAC to every packet, receivers need the same key
+    pub fn set_authentication(&mut self, authentication: Option<Authenticator>) {
+        self.authentication = authentication;
+    }
+
+    /// Drop, delay or reorder published frames to simulate a bad network
+    pub fn set_impairment(&mut self, impairment: Option<Impairment>) {
+        self.impairment = impairment;
+    }
+
+    /// Split payloads into parts so no packet is larger than `max_packet_bytes`
+    pub fn set_max_packet_bytes(&mut self, max_packet_bytes: Option<usize>) {
+        self.max_packet_bytes = max_packet_bytes;
+    }
+
+    /// Ask receivers to acknowledge critical event messages and send them again until they do
+    pub fn set_reliable(&mut self, config: Option<ReliableConfig>) -> Result<()> {
+        let config = match config {
+            Some(config) => config,
+            None => {
+                self.reliable = None;
+                return Ok(());
+            }
+        };
+        let local = match self.socket.local_addr()? {
+            SocketAddr::V4(local) => *local.ip(),
+            SocketAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))
+            .map_err(Error::Network)?;
+        socket
+            .bind(&socket2::SockAddr::from(SocketAddrV4::new(local, 0)))
+            .map_err(Error::Network)?;
+        if !local.is_unspecified() {
+            socket.set_multicast_if_v4(&local).map_err(Error::Network)?;
+        }
+        socket.set_multicast_loop_v4(true).map_err(Error::Network)?;
+        socket.set_nonblocking(true).map_err(Error::Network)?;
+        self.reliable = Some(Reliable {
+            socket: socket.into_udp_socket(),
+            retransmitter: Retransmitter::new(config),
+            captured: None,
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+            plaintext: vec![],
+        });
+        Ok(())
+    }
+
+    /// Send `frame` from the reliable socket with `ack_requested` set and keep its packets
+    fn send_reliable(&mut self, frame: &TrackedObjects, address: SocketAddrV4) -> Result<()> {
+        let mut marked = frame.clone();
+        marked.set_ack_requested(true);
+        if let Some(reliable) = self.reliable.as_mut() {
+            reliable.captured = Some(vec![]);
+        }
+        let result = self.send_frame(&marked, address);
+        if let Some(reliable) = self.reliable.as_mut() {
+            let packets = reliable.captured.take().unwrap_or_default();
+            reliable
+                .retransmitter
+                .sent(frame.seq(), packets, address, Instant::now());
+        }
+        result
+    }
+
+    /// Take the acknowledgements that arrived and send again what is due
+    fn poll_reliable(&mut self) -> Result<()> {
+        let reliable = match self.reliable.as_mut() {
+            Some(reliable) => reliable,
+            None => return Ok(()),
+        };
+        loop {
+            let len = match reliable.socket.recv(&mut reliable.buffer) {
+                Ok(len) => len,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                // an ICMP error for an earlier packet, nothing to read
+                Err(error) if error.kind() == ErrorKind::ConnectionRefused => continue,
+                Err(error) => return Err(error.into()),
+            };
+            let opened = unprotect(
+                &reliable.buffer[..len],
+                &mut reliable.plaintext,
+                self.authentication.as_mut(),
+                self.encryption.as_ref(),
+            );
+            if let Some(ack) = opened.and_then(|_| Ack::decode(&reliable.plaintext)) {
+                reliable.retransmitter.acknowledge(&ack);
+            }
+        }
+        for (mut packet, address) in reliable.retransmitter.due(Instant::now()) {
+            protect(
+                &mut packet,
+                &mut self.sealed,
+                self.authentication.as_mut(),
+                self.encryption.as_mut(),
+            );
+            self.traffic.record(
+                reliable
+                    .socket
+                    .send_to(&packet, address)
+                    .map_err(Error::Network)?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Send each class route its part of the frame, see [`route_frame`]
+    fn publish_routed(
+        &mut self,
+        frame: &TrackedObjects,
+        routed: &mut TrackedObjects,
+    ) -> Result<()> {
+        let routes = std::mem::take(&mut self.class_routes);
+        let main = self.multicast_address;
+        let result = route_frame(frame, &routes, main, routed, |part, address| {
+            self.send_frame(part, address)
+        });
+        self.class_routes = routes;
+        result
+    }
+
+    fn send_frame(&mut self, frame: &TrackedObjects, address: SocketAddrV4) -> Result<()> {
+        self.buffer.clear();
+        match &self.compact {
+            Some(compact) if frame.channel() == Channel::Pose => {
+                compact::encode(frame, compact, &mut self.buffer)?
+            }
+            _ => self.format.write(frame, &mut self.buffer)?,
+        }
+        let part_bytes = part_bytes(
+            self.max_packet_bytes,
+            self.authentication.is_some(),
+            self.encryption.is_some(),
+        );
+        match part_bytes {
+            Some(part_bytes) if self.buffer.len() > part_bytes + fragment::HEADER_SIZE => {
+                for part in self.splitter.split(&self.buffer, part_bytes)? {
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(&part);
+                    self.transmit(address)?;
+                }
+                Ok(())
+            }
+            _ => self.transmit(address),
+        }
+    }
+
+    /// Protect the payload in the buffer and send it as one packet
+    fn transmit(&mut self, address: SocketAddrV4) -> Result<()> {
+        // kept unprotected, retransmissions are signed again so they aren't taken for replays
+        if let Some(Reliable {
+            captured: Some(packets),
+            ..
+        }) = self.reliable.as_mut()
+        {
+            packets.push(self.buffer.clone());
+        }
+        protect(
+            &mut self.buffer,
+            &mut self.sealed,
+            self.authentication.as_mut(),
+            self.encryption.as_mut(),
+        );
+        // packets of acknowledged messages leave from the socket acks come back to
+        let socket = match &self.reliable {
+            Some(reliable) if reliable.captured.is_some() => &reliable.socket,
+            _ => &self.socket,
+        };
+        match &mut self.impairment {
+            Some(impairment) => {
+                let traffic = &self.traffic;
+                impairment.process(&self.buffer, Instant::now(), |packet| {
+                    traffic.record(socket.send_to(packet, address).map_err(Error::Network)?);
+                    Ok(())
+                })
+            }
+            None => {
+                self.traffic.record(
+                    socket
+                        .send_to(&self.buffer, address)
+                        .map_err(Error::Network)?,
+                );
+                Ok(())
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &str) -> Result<()> {
+        self.send_bytes(message.as_bytes())
+    }
+
+    pub fn send_bytes(&mut self, message: &[u8]) -> Result<()> {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(message);
+        protect(
+            &mut self.buffer,
+            &mut self.sealed,
+            self.authentication.as_mut(),
+            self.encryption.as_mut(),
+        );
+        self.socket
+            .send_to(&self.buffer, self.multicast_address)
+            .map_err(Error::Network)?;
+        Ok(())
+    }
+}
+
+/// Payload bytes per part of a split payload, `None` if payloads aren't split
+///
+/// Leaves room for the part header and what signing and encryption add.
+pub(crate) fn part_bytes(
+    max_packet_bytes: Option<usize>,
+    authenticated: bool,
+    encrypted: bool,
+) -> Option<usize> {
+    let mut overhead = fragment::HEADER_SIZE;
+    if authenticated {
+        overhead += authentication::OVERHEAD;
+    }
+    if encrypted {
+        overhead += encryption::OVERHEAD;
+    }
+    Some(max_packet_bytes?.saturating_sub(overhead).max(1))
+}
+
+/// Decrypt and verify `packet` into `plaintext`, `None` if it was rejected
+pub(crate) fn unprotect(
+    packet: &[u8],
+    plaintext: &mut Vec<u8>,
+    authentication: Option<&mut Authenticator>,
+    encryption: Option<&Cipher>,
+) -> Option<()> {
+    plaintext.clear();
+    match encryption {
+        Some(cipher) => cipher.open(packet, plaintext).ok()?,
+        None => plaintext.extend_from_slice(packet),
+    }
+    if let Some(authenticator) = authentication {
+        let len = authenticator.verify(plaintext).ok()?.len();
+        plaintext.truncate(len);
+    }
+    Some(())
+}
+
+/// Sign and then encrypt the payload in `buffer`, using `sealed` as scratch space
+pub(crate) fn protect(
+    buffer: &mut Vec<u8>,
+    sealed: &mut Vec<u8>,
+    authentication: Option<&mut Authenticator>,
+    encryption: Option<&mut Cipher>,
+) {
+    if let Some(authenticator) = authentication {
+        authenticator.sign(buffer);
+    }
+    if let Some(cipher) = encryption {
+        cipher.seal(buffer, sealed);
+        std::mem::swap(buffer, sealed);
+    }
+}
+
+/// Split frame by class route, handing each group its part
+///
+/// Groups without devices in this frame still get it empty, so their
+/// receivers see every sequence number and partial frames keep their keyframe.
+/// Devices no route takes go to `main`.
+fn route_frame(
+    frame: &TrackedObjects,
+    routes: &[ClassRoute],
+    main: SocketAddrV4,
+    routed: &mut TrackedObjects,
+    mut send: impl FnMut(&TrackedObjects, SocketAddrV4) -> Result<()>,
+) -> Result<()> {
+    for route in routes {
+        frame.filter_into(routed, |device| route.classes.contains(&device.class()));
+        send(routed, route.address)?;
+    }
+    frame.filter_into(routed, |device| {
+        !routes
+            .iter()
+            .any(|route| route.classes.contains(&device.class()))
+    });
+    send(routed, main)
+}
+
+impl PoseSink for MessageSender {
+    fn name(&self) -> &str {
+        "multicast"
+    }
+
+    fn traffic(&self) -> Option<&Traffic> {
+        Some(&self.traffic)
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        self.poll_reliable()?;
+        if frame.channel() == Channel::Event {
+            let address = self.event_address.unwrap_or(self.multicast_address);
+            let requires_ack = matches!(&self.reliable, Some(reliable) if reliable.retransmitter.config().requires_ack(frame));
+            if requires_ack {
+                return self.send_reliable(frame, address);
+            }
+            return self.send_frame(frame, address);
+        }
+        if self.class_routes.is_empty() {
+            return self.send_frame(frame, self.multicast_address);
+        }
+        let mut routed = std::mem::replace(&mut self.routed, TrackedObjects::new(0, 0, vec![]));
+        let result = self.publish_routed(frame, &mut routed);
+        self.routed = routed;
+        result
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(impairment) = &self.impairment {
+            eprintln!("Network impairment: {}", impairment.summary());
+        }
+        if let Some(reliable) = &self.reliable {
+            let retransmitter = &reliable.retransmitter;
+            if retransmitter.failed() > 0 || retransmitter.pending() > 0 {
+                eprintln!(
+                    "Reliable events: {} not acknowledged, {} still waiting",
+                    retransmitter.failed(),
+                    retransmitter.pending()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct MessageReceiver {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+    encryption: Option<Cipher>,
+    authentication: Option<Authenticator>,
+    /// Decrypted payload of the last packet
+    plaintext: Vec<u8>,
+    reassembler: Reassembler,
+    rejected: u64,
+    /// Where the last packet came from, acknowledgements go there
+    source: Option<SocketAddr>,
+}
+
+impl MessageReceiver {
+    pub fn new(multicast_address: SocketAddrV4) -> io::Result<Self> {
+        let socket = join_multicast_group(multicast_address)?;
+        socket.set_nonblocking(false)?;
+        Ok(Self {
+            socket,
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+            encryption: None,
+            authentication: None,
+            plaintext: Vec::with_capacity(MAX_DATAGRAM_SIZE),
+            reassembler: Reassembler::new(),
+            rejected: 0,
+            source: None,
+        })
+    }
+
+    /// Only accept packets encrypted with this key, others are dropped
+    pub fn set_encryption(&mut self, encryption: Option<Cipher>) {
+        self.encryption = encryption;
+    }
+
+    /// Only accept packets signed with this key, others are dropped
+    pub fn set_authentication(&mut self, authentication: Option<Authenticator>) {
+        self.authentication = authentication;
+    }
+
+    /// Packets dropped because they weren't encrypted or signed with the key
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Split messages dropped because some of their parts never arrived
+    pub fn incomplete(&self) -> u64 {
+        self.reassembler.incomplete()
+    }
+
+    /// Block until a datagram arrives
+    pub fn recv(&mut self) -> io::Result<&[u8]> {
+        self.socket.set_read_timeout(None)?;
+        loop {
+            let (len, source) = self.socket.recv_from(&mut self.buffer)?;
+            self.source = Some(source);
+            if self.accept(len) && self.join(source) {
+                break;
+            }
+        }
+        Ok(self.payload())
+    }
+
+    /// Block until a datagram arrives or the timeout runs out
+    ///
+    /// Returns `None` on timeout, when interrupted by a signal, when a packet was rejected
+    /// or when it was a part of a message that isn't complete yet
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<&[u8]>> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        let received = self
+            .socket
+            .recv_from(&mut self.buffer)
+            .map(|(len, source)| {
+                self.source = Some(source);
+                (len, source)
+            });
+        match received {
+            Ok((len, source)) if self.accept(len) && self.join(source) => Ok(Some(self.payload())),
+            Ok(_) => Ok(None),
+            Err(error)
+                if error.kind() == ErrorKind::WouldBlock
+                    || error.kind() == ErrorKind::TimedOut
+                    || error.kind() == ErrorKind::Interrupted =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Acknowledge message `seq` as `receiver` to the sender of the last packet
+    ///
+    /// For messages with `ack_requested`, signed and encrypted like the stream.
+    pub fn acknowledge(&mut self, seq: u64, receiver: &str) -> io::Result<()> {
+        let source = match self.source {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        let mut packet = Ack {
+            seq,
+            receiver: receiver.to_owned(),
+        }
+        .encode();
+        let mut sealed = vec![];
+        protect(
+            &mut packet,
+            &mut sealed,
+            self.authentication.as_mut(),
+            self.encryption.as_mut(),
+        );
+        self.socket.send_to(&packet, source)?;
+        Ok(())
+    }
+
+    /// Decrypt and verify the packet of `len` bytes in the buffer, false if it was rejected
+    fn accept(&mut self, len: usize) -> bool {
+        let verified = unprotect(
+            &self.buffer[..len],
+            &mut self.plaintext,
+            self.authentication.as_mut(),
+            self.encryption.as_ref(),
+        )
+        .is_some();
+        if !verified {
+            self.rejected += 1;
+        }
+        verified
+    }
+
+    /// Collect an accepted part sent from `source`, true when the payload is a whole message
+    fn join(&mut self, source: SocketAddr) -> bool {
+        if !fragment::is_part(&self.plaintext) {
+            return true;
+        }
+        match self.reassembler.add(source, &self.plaintext) {
+            Some(payload) => {
+                self.plaintext = payload;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.plaintext
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::StreamStats;
+    use crate::fixtures::device;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_routed_group_has_no_gaps() {
+        let main: SocketAddrV4 = "239.0.0.22:7392".parse().unwrap();
+        let trackers: SocketAddrV4 = "239.0.0.23:7392".parse().unwrap();
+        let routes = vec![ClassRoute {
+            classes: vec![VrDeviceClass::Tracker],
+            address: trackers,
+        }];
+        let mut routed = TrackedObjects::new(0, 0, vec![]);
+        let mut sent = vec![];
+
+        for seq in 0..10 {
+            // the tracker is only in every other frame
+            let mut devices = vec![device(0).class(VrDeviceClass::HMD).build()];
+            if seq % 2 == 0 {
+                devices.push(device(1).class(VrDeviceClass::Tracker).build());
+            }
+            let frame = TrackedObjects::new(seq, 0, devices);
+            route_frame(&frame, &routes, main, &mut routed, |part, address| {
+                sent.push((address, part.clone()));
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        for (address, class) in [
+            (main, VrDeviceClass::HMD),
+            (trackers, VrDeviceClass::Tracker),
+        ] {
+            let mut stats = StreamStats::new();
+            for (_, frame) in sent.iter().filter(|(to, _)| *to == address) {
+                assert!(frame
+                    .trackers()
+                    .iter()
+                    .all(|device| device.class() == class));
+                stats.record(frame);
+            }
+            assert_eq!(stats.received(), 10);
+            assert_eq!(stats.lost(), 0);
+        }
+    }
 }
@@ -1,53 +1,580 @@
+use crate::backend::{ControllerButton, DeviceSample, TrackingBackend};
+use crate::device_ids::DeviceIdMap;
+use crate::error::Result;
+use crate::input::{ActionInput, InputConfig};
+use crate::simulation::SimulatedBackend;
+use crate::tracked_camera::{CameraStream, TrackedCamera};
 use crate::tracking_messages::*;
-use anyhow::Result;
 use nalgebra as na;
-use std::{collections::HashMap, usize};
+use std::time::{Duration, Instant};
+use std::usize;
 
-pub struct VrDeviceManager {
-    devices: HashMap<usize, VrDevice>,
+/// Battery state changes slowly, no need to query it every frame
+const BATTERY_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest pulse OpenVR accepts
+const HAPTIC_PULSE_US: u16 = 3999;
+
+const RUNTIME_RETRY_MIN: Duration = Duration::from_secs(1);
+const RUNTIME_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Device index of the headset camera, right after the OpenVR device slots
+pub const CAMERA_INDEX: usize = openvr::MAX_TRACKED_DEVICE_COUNT;
+
+/// Device index of the first virtual device, after the camera
+pub const VIRTUAL_INDEX: usize = CAMERA_INDEX + 1;
+
+pub struct VrDeviceManager<B: TrackingBackend = OpenVrBackend> {
+    /// Indexed by OpenVR device index, ids differ from it with `device_ids`
+    devices: Vec<VrDevice>,
+    samples: Vec<DeviceSample>,
+    backend: B,
+    last_battery_refresh: Option<Instant>,
+    /// Frames the runtime counted at the latest sample
+    frame_count: Option<u64>,
+    /// Stable ids by serial, the OpenVR index is the id if not set
+    device_ids: Option<DeviceIdMap>,
+    /// Scripted devices merged into the real ones from [`VIRTUAL_INDEX`] on
+    virtual_devices: Option<SimulatedBackend>,
+    virtual_samples: Vec<DeviceSample>,
+    /// Universes every device is also posed in
+    universes: Vec<Universe>,
+    universe_poses: Vec<[[f32; 4]; 3]>,
+}
+
+impl VrDeviceManager<OpenVrBackend> {
+    pub fn new() -> Result<Self> {
+        Self::connect(false)
+    }
+
+    /// Connect, as the scene application paced by the compositor if `compositor_sync` is set
+    pub fn connect(compositor_sync: bool) -> Result<Self> {
+        let backend = if compositor_sync {
+            OpenVrBackend::synced()?
+        } else {
+            OpenVrBackend::new()?
+        };
+        Ok(Self::with_backend(backend))
+    }
+
+    /// Block until SteamVR is running, then connect
+    ///
+    /// Checks for the runtime with exponential backoff between attempts.
+    pub fn wait_for_runtime(compositor_sync: bool) -> Result<Self> {
+        let mut backoff = Backoff::new(RUNTIME_RETRY_MIN, RUNTIME_RETRY_MAX);
+        while !runtime_running() {
+            let delay = backoff.next_delay();
+            eprintln!(
+                "SteamVR is not running, retrying in {}s",
+                delay.as_secs_f32()
+            );
+            std::thread::sleep(delay);
+        }
+        Self::connect(compositor_sync)
+    }
+}
+
+fn tracking_state(pose: &openvr::TrackedDevicePose) -> TrackingState {
+    if !pose.device_is_connected() {
+        return TrackingState::Disconnected;
+    }
+    // read the raw value, `tracking_result` panics on results newer than the bindings
+    // safe because TrackedDevicePose is a repr(C) wrapper around the raw struct
+    let raw: openvr_sys::TrackedDevicePose_t = unsafe { std::mem::transmute(*pose) };
+    match raw.eTrackingResult {
+        openvr_sys::ETrackingResult_TrackingResult_Running_OK => TrackingState::Ok,
+        openvr_sys::ETrackingResult_TrackingResult_Running_OutOfRange
+        | openvr_sys::ETrackingResult_TrackingResult_Calibrating_OutOfRange => {
+            TrackingState::OutOfRange
+        }
+        openvr_sys::ETrackingResult_TrackingResult_Calibrating_InProgress => {
+            TrackingState::Calibrating
+        }
+        openvr_sys::ETrackingResult_TrackingResult_Fallback_RotationOnly => {
+            TrackingState::RotationOnly
+        }
+        _ => TrackingState::Uninitialized,
+    }
+}
+
+/// Whether a running SteamVR accepts connections
+///
+/// Connects as a background application, which never launches SteamVR, and disconnects again.
+/// `openvr::init` can't be retried after it failed once, so this is checked before calling it.
+pub fn runtime_running() -> bool {
+    let mut error = openvr_sys::EVRInitError_VRInitError_None;
+    unsafe {
+        openvr_sys::VR_InitInternal(
+            &mut error,
+            openvr_sys::EVRApplicationType_VRApplication_Background,
+        );
+        if error != openvr_sys::EVRInitError_VRInitError_None {
+            return false;
+        }
+        openvr_sys::VR_ShutdownInternal();
+    }
+    true
+}
+
+/// Doubling delay between retries
+struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Self {
+        Self { next: min, max }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+}
+
+impl<B: TrackingBackend> VrDeviceManager<B> {
+    pub fn with_backend(backend: B) -> Self {
+        Self {
+            devices: Vec::with_capacity(openvr::MAX_TRACKED_DEVICE_COUNT),
+            samples: Vec::with_capacity(openvr::MAX_TRACKED_DEVICE_COUNT),
+            backend,
+            last_battery_refresh: None,
+            frame_count: None,
+            device_ids: None,
+            virtual_devices: None,
+            virtual_samples: vec![],
+            universes: vec![],
+            universe_poses: vec![],
+        }
+    }
+
+    /// Also pose every OpenVR device in these universes
+    pub fn set_universes(&mut self, universes: Vec<Universe>) {
+        self.universes = universes;
+    }
+
+    pub fn universes(&self) -> &[Universe] {
+        &self.universes
+    }
+
+    /// Merge scripted devices into every sample
+    pub fn set_virtual_devices(&mut self, virtual_devices: Option<SimulatedBackend>) {
+        self.virtual_devices = virtual_devices;
+    }
+
+    pub fn take_virtual_devices(&mut self) -> Option<SimulatedBackend> {
+        self.virtual_devices.take()
+    }
+
+    pub fn set_device_ids(&mut self, device_ids: Option<DeviceIdMap>) {
+        self.device_ids = device_ids;
+    }
+
+    pub fn take_device_ids(&mut self) -> Option<DeviceIdMap> {
+        self.device_ids.take()
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn update(&mut self) {
+        self.backend.poll(&mut self.samples);
+        self.frame_count = self.backend.frame_count();
+        if let Some(virtual_devices) = self.virtual_devices.as_mut() {
+            virtual_devices.poll(&mut self.virtual_samples);
+            self.samples
+                .extend(self.virtual_samples.iter().map(|sample| DeviceSample {
+                    index: sample.index + VIRTUAL_INDEX,
+                    ..*sample
+                }));
+        }
+        let refresh_battery = match self.last_battery_refresh {
+            Some(last) => last.elapsed() >= BATTERY_REFRESH_INTERVAL,
+            None => true,
+        };
+        if refresh_battery {
+            self.last_battery_refresh = Some(Instant::now());
+        }
+        for sample in &self.samples {
+            while self.devices.len() <= sample.index {
+                self.devices.push(VrDevice::new(self.devices.len()));
+            }
+            let device_entry = &mut self.devices[sample.index];
+            let (backend, index): (&dyn TrackingBackend, usize) = match &self.virtual_devices {
+                Some(virtual_devices) if sample.index >= VIRTUAL_INDEX => {
+                    (virtual_devices, sample.index - VIRTUAL_INDEX)
+                }
+                _ => (&self.backend, sample.index),
+            };
+            if device_entry.serial().is_none() && sample.connected {
+                device_entry.set_serial(backend.serial(index).map(Into::into));
+                device_entry.set_dongle(backend.wireless_dongle(index).map(Into::into));
+                if sample.class == VrDeviceClass::Tracker {
+                    device_entry.set_role(backend.tracker_role(index));
+                }
+                let id = match (self.device_ids.as_mut(), device_entry.serial()) {
+                    (Some(device_ids), Some(serial)) => Some(device_ids.id(serial)),
+                    _ => None,
+                };
+                if let Some(id) = id {
+                    device_entry.set_id(id);
+                }
+            }
+            device_entry.set_state(sample.state);
+            if refresh_battery && sample.connected {
+                device_entry.set_battery(backend.battery(index));
+            }
+            device_entry.update(sample.pose_valid, &sample.pose, sample.class);
+            device_entry.set_velocity(
+                na::Vector3::from(sample.velocity),
+                na::Vector3::from(sample.angular_velocity),
+            );
+            device_entry.universes_mut().clear();
+        }
+        for &universe in &self.universes {
+            self.backend
+                .poll_universe(universe, &mut self.universe_poses);
+            // virtual devices only exist in the main universe
+            for sample in &self.samples {
+                match self.universe_poses.get(sample.index) {
+                    Some(pose) if sample.pose_valid => self.devices[sample.index]
+                        .universes_mut()
+                        .push(UniversePose {
+                            universe,
+                            position: pose.to_position(),
+                            rotation: pose.to_rotation(),
+                        }),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Devices ordered by OpenVR index, which is also their id unless device ids are set
+    pub fn devices(&self) -> &[VrDevice] {
+        &self.devices
+    }
+
+    pub fn device_list(&self) -> Vec<VrDevice> {
+        self.devices.clone()
+    }
+
+    /// The headset, if one has been seen
+    pub fn hmd(&self) -> Option<&VrDevice> {
+        self.devices_of_class(VrDeviceClass::HMD).next()
+    }
+
+    pub fn device_by_serial(&self, serial: &str) -> Option<&VrDevice> {
+        self.devices
+            .iter()
+            .find(|device| device.serial() == Some(serial))
+    }
+
+    /// Seen devices of exactly `class`, so `Controller` doesn't include controllers with a hand role
+    pub fn devices_of_class(&self, class: VrDeviceClass) -> impl Iterator<Item = &VrDevice> {
+        self.devices
+            .iter()
+            .filter(move |device| device.seen() && device.class() == class)
+    }
+
+    /// One haptic pulse on every connected controller
+    ///
+    /// OpenVR takes one pulse per controller per frame, call this every sample for a longer buzz.
+    pub fn buzz_controllers(&mut self) {
+        for sample in &self.samples {
+            // virtual controllers have nothing to buzz
+            if sample.class.is_controller() && sample.connected && sample.index < VIRTUAL_INDEX {
+                self.backend.haptic_pulse(sample.index);
+            }
+        }
+    }
+
+    /// First controller with its trigger pulled in the latest sample
+    pub fn pulled_trigger(&self) -> Option<&VrDevice> {
+        self.pressing(ControllerButton::Trigger)
+            .next()
+            .map(|sample| &self.devices[sample.index])
+    }
+
+    /// Frames the runtime counted at the latest sample, `None` if it doesn't count them
+    pub fn frame_count(&self) -> Option<u64> {
+        self.frame_count
+    }
+
+    /// Block until the compositor's next frame, false if sampling isn't paced by it
+    pub fn wait_for_frame(&mut self) -> bool {
+        self.backend.wait_for_frame()
+    }
+
+    /// Whether at least two controllers press `button` in the latest sample
+    pub fn chord_held(&self, button: ControllerButton) -> bool {
+        self.pressing(button).nth(1).is_some()
+    }
+
+    /// Connected controllers pressing `button`, virtual ones have no buttons
+    fn pressing(&self, button: ControllerButton) -> impl Iterator<Item = &DeviceSample> {
+        self.samples.iter().filter(move |sample| {
+            sample.class.is_controller()
+                && sample.connected
+                && sample.index < VIRTUAL_INDEX
+                && self.backend.button_pressed(sample.index, button)
+        })
+    }
+}
+
+pub struct OpenVrBackend {
     /// Context needs to be kept around for interop reasons
     /// Otherwise you get a segfault
     #[allow(dead_code)]
     context: openvr::Context,
     openvr_system: openvr::System,
+    chaperone: Option<openvr::Chaperone>,
+    camera: Option<TrackedCamera>,
+    /// Receives camera images when frames are streamed
+    camera_stream: Option<CameraStream>,
+    /// Paces sampling to the headset refresh when set
+    compositor: Option<openvr::Compositor>,
+    /// Buttons from input actions instead of the legacy controller state
+    input: Option<ActionInput>,
 }
 
-impl VrDeviceManager {
+impl OpenVrBackend {
     pub fn new() -> Result<Self> {
         let context = unsafe { openvr::init(openvr::ApplicationType::Other) }?;
         let openvr_system = context.system()?;
+        let chaperone = context.chaperone().ok();
         Ok(Self {
-            devices: HashMap::new(),
             context,
             openvr_system,
+            chaperone,
+            camera: None,
+            camera_stream: None,
+            compositor: None,
+            input: None,
         })
     }
 
-    pub fn update(&mut self) {
+    /// Connect as the scene application, so sampling can wait for compositor frames
+    ///
+    /// Only one scene application runs at a time, this can't be used next to a game.
+    pub fn synced() -> Result<Self> {
+        let context = unsafe { openvr::init(openvr::ApplicationType::Scene) }?;
+        let openvr_system = context.system()?;
+        let chaperone = context.chaperone().ok();
+        let compositor = context.compositor()?;
+        compositor.set_tracking_space(openvr::TrackingUniverseOrigin::Standing);
+        Ok(Self {
+            context,
+            openvr_system,
+            chaperone,
+            camera: None,
+            camera_stream: None,
+            compositor: Some(compositor),
+            input: None,
+        })
+    }
+
+    pub fn system(&self) -> &openvr::System {
+        &self.openvr_system
+    }
+
+    /// Publish the headset camera pose, and its images to `stream` if set
+    pub fn enable_camera(&mut self, stream: Option<CameraStream>) -> Result<()> {
+        self.camera = Some(TrackedCamera::open(openvr::tracked_device_index::HMD)?);
+        self.camera_stream = stream;
+        Ok(())
+    }
+
+    /// Read buttons through the input actions of `config`
+    pub fn enable_input(&mut self, config: &InputConfig) -> Result<()> {
+        self.input = Some(ActionInput::open(config)?);
+        Ok(())
+    }
+}
+
+impl TrackingBackend for OpenVrBackend {
+    fn poll(&mut self, samples: &mut Vec<DeviceSample>) {
+        if let Some(input) = self.input.as_mut() {
+            input.update();
+        }
         let poses = self
             .openvr_system
             .device_to_absolute_tracking_pose(openvr::TrackingUniverseOrigin::Standing, 0.0);
-        for (index, pose) in poses.iter().enumerate() {
-            let device_entry = self
-                .devices
-                .entry(index)
-                .or_insert_with(|| VrDevice::new(index));
-            let tracked = pose.pose_is_valid();
+        samples.clear();
+        samples.extend(poses.iter().enumerate().map(|(index, pose)| {
             let device_class = self.openvr_system.tracked_device_class(index as u32);
             let controller_class = self
                 .openvr_system
                 .get_controller_role_for_tracked_device_index(index as u32);
-            let class = VrDeviceClass::from_openvr_types(device_class, controller_class);
-            let pose = pose.device_to_absolute_tracking();
-            device_entry.update(tracked, pose, class);
+            DeviceSample {
+                index,
+                connected: pose.device_is_connected(),
+                pose_valid: pose.pose_is_valid(),
+                pose: *pose.device_to_absolute_tracking(),
+                class: VrDeviceClass::from_openvr_types(device_class, controller_class),
+                state: tracking_state(pose),
+                velocity: *pose.velocity(),
+                angular_velocity: *pose.angular_velocity(),
+            }
+        }));
+        let camera_pose = match self.camera.as_mut() {
+            Some(camera) => camera.poll(self.camera_stream.as_ref()),
+            None => None,
+        };
+        if let Some(pose) = camera_pose {
+            samples.push(DeviceSample {
+                index: CAMERA_INDEX,
+                connected: pose.device_is_connected(),
+                pose_valid: pose.pose_is_valid(),
+                pose: *pose.device_to_absolute_tracking(),
+                class: VrDeviceClass::Camera,
+                state: tracking_state(&pose),
+                velocity: *pose.velocity(),
+                angular_velocity: *pose.angular_velocity(),
+            });
         }
     }
 
-    pub fn device_list(&self) -> Vec<VrDevice> {
-        // super inefficient. But do we really care? It's only 64 elements
-        let mut devices: Vec<_> = self.devices.values().cloned().collect();
-        devices.sort_by_key(|item| item.id());
-        devices
+    fn serial(&self, index: usize) -> Option<String> {
+        if index == CAMERA_INDEX {
+            let hmd = self.serial(openvr::tracked_device_index::HMD as usize)?;
+            return Some(format!("{}/camera", hmd));
+        }
+        self.openvr_system
+            .string_tracked_device_property(index as u32, openvr::property::SerialNumber_String)
+            .ok()
+            .map(|serial| serial.to_string_lossy().into_owned())
+    }
+
+    fn wireless_dongle(&self, index: usize) -> Option<String> {
+        let wireless = self
+            .openvr_system
+            .bool_tracked_device_property(index as u32, openvr::property::DeviceIsWireless_Bool)
+            .unwrap_or(false);
+        if !wireless {
+            return None;
+        }
+        let dongle = self
+            .openvr_system
+            .string_tracked_device_property(
+                index as u32,
+                openvr::property::ConnectedWirelessDongle_String,
+            )
+            .map(|dongle| dongle.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Some(dongle)
+    }
+
+    fn tracker_role(&self, index: usize) -> Option<DeviceRole> {
+        let controller_type = self
+            .openvr_system
+            .string_tracked_device_property(
+                index as u32,
+                openvr_sys::ETrackedDeviceProperty_Prop_ControllerType_String,
+            )
+            .ok()?;
+        DeviceRole::from_controller_type(&controller_type.to_string_lossy())
+    }
+
+    fn battery(&self, index: usize) -> Option<f32> {
+        let provides_battery = self
+            .openvr_system
+            .bool_tracked_device_property(
+                index as u32,
+                openvr::property::DeviceProvidesBatteryStatus_Bool,
+            )
+            .unwrap_or(false);
+        if !provides_battery {
+            return None;
+        }
+        self.openvr_system
+            .float_tracked_device_property(
+                index as u32,
+                openvr::property::DeviceBatteryPercentage_Float,
+            )
+            .ok()
+    }
+
+    fn button_pressed(&self, index: usize, button: ControllerButton) -> bool {
+        if let Some(input) = &self.input {
+            return input.pressed(index, button);
+        }
+        let id = match button {
+            ControllerButton::Trigger => openvr::button_id::AXIS1,
+            ControllerButton::Menu => openvr::button_id::APPLICATION_MENU,
+            ControllerButton::Grip => openvr::button_id::GRIP,
+            ControllerButton::Touchpad => openvr::button_id::AXIS0,
+        };
+        match self.openvr_system.controller_state(index as u32) {
+            Some(state) => state.button_pressed & (1 << id) != 0,
+            None => false,
+        }
+    }
+
+    fn poll_universe(&mut self, universe: Universe, poses: &mut Vec<[[f32; 4]; 3]>) {
+        let origin = match universe {
+            Universe::Standing => openvr::TrackingUniverseOrigin::Standing,
+            Universe::Seated => openvr::TrackingUniverseOrigin::Seated,
+            Universe::Raw => openvr::TrackingUniverseOrigin::RawAndUncalibrated,
+        };
+        let tracked = self
+            .openvr_system
+            .device_to_absolute_tracking_pose(origin, 0.0);
+        poses.clear();
+        poses.extend(
+            tracked
+                .iter()
+                .map(|pose| *pose.device_to_absolute_tracking()),
+        );
+    }
+
+    fn frame_count(&self) -> Option<u64> {
+        self.openvr_system
+            .time_since_last_vsync()
+            .map(|(_, frame_count)| frame_count)
+    }
+
+    fn wait_for_frame(&mut self) -> bool {
+        match &self.compositor {
+            Some(compositor) => compositor.wait_get_poses().is_ok(),
+            None => false,
+        }
+    }
+
+    fn haptic_pulse(&mut self, index: usize) {
+        self.openvr_system
+            .trigger_haptic_pulse(index as u32, 0, HAPTIC_PULSE_US);
+    }
+
+    fn play_area(&self) -> Option<[[f32; 3]; 4]> {
+        self.chaperone.as_ref()?.get_play_area_rect()
+    }
+
+    fn display(&self) -> Option<DisplayInfo> {
+        let hmd = openvr::tracked_device_index::HMD;
+        if !self.openvr_system.is_tracked_device_connected(hmd) {
+            return None;
+        }
+        let (render_width, render_height) = self.openvr_system.recommended_render_target_size();
+        Some(DisplayInfo {
+            ipd_m: self
+                .openvr_system
+                .float_tracked_device_property(hmd, openvr::property::UserIpdMeters_Float)
+                .ok(),
+            refresh_rate_hz: self
+                .openvr_system
+                .float_tracked_device_property(hmd, openvr::property::DisplayFrequency_Float)
+                .ok(),
+            render_width,
+            render_height,
+        })
     }
 }
 
@@ -98,4 +625,133 @@ mod tests {
         assert_eq!(position.y as i32, 3);
         assert_eq!(position.z as i32, 7);
     }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn test_manager_with_mock_backend() {
+        use crate::backend::{MockBackend, IDENTITY_POSE};
+
+        let mut backend = MockBackend::new();
+        backend.set_serial(1, "LHR-1");
+        let mut lost_tracking = DeviceSample::new(1, VrDeviceClass::Tracker, IDENTITY_POSE);
+        lost_tracking.pose_valid = false;
+        backend.push_frame(vec![
+            DeviceSample::new(1, VrDeviceClass::Tracker, IDENTITY_POSE),
+            DeviceSample::new(0, VrDeviceClass::HMD, IDENTITY_POSE),
+        ]);
+        backend.push_frame(vec![
+            lost_tracking,
+            DeviceSample::new(0, VrDeviceClass::HMD, IDENTITY_POSE),
+        ]);
+        let mut manager = VrDeviceManager::with_backend(backend);
+        assert!(manager.hmd().is_none());
+
+        manager.update();
+        let devices = manager.device_list();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].class(), VrDeviceClass::HMD);
+        assert_eq!(devices[1].serial(), Some("LHR-1"));
+        assert_eq!(manager.hmd().map(VrDevice::id), Some(0));
+        assert_eq!(manager.device_by_serial("LHR-1").map(VrDevice::id), Some(1));
+        assert_eq!(manager.devices_of_class(VrDeviceClass::Tracker).count(), 1);
+        assert_eq!(
+            manager.devices_of_class(VrDeviceClass::Controller).count(),
+            0
+        );
+
+        manager.update();
+        let devices = manager.device_list();
+        assert!(!devices[1].tracked());
+        assert!(devices[1].seen());
+
+        let mut backend = MockBackend::new();
+        backend.set_serial(0, "LHR-1");
+        backend.push_frame(vec![DeviceSample::new(
+            0,
+            VrDeviceClass::Tracker,
+            IDENTITY_POSE,
+        )]);
+        let mut device_ids = DeviceIdMap::new();
+        device_ids.id("LHR-0");
+        let mut manager = VrDeviceManager::with_backend(backend);
+        manager.set_device_ids(Some(device_ids));
+        manager.update();
+        assert_eq!(
+            manager.devices()[0].id(),
+            crate::device_ids::FIRST_MAPPED_ID + 1
+        );
+    }
+
+    #[test]
+    fn test_virtual_devices_join_real_ones() {
+        use crate::backend::{MockBackend, IDENTITY_POSE};
+        use crate::simulation::SimulationConfig;
+
+        let mut backend = MockBackend::new();
+        backend.set_serial(0, "HMD-1");
+        backend.push_frame(vec![DeviceSample::new(
+            0,
+            VrDeviceClass::HMD,
+            IDENTITY_POSE,
+        )]);
+        let simulation: SimulationConfig = serde_json::from_str(
+            r#"{"devices": [{"serial": "REF-1", "class": "Tracker",
+                "trajectory": {"type": "static", "position": [1, 2, 3]}}]}"#,
+        )
+        .unwrap();
+        let mut manager = VrDeviceManager::with_backend(backend);
+        manager.set_virtual_devices(Some(
+            SimulatedBackend::new(simulation, std::path::Path::new(".")).unwrap(),
+        ));
+        manager.update();
+        let seen: Vec<&VrDevice> = manager.devices().iter().filter(|d| d.seen()).collect();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].serial(), Some("HMD-1"));
+        assert_eq!(seen[1].id(), VIRTUAL_INDEX);
+        assert_eq!(seen[1].serial(), Some("REF-1"));
+        assert_eq!(seen[1].position(), &na::Point3::new(1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn test_poses_in_other_universes() {
+        use crate::backend::{MockBackend, IDENTITY_POSE};
+
+        let mut backend = MockBackend::new();
+        let mut lost = DeviceSample::new(1, VrDeviceClass::Tracker, IDENTITY_POSE);
+        lost.pose_valid = false;
+        backend.push_frame(vec![
+            DeviceSample::new(0, VrDeviceClass::HMD, IDENTITY_POSE),
+            lost,
+        ]);
+        let mut raw = IDENTITY_POSE;
+        raw[1][3] = -1.5;
+        backend.set_universe(Universe::Raw, vec![raw, raw]);
+        let mut manager = VrDeviceManager::with_backend(backend);
+        manager.set_universes(vec![Universe::Raw, Universe::Seated]);
+        manager.update();
+
+        // the mock has no seated poses, lost devices have no pose anywhere
+        let universes = manager.devices()[0].universes();
+        assert_eq!(universes.len(), 1);
+        assert_eq!(universes[0].universe, Universe::Raw);
+        assert_eq!(universes[0].position, na::Point3::new(0.0, -1.5, 0.0));
+        assert!(manager.devices()[1].universes().is_empty());
+    }
+
+    #[test]
+    fn test_controller_roles() {
+        let class = VrDeviceClass::from_openvr_types(
+            openvr::TrackedDeviceClass::Controller,
+            Some(openvr::TrackedControllerRole::LeftHand),
+        );
+        assert_eq!(class, VrDeviceClass::LeftController);
+        let class = VrDeviceClass::from_openvr_types(openvr::TrackedDeviceClass::Controller, None);
+        assert_eq!(class, VrDeviceClass::Controller);
+    }
 }
@@ -0,0 +1,272 @@
+//! Open Sound Control output
+//!
+//! Every frame goes out as one OSC bundle, timetagged with the sampling time,
+//! holding one message per device. Receivers get all devices of a frame in a
+//! single packet and can line them up by timetag.
+//!
+//! Device messages are addressed by a [topic template](crate::topic), so a
+//! receiver can route on the tracker it cares about. The bundle can also hold
+//! one message with the whole frame as JSON, for receivers that want it all.
+
+use crate::error::{Error, Result};
+use crate::sink::PoseSink;
+use crate::socket_options::SocketOptions;
+use crate::topic::TopicTemplate;
+use crate::tracking_messages::{TrackedObjects, VrDevice};
+use crate::traffic::Traffic;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch
+const NTP_UNIX_OFFSET_S: u64 = 2_208_988_800;
+
+/// Characters with a meaning in OSC address patterns
+const RESERVED: [char; 9] = [' ', '#', '*', ',', '?', '[', ']', '{', '}'];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OscConfig {
+    /// Address template of the pose message of every device, none are sent if not set
+    pub address: Option<String>,
+    /// Address of a message holding the whole frame as JSON, not sent if not set
+    pub frame_address: Option<String>,
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        Self {
+            address: Some("/openvr/{name}/pose".to_owned()),
+            frame_address: None,
+        }
+    }
+}
+
+impl OscConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.address.is_none() && self.frame_address.is_none() {
+            problems.push("osc has neither address nor frame_address, nothing is sent".to_owned());
+        }
+        if let Err(error) = OscAddresses::from_config(self) {
+            problems.push(error.to_string());
+        }
+        problems
+    }
+}
+
+/// Parsed addresses of the messages in a bundle
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscAddresses {
+    device: Option<TopicTemplate>,
+    frame: Option<String>,
+}
+
+impl Default for OscAddresses {
+    fn default() -> Self {
+        Self::from_config(&OscConfig::default()).unwrap()
+    }
+}
+
+impl OscAddresses {
+    pub fn from_config(config: &OscConfig) -> Result<Self> {
+        for address in config.address.iter().chain(&config.frame_address) {
+            if !address.starts_with('/') {
+                return Err(Error::config(format!(
+                    "OSC address {} has to start with /",
+                    address
+                )));
+            }
+        }
+        let device = match &config.address {
+            Some(address) => Some(address.parse().map_err(Error::config)?),
+            None => None,
+        };
+        Ok(Self {
+            device,
+            frame: config.frame_address.clone(),
+        })
+    }
+}
+
+pub struct OscSender {
+    socket: UdpSocket,
+    address: SocketAddr,
+    /// Bundle buffer reused between frames
+    buffer: Vec<u8>,
+    /// Message buffer reused between devices
+    message: Vec<u8>,
+    addresses: OscAddresses,
+    traffic: Traffic,
+}
+
+impl OscSender {
+    pub fn new(address: SocketAddr) -> Result<Self> {
+        let local: IpAddr = if address.is_ipv4() {
+            [0, 0, 0, 0].into()
+        } else {
+            [0u16; 8].into()
+        };
+        Self::bind(address, local)
+    }
+
+    /// Send from `local` instead of whichever address the OS picks
+    pub fn bind(address: SocketAddr, local: IpAddr) -> Result<Self> {
+        let socket = UdpSocket::bind((local, 0)).map_err(Error::Network)?;
+        Ok(Self {
+            socket,
+            address,
+            buffer: vec![],
+            message: vec![],
+            addresses: OscAddresses::default(),
+            traffic: Traffic::new(),
+        })
+    }
+
+    /// Address messages as configured instead of `/openvr/<name>/pose`
+    pub fn set_addresses(&mut self, config: &OscConfig) -> Result<()> {
+        self.addresses = OscAddresses::from_config(config)?;
+        Ok(())
+    }
+
+    /// Apply `options` to the socket bundles are sent from
+    pub fn set_socket_options(&self, options: &SocketOptions) -> Result<()> {
+        options.apply(&self.socket)
+    }
+}
+
+impl PoseSink for OscSender {
+    fn name(&self) -> &str {
+        "osc"
+    }
+
+    /// Heartbeats and event messages carry no devices and aren't sent
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        if frame.trackers().is_empty() {
+            return Ok(());
+        }
+        write_bundle(frame, &self.addresses, &mut self.buffer, &mut self.message)?;
+        self.traffic.record(
+            self.socket
+                .send_to(&self.buffer, self.address)
+                .map_err(Error::Network)?,
+        );
+        Ok(())
+    }
+
+    fn traffic(&self) -> Option<&Traffic> {
+        Some(&self.traffic)
+    }
+}
+
+/// Encode frame as a bundle of pose messages, one per device, and the frame message
+///
+/// Pose messages have position x, y, z and rotation x, y, z, w as float
+/// arguments, the frame message a string with the frame as JSON.
+pub fn write_bundle(
+    frame: &TrackedObjects,
+    addresses: &OscAddresses,
+    buffer: &mut Vec<u8>,
+    message: &mut Vec<u8>,
+) -> Result<()> {
+    buffer.clear();
+    write_string(buffer, "#bundle");
+    buffer.extend_from_slice(&timetag(frame.ts()).to_be_bytes());
+    if let Some(template) = &addresses.device {
+        let mut address = String::new();
+        for device in frame.trackers() {
+            if !template.render(device, &RESERVED, &mut address) {
+                continue;
+            }
+            message.clear();
+            write_pose_message(&address, device, message);
+            buffer.extend_from_slice(&(message.len() as i32).to_be_bytes());
+            buffer.extend_from_slice(message);
+        }
+    }
+    if let Some(address) = &addresses.frame {
+        message.clear();
+        write_string(message, address);
+        write_string(message, ",s");
+        write_string(message, &serde_json::to_string(frame)?);
+        buffer.extend_from_slice(&(message.len() as i32).to_be_bytes());
+        buffer.extend_from_slice(message);
+    }
+    Ok(())
+}
+
+fn write_pose_message(address: &str, device: &VrDevice, message: &mut Vec<u8>) {
+    write_string(message, address);
+    write_string(message, ",fffffff");
+    let position = device.position();
+    let rotation = device.rotation();
+    for value in [
+        position.x, position.y, position.z, rotation.i, rotation.j, rotation.k, rotation.w,
+    ]
+    .iter()
+    {
+        message.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// OSC string: null terminated and padded to a multiple of 4 bytes
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(value.as_bytes());
+    let padding = 4 - value.len() % 4;
+    buffer.resize(buffer.len() + padding, 0);
+}
+
+/// NTP timestamp from milliseconds since the Unix epoch
+fn timetag(ts: u128) -> u64 {
+    let seconds = (ts / 1000) as u64 + NTP_UNIX_OFFSET_S;
+    let fraction = (((ts % 1000) as u64) << 32) / 1000;
+    (seconds << 32) | fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_one_bundle_per_frame() {
+        let mut waist = VrDevice::new(3);
+        waist.set_alias(Some("waist".into()));
+        let frame = TrackedObjects::new(0, 1_500, vec![waist, VrDevice::new(4)]);
+        let (mut buffer, mut message) = (vec![], vec![]);
+        write_bundle(&frame, &OscAddresses::default(), &mut buffer, &mut message).unwrap();
+
+        assert_eq!(&buffer[..8], b"#bundle\0");
+        let timetag = u64::from_be_bytes(buffer[8..16].try_into().unwrap());
+        assert_eq!(timetag >> 32, NTP_UNIX_OFFSET_S + 1);
+        assert_eq!(timetag & 0xffff_ffff, 1 << 31);
+
+        // "/openvr/waist/pose" pads to 20 bytes, ",fffffff" to 12, then 7 floats
+        let size = i32::from_be_bytes(buffer[16..20].try_into().unwrap());
+        assert_eq!(size, 20 + 12 + 28);
+        assert_eq!(&buffer[20..40], b"/openvr/waist/pose\0\0");
+        let w = f32::from_be_bytes(buffer[76..80].try_into().unwrap());
+        assert_eq!(w, 1.0);
+        assert_eq!(&buffer[84..100], b"/openvr/4/pose\0\0");
+        assert_eq!(buffer.len(), 16 + 2 * (4 + 60) - 4);
+
+        // only named devices on their own address, and the frame
+        let config = OscConfig {
+            address: Some("/vr/{alias}".to_owned()),
+            frame_address: Some("/vr/frame".to_owned()),
+        };
+        assert!(config.problems().is_empty());
+        let addresses = OscAddresses::from_config(&config).unwrap();
+        write_bundle(&frame, &addresses, &mut buffer, &mut message).unwrap();
+        let size = i32::from_be_bytes(buffer[16..20].try_into().unwrap()) as usize;
+        assert_eq!(&buffer[20..32], b"/vr/waist\0\0\0");
+        let frame_message = &buffer[20 + size + 4..];
+        assert_eq!(&frame_message[..16], b"/vr/frame\0\0\0,s\0\0");
+        assert!(frame_message[16..].starts_with(b"{\"seq\":0"));
+
+        let relative = OscConfig {
+            address: Some("vr/{alias}".to_owned()),
+            frame_address: None,
+        };
+        assert_eq!(relative.problems().len(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Pose of one device relative to another
+//!
+//! Measuring a tool against a workpiece, or a head against a torso, by
+//! subtracting two world poses on the receiving side mixes samples and
+//! doubles the jitter of the world frame. With `pairs` set every pose frame
+//! carries `relative`: the pose of `device` in the frame of `reference` and
+//! the velocity of one relative to the other, both taken from the same sample.
+//!
+//! Velocities are expressed in the reference frame, so a tool resting on a
+//! workpiece that is carried around has none. A pair is only published while
+//! both of its devices are tracked.
+
+use crate::config::device_named;
+use crate::tracking_messages::{RelativePose, VrDevice};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PairConfig {
+    pub name: Arc<str>,
+    /// Serial or alias of the device whose pose is published
+    pub device: String,
+    /// Serial or alias of the device the pose is relative to
+    pub reference: String,
+}
+
+impl PairConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.name.trim().is_empty() {
+            problems.push(format!(
+                "Pair of {} and {} has no name",
+                self.device, self.reference
+            ));
+        }
+        if self.device == self.reference {
+            problems.push(format!("Pair {} is relative to its own device", self.name));
+        }
+        problems
+    }
+}
+
+/// Problems of all pairs, including names used twice
+pub fn problems(pairs: &[PairConfig]) -> Vec<String> {
+    let mut problems = vec![];
+    let mut names = HashSet::new();
+    for pair in pairs {
+        problems.extend(pair.problems());
+        if !names.insert(&pair.name) {
+            problems.push(format!("Two pairs are named {}", pair.name));
+        }
+    }
+    problems
+}
+
+/// Write the relative pose of every pair with both devices tracked into `out`
+pub fn build(
+    pairs: &[PairConfig],
+    devices: &[VrDevice],
+    aliases: &HashMap<String, Arc<str>>,
+    out: &mut Vec<RelativePose>,
+) {
+    out.clear();
+    let find = |name: &str| {
+        devices
+            .iter()
+            .find(|device| device.tracked() && device_named(device, name, aliases))
+    };
+    for pair in pairs {
+        let (device, reference) = match (find(&pair.device), find(&pair.reference)) {
+            (Some(device), Some(reference)) => (device, reference),
+            _ => continue,
+        };
+        let inverse = reference.rotation().inverse();
+        let offset = device.position() - reference.position();
+        // the device also moves relative to a rotating reference when it stands still
+        let velocity =
+            device.velocity() - reference.velocity() - reference.angular_velocity().cross(&offset);
+        out.push(RelativePose {
+            name: pair.name.clone(),
+            id: device.id(),
+            reference_id: reference.id(),
+            translation: inverse * offset,
+            rotation: inverse * device.rotation(),
+            velocity: inverse * velocity,
+            angular_velocity: inverse * (device.angular_velocity() - reference.angular_velocity()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IDENTITY_POSE;
+    use crate::tracking_messages::VrDeviceClass;
+    use nalgebra as na;
+
+    #[test]
+    fn test_tool_relative_to_rotating_workpiece() {
+        let pairs: Vec<PairConfig> = serde_json::from_str(
+            r#"[{"name": "tool_in_workpiece", "device": "tool", "reference": "LHR-2"}]"#,
+        )
+        .unwrap();
+        assert!(problems(&pairs).is_empty());
+        let aliases: HashMap<String, Arc<str>> = vec![("LHR-1".to_owned(), "tool".into())]
+            .into_iter()
+            .collect();
+        let mut tool = VrDevice::new(3);
+        tool.set_serial(Some("LHR-1".into()));
+        let mut pose = IDENTITY_POSE;
+        pose[0][3] = 1.0;
+        pose[2][3] = 1.0;
+        tool.update(true, &pose, VrDeviceClass::Tracker);
+        // workpiece at the origin, turned a quarter about y and spinning about y
+        let mut workpiece = VrDevice::new(4);
+        workpiece.set_serial(Some("LHR-2".into()));
+        let pose = [
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [-1.0, 0.0, 0.0, 0.0],
+        ];
+        workpiece.update(true, &pose, VrDeviceClass::Tracker);
+        workpiece.set_velocity(na::Vector3::zeros(), na::Vector3::new(0.0, 1.0, 0.0));
+
+        let mut out = vec![];
+        build(
+            &pairs,
+            &[tool.clone(), workpiece.clone()],
+            &aliases,
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].id, out[0].reference_id), (3, 4));
+        assert!((out[0].translation - na::Vector3::new(-1.0, 0.0, 1.0)).norm() < 1e-5);
+        // the tool stands still in the world, so it turns backwards around the workpiece
+        assert!((out[0].velocity - na::Vector3::new(-1.0, 0.0, -1.0)).norm() < 1e-5);
+        assert!((out[0].angular_velocity - na::Vector3::new(0.0, -1.0, 0.0)).norm() < 1e-5);
+
+        workpiece.update(false, &pose, VrDeviceClass::Tracker);
+        build(&pairs, &[tool, workpiece], &aliases, &mut out);
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::change_tracker::ChangeTracker;
+use crate::config::{Config, DeviceOrder};
+use crate::events::DeviceEvent;
+use crate::identity::SenderIdentity;
+use crate::markers::Marker;
+use crate::pairs;
+use crate::quality::DeviceQuality;
+use crate::tracking_messages::{
+    Channel, DeviceMetadata, DisplayInfo, StreamStatus, TimeSource, TrackedObjects, VrDevice,
+};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Turns device state into frames
+///
+/// The frame and its device buffer are reused, so building a frame
+/// doesn't allocate once the buffers have grown to size.
+pub struct FrameBuilder {
+    seq: u64,
+    /// Event messages are numbered separately so each channel has gapless sequence numbers
+    event_seq: u64,
+    keyframe_seq: u64,
+    last_publish: Option<Instant>,
+    change_tracker: ChangeTracker,
+    frame: TrackedObjects,
+    /// Captured anchors, added to every frame
+    anchors: Vec<VrDevice>,
+    /// Pose covariance by device id
+    covariances: Vec<(usize, nalgebra::Matrix6<f32>)>,
+}
+
+impl Default for FrameBuilder {
+    fn default() -> Self {
+        Self {
+            seq: 0,
+            event_seq: 0,
+            keyframe_seq: 0,
+            last_publish: None,
+            change_tracker: ChangeTracker::new(),
+            frame: TrackedObjects::new(0, 0, Vec::with_capacity(openvr::MAX_TRACKED_DEVICE_COUNT)),
+            anchors: vec![],
+            covariances: vec![],
+        }
+    }
+}
+
+impl FrameBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name the sender in every frame built from now on
+    pub fn set_sender(&mut self, sender: Option<SenderIdentity>) {
+        self.frame.set_sender(sender);
+    }
+
+    /// Name the clock of timestamps in every frame built from now on
+    pub fn set_time_source(&mut self, time_source: Option<TimeSource>) {
+        self.frame.set_time_source(time_source);
+    }
+
+    /// Publish these anchors in every frame built from now on
+    pub fn set_anchors(&mut self, anchors: Vec<VrDevice>) {
+        self.anchors = anchors;
+    }
+
+    /// Attach these covariances to devices in every frame built from now on
+    pub fn set_covariances(&mut self, covariances: Vec<(usize, nalgebra::Matrix6<f32>)>) {
+        self.covariances = covariances;
+    }
+
+    /// Build next frame from sampled device state
+    ///
+    /// Returns `None` when there is nothing to publish.
+    /// Empty frames are replaced by a heartbeat once per heartbeat interval.
+    pub fn build(
+        &mut self,
+        sampled: &[VrDevice],
+        config: &Config,
+        ts: u128,
+        now: Instant,
+    ) -> Option<&TrackedObjects> {
+        let devices = self.frame.trackers_mut();
+        devices.clear();
+        devices.extend(sampled.iter().filter(|device| device.seen()).cloned());
+        // role overrides can change the class the filter looks at
+        config.assign_roles(devices);
+        devices.retain(|device| config.accepts(device));
+        // virtual devices and the devices they derive from aren't subject to the class filter
+        devices.extend(
+            config
+                .centroids
+                .iter()
+                .filter_map(|centroid| centroid.locate(sampled, &config.aliases)),
+        );
+        devices.extend(self.anchors.iter().cloned());
+        if let Some(skeleton) = &config.skeleton {
+            devices.extend(
+                skeleton
+                    .solve(sampled, &config.aliases)
+                    .into_iter()
+                    .flatten(),
+            );
+        }
+        // named before apply strips the serials, like workspace limits
+        let serials: Vec<Option<Arc<str>>> = match config.transform_tree {
+            Some(_) => devices
+                .iter()
+                .map(|device| device.serial().map(Arc::from))
+                .collect(),
+            None => vec![],
+        };
+        devices.iter_mut().for_each(|device| config.apply(device));
+        // every published device, before the change-only filter drops the ones that didn't move
+        let named: Option<Vec<VrDevice>> = config.transform_tree.as_ref().map(|_| {
+            let mut named = devices.clone();
+            for (device, serial) in named.iter_mut().zip(serials) {
+                device.set_serial(serial);
+            }
+            named
+        });
+        if config.device_order == DeviceOrder::Role {
+            // stable, so devices without a role keep their order
+            devices.sort_by_key(|device| (device.role().is_none(), device.role()));
+        }
+        if !self.covariances.is_empty() {
+            for device in devices.iter_mut() {
+                let covariance = self
+                    .covariances
+                    .iter()
+                    .find(|(id, _)| *id == device.id())
+                    .map(|(_, covariance)| *covariance);
+                device.set_covariance(covariance);
+            }
+        }
+        let mut keyframe = true;
+        if let Some(change_only) = &config.change_only {
+            keyframe = self.change_tracker.filter(devices, change_only, now);
+        }
+        // keyframes are always sent in change-only mode so that deltas have a base
+        let required_keyframe = keyframe && config.change_only.is_some();
+        if !devices.is_empty() || required_keyframe {
+            self.frame.reset(self.seq, ts);
+            if let (Some(tree), Some(named)) = (&config.transform_tree, &named) {
+                tree.build(named, &config.aliases, self.frame.transforms_mut());
+            }
+            if !config.pairs.is_empty() {
+                // raw poses, a change-only frame can lack either device
+                let mut relative = std::mem::take(self.frame.relative_mut());
+                pairs::build(&config.pairs, sampled, &config.aliases, &mut relative);
+                *self.frame.relative_mut() = relative;
+            }
+            if keyframe {
+                self.keyframe_seq = self.seq;
+            } else {
+                self.frame.set_delta_of(self.keyframe_seq);
+            }
+        } else {
+            let heartbeat_due = match self.last_publish {
+                Some(last_publish) => {
+                    now.duration_since(last_publish) >= config.heartbeat_interval()
+                }
+                None => true,
+            };
+            if !heartbeat_due {
+                return None;
+            }
+            self.frame.reset(self.seq, ts);
+            self.frame.set_heartbeat();
+        }
+        self.seq += 1;
+        self.last_publish = Some(now);
+        Some(&self.frame)
+    }
+
+    /// Build a frame from poses predicted between samples, marked as extrapolated
+    pub fn extrapolated(
+        &mut self,
+        predicted: &[VrDevice],
+        config: &Config,
+        ts: u128,
+        now: Instant,
+    ) -> Option<&TrackedObjects> {
+        self.build(predicted, config, ts, now)?;
+        if !self.frame.is_heartbeat() {
+            self.frame.set_extrapolated(true);
+        }
+        Some(&self.frame)
+    }
+
+    /// Build a heartbeat marked paused, once per heartbeat interval
+    ///
+    /// Sent on the pose channel in place of frames while publishing is paused.
+    pub fn paused(&mut self, config: &Config, ts: u128, now: Instant) -> Option<&TrackedObjects> {
+        if let Some(last_publish) = self.last_publish {
+            if now.duration_since(last_publish) < config.heartbeat_interval() {
+                return None;
+            }
+        }
+        // the first frame after resuming is a keyframe
+        self.change_tracker = ChangeTracker::new();
+        self.frame.reset(self.seq, ts);
+        self.frame.set_heartbeat();
+        self.frame.set_status(Some(StreamStatus::Paused));
+        self.seq += 1;
+        self.last_publish = Some(now);
+        Some(&self.frame)
+    }
+
+    /// Build a heartbeat announcing a change in stream health
+    pub fn status(&mut self, status: StreamStatus, ts: u128, now: Instant) -> &TrackedObjects {
+        let frame = self.event_frame(ts, now);
+        frame.set_status(Some(status));
+        frame
+    }
+
+    /// Build a heartbeat carrying tracking quality of every device
+    pub fn diagnostics(
+        &mut self,
+        diagnostics: Vec<DeviceQuality>,
+        ts: u128,
+        now: Instant,
+    ) -> &TrackedObjects {
+        let frame = self.event_frame(ts, now);
+        frame.set_diagnostics(diagnostics);
+        frame
+    }
+
+    /// Build a heartbeat carrying headset, device and play area details
+    pub fn metadata(
+        &mut self,
+        display: Option<DisplayInfo>,
+        devices: Vec<DeviceMetadata>,
+        play_area: Option<[[f32; 3]; 4]>,
+        ts: u128,
+        now: Instant,
+    ) -> &TrackedObjects {
+        let frame = self.event_frame(ts, now);
+        frame.set_display(display);
+        frame.set_devices(devices);
+        frame.set_play_area(play_area);
+        frame
+    }
+
+    /// Build a heartbeat carrying device events
+    pub fn events(&mut self, events: Vec<DeviceEvent>, ts: u128, now: Instant) -> &TrackedObjects {
+        let frame = self.event_frame(ts, now);
+        frame.set_events(events);
+        frame
+    }
+
+    pub fn markers(&mut self, markers: Vec<Marker>, ts: u128, now: Instant) -> &TrackedObjects {
+        let frame = self.event_frame(ts, now);
+        frame.set_markers(markers);
+        frame
+    }
+
+    /// Empty heartbeat on the event channel
+    fn event_frame(&mut self, ts: u128, now: Instant) -> &mut TrackedObjects {
+        self.frame.reset(self.event_seq, ts);
+        self.frame.set_heartbeat();
+        self.frame.set_channel(Channel::Event);
+        self.event_seq += 1;
+        self.last_publish = Some(now);
+        &mut self.frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+    use crate::fixtures::device;
+    use crate::openvr_adaptor::VrDeviceManager;
+    use crate::tracking_messages::{DeviceRole, VrDeviceClass};
+    use std::time::Duration;
+
+    #[test]
+    fn test_heartbeat_when_no_devices() {
+        let mut manager = VrDeviceManager::with_backend(MockBackend::new());
+        let mut builder = FrameBuilder::new();
+        let config = Config::default();
+        let start = Instant::now();
+        manager.update();
+        assert!(builder
+            .build(manager.devices(), &config, 0, start)
+            .unwrap()
+            .is_heartbeat());
+        assert!(builder
+            .build(
+                manager.devices(),
+                &config,
+                0,
+                start + Duration::from_millis(20)
+            )
+            .is_none());
+        let frame = builder
+            .build(
+                manager.devices(),
+                &config,
+                0,
+                start + Duration::from_secs(1),
+            )
+            .unwrap();
+        assert!(frame.is_heartbeat());
+        assert_eq!(frame.seq(), 1);
+    }
+
+    #[test]
+    fn test_paused_heartbeats() {
+        let mut builder = FrameBuilder::new();
+        let config = Config::default();
+        let start = Instant::now();
+        let frame = builder.paused(&config, 0, start).unwrap();
+        assert!(frame.is_heartbeat());
+        assert_eq!(frame.status(), Some(StreamStatus::Paused));
+        assert!(builder
+            .paused(&config, 0, start + Duration::from_millis(20))
+            .is_none());
+        let frame = builder
+            .paused(&config, 0, start + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(frame.seq(), 1);
+    }
+
+    #[test]
+    fn test_frame_contains_seen_devices() {
+        let mut backend = MockBackend::new();
+        let mut unseen = DeviceSample::new(1, VrDeviceClass::Tracker, IDENTITY_POSE);
+        unseen.pose_valid = false;
+        backend.push_frame(vec![
+            DeviceSample::new(0, VrDeviceClass::HMD, IDENTITY_POSE),
+            unseen,
+        ]);
+        let mut manager = VrDeviceManager::with_backend(backend);
+        let mut builder = FrameBuilder::new();
+        manager.update();
+        let frame = builder
+            .build(manager.devices(), &Config::default(), 0, Instant::now())
+            .unwrap();
+        assert!(!frame.is_heartbeat());
+        assert_eq!(frame.trackers().len(), 1);
+    }
+
+    #[test]
+    fn test_order_by_role() {
+        let mut backend = MockBackend::new();
+        backend.push_frame(vec![
+            DeviceSample::new(0, VrDeviceClass::Tracker, IDENTITY_POSE),
+            DeviceSample::new(1, VrDeviceClass::Sensor, IDENTITY_POSE),
+            DeviceSample::new(2, VrDeviceClass::RightController, IDENTITY_POSE),
+            DeviceSample::new(3, VrDeviceClass::Tracker, IDENTITY_POSE),
+            DeviceSample::new(4, VrDeviceClass::HMD, IDENTITY_POSE),
+        ]);
+        backend.set_tracker_role(0, DeviceRole::RightFoot);
+        backend.set_tracker_role(3, DeviceRole::Waist);
+        let mut manager = VrDeviceManager::with_backend(backend);
+        manager.update();
+        let config = Config {
+            device_order: DeviceOrder::Role,
+            ..Default::default()
+        };
+        let frame = FrameBuilder::new()
+            .build(manager.devices(), &config, 0, Instant::now())
+            .unwrap()
+            .clone();
+        let order: Vec<(usize, Option<DeviceRole>)> = frame
+            .trackers()
+            .iter()
+            .map(|device| (device.id(), device.role()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (4, Some(DeviceRole::Head)),
+                (2, Some(DeviceRole::RightHand)),
+                (3, Some(DeviceRole::Waist)),
+                (0, Some(DeviceRole::RightFoot)),
+                (1, None)
+            ]
+        );
+        assert_eq!(
+            DeviceRole::from_controller_type("vive_tracker_left_foot"),
+            Some(DeviceRole::LeftFoot)
+        );
+        assert_eq!(
+            DeviceRole::from_controller_type("vive_tracker_handed"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_transform_tree_names_parents_by_serial() {
+        let config: Config = serde_json::from_str(
+            r#"{"metadata_interval_s": 5, "change_only": {}, "transform_tree": {"static_transforms": [
+                {"parent": "LHR-1", "child": "tool", "transform": {"translation": [0, 0, -0.1], "rotation": [0, 0, 0, 1]}}
+            ]}}"#,
+        )
+        .unwrap();
+        let tracker = device(1).serial("LHR-1").at([1.0, 0.0, 0.0]).build();
+        let mut builder = FrameBuilder::new();
+        let start = Instant::now();
+        let edges = |frame: &TrackedObjects| {
+            frame
+                .transforms()
+                .iter()
+                .map(|edge| (edge.parent.to_string(), edge.child.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            ("world".to_owned(), "LHR-1".to_owned()),
+            ("world".to_owned(), "device_2".to_owned()),
+            ("LHR-1".to_owned(), "tool".to_owned()),
+        ];
+
+        let sampled = vec![tracker.clone(), device(2).build()];
+        let frame = builder.build(&sampled, &config, 0, start).unwrap();
+        assert_eq!(frame.trackers()[0].serial(), None);
+        assert_eq!(edges(frame), expected);
+
+        // the tracker didn't move, so it is missing from the partial frame but not from the tree
+        let sampled = vec![tracker, device(2).at([0.0, 1.0, 0.0]).build()];
+        let later = start + Duration::from_millis(20);
+        let frame = builder.build(&sampled, &config, 0, later).unwrap();
+        assert!(frame.partial());
+        assert_eq!(frame.trackers().len(), 1);
+        assert_eq!(edges(frame), expected);
+    }
+}
@@ -0,0 +1,154 @@
+//! Turning off controllers and trackers at the end of a session
+//!
+//! OpenVR has no call to power off a device. SteamVR ships `lighthouse_console`
+//! for that, which is run once per device with `/serial <serial> poweroff`.
+
+use crate::error::{Error, Result};
+use crate::tracking_messages::{VrDevice, VrDeviceClass};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// `lighthouse_console` inside a SteamVR install
+#[cfg(windows)]
+const CONSOLE_IN_STEAMVR: &str = "tools/lighthouse/bin/win64/lighthouse_console.exe";
+#[cfg(not(windows))]
+const CONSOLE_IN_STEAMVR: &str = "tools/lighthouse/bin/linux64/lighthouse_console";
+
+/// Controllers and trackers run on batteries, the headset and base stations don't
+pub fn can_power_off(class: VrDeviceClass) -> bool {
+    matches!(
+        class,
+        VrDeviceClass::Controller
+            | VrDeviceClass::LeftController
+            | VrDeviceClass::RightController
+            | VrDeviceClass::Tracker
+    )
+}
+
+/// Serials to turn off, every connected controller and tracker if `serials` is empty
+pub fn targets(devices: &[VrDevice], serials: &[String]) -> Vec<String> {
+    if !serials.is_empty() {
+        return serials.to_vec();
+    }
+    devices
+        .iter()
+        .filter(|device| device.seen() && device.state().is_connected())
+        .filter(|device| can_power_off(device.class()))
+        .filter_map(|device| device.serial().map(str::to_owned))
+        .collect()
+}
+
+/// Runs `lighthouse_console` to power off devices
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerControl {
+    console: PathBuf,
+}
+
+impl PowerControl {
+    /// Use `console`, or look for it in the usual SteamVR install locations
+    pub fn new(console: Option<&Path>) -> Result<Self> {
+        let console = match console {
+            Some(console) => console.to_owned(),
+            None => find_console().ok_or_else(|| {
+                Error::config(
+                    "Can't find lighthouse_console in SteamVR, set its path with lighthouse_console",
+                )
+            })?,
+        };
+        Ok(Self { console })
+    }
+
+    pub fn power_off(&self, serial: &str) -> Result<()> {
+        let output = Command::new(&self.console)
+            .args(["/serial", serial, "poweroff"])
+            .output()
+            .map_err(|error| {
+                Error::external_caused(format!("Failed to run {}", self.console.display()), error)
+            })?;
+        if !output.status.success() {
+            return Err(Error::external(format!(
+                "lighthouse_console failed for {}: {}",
+                serial,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Power off every serial, logging failures instead of stopping at the first
+    pub fn power_off_all(&self, serials: &[String]) -> usize {
+        let mut powered_off = 0;
+        for serial in serials {
+            match self.power_off(serial) {
+                Ok(()) => {
+                    eprintln!("Powered off {}", serial);
+                    powered_off += 1;
+                }
+                Err(error) => eprintln!("Failed to power off {}: {}", serial, error.describe()),
+            }
+        }
+        powered_off
+    }
+}
+
+fn find_console() -> Option<PathBuf> {
+    steamvr_dirs()
+        .into_iter()
+        .map(|dir| dir.join(CONSOLE_IN_STEAMVR))
+        .find(|console| console.exists())
+}
+
+fn steamvr_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if cfg!(windows) {
+        dirs.push(PathBuf::from(
+            r"C:\Program Files (x86)\Steam\steamapps\common\SteamVR",
+        ));
+    }
+    if let Some(home) = std::env::var_os("HOME") {
+        let home = PathBuf::from(home);
+        dirs.push(home.join(".steam/steam/steamapps/common/SteamVR"));
+        dirs.push(home.join(".local/share/Steam/steamapps/common/SteamVR"));
+    }
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+    use crate::tracking_messages::TrackingState;
+
+    #[test]
+    fn test_targets_batteries_only() {
+        let devices = vec![
+            device(0).class(VrDeviceClass::HMD).serial("HMD").build(),
+            device(1)
+                .class(VrDeviceClass::Tracker)
+                .serial("LHR-1")
+                .build(),
+            device(2)
+                .class(VrDeviceClass::LeftController)
+                .serial("LHR-2")
+                .state(TrackingState::OutOfRange)
+                .build(),
+            device(3)
+                .class(VrDeviceClass::Tracker)
+                .serial("LHR-3")
+                .state(TrackingState::Disconnected)
+                .build(),
+            device(4)
+                .class(VrDeviceClass::Sensor)
+                .serial("LHB-1")
+                .build(),
+        ];
+        assert_eq!(targets(&devices, &[]), vec!["LHR-1", "LHR-2"]);
+        assert_eq!(targets(&devices, &["LHR-9".into()]), vec!["LHR-9"]);
+        assert!(
+            PowerControl::new(Some(Path::new("/nonexistent/lighthouse_console")))
+                .unwrap()
+                .power_off("LHR-1")
+                .is_err()
+        );
+    }
+}
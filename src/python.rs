@@ -0,0 +1,124 @@
+//! Python bindings
+//!
+//! Build with `maturin develop`, `pyproject.toml` enables the `extension-module` feature.
+//!
+//! ```python
+//! import openvr_tracker
+//! for frame in openvr_tracker.Receiver("239.0.0.22:7070"):
+//!     print(frame["ts"], len(frame["trackers"]))
+//! ```
+
+use crate::client::MulticastClient;
+use crate::openvr_adaptor::VrDeviceManager;
+use crate::tracking_messages::{TrackedObjects, VrDevice};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use pyo3::PyIterProtocol;
+use std::net::SocketAddrV4;
+
+fn to_py_err(error: impl std::fmt::Display) -> PyErr {
+    PyIOError::new_err(error.to_string())
+}
+
+fn device_to_dict(py: Python, device: &VrDevice) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("id", device.id())?;
+    dict.set_item("tracked", device.tracked())?;
+    dict.set_item("seen", device.seen())?;
+    let position = device.position();
+    dict.set_item("position", (position.x, position.y, position.z))?;
+    let rotation = device.rotation();
+    dict.set_item("rotation", (rotation.i, rotation.j, rotation.k, rotation.w))?;
+    dict.set_item("class", format!("{:?}", device.class()))?;
+    Ok(dict.into())
+}
+
+fn frame_to_dict(py: Python, frame: &TrackedObjects) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("seq", frame.seq())?;
+    dict.set_item("ts", frame.ts())?;
+    let trackers = PyList::empty(py);
+    for device in frame.trackers() {
+        trackers.append(device_to_dict(py, device)?)?;
+    }
+    dict.set_item("trackers", trackers)?;
+    Ok(dict.into())
+}
+
+/// Iterates frames received from the multicast group
+#[pyclass]
+struct Receiver {
+    client: MulticastClient,
+}
+
+#[pymethods]
+impl Receiver {
+    #[new]
+    #[args(address = "\"239.0.0.22:7070\"")]
+    fn new(address: &str) -> PyResult<Self> {
+        let address: SocketAddrV4 = address
+            .parse()
+            .map_err(|error: std::net::AddrParseError| PyValueError::new_err(error.to_string()))?;
+        let client = MulticastClient::new(address).map_err(to_py_err)?;
+        Ok(Self { client })
+    }
+
+    /// Block until next frame arrives
+    fn next_frame(&mut self, py: Python) -> PyResult<PyObject> {
+        let client = &mut self.client;
+        let frame = py
+            .allow_threads(|| client.next_frame())
+            .map_err(to_py_err)?;
+        frame_to_dict(py, &frame)
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for Receiver {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<PyObject>> {
+        let py = slf.py();
+        slf.next_frame(py).map(Some)
+    }
+}
+
+/// Polls OpenVR directly, without going through the network
+#[pyclass(unsendable)]
+struct Tracker {
+    manager: VrDeviceManager,
+}
+
+#[pymethods]
+impl Tracker {
+    #[new]
+    fn new() -> PyResult<Self> {
+        let manager = VrDeviceManager::new().map_err(to_py_err)?;
+        Ok(Self { manager })
+    }
+
+    /// Poll OpenVR and return all devices that have been seen
+    fn poll(&mut self, py: Python) -> PyResult<PyObject> {
+        self.manager.update();
+        let devices = PyList::empty(py);
+        for device in self
+            .manager
+            .device_list()
+            .iter()
+            .filter(|device| device.seen())
+        {
+            devices.append(device_to_dict(py, device)?)?;
+        }
+        Ok(devices.into())
+    }
+}
+
+#[pymodule]
+fn openvr_tracker(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Receiver>()?;
+    m.add_class::<Tracker>()?;
+    Ok(())
+}
@@ -0,0 +1,491 @@
+//! Per-device tracking quality statistics
+//!
+//! Counts how often each device had tracking and for how long it was lost,
+//! and estimates position jitter while the device is resting.
+//!
+//! The noise floor is the standard deviation of position over the latest
+//! samples in which the runtime reported the device as still. It follows
+//! changes in the room, like a reflective surface that was put up, where the
+//! jitter estimate averages over the whole session.
+//!
+//! The pose covariance for sensor fusion is built from these statistics: the
+//! noise floor sets the position variance, positions that aren't optically
+//! tracked get a large one, and both position and rotation variance are
+//! inflated for a while after tracking comes back from a dropout. Covariances
+//! are diagonal, x, y, z then rotation about x, y, z, so calibration doesn't
+//! change them.
+
+use crate::tracking_messages::{TrackingState, VrDevice, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Movement smaller than this counts as resting
+const REST_RADIUS: f32 = 0.005;
+/// Smoothing of the resting position and jitter estimates
+const REST_SMOOTHING: f32 = 0.05;
+/// Meters per second below which a device counts as still for the noise floor
+const STILL_SPEED: f32 = 0.01;
+/// Still samples the noise floor is computed over
+const NOISE_WINDOW: usize = 120;
+/// Still samples needed before there is a noise floor
+const NOISE_MIN_SAMPLES: usize = 30;
+
+/// Prometheus metric name, help text, type and how to read it from a report
+type Metric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&DeviceQuality) -> Option<f32>,
+);
+
+/// Label value for the Prometheus text format, with backslashes, quotes and newlines escaped
+pub(crate) fn label_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Tracking quality of one device since the tracker started
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceQuality {
+    pub id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<Arc<str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<Arc<str>>,
+    pub class: VrDeviceClass,
+    /// Share of samples with valid tracking, 0 to 100
+    pub tracked_percent: f32,
+    /// Times tracking was lost
+    pub dropouts: u64,
+    /// Dropouts caused by losing the radio link rather than line of sight
+    pub radio_dropouts: u64,
+    pub longest_dropout_s: f32,
+    /// Standard deviation of position at rest in millimeters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter_mm: Option<f32>,
+    /// Standard deviation of position over the latest still samples in millimeters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise_floor_mm: Option<f32>,
+}
+
+struct DeviceStats {
+    device: VrDevice,
+    samples: u64,
+    tracked_samples: u64,
+    dropouts: u64,
+    radio_dropouts: u64,
+    dropout_start: Option<Instant>,
+    longest_dropout: Duration,
+    rest_position: Option<na::Point3<f32>>,
+    rest_variance: Option<f32>,
+    /// Positions since the device became still
+    still: VecDeque<na::Point3<f32>>,
+    noise_floor: Option<f32>,
+    /// When tracking last came back after a dropout
+    recovered_at: Option<Instant>,
+}
+
+/// How pose covariances are estimated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CovarianceConfig {
+    /// Lowest position standard deviation per axis, also used until there is a noise floor
+    pub position_std_m: f32,
+    /// Rotation standard deviation per axis
+    pub rotation_std_rad: f32,
+    /// Position standard deviation while only rotation is tracked, or out of range
+    pub degraded_position_std_m: f32,
+    /// Standard deviations are multiplied by this right after a dropout
+    pub recovery_factor: f32,
+    /// Seconds over which the factor decays back to 1
+    pub recovery_s: f32,
+}
+
+impl Default for CovarianceConfig {
+    fn default() -> Self {
+        Self {
+            position_std_m: 0.001,
+            rotation_std_rad: 0.005,
+            degraded_position_std_m: 1.0,
+            recovery_factor: 10.0,
+            recovery_s: 2.0,
+        }
+    }
+}
+
+impl CovarianceConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let values = [
+            ("position_std_m", self.position_std_m),
+            ("rotation_std_rad", self.rotation_std_rad),
+            ("degraded_position_std_m", self.degraded_position_std_m),
+        ];
+        for (name, value) in values.iter() {
+            if !(*value > 0.0 && value.is_finite()) {
+                problems.push(format!(
+                    "covariance.{} must be positive, is {}",
+                    name, value
+                ));
+            }
+        }
+        if self.recovery_factor.is_nan() || self.recovery_factor < 1.0 {
+            problems.push(format!(
+                "covariance.recovery_factor must be at least 1, is {}",
+                self.recovery_factor
+            ));
+        }
+        if self.recovery_s.is_nan() || self.recovery_s < 0.0 {
+            problems.push(format!(
+                "covariance.recovery_s can't be negative, is {}",
+                self.recovery_s
+            ));
+        }
+        problems
+    }
+}
+
+impl DeviceStats {
+    fn new(device: &VrDevice) -> Self {
+        Self {
+            device: device.clone(),
+            samples: 0,
+            tracked_samples: 0,
+            dropouts: 0,
+            radio_dropouts: 0,
+            dropout_start: None,
+            longest_dropout: Duration::default(),
+            rest_position: None,
+            rest_variance: None,
+            still: VecDeque::with_capacity(NOISE_WINDOW + 1),
+            noise_floor: None,
+            recovered_at: None,
+        }
+    }
+
+    fn record(&mut self, device: &VrDevice, now: Instant) {
+        self.device.clone_from(device);
+        self.samples += 1;
+        if !device.tracked() {
+            if self.dropout_start.is_none() && self.tracked_samples > 0 {
+                self.dropouts += 1;
+                if device.state() == TrackingState::Disconnected {
+                    self.radio_dropouts += 1;
+                }
+                self.dropout_start = Some(now);
+            }
+            self.rest_position = None;
+            self.still.clear();
+            return;
+        }
+        self.tracked_samples += 1;
+        if let Some(start) = self.dropout_start.take() {
+            self.longest_dropout = self.longest_dropout.max(now.duration_since(start));
+            self.recovered_at = Some(now);
+        }
+        let position = *device.position();
+        match self.rest_position {
+            Some(rest) if (position - rest).norm() < REST_RADIUS => {
+                let offset = (position - rest).norm_squared();
+                self.rest_variance = Some(match self.rest_variance {
+                    Some(variance) => variance + REST_SMOOTHING * (offset - variance),
+                    None => offset,
+                });
+                self.rest_position = Some(rest + (position - rest) * REST_SMOOTHING);
+            }
+            _ => self.rest_position = Some(position),
+        }
+        self.record_noise(device);
+    }
+
+    fn record_noise(&mut self, device: &VrDevice) {
+        let position = *device.position();
+        let moving = device.velocity().norm() >= STILL_SPEED;
+        // devices without velocities, like virtual ones, also have to stay in place
+        let moved =
+            matches!(self.still.front(), Some(first) if (position - first).norm() >= REST_RADIUS);
+        if moving || moved {
+            self.still.clear();
+        }
+        if moving {
+            return;
+        }
+        self.still.push_back(position);
+        if self.still.len() > NOISE_WINDOW {
+            self.still.pop_front();
+        }
+        if self.still.len() >= NOISE_MIN_SAMPLES {
+            let count = self.still.len() as f32;
+            let mean = self
+                .still
+                .iter()
+                .map(|p| p.coords)
+                .sum::<na::Vector3<f32>>()
+                / count;
+            let variance = self
+                .still
+                .iter()
+                .map(|p| (p.coords - mean).norm_squared())
+                .sum::<f32>()
+                / count;
+            self.noise_floor = Some(variance.sqrt());
+        }
+    }
+
+    /// Diagonal pose covariance, `None` while the device isn't tracked
+    fn covariance(&self, config: &CovarianceConfig, now: Instant) -> Option<na::Matrix6<f32>> {
+        if !self.device.tracked() {
+            return None;
+        }
+        // the noise floor is the spread in 3D, split evenly over the axes
+        let measured = self.noise_floor.unwrap_or(0.0) / 3f32.sqrt();
+        let mut position_std = measured.max(config.position_std_m);
+        if matches!(
+            self.device.state(),
+            TrackingState::RotationOnly | TrackingState::OutOfRange | TrackingState::Calibrating
+        ) {
+            position_std = position_std.max(config.degraded_position_std_m);
+        }
+        let mut rotation_std = config.rotation_std_rad;
+        if let (Some(recovered_at), true) = (self.recovered_at, config.recovery_s > 0.0) {
+            let remaining =
+                1.0 - now.duration_since(recovered_at).as_secs_f32() / config.recovery_s;
+            let factor = 1.0 + (config.recovery_factor - 1.0) * remaining.max(0.0);
+            position_std *= factor;
+            rotation_std *= factor;
+        }
+        let position = position_std * position_std;
+        let rotation = rotation_std * rotation_std;
+        Some(na::Matrix6::from_diagonal(&na::Vector6::new(
+            position, position, position, rotation, rotation, rotation,
+        )))
+    }
+
+    fn quality(&self, now: Instant) -> DeviceQuality {
+        let ongoing = match self.dropout_start {
+            Some(start) => now.duration_since(start),
+            None => Duration::default(),
+        };
+        DeviceQuality {
+            id: self.device.id(),
+            serial: self.device.serial().map(Arc::from),
+            alias: self.device.alias().map(Arc::from),
+            class: self.device.class(),
+            tracked_percent: self.tracked_samples as f32 * 100.0 / self.samples.max(1) as f32,
+            dropouts: self.dropouts,
+            radio_dropouts: self.radio_dropouts,
+            longest_dropout_s: self.longest_dropout.max(ongoing).as_secs_f32(),
+            jitter_mm: self.rest_variance.map(|variance| variance.sqrt() * 1000.0),
+            noise_floor_mm: self.noise_floor.map(|noise| noise * 1000.0),
+        }
+    }
+}
+
+/// Keeps [`DeviceQuality`] for every device that was ever seen
+#[derive(Default)]
+pub struct QualityMonitor {
+    /// Indexed by device id
+    devices: Vec<Option<DeviceStats>>,
+}
+
+impl QualityMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add one sample of every device
+    pub fn record(&mut self, devices: &[VrDevice], now: Instant) {
+        for device in devices.iter().filter(|device| device.seen()) {
+            if self.devices.len() <= device.id() {
+                self.devices.resize_with(device.id() + 1, || None);
+            }
+            self.devices[device.id()]
+                .get_or_insert_with(|| DeviceStats::new(device))
+                .record(device, now);
+        }
+    }
+
+    /// Pose covariance of every tracked device by id
+    pub fn covariances(
+        &self,
+        config: &CovarianceConfig,
+        now: Instant,
+    ) -> Vec<(usize, na::Matrix6<f32>)> {
+        self.devices
+            .iter()
+            .flatten()
+            .filter_map(|stats| Some((stats.device.id(), stats.covariance(config, now)?)))
+            .collect()
+    }
+
+    pub fn report(&self, now: Instant) -> Vec<DeviceQuality> {
+        self.devices
+            .iter()
+            .flatten()
+            .map(|stats| stats.quality(now))
+            .collect()
+    }
+
+    /// Report in Prometheus text format
+    pub fn prometheus(&self, now: Instant) -> String {
+        let report = self.report(now);
+        let mut out = String::new();
+        let metrics: [Metric; 6] = [
+            (
+                "openvr_tracker_tracked_percent",
+                "Share of samples with valid tracking",
+                "gauge",
+                |quality| Some(quality.tracked_percent),
+            ),
+            (
+                "openvr_tracker_dropouts_total",
+                "Times tracking was lost",
+                "counter",
+                |quality| Some(quality.dropouts as f32),
+            ),
+            (
+                "openvr_tracker_radio_dropouts_total",
+                "Times tracking was lost because the radio link dropped",
+                "counter",
+                |quality| Some(quality.radio_dropouts as f32),
+            ),
+            (
+                "openvr_tracker_longest_dropout_seconds",
+                "Longest time without tracking",
+                "gauge",
+                |quality| Some(quality.longest_dropout_s),
+            ),
+            (
+                "openvr_tracker_rest_jitter_millimeters",
+                "Position jitter while resting",
+                "gauge",
+                |quality| quality.jitter_mm,
+            ),
+            (
+                "openvr_tracker_noise_floor_millimeters",
+                "Position noise over the latest samples without velocity",
+                "gauge",
+                |quality| quality.noise_floor_mm,
+            ),
+        ];
+        for (name, help, kind, value) in metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for quality in &report {
+                if let Some(value) = value(quality) {
+                    let _ = writeln!(
+                        out,
+                        "{}{{id=\"{}\",class=\"{:?}\",serial=\"{}\"}} {}",
+                        name,
+                        quality.id,
+                        quality.class,
+                        label_value(quality.serial.as_deref().unwrap_or("")),
+                        value
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+    use crate::openvr_adaptor::VrDeviceManager;
+
+    #[test]
+    fn test_counts_dropouts_and_resting_jitter() {
+        let tracked = DeviceSample::new(0, VrDeviceClass::Tracker, IDENTITY_POSE);
+        let mut lost = tracked;
+        lost.pose_valid = false;
+        lost.connected = false;
+        lost.state = TrackingState::Disconnected;
+        let mut backend = MockBackend::new();
+        backend.set_serial(0, "LHR-1");
+        for sample in [tracked, tracked, lost, lost, tracked].iter() {
+            backend.push_frame(vec![*sample]);
+        }
+        let mut manager = VrDeviceManager::with_backend(backend);
+        let mut monitor = QualityMonitor::new();
+        let start = Instant::now();
+        for second in 0..5 {
+            manager.update();
+            monitor.record(manager.devices(), start + Duration::from_secs(second));
+        }
+
+        let report = monitor.report(start + Duration::from_secs(5));
+        assert_eq!(report.len(), 1);
+        let quality = &report[0];
+        assert_eq!(quality.tracked_percent, 60.0);
+        assert_eq!(quality.dropouts, 1);
+        assert_eq!(quality.radio_dropouts, 1);
+        assert_eq!(quality.longest_dropout_s, 2.0);
+        assert_eq!(quality.jitter_mm, Some(0.0));
+
+        // tracking came back at 4 s, half of the recovery inflation is left at 5 s
+        let config = CovarianceConfig::default();
+        let covariances = monitor.covariances(&config, start + Duration::from_secs(5));
+        let (id, covariance) = covariances[0];
+        assert_eq!(id, 0);
+        let position_std = covariance[(0, 0)].sqrt();
+        assert!((position_std - 0.0055).abs() < 1e-6, "{}", position_std);
+        let later = monitor.covariances(&config, start + Duration::from_secs(10));
+        assert!((later[0].1[(5, 5)].sqrt() - config.rotation_std_rad).abs() < 1e-6);
+        assert_eq!(later[0].1[(0, 1)], 0.0);
+
+        let metrics = monitor.prometheus(start);
+        assert!(metrics.contains(
+            "openvr_tracker_dropouts_total{id=\"0\",class=\"Tracker\",serial=\"LHR-1\"} 1"
+        ));
+        assert!(metrics.contains("# TYPE openvr_tracker_dropouts_total counter"));
+        assert_eq!(label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_noise_floor_of_still_samples() {
+        let mut device = VrDevice::new(0);
+        let mut monitor = QualityMonitor::new();
+        let now = Instant::now();
+        for sample in 0..NOISE_MIN_SAMPLES {
+            assert_eq!(
+                monitor.report(now).first().and_then(|q| q.noise_floor_mm),
+                None
+            );
+            // 1 mm either side of the mean
+            let x = if sample % 2 == 0 { 0.001 } else { -0.001 };
+            device.set_pose(
+                true,
+                na::Point3::new(x, 1.0, 0.0),
+                na::UnitQuaternion::identity(),
+            );
+            monitor.record(&[device.clone()], now);
+        }
+        let noise = monitor.report(now)[0].noise_floor_mm.unwrap();
+        assert!((noise - 1.0).abs() < 1e-3);
+
+        // moving starts a new window, the last estimate stays until it is full
+        device.set_velocity(na::Vector3::new(0.5, 0.0, 0.0), na::Vector3::zeros());
+        monitor.record(&[device.clone()], now);
+        device.set_velocity(na::Vector3::zeros(), na::Vector3::zeros());
+        for _ in 0..NOISE_MIN_SAMPLES - 1 {
+            monitor.record(&[device.clone()], now);
+        }
+        assert_eq!(monitor.report(now)[0].noise_floor_mm, Some(noise));
+        monitor.record(&[device.clone()], now);
+        assert_eq!(monitor.report(now)[0].noise_floor_mm, Some(0.0));
+    }
+}
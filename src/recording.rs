@@ -0,0 +1,278 @@
+//! Recording to JSONL files, started and stopped with a controller chord
+//!
+//! The person being captured is usually alone in the volume. Holding a button
+//! on both controllers starts a recording, holding it again stops it, and
+//! holding the split button while recording closes the file and starts the
+//! next one. Controllers buzz to confirm, twice as long when stopping.
+//!
+//! Chords are read on the sampling thread while frames are written by a sink
+//! on the publishing side. Both share one [`Recorder`]. Files start with a
+//! session header, followed by frames in the format of `openvr-tracker-recv`
+//! output, so they can be replayed and exported.
+
+use crate::backend::ControllerButton;
+use crate::error::{Error, Result};
+use crate::session::Session;
+use crate::sink::PoseSink;
+use crate::tracking_messages::TrackedObjects;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Where recordings are written, created if missing
+    pub directory: PathBuf,
+    /// Held on both controllers to start and stop recording
+    pub toggle: ControllerButton,
+    /// Held on both controllers while recording to start a new file
+    pub split: Option<ControllerButton>,
+    /// How long a chord has to be held
+    pub hold_ms: u64,
+    /// Length of the confirmation buzz
+    pub haptic_ms: u64,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("recordings"),
+            toggle: ControllerButton::Menu,
+            split: Some(ControllerButton::Grip),
+            hold_ms: 1000,
+            haptic_ms: 300,
+        }
+    }
+}
+
+impl RecordingConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.split == Some(self.toggle) {
+            problems.push("recording toggle and split use the same button".to_owned());
+        }
+        problems
+    }
+
+    /// Buzz confirming `action`
+    pub fn haptic_ms(&self, action: RecordingAction) -> u64 {
+        match action {
+            RecordingAction::Stop => self.haptic_ms * 2,
+            RecordingAction::Start | RecordingAction::Split => self.haptic_ms,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingAction {
+    Start,
+    Stop,
+    Split,
+}
+
+/// A button chord that fires once per hold
+#[derive(Debug, Default)]
+pub(crate) struct Chord {
+    held_since: Option<Instant>,
+    fired: bool,
+}
+
+impl Chord {
+    pub(crate) fn update(&mut self, held: bool, hold: Duration, now: Instant) -> bool {
+        if !held {
+            *self = Self::default();
+            return false;
+        }
+        let held_since = *self.held_since.get_or_insert(now);
+        if self.fired || now.duration_since(held_since) < hold {
+            return false;
+        }
+        self.fired = true;
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecorderState {
+    toggle: Chord,
+    split: Chord,
+    recording: bool,
+    /// Bumped on every start and split, the sink opens a new file when it changes
+    take: u64,
+}
+
+/// Recording state shared between the sampling and the publishing thread
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    config: Arc<RecordingConfig>,
+    state: Arc<Mutex<RecorderState>>,
+}
+
+impl Recorder {
+    pub fn new(config: RecordingConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            state: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &RecordingConfig {
+        &self.config
+    }
+
+    /// Feed whether the toggle and split chords are held in the current sample
+    pub fn update(&self, toggle: bool, split: bool, now: Instant) -> Option<RecordingAction> {
+        let hold = Duration::from_millis(self.config.hold_ms);
+        let mut state = self.state.lock().unwrap();
+        let toggled = state.toggle.update(toggle, hold, now);
+        let split = state.split.update(split, hold, now);
+        if toggled {
+            state.recording = !state.recording;
+            if state.recording {
+                state.take += 1;
+                return Some(RecordingAction::Start);
+            }
+            return Some(RecordingAction::Stop);
+        }
+        if split && state.recording {
+            state.take += 1;
+            return Some(RecordingAction::Split);
+        }
+        None
+    }
+
+    /// Take number of the running recording
+    fn current_take(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        Some(state.take).filter(|_| state.recording)
+    }
+}
+
+/// Writes frames to the file of the running recording
+pub struct RecordingSink {
+    recorder: Recorder,
+    session: Option<Session>,
+    file: Option<(u64, BufWriter<File>)>,
+}
+
+impl RecordingSink {
+    pub fn new(recorder: Recorder) -> Self {
+        Self {
+            recorder,
+            session: None,
+            file: None,
+        }
+    }
+
+    /// Start every file with the header of `session`
+    pub fn set_session(&mut self, session: Option<Session>) {
+        self.session = session;
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if let Some((_, mut file)) = self.file.take() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl PoseSink for RecordingSink {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        let take = self.recorder.current_take();
+        if take != self.file.as_ref().map(|(take, _)| *take) {
+            if self.file.is_some() {
+                eprintln!("Stopped recording");
+            }
+            self.close()?;
+            if let Some(take) = take {
+                let directory = &self.recorder.config.directory;
+                std::fs::create_dir_all(directory).map_err(|source| Error::Create {
+                    path: directory.clone(),
+                    source,
+                })?;
+                let path = directory.join(format!("recording-{}.jsonl", frame.ts()));
+                let file = File::create(&path).map_err(|source| Error::Create {
+                    path: path.clone(),
+                    source,
+                })?;
+                eprintln!("Recording to {}", path.display());
+                let mut file = BufWriter::new(file);
+                if let Some(session) = &self.session {
+                    session.header(frame.ts()).write(&mut file)?;
+                }
+                self.file = Some((take, file));
+            }
+        }
+        if let Some((_, file)) = &mut self.file {
+            serde_json::to_writer(&mut *file, frame)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::Recording;
+
+    #[test]
+    fn test_chords_start_split_and_stop() {
+        let directory = std::env::temp_dir().join(format!("recordings-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let recorder = Recorder::new(RecordingConfig {
+            directory: directory.clone(),
+            ..Default::default()
+        });
+        let mut sink = RecordingSink::new(recorder.clone());
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        // nothing is written before the chord was held long enough
+        assert_eq!(recorder.update(true, false, at(0)), None);
+        sink.publish(&TrackedObjects::new(1, 1000, vec![])).unwrap();
+        assert!(!directory.exists());
+        assert_eq!(
+            recorder.update(true, false, at(1000)),
+            Some(RecordingAction::Start)
+        );
+        // keeping it held doesn't stop again
+        assert_eq!(recorder.update(true, false, at(2500)), None);
+        sink.publish(&TrackedObjects::new(2, 2000, vec![])).unwrap();
+        assert_eq!(recorder.update(false, true, at(3000)), None);
+        assert_eq!(
+            recorder.update(false, true, at(4000)),
+            Some(RecordingAction::Split)
+        );
+        sink.publish(&TrackedObjects::new(3, 3000, vec![])).unwrap();
+        sink.publish(&TrackedObjects::new(4, 4000, vec![])).unwrap();
+        recorder.update(true, false, at(5000));
+        assert_eq!(
+            recorder.update(true, false, at(6000)),
+            Some(RecordingAction::Stop)
+        );
+        sink.publish(&TrackedObjects::new(5, 5000, vec![])).unwrap();
+        sink.stop().unwrap();
+
+        let first = Recording::load(&directory.join("recording-2000.jsonl")).unwrap();
+        assert_eq!(first.frames().len(), 1);
+        let second = Recording::load(&directory.join("recording-3000.jsonl")).unwrap();
+        let seqs: Vec<u64> = second.frames().iter().map(|frame| frame.seq()).collect();
+        assert_eq!(seqs, [3, 4]);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
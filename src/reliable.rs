@@ -0,0 +1,320 @@
+//! Acknowledged delivery of critical event channel messages
+//!
+//! Pose frames are sent once: a lost one is replaced by the next a few
+//! milliseconds later. A lost `tracking_lost` or stream status event isn't. With
+//! `reliable_events` set, the multicast sender marks such messages with
+//! `ack_requested`, sends them from a socket of their own and keeps their
+//! packets. Receivers with a name answer each with an [`Ack`] to the address the
+//! message came from, signed and encrypted like the stream. The sender sends the
+//! packets again every `retry_interval_ms` until every listed receiver, or any
+//! receiver if none are listed, acknowledged, or `max_attempts` ran out.
+//!
+//! Retries repeat the sequence number, so receivers drop messages they already
+//! handled with a [`Deduplicator`].
+
+use crate::events::DeviceEventKind;
+use crate::tracking_messages::TrackedObjects;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Messages remembered by a [`Deduplicator`]
+const REMEMBERED_MESSAGES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReliableConfig {
+    /// Names of the receivers that have to acknowledge, any one receiver if empty
+    pub receivers: Vec<String>,
+    /// Event kinds that need acknowledging, every message with events, markers or a stream status if empty
+    pub kinds: Vec<DeviceEventKind>,
+    pub retry_interval_ms: u64,
+    /// Sends of a message, the first one included, before giving up on it
+    pub max_attempts: u32,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        Self {
+            receivers: vec![],
+            kinds: vec![],
+            retry_interval_ms: 200,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReliableConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.retry_interval_ms == 0 {
+            problems.push("reliable_events.retry_interval_ms must be positive".to_owned());
+        }
+        if self.max_attempts == 0 {
+            problems.push("reliable_events.max_attempts must be positive".to_owned());
+        }
+        problems
+    }
+
+    /// Whether receivers have to acknowledge `frame`
+    pub fn requires_ack(&self, frame: &TrackedObjects) -> bool {
+        if self.kinds.is_empty() {
+            return !frame.events().is_empty()
+                || !frame.markers().is_empty()
+                || frame.status().is_some();
+        }
+        frame
+            .events()
+            .iter()
+            .any(|event| self.kinds.contains(&event.kind))
+    }
+}
+
+/// Answer of a receiver to a message with `ack_requested`
+///
+/// Sent as `{"ack": {"seq": 12, "receiver": "robot"}}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ack {
+    /// Event channel sequence number of the message
+    pub seq: u64,
+    pub receiver: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AckMessage {
+    ack: Ack,
+}
+
+impl Ack {
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(&AckMessage { ack: self.clone() }).unwrap_or_default()
+    }
+
+    /// `None` for anything that isn't an ack
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<AckMessage>(bytes)
+            .ok()
+            .map(|message| message.ack)
+    }
+}
+
+struct Pending {
+    seq: u64,
+    /// Payloads of the packets before signing and encryption, protected again on every retry
+    packets: Vec<Vec<u8>>,
+    address: SocketAddrV4,
+    sent_at: Instant,
+    attempts: u32,
+    acknowledged: Vec<String>,
+}
+
+/// Messages waiting for acknowledgements
+pub struct Retransmitter {
+    config: ReliableConfig,
+    pending: Vec<Pending>,
+    /// Messages given up on
+    failed: u64,
+}
+
+impl Retransmitter {
+    pub fn new(config: ReliableConfig) -> Self {
+        Self {
+            config,
+            pending: vec![],
+            failed: 0,
+        }
+    }
+
+    pub fn config(&self) -> &ReliableConfig {
+        &self.config
+    }
+
+    /// Keep the payloads of message `seq`, just sent to `address` for the first time
+    ///
+    /// Payloads are kept before signing and encryption, which are applied again on every retry.
+    pub fn sent(&mut self, seq: u64, packets: Vec<Vec<u8>>, address: SocketAddrV4, now: Instant) {
+        self.pending.push(Pending {
+            seq,
+            packets,
+            address,
+            sent_at: now,
+            attempts: 1,
+            acknowledged: vec![],
+        });
+    }
+
+    /// Count `ack`, returns whether it completed its message
+    pub fn acknowledge(&mut self, ack: &Ack) -> bool {
+        let receivers = &self.config.receivers;
+        let position = self.pending.iter_mut().position(|pending| {
+            if pending.seq != ack.seq {
+                return false;
+            }
+            if !pending.acknowledged.contains(&ack.receiver) {
+                pending.acknowledged.push(ack.receiver.clone());
+            }
+            receivers.is_empty()
+                || receivers
+                    .iter()
+                    .all(|receiver| pending.acknowledged.contains(receiver))
+        });
+        match position {
+            Some(position) => {
+                self.pending.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Unprotected payloads to send again, with their address
+    ///
+    /// Messages out of attempts are dropped and logged with the receivers that didn't answer.
+    pub fn due(&mut self, now: Instant) -> Vec<(Vec<u8>, SocketAddrV4)> {
+        let interval = Duration::from_millis(self.config.retry_interval_ms);
+        let max_attempts = self.config.max_attempts;
+        let receivers = &self.config.receivers;
+        let failed = &mut self.failed;
+        let mut due = vec![];
+        self.pending.retain_mut(|pending| {
+            if now.duration_since(pending.sent_at) < interval {
+                return true;
+            }
+            if pending.attempts >= max_attempts {
+                let missing: Vec<&str> = receivers
+                    .iter()
+                    .filter(|receiver| !pending.acknowledged.contains(receiver))
+                    .map(String::as_str)
+                    .collect();
+                let missing = if missing.is_empty() {
+                    "any receiver".to_owned()
+                } else {
+                    missing.join(", ")
+                };
+                eprintln!(
+                    "Event seq {} not acknowledged by {} after {} attempts",
+                    pending.seq, missing, pending.attempts
+                );
+                *failed += 1;
+                return false;
+            }
+            pending.attempts += 1;
+            pending.sent_at = now;
+            due.extend(
+                pending
+                    .packets
+                    .iter()
+                    .map(|packet| (packet.clone(), pending.address)),
+            );
+            true
+        });
+        due
+    }
+
+    /// Messages still waiting for acknowledgements
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Messages given up on since startup
+    pub fn failed(&self) -> u64 {
+        self.failed
+    }
+}
+
+/// Recognizes retries of messages a receiver already handled
+#[derive(Default)]
+pub struct Deduplicator {
+    seen: VecDeque<(Option<Arc<str>>, u64)>,
+}
+
+impl Deduplicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// False for a message with `ack_requested` that was seen before, true otherwise
+    pub fn first_time(&mut self, frame: &TrackedObjects) -> bool {
+        if !frame.ack_requested() {
+            return true;
+        }
+        let key = (frame.sender().map(|sender| sender.id.clone()), frame.seq());
+        if self.seen.contains(&key) {
+            return false;
+        }
+        if self.seen.len() == REMEMBERED_MESSAGES {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::DeviceEvent;
+    use crate::tracking_messages::Channel;
+
+    #[test]
+    fn test_retry_until_every_receiver_acknowledged() {
+        let config = ReliableConfig {
+            receivers: vec!["robot".to_owned(), "show".to_owned()],
+            retry_interval_ms: 100,
+            max_attempts: 3,
+            ..Default::default()
+        };
+        let mut frame = TrackedObjects::heartbeat(7, 0);
+        frame.set_channel(Channel::Event);
+        assert!(!config.requires_ack(&frame));
+        frame.set_events(vec![DeviceEvent {
+            id: Some(1),
+            serial: None,
+            name: None,
+            kind: DeviceEventKind::TrackingLost,
+            battery: None,
+            dropout_s: None,
+            distance_m: None,
+        }]);
+        assert!(config.requires_ack(&frame));
+
+        let address = "239.0.0.22:7070".parse().unwrap();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut retransmitter = Retransmitter::new(config);
+        retransmitter.sent(7, vec![b"seven".to_vec()], address, start);
+        retransmitter.sent(8, vec![b"eight".to_vec()], address, start);
+        assert!(retransmitter.due(at(50)).is_empty());
+        assert_eq!(retransmitter.due(at(100)).len(), 2);
+
+        let ack = |seq, receiver: &str| {
+            Ack::decode(
+                &Ack {
+                    seq,
+                    receiver: receiver.to_owned(),
+                }
+                .encode(),
+            )
+            .unwrap()
+        };
+        assert!(!retransmitter.acknowledge(&ack(7, "robot")));
+        assert!(!retransmitter.acknowledge(&ack(7, "robot")));
+        assert!(retransmitter.acknowledge(&ack(7, "show")));
+        assert_eq!(
+            retransmitter.due(at(200)),
+            vec![(b"eight".to_vec(), address)]
+        );
+        // third attempt was the last
+        assert!(retransmitter.due(at(300)).is_empty());
+        assert_eq!(retransmitter.pending(), 0);
+        assert_eq!(retransmitter.failed(), 1);
+
+        frame.set_ack_requested(true);
+        let mut deduplicator = Deduplicator::new();
+        assert!(deduplicator.first_time(&frame));
+        assert!(!deduplicator.first_time(&frame));
+        assert_eq!(Ack::decode(b"{\"seq\": 7}"), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Playback of recorded streams
+//!
+//! A recording is the output of `openvr-tracker-recv`, one JSON frame per
+//! line, after a session header in files the tracker wrote itself. Frames are
+//! played with their original spacing, scaled by the speed.
+
+use crate::config::read_file;
+use crate::error::{Error, Result};
+use crate::session::SessionHeader;
+use crate::tracking_messages::TrackedObjects;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayOptions {
+    /// 2.0 plays twice as fast, 0.5 in slow motion
+    pub speed: f32,
+    /// Start over after the last frame
+    pub looped: bool,
+    /// Replace timestamps with the wall clock time of playback
+    pub restamp: bool,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            looped: false,
+            restamp: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    header: Option<SessionHeader>,
+    frames: Vec<TrackedObjects>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = read_file(path, "recording")?;
+        Self::parse(&text).map_err(|error| {
+            Error::config_caused(
+                format!("Failed to parse recording {}", path.display()),
+                error,
+            )
+        })
+    }
+
+    /// Frames from JSON lines, after an optional session header. Blank lines are skipped
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut header = None;
+        let mut frames = vec![];
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if frames.is_empty() && header.is_none() {
+                header = SessionHeader::parse(line);
+                if header.is_some() {
+                    continue;
+                }
+            }
+            let frame = serde_json::from_str(line)
+                .map_err(|error| Error::config_caused(format!("Line {}", number + 1), error))?;
+            frames.push(frame);
+        }
+        if frames.is_empty() {
+            return Err(Error::config("Recording has no frames"));
+        }
+        Ok(Self { header, frames })
+    }
+
+    /// Session header of files written by the tracker
+    pub fn header(&self) -> Option<&SessionHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn frames(&self) -> &[TrackedObjects] {
+        &self.frames
+    }
+
+    /// Milliseconds from the first to the last frame
+    fn duration_ms(&self) -> u128 {
+        let first = self.frames[0].ts();
+        self.frames
+            .iter()
+            .map(|frame| frame.ts())
+            .max()
+            .unwrap_or(first)
+            - first
+    }
+}
+
+/// Yields frames of a recording with the time they are due
+pub struct Player<'a> {
+    recording: &'a Recording,
+    options: ReplayOptions,
+    /// Wall clock time in milliseconds when playback started
+    start_ts: u128,
+    index: usize,
+    lap: u64,
+    /// Recorded time of one pass, including the gap before starting over
+    lap_ms: u128,
+    /// Sequence numbers of one pass, so looped frames keep counting up
+    seq_span: u64,
+}
+
+impl<'a> Player<'a> {
+    pub fn new(recording: &'a Recording, options: ReplayOptions, start_ts: u128) -> Self {
+        let frames = recording.frames();
+        let duration_ms = recording.duration_ms();
+        // one average frame interval between the last frame and the first of the next lap
+        let gap_ms = duration_ms / (frames.len() as u128).saturating_sub(1).max(1);
+        Self {
+            recording,
+            options,
+            start_ts,
+            index: 0,
+            lap: 0,
+            lap_ms: duration_ms + gap_ms,
+            seq_span: frames.iter().map(|frame| frame.seq()).max().unwrap_or(0) + 1,
+        }
+    }
+
+    /// Next frame and its time since playback started, `None` at the end
+    pub fn next_frame(&mut self) -> Option<(Duration, TrackedObjects)> {
+        if self.index == self.recording.frames().len() {
+            if !self.options.looped {
+                return None;
+            }
+            self.index = 0;
+            self.lap += 1;
+        }
+        let mut frame = self.recording.frames()[self.index].clone();
+        self.index += 1;
+
+        let recorded_ms = frame.ts().saturating_sub(self.recording.frames()[0].ts())
+            + self.lap as u128 * self.lap_ms;
+        let offset = Duration::from_secs_f64(recorded_ms as f64 / 1000.0 / self.speed());
+        if self.options.restamp {
+            frame.set_ts(self.start_ts + offset.as_millis());
+        }
+        frame.offset_seq(self.lap * self.seq_span);
+        Some((offset, frame))
+    }
+
+    fn speed(&self) -> f64 {
+        (self.options.speed as f64).max(0.001)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looped_restamped_playback() {
+        let recording = Recording::parse(
+            r#"{"seq":0,"ts":1000,"trackers":[]}
+
+{"seq":1,"ts":1100,"trackers":[]}
+{"seq":2,"ts":1200,"partial":true,"keyframe_seq":0,"trackers":[]}"#,
+        )
+        .unwrap();
+        let options = ReplayOptions {
+            speed: 2.0,
+            looped: true,
+            restamp: true,
+        };
+        let mut player = Player::new(&recording, options, 50_000);
+        let played: Vec<(Duration, TrackedObjects)> =
+            (0..4).filter_map(|_| player.next_frame()).collect();
+        let offsets: Vec<u128> = played
+            .iter()
+            .map(|(offset, _)| offset.as_millis())
+            .collect();
+        assert_eq!(offsets, vec![0, 50, 100, 150]);
+        assert_eq!(played[2].1.ts(), 50_100);
+        assert_eq!(played[3].1.seq(), 3);
+
+        let mut player = Player::new(&recording, ReplayOptions::default(), 0);
+        let last = (0..3).filter_map(|_| player.next_frame()).last().unwrap().1;
+        assert_eq!(last.ts(), 1200);
+        assert_eq!(last.keyframe_seq(), Some(0));
+        assert!(player.next_frame().is_none());
+        assert!(Recording::parse("{\"seq\":0}\n").is_err());
+    }
+}
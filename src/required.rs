@@ -0,0 +1,179 @@
+//! Devices a session can't do without
+//!
+//! A tracker that never joined otherwise only shows up as a gap in the data
+//! afterwards. With `required_devices` set, a listed device that isn't tracked
+//! for `missing_after_s`, counted from startup for one that never appeared,
+//! raises one `missing` event and stays in the `missing` list of the status
+//! until it is tracked again.
+
+use crate::config::device_named;
+use crate::events::{DeviceEvent, DeviceEventKind};
+use crate::tracking_messages::VrDevice;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequiredDevicesConfig {
+    /// Serials or aliases
+    pub devices: Vec<String>,
+    /// Seconds a device may go untracked before it counts as missing
+    pub missing_after_s: f32,
+}
+
+impl Default for RequiredDevicesConfig {
+    fn default() -> Self {
+        Self {
+            devices: vec![],
+            missing_after_s: 10.0,
+        }
+    }
+}
+
+impl RequiredDevicesConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.missing_after_s.is_nan() || self.missing_after_s < 0.0 {
+            problems.push(format!(
+                "required_devices.missing_after_s must not be negative, it is {}",
+                self.missing_after_s
+            ));
+        }
+        for (index, name) in self.devices.iter().enumerate() {
+            if self.devices[..index].contains(name) {
+                problems.push(format!("Required device {} is listed twice", name));
+            }
+        }
+        problems
+    }
+}
+
+struct Required {
+    name: String,
+    /// When the device was last tracked, or when it became required
+    present_at: Instant,
+    missing: bool,
+}
+
+/// Follows whether the required devices are tracked
+#[derive(Default)]
+pub struct RequiredDeviceGuard {
+    required: Vec<Required>,
+}
+
+impl RequiredDeviceGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `missing` events for devices that just went missing
+    ///
+    /// Devices added to the config get the full `missing_after_s` from now.
+    pub fn check(
+        &mut self,
+        devices: &[VrDevice],
+        config: &RequiredDevicesConfig,
+        aliases: &HashMap<String, Arc<str>>,
+        now: Instant,
+    ) -> Vec<DeviceEvent> {
+        self.required
+            .retain(|required| config.devices.contains(&required.name));
+        for name in &config.devices {
+            if !self.required.iter().any(|required| required.name == *name) {
+                self.required.push(Required {
+                    name: name.clone(),
+                    present_at: now,
+                    missing: false,
+                });
+            }
+        }
+        let threshold = Duration::from_secs_f32(config.missing_after_s.max(0.0));
+        let mut events = vec![];
+        for required in &mut self.required {
+            let device = devices
+                .iter()
+                .filter(|device| device.seen())
+                .find(|device| device_named(device, &required.name, aliases));
+            if matches!(device, Some(device) if device.tracked()) {
+                if required.missing {
+                    eprintln!("Required device {} is back", required.name);
+                }
+                required.present_at = now;
+                required.missing = false;
+            } else if !required.missing && now.duration_since(required.present_at) >= threshold {
+                required.missing = true;
+                events.push(DeviceEvent {
+                    id: device.map(VrDevice::id),
+                    serial: device.and_then(VrDevice::serial).map(Arc::from),
+                    name: Some(required.name.as_str().into()),
+                    kind: DeviceEventKind::Missing,
+                    battery: None,
+                    dropout_s: None,
+                    distance_m: None,
+                });
+            }
+        }
+        events
+    }
+
+    /// Names of the required devices currently missing
+    pub fn missing(&self) -> Vec<String> {
+        self.required
+            .iter()
+            .filter(|required| required.missing)
+            .map(|required| required.name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IDENTITY_POSE;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_missing_once_until_tracked_again() {
+        let config = RequiredDevicesConfig {
+            devices: vec!["chest".to_owned(), "LHR-2".to_owned()],
+            missing_after_s: 5.0,
+        };
+        assert!(config.problems().is_empty());
+        let aliases: HashMap<String, Arc<str>> = vec![("LHR-1".to_owned(), "chest".into())]
+            .into_iter()
+            .collect();
+        let mut chest = VrDevice::new(3);
+        chest.update(true, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        chest.set_serial(Some("LHR-1".into()));
+        let start = Instant::now();
+        let mut guard = RequiredDeviceGuard::new();
+        let at = |s| start + Duration::from_secs(s);
+
+        assert!(guard
+            .check(&[chest.clone()], &config, &aliases, at(0))
+            .is_empty());
+        let events = guard.check(&[chest.clone()], &config, &aliases, at(5));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, DeviceEventKind::Missing);
+        assert_eq!(events[0].id, None);
+        assert_eq!(events[0].name.as_deref(), Some("LHR-2"));
+
+        chest.update(false, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        assert!(guard
+            .check(&[chest.clone()], &config, &aliases, at(9))
+            .is_empty());
+        let events = guard.check(&[chest.clone()], &config, &aliases, at(10));
+        assert_eq!(events[0].id, Some(3));
+        assert_eq!(events[0].serial.as_deref(), Some("LHR-1"));
+        assert_eq!(guard.missing(), vec!["chest", "LHR-2"]);
+        assert!(guard
+            .check(&[chest.clone()], &config, &aliases, at(20))
+            .is_empty());
+
+        chest.update(true, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        guard.check(&[chest], &config, &aliases, at(21));
+        assert_eq!(guard.missing(), vec!["LHR-2"]);
+    }
+}
@@ -0,0 +1,157 @@
+//! Hand-off of device state from the polling thread to the publishing thread
+//!
+//! Polling only ever copies devices into a bounded channel, so a slow sink can't
+//! delay sampling. When the publisher falls behind, new snapshots are dropped
+//! instead of blocking. Device buffers are sent back and reused.
+
+use crate::error::{Error, Result};
+use crate::tracking_messages::VrDevice;
+use std::sync::mpsc;
+
+/// Device state at one point in time
+pub struct Snapshot {
+    ts: u128,
+    frame_count: Option<u64>,
+    devices: Vec<VrDevice>,
+}
+
+impl Snapshot {
+    /// Time of sampling in milliseconds since the Unix epoch
+    pub fn ts(&self) -> u128 {
+        self.ts
+    }
+
+    /// Frames the runtime had counted, `None` if it doesn't count them
+    pub fn frame_count(&self) -> Option<u64> {
+        self.frame_count
+    }
+
+    pub fn devices(&self) -> &[VrDevice] {
+        &self.devices
+    }
+}
+
+/// Create a connected pair holding at most `capacity` unread snapshots
+pub fn snapshot_channel(capacity: usize) -> (SnapshotSender, SnapshotReceiver) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let (recycle, recycled) = mpsc::channel();
+    (
+        SnapshotSender {
+            sender,
+            recycled,
+            dropped: 0,
+        },
+        SnapshotReceiver {
+            receiver,
+            recycle,
+            latest: None,
+        },
+    )
+}
+
+pub struct SnapshotSender {
+    sender: mpsc::SyncSender<Snapshot>,
+    recycled: mpsc::Receiver<Vec<VrDevice>>,
+    dropped: u64,
+}
+
+impl SnapshotSender {
+    /// Copy devices into a snapshot without waiting for the publisher
+    ///
+    /// Fails once the receiving side is gone.
+    pub fn send(&mut self, devices: &[VrDevice], frame_count: Option<u64>, ts: u128) -> Result<()> {
+        let mut buffer = self.recycled.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(devices);
+        match self.sender.try_send(Snapshot {
+            ts,
+            frame_count,
+            devices: buffer,
+        }) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped += 1;
+                Ok(())
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                Err(Error::Closed("Publisher stopped".to_owned()))
+            }
+        }
+    }
+
+    /// Snapshots dropped because the publisher was behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+pub struct SnapshotReceiver {
+    receiver: mpsc::Receiver<Snapshot>,
+    recycle: mpsc::Sender<Vec<VrDevice>>,
+    latest: Option<Snapshot>,
+}
+
+impl SnapshotReceiver {
+    /// Newest snapshot sampled since the last call
+    ///
+    /// Returns `Ok(None)` if nothing new arrived and fails once the sender is gone.
+    pub fn latest(&mut self) -> Result<Option<&Snapshot>> {
+        let mut received = false;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(snapshot) => {
+                    received = true;
+                    if let Some(previous) = self.latest.replace(snapshot) {
+                        // the sender may already be gone, the buffer is just freed then
+                        let _ = self.recycle.send(previous.devices);
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) if !received => {
+                    return Err(Error::Closed("Sampling stopped".to_owned()))
+                }
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        }
+        if received {
+            Ok(self.latest.as_ref())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Newest snapshot received so far, whether it was seen before or not
+    pub fn current(&self) -> Option<&Snapshot> {
+        self.latest.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_newest_and_drops_when_full() {
+        let (mut sender, mut receiver) = snapshot_channel(2);
+        let devices = vec![VrDevice::new(0)];
+        sender.send(&devices, None, 1).unwrap();
+        sender.send(&devices, None, 2).unwrap();
+        sender.send(&devices, None, 3).unwrap();
+        assert_eq!(sender.dropped(), 1);
+
+        let snapshot = receiver.latest().unwrap().unwrap();
+        assert_eq!(snapshot.ts(), 2);
+        assert_eq!(snapshot.devices().len(), 1);
+        assert!(receiver.latest().unwrap().is_none());
+
+        sender.send(&[], None, 4).unwrap();
+        assert_eq!(receiver.latest().unwrap().unwrap().ts(), 4);
+
+        drop(sender);
+        assert!(receiver.latest().is_err());
+        drop(receiver);
+        let (mut sender, receiver) = snapshot_channel(1);
+        drop(receiver);
+        assert!(sender.send(&devices, None, 5).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! JSON Schema of the wire format
+//!
+//! Written by hand to match the serde layout of [`crate::tracking_messages`].
+//! The tests serialize a frame with every field set and check that the schema
+//! declares all of them, so new fields can't be forgotten here.
+
+use serde_json::{json, Value};
+
+const DEVICE_CLASSES: [&str; 8] = [
+    "Controller",
+    "LeftController",
+    "RightController",
+    "Tracker",
+    "HMD",
+    "Sensor",
+    "Other",
+    "Camera",
+];
+
+const DEVICE_ROLES: [&str; 19] = [
+    "head",
+    "left_hand",
+    "right_hand",
+    "waist",
+    "chest",
+    "left_foot",
+    "right_foot",
+    "left_knee",
+    "right_knee",
+    "left_ankle",
+    "right_ankle",
+    "left_shoulder",
+    "right_shoulder",
+    "left_elbow",
+    "right_elbow",
+    "left_wrist",
+    "right_wrist",
+    "camera",
+    "keyboard",
+];
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/definitions/{}", name) })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn universe_pose() -> Value {
+    json!({
+        "type": "object",
+        "required": ["universe", "position", "rotation"],
+        "properties": {
+            "universe": { "enum": ["standing", "seated", "raw"] },
+            "position": { "type": "array", "items": { "type": "number" }, "minItems": 3, "maxItems": 3 },
+            "rotation": { "type": "array", "items": { "type": "number" }, "minItems": 4, "maxItems": 4 }
+        }
+    })
+}
+
+/// Draft-07 JSON Schema of a message in the default JSON layout
+pub fn json_schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "TrackedObjects",
+        "description": "One message of the openvr-tracker stream",
+        "type": "object",
+        "required": ["ts", "trackers"],
+        "properties": {
+            "seq": { "type": "integer", "minimum": 0, "description": "Message counter, counted per channel" },
+            "ts": { "type": "integer", "minimum": 0, "description": "Milliseconds since the Unix epoch" },
+            "sender": reference("SenderIdentity"),
+            "time_source": { "enum": ["wall", "monotonic", "disciplined", "ptp"], "description": "Clock of ts, missing means wall" },
+            "partial": { "type": "boolean", "description": "Only contains devices that changed since the keyframe" },
+            "keyframe_seq": { "type": "integer", "minimum": 0 },
+            "heartbeat": { "type": "boolean" },
+            "extrapolated": { "type": "boolean", "description": "Poses were predicted from velocities between samples" },
+            "channel": { "enum": ["pose", "event"], "description": "Missing means pose" },
+            "ack_requested": { "type": "boolean", "description": "Named receivers should acknowledge the message" },
+            "events": { "type": "array", "items": reference("DeviceEvent") },
+            "markers": { "type": "array", "items": reference("Marker") },
+            "status": { "enum": ["stale", "recovered", "paused", "resumed"] },
+            "diagnostics": { "type": "array", "items": reference("DeviceQuality") },
+            "display": reference("DisplayInfo"),
+            "devices": { "type": "array", "items": reference("DeviceMetadata") },
+            "play_area": {
+                "type": "array",
+                "description": "Corners of the play area rectangle, x, y, z each",
+                "items": { "type": "array", "items": { "type": "number" }, "minItems": 3, "maxItems": 3 },
+                "minItems": 4,
+                "maxItems": 4
+            },
+            "trackers": { "type": "array", "items": reference("VrDevice") },
+            "transforms": { "type": "array", "items": reference("FrameTransform") },
+            "relative": { "type": "array", "items": reference("RelativePose") }
+        },
+        "definitions": {
+            "VrDeviceClass": { "enum": DEVICE_CLASSES },
+            "DeviceRole": { "enum": DEVICE_ROLES },
+            "SenderIdentity": {
+                "type": "object",
+                "required": ["id"],
+                "properties": {
+                    "id": { "type": "string" },
+                    "hostname": string()
+                }
+            },
+            "VrDevice": {
+                "type": "object",
+                "required": ["id", "tracked", "seen", "position", "rotation", "class"],
+                "properties": {
+                    "id": { "type": "integer", "minimum": 0 },
+                    "serial": string(),
+                    "alias": string(),
+                    "guid": string(),
+                    "source": string(),
+                    "tracked": { "type": "boolean" },
+                    "seen": { "type": "boolean" },
+                    "battery": { "type": "number", "minimum": 0, "maximum": 1 },
+                    "state": {
+                        "enum": ["ok", "out_of_range", "calibrating", "rotation_only", "disconnected", "uninitialized"],
+                        "description": "Missing means ok"
+                    },
+                    "dongle": string(),
+                    "position": {
+                        "type": "array",
+                        "description": "x, y, z in meters",
+                        "items": { "type": "number" },
+                        "minItems": 3,
+                        "maxItems": 3
+                    },
+                    "rotation": {
+                        "type": "array",
+                        "description": "Unit quaternion x, y, z, w",
+                        "items": { "type": "number" },
+                        "minItems": 4,
+                        "maxItems": 4
+                    },
+                    "class": reference("VrDeviceClass"),
+                    "role": reference("DeviceRole"),
+                    "universes": {
+                        "type": "array",
+                        "description": "Poses in other tracking universes, before calibration",
+                        "items": universe_pose()
+                    },
+                    "forward": {
+                        "type": "array",
+                        "description": "Unit vector along -z of the rotation",
+                        "items": { "type": "number" },
+                        "minItems": 3,
+                        "maxItems": 3
+                    },
+                    "up": {
+                        "type": "array",
+                        "description": "Unit vector along y of the rotation",
+                        "items": { "type": "number" },
+                        "minItems": 3,
+                        "maxItems": 3
+                    },
+                    "covariance": {
+                        "type": "array",
+                        "description": "6x6 pose covariance, x, y, z then rotation about x, y, z",
+                        "items": { "type": "number" },
+                        "minItems": 36,
+                        "maxItems": 36
+                    },
+                    "outside_workspace": {
+                        "type": "boolean",
+                        "description": "Pose was outside of its workspace limits, and clamped into them if configured"
+                    }
+                }
+            },
+            "FrameTransform": {
+                "type": "object",
+                "description": "Pose of child in the parent frame",
+                "required": ["id", "parent", "child", "translation", "rotation"],
+                "properties": {
+                    "id": { "type": "integer", "minimum": 0, "description": "Device the child moves with" },
+                    "parent": string(),
+                    "child": string(),
+                    "translation": { "type": "array", "items": { "type": "number" }, "minItems": 3, "maxItems": 3 },
+                    "rotation": { "type": "array", "items": { "type": "number" }, "minItems": 4, "maxItems": 4 }
+                }
+            },
+            "RelativePose": {
+                "type": "object",
+                "description": "Pose and velocity of device id in the frame of device reference_id",
+                "required": ["name", "id", "reference_id", "translation", "rotation", "velocity", "angular_velocity"],
+                "properties": {
+                    "name": string(),
+                    "id": { "type": "integer", "minimum": 0 },
+                    "reference_id": { "type": "integer", "minimum": 0 },
+                    "translation": { "type": "array", "items": { "type": "number" }, "minItems": 3, "maxItems": 3 },
+                    "rotation": { "type": "array", "items": { "type": "number" }, "minItems": 4, "maxItems": 4 },
+                    "velocity": { "type": "array", "items": { "type": "number" }, "minItems": 3, "maxItems": 3 },
+                    "angular_velocity": { "type": "array", "items": { "type": "number" }, "minItems": 3, "maxItems": 3 }
+                }
+            },
+            "DeviceMetadata": {
+                "type": "object",
+                "required": ["id", "class"],
+                "properties": {
+                    "id": { "type": "integer", "minimum": 0 },
+                    "serial": string(),
+                    "alias": string(),
+                    "guid": string(),
+                    "class": reference("VrDeviceClass"),
+                    "battery": { "type": "number", "minimum": 0, "maximum": 1 },
+                    "dongle": string()
+                }
+            },
+            "DisplayInfo": {
+                "type": "object",
+                "required": ["render_width", "render_height"],
+                "properties": {
+                    "ipd_m": { "type": "number" },
+                    "refresh_rate_hz": { "type": "number" },
+                    "render_width": { "type": "integer", "minimum": 0 },
+                    "render_height": { "type": "integer", "minimum": 0 }
+                }
+            },
+            "Marker": {
+                "type": "object",
+                "required": ["name", "ts", "source"],
+                "properties": {
+                    "name": string(),
+                    "ts": { "type": "integer", "minimum": 0, "description": "Milliseconds since the Unix epoch when the marker was set" },
+                    "source": { "enum": ["control", "key", "chord"] }
+                }
+            },
+            "DeviceEvent": {
+                "type": "object",
+                "required": ["kind"],
+                "properties": {
+                    "id": { "type": "integer", "minimum": 0 },
+                    "serial": string(),
+                    "name": string(),
+                    "kind": { "enum": ["connected", "disconnected", "low_battery", "tracking_lost", "tracking_recovered", "missing", "near_boundary", "boundary_clear"] },
+                    "battery": { "type": "number", "minimum": 0, "maximum": 1 },
+                    "dropout_s": { "type": "number", "minimum": 0 },
+                    "distance_m": { "type": "number" }
+                }
+            },
+            "DeviceQuality": {
+                "type": "object",
+                "required": ["id", "class", "tracked_percent", "dropouts", "radio_dropouts", "longest_dropout_s"],
+                "properties": {
+                    "id": { "type": "integer", "minimum": 0 },
+                    "serial": string(),
+                    "alias": string(),
+                    "class": reference("VrDeviceClass"),
+                    "tracked_percent": { "type": "number" },
+                    "dropouts": { "type": "integer", "minimum": 0 },
+                    "radio_dropouts": { "type": "integer", "minimum": 0 },
+                    "longest_dropout_s": { "type": "number" },
+                    "jitter_mm": { "type": "number" },
+                    "noise_floor_mm": { "type": "number" }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{DeviceEvent, DeviceEventKind};
+    use crate::identity::SenderIdentity;
+    use crate::markers::{Marker, MarkerSource};
+    use crate::quality::DeviceQuality;
+    use crate::tracking_messages::{
+        Channel, DeviceMetadata, DeviceRole, DisplayInfo, FrameTransform, RelativePose,
+        StreamStatus, TimeSource, TrackedObjects, TrackingState, Universe, UniversePose, VrDevice,
+        VrDeviceClass,
+    };
+
+    fn resolve<'a>(schema: &'a Value, node: &'a Value) -> &'a Value {
+        match node["$ref"].as_str() {
+            Some(path) => {
+                let name = path.trim_start_matches("#/definitions/");
+                &schema["definitions"][name]
+            }
+            None => node,
+        }
+    }
+
+    fn check(schema: &Value, node: &Value, value: &Value, path: &str) {
+        let node = resolve(schema, node);
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields {
+                    let declared = &node["properties"][key];
+                    assert!(!declared.is_null(), "{}.{} missing in schema", path, key);
+                    check(schema, declared, field, &format!("{}.{}", path, key));
+                }
+                for required in node["required"].as_array().into_iter().flatten() {
+                    let required = required.as_str().unwrap();
+                    assert!(
+                        fields.contains_key(required),
+                        "{}.{} required",
+                        path,
+                        required
+                    );
+                }
+            }
+            Value::Array(items) if !node["items"].is_null() => {
+                for item in items {
+                    check(schema, &node["items"], item, path);
+                }
+            }
+            Value::String(text) if !node["enum"].is_null() => {
+                let allowed = node["enum"].as_array().unwrap();
+                assert!(allowed.contains(value), "{} = {} not in enum", path, text);
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_schema_covers_every_field() {
+        let mut device = VrDevice::new(1);
+        device.set_serial(Some("LHR-1".into()));
+        device.set_alias(Some("waist".into()));
+        device.set_source(Some("north".into()));
+        device.set_battery(Some(0.5));
+        device.set_state(TrackingState::RotationOnly);
+        device.set_dongle(Some("dongle".into()));
+        device.set_class(VrDeviceClass::Tracker);
+        device.set_role(Some(DeviceRole::Waist));
+        device.universes_mut().push(UniversePose {
+            universe: Universe::Raw,
+            position: nalgebra::Point3::origin(),
+            rotation: nalgebra::UnitQuaternion::identity(),
+        });
+        device.set_directions();
+        device.set_covariance(Some(nalgebra::Matrix6::identity()));
+        device.set_outside_workspace(true);
+        let mut frame = TrackedObjects::new(3, 4, vec![device]);
+        frame.set_delta_of(2);
+        frame.set_sender(Some(SenderIdentity::new(None)));
+        frame.set_time_source(Some(TimeSource::Ptp));
+        frame.set_channel(Channel::Event);
+        frame.set_ack_requested(true);
+        frame.set_status(Some(StreamStatus::Stale));
+        frame.set_events(vec![DeviceEvent {
+            id: Some(1),
+            serial: Some("LHR-1".into()),
+            name: None,
+            kind: DeviceEventKind::LowBattery,
+            battery: Some(0.1),
+            dropout_s: None,
+            distance_m: None,
+        }]);
+        frame.set_markers(vec![Marker {
+            name: "trial 3 start".into(),
+            ts: 4,
+            source: MarkerSource::Control,
+        }]);
+        frame.set_diagnostics(vec![DeviceQuality {
+            id: 1,
+            serial: Some("LHR-1".into()),
+            alias: Some("waist".into()),
+            class: VrDeviceClass::Tracker,
+            tracked_percent: 99.0,
+            dropouts: 1,
+            radio_dropouts: 0,
+            longest_dropout_s: 0.5,
+            jitter_mm: Some(0.2),
+            noise_floor_mm: Some(0.3),
+        }]);
+        frame.set_devices(vec![DeviceMetadata::from(&frame.trackers()[0])]);
+        frame.set_play_area(Some([[1.0, 0.0, 1.0]; 4]));
+        frame.transforms_mut().push(FrameTransform {
+            id: 1,
+            parent: "world".into(),
+            child: "waist".into(),
+            translation: nalgebra::Vector3::zeros(),
+            rotation: nalgebra::UnitQuaternion::identity(),
+        });
+        frame.relative_mut().push(RelativePose {
+            name: "tool_in_workpiece".into(),
+            id: 1,
+            reference_id: 2,
+            translation: nalgebra::Vector3::zeros(),
+            rotation: nalgebra::UnitQuaternion::identity(),
+            velocity: nalgebra::Vector3::zeros(),
+            angular_velocity: nalgebra::Vector3::zeros(),
+        });
+        frame.set_display(Some(DisplayInfo {
+            ipd_m: Some(0.063),
+            refresh_rate_hz: Some(90.0),
+            render_width: 2016,
+            render_height: 2240,
+        }));
+        // through text, `to_value` can't hold the u128 timestamp
+        let value: Value = serde_json::from_str(&serde_json::to_string(&frame).unwrap()).unwrap();
+
+        let schema = json_schema();
+        check(&schema, &schema, &value, "frame");
+    }
+}
@@ -0,0 +1,196 @@
+//! Header identifying the session a recording belongs to
+//!
+//! Recordings and black box dumps start with one line of session metadata
+//! before the frames: the session name and subject given with `--session`
+//! and `--subject`, the sender, the tracker version, the calibration and
+//! heading in effect and which serial and alias each device id had. A file
+//! found weeks later still says what it is.
+//!
+//! The publishing thread keeps the header current in a shared [`Session`],
+//! the sinks write it whenever they open a file. [`crate::replay::Recording`]
+//! reads it back and plays files with and without one.
+
+use crate::config::{Config, HeadingConfig};
+use crate::error::Result;
+use crate::identity::SenderIdentity;
+use crate::tracking_messages::{DeviceRole, VrDevice, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionHeader {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Subject or participant ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub sender: SenderIdentity,
+    /// Version of the tracker that wrote the file
+    pub version: String,
+    /// Milliseconds since the Unix epoch when the file was started, the trigger time for black box dumps
+    pub started: u128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<na::Isometry3<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<HeadingConfig>,
+    /// Devices seen so far that have a serial
+    pub devices: Vec<SessionDevice>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionDevice {
+    pub id: usize,
+    pub serial: Arc<str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<Arc<str>>,
+    pub class: VrDeviceClass,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<DeviceRole>,
+}
+
+/// Line the header is written as, `{"session": {...}}`
+#[derive(Serialize, Deserialize)]
+struct HeaderLine {
+    session: SessionHeader,
+}
+
+impl SessionHeader {
+    /// Write the header as one line of JSON
+    pub fn write(&self, out: &mut impl Write) -> Result<()> {
+        serde_json::to_writer(
+            &mut *out,
+            &HeaderLine {
+                session: self.clone(),
+            },
+        )?;
+        out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Header from a line of a recording, `None` for a frame
+    pub fn parse(line: &str) -> Option<Self> {
+        serde_json::from_str::<HeaderLine>(line)
+            .ok()
+            .map(|line| line.session)
+    }
+}
+
+/// Session metadata shared between the publishing thread and the sinks
+#[derive(Debug, Clone)]
+pub struct Session {
+    header: Arc<Mutex<SessionHeader>>,
+}
+
+impl Session {
+    pub fn new(name: Option<String>, subject: Option<String>, sender: SenderIdentity) -> Self {
+        Self {
+            header: Arc::new(Mutex::new(SessionHeader {
+                name,
+                subject,
+                sender,
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                started: 0,
+                calibration: None,
+                heading: None,
+                devices: vec![],
+            })),
+        }
+    }
+
+    /// Take calibration, heading and the serial map from the latest sample
+    ///
+    /// Devices stay listed once seen, with their latest serial and alias.
+    pub fn update(&self, devices: &[VrDevice], config: &Config) {
+        let mut header = self.header.lock().unwrap();
+        header.calibration = config.calibration;
+        if header.heading != config.heading {
+            header.heading = config.heading.clone();
+        }
+        for device in devices.iter().filter(|device| device.seen()) {
+            let serial = match device.serial() {
+                Some(serial) => serial,
+                None => continue,
+            };
+            let alias = device
+                .alias()
+                .or_else(|| config.aliases.get(serial).map(|alias| &**alias));
+            let listed = header
+                .devices
+                .iter()
+                .position(|listed| listed.id == device.id());
+            if let Some(listed) = listed.map(|index| &header.devices[index]) {
+                let unchanged = &*listed.serial == serial
+                    && listed.alias.as_deref() == alias
+                    && listed.class == device.class()
+                    && listed.role == device.role();
+                if unchanged {
+                    continue;
+                }
+            }
+            let entry = SessionDevice {
+                id: device.id(),
+                serial: serial.into(),
+                alias: alias.map(Arc::from),
+                class: device.class(),
+                role: device.role(),
+            };
+            match listed {
+                Some(index) => header.devices[index] = entry,
+                None => {
+                    header.devices.push(entry);
+                    header.devices.sort_by_key(|device| device.id);
+                }
+            }
+        }
+    }
+
+    /// Header for a file started at `started`
+    pub fn header(&self, started: u128) -> SessionHeader {
+        SessionHeader {
+            started,
+            ..self.header.lock().unwrap().clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IDENTITY_POSE;
+    use crate::replay::Recording;
+    use crate::tracking_messages::TrackedObjects;
+
+    #[test]
+    fn test_header_before_frames() {
+        let sender = SenderIdentity {
+            id: "rig-1".into(),
+            hostname: None,
+        };
+        let session = Session::new(Some("gait".to_owned()), Some("P07".to_owned()), sender);
+        let mut config = Config::default();
+        config.aliases.insert("LHR-1".to_owned(), "waist".into());
+        config.calibration = Some(na::Isometry3::translation(1.0, 0.0, 0.0));
+        let mut device = VrDevice::new(3);
+        device.update(true, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        session.update(&[device.clone()], &config);
+        device.set_serial(Some("LHR-1".into()));
+        session.update(&[device.clone(), VrDevice::new(4)], &config);
+        session.update(&[device], &config);
+
+        let header = session.header(1000);
+        assert_eq!(header.started, 1000);
+        assert_eq!(header.devices.len(), 1);
+        assert_eq!(header.devices[0].alias.as_deref(), Some("waist"));
+        let mut file = vec![];
+        header.write(&mut file).unwrap();
+        let line = String::from_utf8(file.clone()).unwrap();
+        assert!(line
+            .starts_with(r#"{"session":{"name":"gait","subject":"P07","sender":{"id":"rig-1"}"#));
+        serde_json::to_writer(&mut file, &TrackedObjects::new(5, 1001, vec![])).unwrap();
+        let recording = Recording::parse(&String::from_utf8(file).unwrap()).unwrap();
+        assert_eq!(recording.header(), Some(&header));
+        assert_eq!(recording.frames().len(), 1);
+    }
+}
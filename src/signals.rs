@@ -0,0 +1,63 @@
+//! Ctrl-C handling so loops can shut down cleanly, SIGUSR1 to toggle pause and
+//! SIGUSR2 to dump the black box
+//!
+//! Ctrl-C, and SIGTERM on unix, go through the `ctrlc` crate on every platform.
+//! SIGUSR1 and SIGUSR2 only exist on unix, elsewhere [`pause_toggled`] and
+//! [`dump_requested`] never return true.
+
+use crate::error::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static PAUSE_TOGGLED: AtomicBool = AtomicBool::new(false);
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_pause_signal(_: libc::c_int) {
+    PAUSE_TOGGLED.fetch_xor(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn handle_dump_signal(_: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Catch SIGUSR1 to pause and resume instead of terminating the process
+pub fn install_pause_handler() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle_pause_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGUSR1, handler);
+    }
+}
+
+/// Whether SIGUSR1 arrived an odd number of times since the last call
+pub fn pause_toggled() -> bool {
+    PAUSE_TOGGLED.swap(false, Ordering::SeqCst)
+}
+
+/// Catch SIGUSR2 to dump the black box instead of terminating the process
+pub fn install_dump_handler() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle_dump_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGUSR2, handler);
+    }
+}
+
+/// Whether SIGUSR2 arrived since the last call
+pub fn dump_requested() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Catch Ctrl-C, and SIGTERM on unix, instead of terminating the process
+///
+/// Fails if the process already has a handler of its own.
+pub fn install_shutdown_handler() -> Result<()> {
+    ctrlc::set_handler(|| SHUTDOWN.store(true, Ordering::SeqCst))
+        .map_err(|error| Error::external_caused("Failed to catch Ctrl-C", error))
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
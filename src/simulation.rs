@@ -0,0 +1,475 @@
+//! Scripted devices that stand in for OpenVR
+//!
+//! Every device follows a trajectory that only depends on the time since
+//! the simulation started, so runs are repeatable. Random walks are seeded
+//! and advance in fixed steps for the same reason.
+
+use crate::backend::{DeviceSample, TrackingBackend};
+use crate::config::{load_json, read_file};
+use crate::error::{Error, Result};
+use crate::tracking_messages::{TrackingState, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Time step of random walks in seconds
+const WALK_STEP_S: f32 = 0.01;
+/// Time ahead used to find the direction of motion
+const HEADING_LOOKAHEAD_S: f32 = 0.01;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulationConfig {
+    pub devices: Vec<SimulatedDevice>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedDevice {
+    pub serial: String,
+    pub class: VrDeviceClass,
+    pub trajectory: Trajectory,
+    #[serde(default)]
+    pub dropouts: Vec<Dropout>,
+}
+
+/// Path of a device. Devices face the direction they move in, unless waypoints give a rotation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Trajectory {
+    Static {
+        position: [f32; 3],
+    },
+    /// Horizontal circle
+    Circle {
+        center: [f32; 3],
+        radius: f32,
+        period_s: f32,
+    },
+    /// Horizontal figure eight
+    Lemniscate {
+        center: [f32; 3],
+        size: f32,
+        period_s: f32,
+    },
+    /// Linear interpolation between rows of `t,x,y,z` or `t,x,y,z,qx,qy,qz,qw`
+    ///
+    /// The path is relative to the simulation file.
+    Waypoints {
+        csv: PathBuf,
+        #[serde(default)]
+        looped: bool,
+    },
+    /// Wanders at a constant speed, turning randomly and bouncing off the bounds
+    RandomWalk {
+        seed: u64,
+        bounds_min: [f32; 3],
+        bounds_max: [f32; 3],
+        speed_m_s: f32,
+    },
+}
+
+/// Time the device reports `state` instead of a valid pose
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dropout {
+    pub start_s: f32,
+    pub duration_s: f32,
+    /// Repeat the dropout with this period
+    #[serde(default)]
+    pub every_s: Option<f32>,
+    #[serde(default = "out_of_range")]
+    pub state: TrackingState,
+}
+
+fn out_of_range() -> TrackingState {
+    TrackingState::OutOfRange
+}
+
+impl Dropout {
+    fn active(&self, t: f32) -> bool {
+        if t < self.start_s {
+            return false;
+        }
+        let since = match self.every_s {
+            Some(every_s) if every_s > 0.0 => (t - self.start_s) % every_s,
+            _ => t - self.start_s,
+        };
+        since < self.duration_s
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Waypoint {
+    pub t: f32,
+    pub position: na::Point3<f32>,
+    pub rotation: Option<na::UnitQuaternion<f32>>,
+}
+
+/// Parse waypoint rows, skipping blank lines, `#` comments and a header
+pub fn parse_waypoints(text: &str) -> Result<Vec<Waypoint>> {
+    let mut waypoints: Vec<Waypoint> = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values: Result<Vec<f32>, _> = line.split(',').map(|v| v.trim().parse()).collect();
+        let values = match values {
+            Ok(values) => values,
+            Err(_) if waypoints.is_empty() && number == 0 => continue,
+            Err(error) => return Err(Error::config_caused(format!("Line {}", number + 1), error)),
+        };
+        let rotation = match values.len() {
+            4 => None,
+            8 => Some(na::UnitQuaternion::new_normalize(na::Quaternion::new(
+                values[7], values[4], values[5], values[6],
+            ))),
+            count => {
+                return Err(Error::config(format!(
+                    "Line {}: expected 4 or 8 columns, got {}",
+                    number + 1,
+                    count
+                )))
+            }
+        };
+        if let Some(previous) = waypoints.last() {
+            if values[0] < previous.t {
+                return Err(Error::config(format!(
+                    "Line {}: time goes backwards",
+                    number + 1
+                )));
+            }
+        }
+        waypoints.push(Waypoint {
+            t: values[0],
+            position: na::Point3::new(values[1], values[2], values[3]),
+            rotation,
+        });
+    }
+    if waypoints.is_empty() {
+        return Err(Error::config("No waypoints"));
+    }
+    Ok(waypoints)
+}
+
+/// Deterministic xorshift64* generator
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    /// Uniform in -1..1
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let bits = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40;
+        bits as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+struct RandomWalk {
+    random: Random,
+    position: na::Point3<f32>,
+    direction: na::Vector3<f32>,
+    steps: u64,
+}
+
+impl RandomWalk {
+    fn new(seed: u64, bounds_min: [f32; 3], bounds_max: [f32; 3]) -> Self {
+        let center = (na::Vector3::from(bounds_min) + na::Vector3::from(bounds_max)) / 2.0;
+        Self {
+            random: Random::new(seed),
+            position: center.into(),
+            direction: na::Vector3::x(),
+            steps: 0,
+        }
+    }
+
+    fn advance_to(&mut self, t: f32, bounds_min: [f32; 3], bounds_max: [f32; 3], speed: f32) {
+        let target = (t.max(0.0) / WALK_STEP_S) as u64;
+        while self.steps < target {
+            let turn = na::Vector3::new(
+                self.random.next(),
+                self.random.next() * 0.2,
+                self.random.next(),
+            );
+            self.direction = (self.direction + turn * 0.2)
+                .try_normalize(f32::EPSILON)
+                .unwrap_or_else(na::Vector3::x);
+            self.position += self.direction * speed * WALK_STEP_S;
+            for axis in 0..3 {
+                let (min, max) = (bounds_min[axis], bounds_max[axis]);
+                if self.position[axis] < min || self.position[axis] > max {
+                    self.direction[axis] = -self.direction[axis];
+                    self.position[axis] = self.position[axis].clamp(min, max);
+                }
+            }
+            self.steps += 1;
+        }
+    }
+}
+
+struct DeviceState {
+    device: SimulatedDevice,
+    waypoints: Vec<Waypoint>,
+    walk: Option<RandomWalk>,
+}
+
+impl DeviceState {
+    fn position(&mut self, t: f32) -> na::Point3<f32> {
+        match &self.device.trajectory {
+            Trajectory::Static { position } => (*position).into(),
+            Trajectory::Circle {
+                center,
+                radius,
+                period_s,
+            } => {
+                let angle = 2.0 * PI * t / period_s.max(f32::EPSILON);
+                na::Point3::from(*center)
+                    + na::Vector3::new(angle.cos(), 0.0, angle.sin()) * *radius
+            }
+            Trajectory::Lemniscate {
+                center,
+                size,
+                period_s,
+            } => {
+                let angle = 2.0 * PI * t / period_s.max(f32::EPSILON);
+                let scale = size / (1.0 + angle.sin().powi(2));
+                na::Point3::from(*center)
+                    + na::Vector3::new(angle.cos(), 0.0, angle.sin() * angle.cos()) * scale
+            }
+            Trajectory::Waypoints { looped, .. } => interpolate(&self.waypoints, t, *looped).0,
+            Trajectory::RandomWalk {
+                bounds_min,
+                bounds_max,
+                speed_m_s,
+                ..
+            } => {
+                let walk = self.walk.as_mut().expect("random walk without state");
+                walk.advance_to(t, *bounds_min, *bounds_max, *speed_m_s);
+                walk.position
+            }
+        }
+    }
+
+    fn pose(&mut self, t: f32) -> (na::Point3<f32>, na::UnitQuaternion<f32>) {
+        if let Trajectory::Waypoints { looped, .. } = self.device.trajectory {
+            if let (position, Some(rotation)) = interpolate(&self.waypoints, t, looped) {
+                return (position, rotation);
+            }
+        }
+        if let Trajectory::RandomWalk { .. } = self.device.trajectory {
+            let position = self.position(t);
+            let direction = self
+                .walk
+                .as_ref()
+                .map(|walk| walk.direction)
+                .unwrap_or_default();
+            return (position, heading(&direction));
+        }
+        let position = self.position(t);
+        let ahead = self.position(t + HEADING_LOOKAHEAD_S);
+        (position, heading(&(ahead - position)))
+    }
+}
+
+/// Rotation about the vertical axis that points -z along `direction`
+fn heading(direction: &na::Vector3<f32>) -> na::UnitQuaternion<f32> {
+    if direction.x == 0.0 && direction.z == 0.0 {
+        return na::UnitQuaternion::identity();
+    }
+    na::UnitQuaternion::from_axis_angle(&na::Vector3::y_axis(), (-direction.x).atan2(-direction.z))
+}
+
+/// Position and, if the waypoints have them, rotation at `t`
+fn interpolate(
+    waypoints: &[Waypoint],
+    t: f32,
+    looped: bool,
+) -> (na::Point3<f32>, Option<na::UnitQuaternion<f32>>) {
+    let first = waypoints[0];
+    let last = waypoints[waypoints.len() - 1];
+    let duration = last.t - first.t;
+    let t = if looped && duration > 0.0 {
+        first.t + (t - first.t).rem_euclid(duration)
+    } else {
+        t.clamp(first.t, last.t)
+    };
+    let next = waypoints
+        .iter()
+        .position(|waypoint| waypoint.t >= t)
+        .unwrap_or(0);
+    if next == 0 {
+        return (waypoints[0].position, waypoints[0].rotation);
+    }
+    let (from, to) = (waypoints[next - 1], waypoints[next]);
+    let fraction = if to.t > from.t {
+        (t - from.t) / (to.t - from.t)
+    } else {
+        1.0
+    };
+    let position = from.position + (to.position - from.position) * fraction;
+    let rotation = match (from.rotation, to.rotation) {
+        (Some(from), Some(to)) => Some(from.slerp(&to, fraction)),
+        _ => None,
+    };
+    (position, rotation)
+}
+
+fn pose_matrix(position: &na::Point3<f32>, rotation: &na::UnitQuaternion<f32>) -> [[f32; 4]; 3] {
+    let matrix = rotation.to_rotation_matrix();
+    let mut pose = [[0.0; 4]; 3];
+    for (row, values) in pose.iter_mut().enumerate() {
+        for (column, value) in values.iter_mut().take(3).enumerate() {
+            *value = matrix[(row, column)];
+        }
+        values[3] = position[row];
+    }
+    pose
+}
+
+/// Tracking backend that plays the devices of a [`SimulationConfig`]
+pub struct SimulatedBackend {
+    devices: Vec<DeviceState>,
+    started: Option<Instant>,
+}
+
+impl SimulatedBackend {
+    /// Load simulation from a JSON file, waypoint files are relative to it
+    pub fn load(path: &Path) -> Result<Self> {
+        let config: SimulationConfig = load_json(path, "simulation")?;
+        Self::new(config, path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    /// Simulation with waypoint files relative to `directory`
+    pub fn new(config: SimulationConfig, directory: &Path) -> Result<Self> {
+        let mut devices = vec![];
+        for device in config.devices {
+            let waypoints = match &device.trajectory {
+                Trajectory::Waypoints { csv, .. } => {
+                    let path = directory.join(csv);
+                    let text = read_file(&path, "waypoints")?;
+                    parse_waypoints(&text).map_err(|error| {
+                        Error::config_caused(
+                            format!("Failed to parse waypoints {}", path.display()),
+                            error,
+                        )
+                    })?
+                }
+                _ => vec![],
+            };
+            let walk = match &device.trajectory {
+                Trajectory::RandomWalk {
+                    seed,
+                    bounds_min,
+                    bounds_max,
+                    ..
+                } => Some(RandomWalk::new(*seed, *bounds_min, *bounds_max)),
+                _ => None,
+            };
+            devices.push(DeviceState {
+                device,
+                waypoints,
+                walk,
+            });
+        }
+        Ok(Self {
+            devices,
+            started: None,
+        })
+    }
+
+    /// Samples at `t` seconds into the simulation
+    ///
+    /// Random walks only move forward, so `t` should not decrease between calls.
+    pub fn poll_at(&mut self, t: f32, samples: &mut Vec<DeviceSample>) {
+        samples.clear();
+        for (index, state) in self.devices.iter_mut().enumerate() {
+            let (position, rotation) = state.pose(t);
+            let mut sample =
+                DeviceSample::new(index, state.device.class, pose_matrix(&position, &rotation));
+            let dropout = state
+                .device
+                .dropouts
+                .iter()
+                .find(|dropout| dropout.active(t));
+            if let Some(dropout) = dropout {
+                sample.pose_valid = false;
+                sample.state = dropout.state;
+                sample.connected = dropout.state != TrackingState::Disconnected;
+            }
+            samples.push(sample);
+        }
+    }
+}
+
+impl TrackingBackend for SimulatedBackend {
+    fn poll(&mut self, samples: &mut Vec<DeviceSample>) {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.poll_at(started.elapsed().as_secs_f32(), samples);
+    }
+
+    fn serial(&self, index: usize) -> Option<String> {
+        self.devices
+            .get(index)
+            .map(|state| state.device.serial.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openvr_adaptor::OpenVRPose;
+
+    fn simulation(json: &str) -> SimulatedBackend {
+        SimulatedBackend::new(serde_json::from_str(json).unwrap(), Path::new(".")).unwrap()
+    }
+
+    #[test]
+    fn test_scripted_trajectories_and_dropouts() {
+        let mut backend = simulation(
+            r#"{"devices": [
+                {"serial": "circle", "class": "Tracker",
+                 "trajectory": {"type": "circle", "center": [0, 1, 0], "radius": 2, "period_s": 4},
+                 "dropouts": [{"start_s": 2, "duration_s": 0.5}]},
+                {"serial": "walk", "class": "Tracker",
+                 "trajectory": {"type": "random_walk", "seed": 7, "bounds_min": [-1, 0, -1], "bounds_max": [1, 2, 1], "speed_m_s": 3}}
+            ]}"#,
+        );
+        let mut samples = vec![];
+        backend.poll_at(1.0, &mut samples);
+        assert!((samples[0].pose.to_position() - na::Point3::new(0.0, 1.0, 2.0)).norm() < 1e-4);
+        assert!(samples[0].pose_valid);
+        let first_walk = samples[1].pose.to_position();
+
+        backend.poll_at(2.2, &mut samples);
+        assert!(!samples[0].pose_valid);
+        assert_eq!(samples[0].state, TrackingState::OutOfRange);
+
+        let mut again = simulation(
+            r#"{"devices": [{"serial": "walk", "class": "Tracker",
+                 "trajectory": {"type": "random_walk", "seed": 7, "bounds_min": [-1, 0, -1], "bounds_max": [1, 2, 1], "speed_m_s": 3}}]}"#,
+        );
+        again.poll_at(1.0, &mut samples);
+        assert_eq!(samples[0].pose.to_position(), first_walk);
+        for t in 1..100 {
+            again.poll_at(t as f32, &mut samples);
+            let position = samples[0].pose.to_position();
+            assert!(position.x.abs() <= 1.0 && position.y >= 0.0 && position.y <= 2.0);
+        }
+
+        let waypoints = parse_waypoints("t,x,y,z\n0,0,0,0\n2,2,0,0\n").unwrap();
+        let (position, rotation) = interpolate(&waypoints, 0.5, false);
+        assert_eq!(position, na::Point3::new(0.5, 0.0, 0.0));
+        assert!(rotation.is_none());
+        assert_eq!(interpolate(&waypoints, 2.5, true).0, position);
+        assert!(parse_waypoints("0,0,0,0\n1,1,1").is_err());
+    }
+}
@@ -0,0 +1,1050 @@
+use crate::change_tracker::SkippedChanges;
+use crate::error::{Error, Result};
+use crate::quality::label_value;
+use crate::tracking_messages::TrackedObjects;
+use crate::traffic::Traffic;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Output for tracking frames
+///
+/// Implement this to add a new output without touching the main loop.
+pub trait PoseSink: Send {
+    /// Name used in logs
+    fn name(&self) -> &str;
+
+    /// Called once before the first frame is published
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()>;
+
+    /// Called once when the stream shuts down
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Frames this sink has thrown away so far
+    fn dropped(&self) -> u64 {
+        0
+    }
+
+    /// What this sink has sent, for sinks that write to the network
+    fn traffic(&self) -> Option<&Traffic> {
+        None
+    }
+
+    /// Drops, failures and latency of this sink
+    fn status(&self) -> SinkStatus {
+        SinkStatus {
+            dropped: self.dropped(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Forwards frames to consumers inside the process, such as the visualizer
+///
+/// Frames are dropped rather than blocking when the consumer falls behind.
+pub struct ChannelSink {
+    sender: mpsc::SyncSender<TrackedObjects>,
+}
+
+impl ChannelSink {
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<TrackedObjects>) {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (Self { sender }, receiver)
+    }
+}
+
+impl PoseSink for ChannelSink {
+    fn name(&self) -> &str {
+        "channel"
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        match self.sender.try_send(frame.clone()) {
+            Ok(()) | Err(mpsc::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                Err(Error::Closed("Frame channel closed".to_owned()))
+            }
+        }
+    }
+}
+
+/// What a sink queue does with a frame when it is full
+///
+/// A dropped change-only frame is folded into the next queued frame of its
+/// run, so the sink still gets its devices. If there is none, later partial
+/// frames of that run are dropped too until the next keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued frame to make room
+    DropOldest,
+    /// Discard the frame being published
+    DropNewest,
+    /// Wait until the sink catches up
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Frames that can wait for a sink
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 8,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// When a failing sink is switched off instead of stopping the tracker
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinkHealthConfig {
+    /// Failed publishes in a row that disable a sink
+    pub disable_after_failures: u32,
+    /// Seconds until a disabled sink gets another frame
+    pub retry_after_s: f32,
+}
+
+impl Default for SinkHealthConfig {
+    fn default() -> Self {
+        Self {
+            disable_after_failures: 5,
+            retry_after_s: 10.0,
+        }
+    }
+}
+
+impl SinkHealthConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.disable_after_failures == 0 {
+            problems.push("sink_health.disable_after_failures must be positive".to_owned());
+        }
+        if !self.retry_after_s.is_finite() || self.retry_after_s < 0.0 {
+            problems.push(format!(
+                "sink_health.retry_after_s must not be negative, it is {}",
+                self.retry_after_s
+            ));
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SinkStatus {
+    /// Frames thrown away, by the queue or while disabled
+    pub dropped: u64,
+    /// Failed publishes since startup
+    pub failures: u64,
+    pub disabled: bool,
+    /// Moving average of the time a publish takes
+    pub latency_ms: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Failures and latency of a sink, updated by its worker
+#[derive(Debug, Default)]
+struct SinkHealth {
+    status: SinkStatus,
+    /// Failures since the last publish that went through
+    consecutive: u32,
+    retry_at: Option<Instant>,
+}
+
+impl SinkHealth {
+    /// Whether the sink should get a frame, false while it is disabled
+    fn accepts(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+
+    fn succeeded(&mut self, name: &str, latency: Duration) {
+        let latency_ms = latency.as_secs_f32() * 1000.0;
+        self.status.latency_ms = if self.status.latency_ms > 0.0 {
+            self.status.latency_ms * 0.9 + latency_ms * 0.1
+        } else {
+            latency_ms
+        };
+        if self.status.disabled {
+            eprintln!("Sink {} recovered", name);
+        }
+        self.status.disabled = false;
+        self.consecutive = 0;
+        self.retry_at = None;
+    }
+
+    fn failed(&mut self, name: &str, error: &Error, config: &SinkHealthConfig, now: Instant) {
+        self.status.failures += 1;
+        self.status.last_error = Some(error.describe());
+        self.consecutive += 1;
+        if self.consecutive < config.disable_after_failures {
+            return;
+        }
+        if !self.status.disabled {
+            eprintln!(
+                "Sink {} disabled after {} failures in a row, retrying every {} s: {}",
+                name,
+                self.consecutive,
+                config.retry_after_s,
+                error.describe()
+            );
+        }
+        self.status.disabled = true;
+        self.retry_at = Some(now + Duration::from_secs_f32(config.retry_after_s.max(0.0)));
+    }
+}
+
+struct QueueState {
+    frames: VecDeque<TrackedObjects>,
+    closed: bool,
+    /// Keyframe of partial frames that lost a dropped frame they build on
+    stale_keyframe: Option<u64>,
+}
+
+impl QueueState {
+    /// Fold the dropped `frame` into the queued frame it comes before or after
+    fn discard(&mut self, frame: TrackedObjects, newest: bool) {
+        if !frame.channel().is_pose() || frame.is_heartbeat() {
+            return;
+        }
+        let mut queued = self
+            .frames
+            .iter_mut()
+            .filter(|queued| queued.channel().is_pose() && !queued.is_heartbeat());
+        let neighbour = if newest {
+            queued.next_back()
+        } else {
+            queued.next()
+        };
+        let stale = run(&frame);
+        let kept = match neighbour {
+            Some(queued) if newest => fold(queued, &frame),
+            // a keyframe replaces what the dropped frame had
+            Some(queued) if !queued.partial() => true,
+            Some(queued) => {
+                let mut older = frame;
+                let folded = fold(&mut older, queued);
+                if folded {
+                    *queued = older;
+                }
+                folded
+            }
+            None => false,
+        };
+        if !kept {
+            self.stale_keyframe = stale;
+        }
+    }
+}
+
+/// Keyframe the partial frames after `frame` build on
+fn run(frame: &TrackedObjects) -> Option<u64> {
+    if frame.partial() {
+        frame.keyframe_seq()
+    } else {
+        Some(frame.seq())
+    }
+}
+
+/// Merge the devices of `later` into `frame`, false if it doesn't build on the same keyframe
+fn fold(frame: &mut TrackedObjects, later: &TrackedObjects) -> bool {
+    if !later.partial() || later.keyframe_seq() != run(frame) {
+        return false;
+    }
+    let devices = frame.trackers_mut();
+    for device in later.trackers() {
+        match devices.iter_mut().find(|known| known.id() == device.id()) {
+            Some(known) => *known = device.clone(),
+            None => devices.push(device.clone()),
+        }
+    }
+    frame.set_ts(later.ts());
+    true
+}
+
+struct FrameQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    config: QueueConfig,
+    dropped: AtomicU64,
+}
+
+impl FrameQueue {
+    /// Returns false once the queue is closed
+    fn push(&self, frame: &TrackedObjects) -> bool {
+        let capacity = self.config.capacity.max(1);
+        let mut state = self.state.lock().unwrap();
+        if self.config.overflow == OverflowPolicy::Block {
+            while !state.closed && state.frames.len() >= capacity {
+                state = self.changed.wait(state).unwrap();
+            }
+        }
+        if state.closed {
+            return false;
+        }
+        if frame.channel().is_pose() && !frame.is_heartbeat() {
+            if !frame.partial() {
+                state.stale_keyframe = None;
+            } else if frame.keyframe_seq() == state.stale_keyframe {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+        state.frames.push_back(frame.clone());
+        while state.frames.len() > capacity {
+            let newest = self.config.overflow == OverflowPolicy::DropNewest;
+            let dropped = if newest {
+                state.frames.pop_back()
+            } else {
+                state.frames.pop_front()
+            };
+            if let Some(dropped) = dropped {
+                state.discard(dropped, newest);
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.changed.notify_all();
+        true
+    }
+
+    /// Wait for the next frame. Returns `None` once closed and drained
+    fn pop(&self) -> Option<TrackedObjects> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                self.changed.notify_all();
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Runs a sink on its own thread behind a bounded queue
+///
+/// Publishing only enqueues, so a stalled sink can't hold up the others
+/// unless its queue uses [`OverflowPolicy::Block`]. A failing sink stops the
+/// stream, or with [`SinkHealthConfig`] is disabled for a while.
+pub struct QueuedSink {
+    name: String,
+    queue: Arc<FrameQueue>,
+    sink: Option<Box<dyn PoseSink>>,
+    traffic: Option<Traffic>,
+    health_config: Option<SinkHealthConfig>,
+    health: Arc<Mutex<SinkHealth>>,
+    worker: Option<JoinHandle<Result<()>>>,
+}
+
+impl QueuedSink {
+    pub fn new(sink: Box<dyn PoseSink>, config: QueueConfig) -> Self {
+        Self {
+            name: sink.name().to_owned(),
+            queue: Arc::new(FrameQueue {
+                state: Mutex::new(QueueState {
+                    frames: VecDeque::new(),
+                    closed: false,
+                    stale_keyframe: None,
+                }),
+                changed: Condvar::new(),
+                config,
+                dropped: AtomicU64::new(0),
+            }),
+            traffic: sink.traffic().cloned(),
+            sink: Some(sink),
+            health_config: None,
+            health: Arc::default(),
+            worker: None,
+        }
+    }
+
+    /// Disable the sink after repeated failures instead of stopping. Takes effect on start
+    pub fn set_health(&mut self, config: Option<SinkHealthConfig>) {
+        self.health_config = config;
+    }
+
+    fn join_worker(&mut self) -> Result<()> {
+        match self.worker.take() {
+            Some(worker) => worker
+                .join()
+                .map_err(|_| Error::Closed(format!("Sink {} panicked", self.name)))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl PoseSink for QueuedSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self) -> Result<()> {
+        let mut sink = self
+            .sink
+            .take()
+            .ok_or_else(|| Error::InvalidInput(format!("Sink {} already started", self.name)))?;
+        sink.start()?;
+        let queue = self.queue.clone();
+        let health = self.health.clone();
+        let config = self.health_config.clone();
+        let name = self.name.clone();
+        self.worker = Some(thread::spawn(move || {
+            let mut result = Ok(());
+            while let Some(frame) = queue.pop() {
+                let started = Instant::now();
+                if !health.lock().unwrap().accepts(started) {
+                    queue.dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let published = sink.publish(&frame);
+                let mut health = health.lock().unwrap();
+                match (published, &config) {
+                    (Ok(()), _) => health.succeeded(&name, started.elapsed()),
+                    (Err(error), Some(config)) => {
+                        health.failed(&name, &error, config, Instant::now())
+                    }
+                    (Err(error), None) => {
+                        result = Err(error);
+                        break;
+                    }
+                }
+            }
+            // wakes up a blocked publisher if the sink failed
+            queue.close();
+            let stopped = sink.stop();
+            result.and(stopped)
+        }));
+        Ok(())
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        if self.queue.push(frame) {
+            return Ok(());
+        }
+        self.join_worker()?;
+        Err(Error::Closed(format!("Sink {} stopped", self.name)))
+    }
+
+    /// Deliver queued frames and stop the sink
+    fn stop(&mut self) -> Result<()> {
+        self.queue.close();
+        self.join_worker()
+    }
+
+    fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    fn traffic(&self) -> Option<&Traffic> {
+        self.traffic.as_ref()
+    }
+
+    fn status(&self) -> SinkStatus {
+        SinkStatus {
+            dropped: self.dropped(),
+            ..self.health.lock().unwrap().status.clone()
+        }
+    }
+}
+
+/// Passes pose frames of one sink at no more than a set rate
+///
+/// Frames on the event channel and heartbeats always pass. Due times stay on
+/// a fixed grid so jitter in the sample times doesn't lower the average rate.
+/// Skipped change-only frames are folded into the next one that passes.
+#[derive(Debug, Clone, Default)]
+struct Decimator {
+    period_ms: Option<f64>,
+    due_ms: Option<f64>,
+    skipped: SkippedChanges,
+}
+
+impl Decimator {
+    fn new(rate_hz: Option<f32>) -> Self {
+        Self {
+            period_ms: rate_hz.map(|rate| 1000.0 / f64::from(rate)),
+            ..Default::default()
+        }
+    }
+
+    /// What to publish instead of `frame`, `None` if it is skipped
+    fn pass<'a>(&mut self, frame: &'a TrackedObjects) -> Option<Cow<'a, TrackedObjects>> {
+        let period = match self.period_ms {
+            Some(period) if frame.channel().is_pose() && !frame.is_heartbeat() => period,
+            _ => return Some(Cow::Borrowed(frame)),
+        };
+        // nothing to build on until the next keyframe
+        if !self.skipped.add(frame) {
+            return None;
+        }
+        let ts = frame.ts() as f64;
+        let due = self.due_ms.unwrap_or(ts);
+        if ts < due {
+            return None;
+        }
+        // start over from this frame if the stream fell behind the grid
+        self.due_ms = Some(if ts - due < period {
+            due + period
+        } else {
+            ts + period
+        });
+        Some(Cow::Owned(self.skipped.take(frame)))
+    }
+}
+
+/// Set of sinks that every frame is routed through
+///
+/// Each sink gets its own [`QueuedSink`], configured per sink name, and can be
+/// limited to a lower rate of pose frames.
+#[derive(Default)]
+pub struct SinkRegistry {
+    sinks: Vec<Box<dyn PoseSink>>,
+    decimators: Vec<Decimator>,
+    queues: HashMap<String, QueueConfig>,
+    rates: HashMap<String, f32>,
+    health: Option<SinkHealthConfig>,
+}
+
+impl SinkRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry using queue settings by sink name. Other sinks use the default
+    pub fn with_queues(queues: HashMap<String, QueueConfig>) -> Self {
+        Self {
+            queues,
+            ..Default::default()
+        }
+    }
+
+    /// Failure handling of sinks registered from now on, `None` stops the stream on the first error
+    pub fn set_health(&mut self, health: Option<SinkHealthConfig>) {
+        self.health = health;
+    }
+
+    pub fn register(&mut self, sink: Box<dyn PoseSink>) {
+        let config = self.queues.get(sink.name()).cloned().unwrap_or_default();
+        self.decimators
+            .push(Decimator::new(self.rates.get(sink.name()).copied()));
+        let mut queued = QueuedSink::new(sink, config);
+        queued.set_health(self.health.clone());
+        self.sinks.push(Box::new(queued));
+    }
+
+    /// Highest rate of pose frames in Hz by sink name, sinks not listed get every frame
+    pub fn set_rates(&mut self, rates: HashMap<String, f32>) {
+        for (sink, decimator) in self.sinks.iter().zip(&mut self.decimators) {
+            let changed = Decimator::new(rates.get(sink.name()).copied());
+            if changed.period_ms != decimator.period_ms {
+                // keep the skipped changes, the sink still builds on them
+                decimator.period_ms = changed.period_ms;
+                decimator.due_ms = None;
+            }
+        }
+        self.rates = rates;
+    }
+
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.start()?;
+        }
+        Ok(())
+    }
+
+    /// Publish to all sinks, even if some of them fail
+    pub fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        let mut result = Ok(());
+        for (sink, decimator) in self.sinks.iter_mut().zip(&mut self.decimators) {
+            let frame = match decimator.pass(frame) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if let Err(error) = sink.publish(&frame) {
+                eprintln!("Failed to publish to sink {}: {}", sink.name(), error);
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            }
+        }
+        result
+    }
+
+    /// Stop all sinks, even if some of them fail
+    pub fn stop(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            if sink.dropped() > 0 {
+                eprintln!("Sink {} dropped {} frames", sink.name(), sink.dropped());
+            }
+            if let Err(error) = sink.stop() {
+                eprintln!("Failed to stop sink {}: {}", sink.name(), error);
+                result = Err(error);
+            }
+        }
+        result
+    }
+
+    /// Dropped frame count of every sink
+    pub fn dropped(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.sinks.iter().map(|sink| (sink.name(), sink.dropped()))
+    }
+
+    /// Drops, failures and latency of every sink
+    pub fn statuses(&self) -> impl Iterator<Item = (&str, SinkStatus)> {
+        self.sinks.iter().map(|sink| (sink.name(), sink.status()))
+    }
+
+    /// Failures, disabled state and latency of every sink in the Prometheus text format
+    pub fn prometheus(&self) -> String {
+        type Metric = (
+            &'static str,
+            &'static str,
+            &'static str,
+            fn(&SinkStatus) -> f64,
+        );
+        let metrics: [Metric; 3] = [
+            (
+                "openvr_tracker_sink_failures_total",
+                "Failed publishes of a sink",
+                "counter",
+                |status| status.failures as f64,
+            ),
+            (
+                "openvr_tracker_sink_disabled",
+                "Whether a sink is disabled after repeated failures",
+                "gauge",
+                |status| f64::from(u8::from(status.disabled)),
+            ),
+            (
+                "openvr_tracker_sink_latency_milliseconds",
+                "Moving average of the time a publish takes",
+                "gauge",
+                |status| f64::from(status.latency_ms),
+            ),
+        ];
+        let statuses: Vec<_> = self.statuses().collect();
+        let mut out = String::new();
+        for (name, help, kind, value) in metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (sink, status) in &statuses {
+                let _ = writeln!(
+                    out,
+                    "{}{{sink=\"{}\"}} {}",
+                    name,
+                    label_value(sink),
+                    value(status)
+                );
+            }
+        }
+        out
+    }
+
+    /// Sent bytes and packets of every sink that counts them
+    pub fn traffic(&self) -> impl Iterator<Item = (&str, &Traffic)> {
+        self.sinks
+            .iter()
+            .filter_map(|sink| Some((sink.name(), sink.traffic()?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FrameAssembler;
+    use crate::tracking_messages::{Channel, VrDevice};
+    use nalgebra::{Isometry3, Vector3};
+
+    struct RecordingSink {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PoseSink for RecordingSink {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn start(&mut self) -> Result<()> {
+            self.events.lock().unwrap().push("start".to_owned());
+            Ok(())
+        }
+
+        fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("frame {}", frame.seq()));
+            Ok(())
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            self.events.lock().unwrap().push("stop".to_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_registry_lifecycle() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut registry = SinkRegistry::new();
+        registry.register(Box::new(RecordingSink {
+            events: events.clone(),
+        }));
+        registry.start().unwrap();
+        registry
+            .publish(&TrackedObjects::new(1, 0, vec![]))
+            .unwrap();
+        registry.stop().unwrap();
+        assert_eq!(*events.lock().unwrap(), vec!["start", "frame 1", "stop"]);
+    }
+
+    #[test]
+    fn test_channel_sink_drops_when_full() {
+        let (mut sink, receiver) = ChannelSink::new(1);
+        sink.publish(&TrackedObjects::new(1, 0, vec![])).unwrap();
+        sink.publish(&TrackedObjects::new(2, 0, vec![])).unwrap();
+        assert_eq!(receiver.try_recv().unwrap().seq(), 1);
+        assert!(receiver.try_recv().is_err());
+        drop(receiver);
+        assert!(sink.publish(&TrackedObjects::new(3, 0, vec![])).is_err());
+    }
+
+    struct StalledSink {
+        release: mpsc::Receiver<()>,
+    }
+
+    impl PoseSink for StalledSink {
+        fn name(&self) -> &str {
+            "stalled"
+        }
+
+        fn publish(&mut self, _: &TrackedObjects) -> Result<()> {
+            self.release
+                .recv()
+                .map_err(|_| Error::Closed("Release channel closed".into()))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stalled_sink_drops_by_policy() {
+        for (overflow, kept) in [
+            (OverflowPolicy::DropOldest, vec![5, 6]),
+            (OverflowPolicy::DropNewest, vec![2, 3]),
+        ] {
+            let (release, stalled) = mpsc::channel();
+            let (channel, frames) = ChannelSink::new(16);
+            let mut sink = QueuedSink::new(
+                Box::new(StalledSink { release: stalled }),
+                QueueConfig {
+                    capacity: 2,
+                    overflow,
+                },
+            );
+            let mut channel = QueuedSink::new(Box::new(channel), QueueConfig::default());
+            sink.start().unwrap();
+            channel.start().unwrap();
+            sink.publish(&TrackedObjects::new(1, 0, vec![])).unwrap();
+            // wait for the worker to pick up the first frame and stall on it
+            while !sink.queue.state.lock().unwrap().frames.is_empty() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            for seq in 2..7 {
+                sink.publish(&TrackedObjects::new(seq, 0, vec![])).unwrap();
+                channel
+                    .publish(&TrackedObjects::new(seq, 0, vec![]))
+                    .unwrap();
+            }
+            assert_eq!(sink.dropped(), 3);
+            let queued: Vec<u64> = sink
+                .queue
+                .state
+                .lock()
+                .unwrap()
+                .frames
+                .iter()
+                .map(|frame| frame.seq())
+                .collect();
+            assert_eq!(queued, kept);
+            drop(release);
+            assert!(sink.stop().is_err());
+
+            // a healthy sink behind the same producer got everything
+            channel.stop().unwrap();
+            assert_eq!(frames.try_iter().count(), 5);
+            assert_eq!(channel.dropped(), 0);
+        }
+    }
+
+    #[test]
+    fn test_dropped_partial_frames_are_folded() {
+        let queue = |overflow| FrameQueue {
+            state: Mutex::new(QueueState {
+                frames: VecDeque::new(),
+                closed: false,
+                stale_keyframe: None,
+            }),
+            changed: Condvar::new(),
+            config: QueueConfig {
+                capacity: 2,
+                overflow,
+            },
+            dropped: AtomicU64::new(0),
+        };
+        let mut devices = vec![VrDevice::new(0), VrDevice::new(1)];
+        let mut upstream = FrameAssembler::new();
+        let mut keyframe_seq = 0;
+        // device `seq % 2` moves in every frame, every third one is a keyframe
+        let mut frame = |seq: u64| {
+            let moved = seq as usize % 2;
+            devices[moved].transform(&Isometry3::translation(0.1, 0.0, 0.0));
+            let frame = if [0, 3, 6].contains(&seq) {
+                keyframe_seq = seq;
+                TrackedObjects::new(seq, seq as u128, devices.clone())
+            } else {
+                let mut frame = TrackedObjects::new(seq, seq as u128, vec![devices[moved].clone()]);
+                frame.set_delta_of(keyframe_seq);
+                frame
+            };
+            let full = upstream.push(frame.clone()).unwrap();
+            (frame, positions(&full))
+        };
+        let mut frames: Vec<_> = (0..8).map(&mut frame).collect();
+        let (sent, last) = frames.pop().unwrap();
+
+        for overflow in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let queue = queue(overflow);
+            let mut sink = FrameAssembler::new();
+            let mut received = vec![];
+            for (frame, _) in &frames {
+                queue.push(frame);
+                // the sink takes a frame now and then
+                if frame.seq() % 3 == 2 {
+                    received.push(queue.pop().unwrap());
+                }
+            }
+            queue.push(&sent);
+            queue.close();
+            received.extend(std::iter::from_fn(|| queue.pop()));
+            let rebuilt: Vec<_> = received
+                .into_iter()
+                .map(|frame| sink.push(frame).map(|full| positions(&full)))
+                .collect();
+            assert!(rebuilt.iter().all(Option::is_some), "{:?}", overflow);
+            if overflow == OverflowPolicy::DropOldest {
+                assert_eq!(rebuilt.last().unwrap().as_ref(), Some(&last));
+            }
+        }
+    }
+
+    struct FailingSink {
+        fail: Arc<Mutex<bool>>,
+    }
+
+    impl PoseSink for FailingSink {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn publish(&mut self, _: &TrackedObjects) -> Result<()> {
+            if *self.fail.lock().unwrap() {
+                return Err(Error::external("Broker unreachable"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failing_sink_is_disabled_and_retried() {
+        let fail = Arc::new(Mutex::new(true));
+        let (channel, frames) = ChannelSink::new(64);
+        let mut registry = SinkRegistry::new();
+        registry.set_health(Some(SinkHealthConfig {
+            disable_after_failures: 2,
+            retry_after_s: 0.05,
+        }));
+        registry.register(Box::new(FailingSink { fail: fail.clone() }));
+        registry.register(Box::new(channel));
+        registry.start().unwrap();
+        let wait_for = |registry: &SinkRegistry, done: &dyn Fn(&SinkStatus) -> bool| {
+            while !done(&registry.statuses().next().unwrap().1) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        for seq in 0..2 {
+            registry
+                .publish(&TrackedObjects::new(seq, 0, vec![]))
+                .unwrap();
+        }
+        wait_for(&registry, &|status| status.disabled);
+        // thrown away while disabled, the other sink is unaffected
+        registry
+            .publish(&TrackedObjects::new(2, 0, vec![]))
+            .unwrap();
+        wait_for(&registry, &|status| status.dropped == 1);
+        let status = registry.statuses().next().unwrap().1;
+        assert_eq!(status.failures, 2);
+        assert_eq!(status.last_error.as_deref(), Some("Broker unreachable"));
+        assert!(registry
+            .prometheus()
+            .contains("openvr_tracker_sink_disabled{sink=\"failing\"} 1\n"));
+
+        *fail.lock().unwrap() = false;
+        thread::sleep(Duration::from_millis(60));
+        registry
+            .publish(&TrackedObjects::new(3, 0, vec![]))
+            .unwrap();
+        wait_for(&registry, &|status| !status.disabled);
+        registry.stop().unwrap();
+        assert_eq!(frames.try_iter().count(), 4);
+    }
+
+    #[test]
+    fn test_rate_limited_sink() {
+        let (channel, frames) = ChannelSink::new(64);
+        let mut registry = SinkRegistry::new();
+        let mut rates = HashMap::new();
+        rates.insert("channel".to_owned(), 10.0);
+        registry.set_rates(rates);
+        registry.register(Box::new(channel));
+        registry.start().unwrap();
+        for seq in 0..26 {
+            let ts = seq as u128 * 10 + 1000;
+            registry
+                .publish(&TrackedObjects::new(seq, ts, vec![]))
+                .unwrap();
+            if seq == 5 {
+                let mut event = TrackedObjects::new(100, ts, vec![]);
+                event.set_channel(Channel::Event);
+                registry.publish(&event).unwrap();
+            }
+        }
+        registry.stop().unwrap();
+        let seqs: Vec<u64> = frames.try_iter().map(|frame| frame.seq()).collect();
+        assert_eq!(seqs, [0, 100, 10, 20]);
+    }
+
+    #[test]
+    fn test_rate_limited_sink_folds_skipped_changes() {
+        let (channel, frames) = ChannelSink::new(64);
+        let mut queues = HashMap::new();
+        queues.insert(
+            "channel".to_owned(),
+            QueueConfig {
+                capacity: 64,
+                overflow: OverflowPolicy::Block,
+            },
+        );
+        let mut registry = SinkRegistry::with_queues(queues);
+        let mut rates = HashMap::new();
+        rates.insert("channel".to_owned(), 10.0);
+        registry.set_rates(rates);
+        registry.register(Box::new(channel));
+        registry.start().unwrap();
+
+        let mut devices = vec![VrDevice::new(0), VrDevice::new(1)];
+        let mut keyframe_seq = 0;
+        let mut upstream = FrameAssembler::new();
+        let mut expected = vec![];
+        for seq in 0..50 {
+            // every 4th frame passes, the keyframes at 15, 30 and 45 are skipped
+            devices[0].transform(&Isometry3::translation(0.01, 0.0, 0.0));
+            let ts = seq as u128 * 25 + 1000;
+            let frame = if seq % 15 == 0 {
+                keyframe_seq = seq;
+                TrackedObjects::new(seq, ts, devices.clone())
+            } else {
+                let mut changed = vec![devices[0].clone()];
+                // only moves in a skipped frame
+                if seq == 2 {
+                    devices[1].transform(&Isometry3::translation(0.0, 0.5, 0.0));
+                    changed.push(devices[1].clone());
+                }
+                let mut frame = TrackedObjects::new(seq, ts, changed);
+                frame.set_delta_of(keyframe_seq);
+                frame
+            };
+            let full = upstream.push(frame.clone()).unwrap();
+            if seq % 4 == 0 {
+                expected.push(positions(&full));
+            }
+            registry.publish(&frame).unwrap();
+        }
+        registry.stop().unwrap();
+
+        let mut sink = FrameAssembler::new();
+        let received: Vec<_> = frames
+            .try_iter()
+            .map(|frame| {
+                positions(
+                    &sink
+                        .push(frame)
+                        .expect("partial frame refers to a keyframe the sink got"),
+                )
+            })
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_failing_sink_does_not_starve_the_others() {
+        let (channel, frames) = ChannelSink::new(64);
+        let mut registry = SinkRegistry::new();
+        registry.register(Box::new(FailingSink {
+            fail: Arc::new(Mutex::new(true)),
+        }));
+        registry.register(Box::new(channel));
+        registry.start().unwrap();
+        let mut seq = 0;
+        while registry
+            .publish(&TrackedObjects::new(seq, 0, vec![]))
+            .is_ok()
+        {
+            seq += 1;
+            thread::sleep(Duration::from_millis(1));
+        }
+        registry.stop().unwrap();
+        assert_eq!(frames.try_iter().count() as u64, seq + 1);
+    }
+
+    fn positions(frame: &TrackedObjects) -> Vec<(usize, Vector3<f32>)> {
+        frame
+            .trackers()
+            .iter()
+            .map(|device| (device.id(), device.position().coords))
+            .collect()
+    }
+}
@@ -0,0 +1,226 @@
+//! Minimal full-body skeleton from the headset, a waist tracker and two foot trackers
+//!
+//! Head, hip and feet follow their devices. Knees are placed by two-bone IK:
+//! each leg runs from a hip joint beside the waist tracker to its foot tracker,
+//! with thigh and shin of fixed length, and bends towards the front of the
+//! waist tracker. Legs stretched beyond their length are kept straight.
+//!
+//! Joints are published as virtual devices of class `Other`, aliased
+//! `<name>/<joint>`. Knee rotations have Y along the thigh towards the hip and
+//! -Z pointing where the knee bends to, the OpenVR convention for forward.
+
+use crate::config::device_named;
+use crate::tracking_messages::{VrDevice, VrDeviceClass};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Joints in the order of their ids, starting at `first_id`
+pub const JOINTS: [&str; 6] = [
+    "head",
+    "hip",
+    "left_knee",
+    "right_knee",
+    "left_foot",
+    "right_foot",
+];
+
+/// Center of the head in headset coordinates, behind and above the displays
+const HEAD_OFFSET: [f32; 3] = [0.0, 0.05, 0.1];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SkeletonConfig {
+    /// Prefix of the joint aliases
+    pub name: Arc<str>,
+    /// Device id of the head, the other joints follow in the order of [`JOINTS`]
+    pub first_id: usize,
+    /// Serial or alias of the waist tracker
+    pub waist: String,
+    /// Serial or alias of the left foot tracker
+    pub left_foot: String,
+    /// Serial or alias of the right foot tracker
+    pub right_foot: String,
+    /// Meters between the hip joints
+    pub hip_width_m: f32,
+    pub thigh_length_m: f32,
+    pub shin_length_m: f32,
+}
+
+impl Default for SkeletonConfig {
+    fn default() -> Self {
+        Self {
+            name: "skeleton".into(),
+            first_id: 200,
+            waist: "waist".to_owned(),
+            left_foot: "left_foot".to_owned(),
+            right_foot: "right_foot".to_owned(),
+            hip_width_m: 0.2,
+            thigh_length_m: 0.45,
+            shin_length_m: 0.45,
+        }
+    }
+}
+
+impl SkeletonConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let lengths = [self.hip_width_m, self.thigh_length_m, self.shin_length_m];
+        if !lengths
+            .iter()
+            .all(|length| *length > 0.0 && length.is_finite())
+        {
+            problems.push("skeleton lengths must be positive".to_owned());
+        }
+        if self.name.trim().is_empty() {
+            problems.push("skeleton name is empty".to_owned());
+        }
+        problems
+    }
+
+    /// Joints solved from `devices`, `None` until the headset and all three trackers were seen
+    ///
+    /// Joints are only tracked while all four devices are.
+    pub fn solve(
+        &self,
+        devices: &[VrDevice],
+        aliases: &HashMap<String, Arc<str>>,
+    ) -> Option<[VrDevice; 6]> {
+        let find = |name: &str| {
+            devices
+                .iter()
+                .find(|device| device.seen() && device_named(device, name, aliases))
+        };
+        let head = devices
+            .iter()
+            .find(|device| device.seen() && device.class() == VrDeviceClass::HMD)?;
+        let waist = find(&self.waist)?;
+        let left_foot = find(&self.left_foot)?;
+        let right_foot = find(&self.right_foot)?;
+        let tracked = [head, waist, left_foot, right_foot]
+            .iter()
+            .all(|device| device.tracked());
+
+        let side = waist.rotation() * na::Vector3::x() * (self.hip_width_m / 2.0);
+        let forward = waist.rotation() * -na::Vector3::z();
+        let left_knee = self.knee(waist.position() - side, left_foot.position(), &forward);
+        let right_knee = self.knee(waist.position() + side, right_foot.position(), &forward);
+        let poses = [
+            (
+                head.position() + head.rotation() * na::Vector3::from(HEAD_OFFSET),
+                *head.rotation(),
+            ),
+            (*waist.position(), *waist.rotation()),
+            left_knee,
+            right_knee,
+            (*left_foot.position(), *left_foot.rotation()),
+            (*right_foot.position(), *right_foot.rotation()),
+        ];
+        let mut index = 0;
+        Some(poses.map(|(position, rotation)| {
+            let mut joint = VrDevice::new(self.first_id + index);
+            joint.set_class(VrDeviceClass::Other);
+            joint.set_alias(Some(format!("{}/{}", self.name, JOINTS[index]).into()));
+            joint.set_pose(tracked, position, rotation);
+            index += 1;
+            joint
+        }))
+    }
+
+    /// Knee between a hip joint and a foot, bent towards `forward`
+    fn knee(
+        &self,
+        hip: na::Point3<f32>,
+        foot: &na::Point3<f32>,
+        forward: &na::Vector3<f32>,
+    ) -> (na::Point3<f32>, na::UnitQuaternion<f32>) {
+        let (thigh, shin) = (self.thigh_length_m, self.shin_length_m);
+        let leg = foot - hip;
+        let direction = na::Unit::try_new(leg, 1e-6)
+            .map(|direction| direction.into_inner())
+            .unwrap_or_else(|| -na::Vector3::y());
+        let distance = leg.norm().clamp((thigh - shin).abs(), thigh + shin);
+        // law of cosines for the angle between thigh and leg at the hip
+        let cos_angle = ((thigh * thigh + distance * distance - shin * shin)
+            / (2.0 * thigh * distance.max(1e-6)))
+        .clamp(-1.0, 1.0);
+        let bend = na::Unit::try_new(forward - direction * forward.dot(&direction), 1e-6)
+            .map(|bend| bend.into_inner())
+            .unwrap_or_else(|| direction.cross(&na::Vector3::x()).normalize());
+        let knee = hip
+            + direction * thigh * cos_angle
+            + bend * thigh * (1.0 - cos_angle * cos_angle).sqrt();
+
+        let up = na::Unit::try_new(hip - knee, 1e-6)
+            .map(|up| up.into_inner())
+            .unwrap_or_else(na::Vector3::y);
+        let back = na::Unit::try_new(-bend - up * (-bend).dot(&up), 1e-6)
+            .map(|back| back.into_inner())
+            .unwrap_or_else(|| up.cross(&na::Vector3::x()).normalize());
+        let rotation = na::Rotation3::from_basis_unchecked(&[up.cross(&back), up, back]);
+        (knee, na::UnitQuaternion::from_rotation_matrix(&rotation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+
+    #[test]
+    fn test_knees_bend_forward() {
+        let skeleton = SkeletonConfig::default();
+        let mut aliases = HashMap::new();
+        aliases.insert("LHR-W".to_owned(), Arc::from("waist"));
+        aliases.insert("LHR-L".to_owned(), Arc::from("left_foot"));
+        aliases.insert("LHR-R".to_owned(), Arc::from("right_foot"));
+        // crouching, feet closer to the hip than the legs are long
+        let devices = vec![
+            device(0)
+                .serial("HMD")
+                .class(VrDeviceClass::HMD)
+                .at([0.0, 1.2, 0.0])
+                .build(),
+            device(1)
+                .serial("LHR-W")
+                .class(VrDeviceClass::Tracker)
+                .at([0.0, 0.7, 0.0])
+                .build(),
+            device(2)
+                .serial("LHR-L")
+                .class(VrDeviceClass::Tracker)
+                .at([-0.1, 0.0, 0.0])
+                .build(),
+            device(3)
+                .serial("LHR-R")
+                .class(VrDeviceClass::Tracker)
+                .at([0.1, 0.0, 0.0])
+                .build(),
+        ];
+        let joints = skeleton.solve(&devices, &aliases).unwrap();
+        assert_eq!(joints[2].alias(), Some("skeleton/left_knee"));
+        assert_eq!(joints[5].id(), 205);
+        assert!(joints.iter().all(|joint| joint.tracked()));
+        let knee = joints[2].position();
+        assert!(knee.z < -0.1, "knee {:?} should be in front", knee);
+        let hip = na::Point3::new(-0.1, 0.7, 0.0);
+        assert!(((knee - hip).norm() - 0.45).abs() < 1e-4);
+        assert!(((knee - devices[2].position()).norm() - 0.45).abs() < 1e-4);
+        // Y of the knee points up the thigh
+        let up = joints[2].rotation() * na::Vector3::y();
+        assert!((up - (hip - knee).normalize()).norm() < 1e-4);
+
+        // straight legs when the feet are out of reach
+        let mut standing = devices.clone();
+        standing[1].set_pose(
+            true,
+            na::Point3::new(0.0, 1.0, 0.0),
+            na::UnitQuaternion::identity(),
+        );
+        let joints = skeleton.solve(&standing, &aliases).unwrap();
+        assert!(joints[2].position().z.abs() < 1e-4);
+
+        assert!(skeleton.solve(&devices[..3], &aliases).is_none());
+    }
+}
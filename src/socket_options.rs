@@ -0,0 +1,165 @@
+//! Send buffer size and QoS marking of outgoing UDP sockets
+//!
+//! DSCP sets the upper six bits of the IP TOS byte (traffic class on IPv6),
+//! which switches and routers that honor it use to queue packets ahead of bulk
+//! traffic. `priority` is the Linux `SO_PRIORITY`, used by local queueing
+//! disciplines and VLAN priority mapping.
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::net::UdpSocket;
+
+/// Expedited forwarding, the usual class for latency sensitive streams
+pub const DSCP_EXPEDITED: u8 = 46;
+
+/// Options of outgoing UDP sockets, applied by `set_socket_options` of the multicast, unicast and OSC senders
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketOptions {
+    /// Kernel send buffer in bytes, system default if not set
+    pub send_buffer_size: Option<usize>,
+    /// Differentiated services code point, 0 to 63. 46 is expedited forwarding
+    ///
+    /// Ignored with a warning where it can't be set per socket, Windows needs a QoS policy instead.
+    pub dscp: Option<u8>,
+    /// `SO_PRIORITY`, 0 to 6 without `CAP_NET_ADMIN`. Linux only
+    pub priority: Option<u32>,
+}
+
+impl SocketOptions {
+    /// Settings that can't be applied
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(dscp) = self.dscp {
+            if dscp > 63 {
+                problems.push(format!("socket_options.dscp must be 0 to 63, is {}", dscp));
+            }
+        }
+        if self.send_buffer_size == Some(0) {
+            problems.push("socket_options.send_buffer_size can't be 0".to_owned());
+        }
+        if self.priority.is_some() && !cfg!(target_os = "linux") {
+            problems.push("socket_options.priority is only supported on Linux".to_owned());
+        }
+        problems
+    }
+
+    pub fn apply(&self, socket: &UdpSocket) -> Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket
+                .try_clone()
+                .and_then(|socket| socket2::Socket::from(socket).set_send_buffer_size(size))
+                .map_err(|error| Error::config_caused("Failed to set send buffer size", error))?;
+        }
+        if let Some(dscp) = self.dscp {
+            if dscp > 63 {
+                return Err(Error::config(format!("DSCP must be 0 to 63, is {}", dscp)));
+            }
+            set_traffic_class(socket, dscp)
+                .map_err(|error| Error::config_caused("Failed to set DSCP", error))?;
+        }
+        if let Some(priority) = self.priority {
+            set_priority(socket, priority)
+                .map_err(|error| Error::config_caused("Failed to set socket priority", error))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_option(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: i32) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let value = value as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_traffic_class(socket: &UdpSocket, dscp: u8) -> Result<()> {
+    let tos = (dscp as i32) << 2;
+    if socket.local_addr()?.is_ipv6() {
+        set_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)
+    } else {
+        set_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos)
+    }
+}
+
+#[cfg(not(unix))]
+fn set_traffic_class(_socket: &UdpSocket, dscp: u8) -> Result<()> {
+    // Windows ignores IP_TOS, marking takes a QoS policy set up outside
+    eprintln!(
+        "Not marking packets with DSCP {}, use a QoS policy on this platform",
+        dscp
+    );
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_priority(socket: &UdpSocket, priority: u32) -> Result<()> {
+    set_option(socket, libc::SOL_SOCKET, libc::SO_PRIORITY, priority as i32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_priority(_socket: &UdpSocket, _priority: u32) -> Result<()> {
+    Err(Error::Unsupported(
+        "Socket priority is only supported on Linux".to_owned(),
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    fn get_option(socket: &UdpSocket, level: libc::c_int, name: libc::c_int) -> i32 {
+        let mut value: libc::c_int = 0;
+        let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        assert_eq!(result, 0);
+        value
+    }
+
+    #[test]
+    fn test_apply_marks_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = SocketOptions {
+            send_buffer_size: Some(64 * 1024),
+            dscp: Some(DSCP_EXPEDITED),
+            priority: Some(5),
+        };
+        options.apply(&socket).unwrap();
+        assert_eq!(
+            get_option(&socket, libc::IPPROTO_IP, libc::IP_TOS),
+            (DSCP_EXPEDITED as i32) << 2
+        );
+        assert_eq!(get_option(&socket, libc::SOL_SOCKET, libc::SO_PRIORITY), 5);
+        // Linux doubles the requested size for bookkeeping
+        assert!(get_option(&socket, libc::SOL_SOCKET, libc::SO_SNDBUF) >= 64 * 1024);
+        assert!(SocketOptions {
+            dscp: Some(64),
+            ..Default::default()
+        }
+        .apply(&socket)
+        .is_err());
+    }
+}
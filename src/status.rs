@@ -0,0 +1,226 @@
+//! Periodic health records for supervisors
+//!
+//! With `--status-json` the tracker prints a [`StatusRecord`] as one line of
+//! JSON on stdout every interval, so supervisors can follow its health without
+//! joining the multicast group or polling the HTTP server. Logs stay on stderr.
+//! Before exiting on an error a last record with `exiting` set is printed.
+
+use crate::control::ControlStatus;
+use crate::sink::SinkStatus;
+use crate::traffic::SinkTraffic;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Errors kept between two records, later ones are counted but not listed
+const MAX_ERRORS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusRecord {
+    /// Milliseconds since the Unix epoch
+    pub ts: u128,
+    #[serde(flatten)]
+    pub status: ControlStatus,
+    /// Pose frames published per second since the previous record
+    pub frame_rate_hz: f32,
+    pub sinks: Vec<SinkHealth>,
+    /// Problems since the previous record, at most 16
+    pub errors: Vec<String>,
+    /// Problems since the previous record, including ones not listed
+    pub error_count: usize,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub exiting: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SinkHealth {
+    pub name: String,
+    /// Frames the sink threw away since startup
+    pub dropped: u64,
+    /// Failed publishes since startup
+    pub failures: u64,
+    /// Disabled after repeated failures, retried periodically
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    pub latency_ms: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Outgoing rates, for sinks that send on the network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_per_s: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packets_per_s: Option<f32>,
+}
+
+/// Health of every sink from its status and, if it has one, its traffic
+pub fn sink_health<'a>(
+    statuses: impl Iterator<Item = (&'a str, SinkStatus)>,
+    traffic: &[SinkTraffic],
+) -> Vec<SinkHealth> {
+    statuses
+        .map(|(name, status)| {
+            let traffic = traffic.iter().find(|traffic| traffic.sink == name);
+            SinkHealth {
+                name: name.to_owned(),
+                dropped: status.dropped,
+                failures: status.failures,
+                disabled: status.disabled,
+                latency_ms: status.latency_ms,
+                last_error: status.last_error,
+                bytes_per_s: traffic.map(|traffic| traffic.bytes_per_s),
+                packets_per_s: traffic.map(|traffic| traffic.packets_per_s),
+            }
+        })
+        .collect()
+}
+
+/// Counts frames and errors between records
+pub struct StatusReporter {
+    interval: Duration,
+    last_report: Option<Instant>,
+    frames: u64,
+    errors: Vec<String>,
+    error_count: usize,
+    last: Option<StatusRecord>,
+}
+
+impl StatusReporter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_report: None,
+            frames: 0,
+            errors: vec![],
+            error_count: 0,
+            last: None,
+        }
+    }
+
+    pub fn record_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn record_error(&mut self, error: impl Into<String>) {
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(error.into());
+        }
+        self.error_count += 1;
+    }
+
+    /// Whether the interval passed since the previous record
+    pub fn is_due(&self, now: Instant) -> bool {
+        match self.last_report {
+            Some(last_report) => now.duration_since(last_report) >= self.interval,
+            None => true,
+        }
+    }
+
+    /// Record of the frames and errors since the previous one
+    pub fn report(
+        &mut self,
+        ts: u128,
+        status: ControlStatus,
+        sinks: Vec<SinkHealth>,
+        now: Instant,
+    ) -> StatusRecord {
+        let elapsed = self
+            .last_report
+            .map(|last_report| now.duration_since(last_report).as_secs_f32())
+            .unwrap_or_default();
+        let frame_rate_hz = if elapsed > 0.0 {
+            self.frames as f32 / elapsed
+        } else {
+            0.0
+        };
+        let record = StatusRecord {
+            ts,
+            status,
+            frame_rate_hz,
+            sinks,
+            errors: std::mem::take(&mut self.errors),
+            error_count: std::mem::take(&mut self.error_count),
+            exiting: false,
+        };
+        self.frames = 0;
+        self.last_report = Some(now);
+        self.last = Some(record.clone());
+        record
+    }
+
+    /// Last record again, marked as exiting because of `error`
+    pub fn exit_record(&mut self, ts: u128, error: impl Into<String>) -> StatusRecord {
+        self.record_error(error);
+        let mut record = self.last.clone().unwrap_or(StatusRecord {
+            ts,
+            status: ControlStatus::default(),
+            frame_rate_hz: 0.0,
+            sinks: vec![],
+            errors: vec![],
+            error_count: 0,
+            exiting: true,
+        });
+        record.ts = ts;
+        record.frame_rate_hz = 0.0;
+        record.errors = std::mem::take(&mut self.errors);
+        record.error_count = std::mem::take(&mut self.error_count);
+        record.exiting = true;
+        record
+    }
+}
+
+/// Write `record` as one line of JSON and flush, so supervisors see it right away
+pub fn write_record(out: &mut impl Write, record: &StatusRecord) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_count_since_previous() {
+        let start = Instant::now();
+        let mut reporter = StatusReporter::new(Duration::from_secs(1));
+        assert!(reporter.is_due(start));
+        reporter.report(0, ControlStatus::default(), vec![], start);
+        assert!(!reporter.is_due(start + Duration::from_millis(500)));
+        for _ in 0..90 {
+            reporter.record_frame();
+        }
+        reporter.record_error("Tracking stream is stale");
+        let traffic = [SinkTraffic {
+            sink: "multicast".to_owned(),
+            bytes: 1000,
+            packets: 10,
+            bytes_per_s: 100.0,
+            packets_per_s: 1.0,
+        }];
+        let http = SinkStatus {
+            dropped: 3,
+            ..Default::default()
+        };
+        let sinks = sink_health(
+            vec![("multicast", SinkStatus::default()), ("http", http)].into_iter(),
+            &traffic,
+        );
+        let now = start + Duration::from_secs(2);
+        let record = reporter.report(2000, ControlStatus::default(), sinks, now);
+        assert_eq!(record.frame_rate_hz, 45.0);
+        assert_eq!(record.errors, vec!["Tracking stream is stale"]);
+
+        let mut line = vec![];
+        write_record(&mut line, &record).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(json["stale"], false);
+        assert_eq!(json["sinks"][1]["dropped"], 3);
+        assert!(json["sinks"][1].get("bytes_per_s").is_none());
+        assert!(json.get("exiting").is_none());
+
+        let exit = reporter.exit_record(3000, "Sink multicast stopped");
+        assert!(exit.exiting);
+        assert_eq!(exit.sinks.len(), 2);
+        assert_eq!(exit.errors, vec!["Sink multicast stopped"]);
+    }
+}
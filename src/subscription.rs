@@ -0,0 +1,386 @@
+//! Callbacks and channels for new frames and device events
+//!
+//! Apps that sample OpenVR in process would otherwise each write the same loop
+//! of `update()`, filtering `devices()` and diffing against the previous
+//! sample. [`Subscriptions`] does it once. Closures and channels registered
+//! with it get every frame, or the device events of the kinds they asked for,
+//! detected the same way as for the event channel.
+//!
+//! Handlers run on the thread that dispatches, [`Subscriptions::spawn`] runs
+//! the whole loop on a thread of its own. Channels whose receiver was dropped
+//! are unsubscribed.
+//!
+//! With the `async` feature, [`Subscriptions::frame_stream`] turns the frames
+//! into a `futures::Stream` for async apps.
+//!
+//! Consumers that fall behind a 90 Hz stream don't make queues grow: frame
+//! channels hold at most [`FRAME_QUEUE`] frames, and a frame stream only keeps
+//! the newest one.
+
+use crate::backend::TrackingBackend;
+use crate::error::Result;
+use crate::events::{DeviceEvent, DeviceEventKind, EventDetector};
+use crate::openvr_adaptor::VrDeviceManager;
+use crate::tracking_messages::{TrackedObjects, VrDevice};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Frames a channel from [`Subscriptions::frames`] holds before newer ones are dropped
+pub const FRAME_QUEUE: usize = 16;
+
+/// Returns false to unsubscribe
+type FrameHandler = Box<dyn FnMut(&TrackedObjects) -> bool + Send>;
+type EventHandler = Box<dyn FnMut(&DeviceEvent) -> bool + Send>;
+
+/// Handlers for frames and device events
+#[derive(Default)]
+pub struct Subscriptions {
+    detector: EventDetector,
+    battery_thresholds: Vec<f32>,
+    seq: u64,
+    frame_handlers: Vec<FrameHandler>,
+    /// Kinds each handler wants, all if empty
+    event_handlers: Vec<(Vec<DeviceEventKind>, EventHandler)>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Charge levels that raise a `low_battery` event, none by default
+    pub fn set_battery_thresholds(&mut self, battery_thresholds: Vec<f32>) {
+        self.battery_thresholds = battery_thresholds;
+    }
+
+    /// Call `handler` with every frame
+    pub fn on_frame(&mut self, mut handler: impl FnMut(&TrackedObjects) + Send + 'static) {
+        self.frame_handlers.push(Box::new(move |frame| {
+            handler(frame);
+            true
+        }));
+    }
+
+    /// Call `handler` with device events of `kinds`, or of every kind if empty
+    pub fn on_event(
+        &mut self,
+        kinds: &[DeviceEventKind],
+        mut handler: impl FnMut(&DeviceEvent) + Send + 'static,
+    ) {
+        self.event_handlers.push((
+            kinds.to_vec(),
+            Box::new(move |event| {
+                handler(event);
+                true
+            }),
+        ));
+    }
+
+    /// Channel receiving every frame, while it holds less than [`FRAME_QUEUE`]
+    pub fn frames(&mut self) -> mpsc::Receiver<TrackedObjects> {
+        let (sender, receiver) = mpsc::sync_channel(FRAME_QUEUE);
+        self.frame_handlers.push(Box::new(move |frame| {
+            !matches!(
+                sender.try_send(frame.clone()),
+                Err(mpsc::TrySendError::Disconnected(_))
+            )
+        }));
+        receiver
+    }
+
+    /// Channel receiving device events of `kinds`, or of every kind if empty
+    pub fn events(&mut self, kinds: &[DeviceEventKind]) -> mpsc::Receiver<DeviceEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.event_handlers.push((
+            kinds.to_vec(),
+            Box::new(move |event| sender.send(event.clone()).is_ok()),
+        ));
+        receiver
+    }
+
+    /// Hand the latest sample to the handlers, `ts` in milliseconds since the Unix epoch
+    pub fn dispatch(&mut self, devices: &[VrDevice], ts: u128, now: Instant) {
+        let events = self.detector.detect(devices, &self.battery_thresholds, now);
+        for event in &events {
+            self.event_handlers.retain_mut(|(kinds, handler)| {
+                if kinds.is_empty() || kinds.contains(&event.kind) {
+                    handler(event)
+                } else {
+                    true
+                }
+            });
+        }
+        if !self.frame_handlers.is_empty() {
+            let seen = devices.iter().filter(|device| device.seen()).cloned();
+            let frame = TrackedObjects::new(self.seq, ts, seen.collect());
+            self.frame_handlers.retain_mut(|handler| handler(&frame));
+        }
+        self.seq += 1;
+    }
+
+    /// Sample devices every `period` on a new thread and dispatch to the handlers
+    ///
+    /// The manager is created by `connect` on that thread, as OpenVR contexts
+    /// can't move between threads. Fails if `connect` does.
+    pub fn spawn<B, F>(mut self, connect: F, period: Duration) -> Result<SubscriptionThread>
+    where
+        B: TrackingBackend,
+        F: FnOnce() -> Result<VrDeviceManager<B>> + Send + 'static,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let (init_tx, init_rx) = mpsc::channel();
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                let mut manager = match connect() {
+                    Ok(manager) => {
+                        let _ = init_tx.send(Ok(()));
+                        manager
+                    }
+                    Err(error) => {
+                        let _ = init_tx.send(Err(error));
+                        return;
+                    }
+                };
+                while running.load(Ordering::Relaxed) {
+                    manager.update();
+                    let ts = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis();
+                    self.dispatch(manager.devices(), ts, Instant::now());
+                    thread::sleep(period);
+                }
+            })
+        };
+        match init_rx.recv() {
+            Ok(Ok(())) => Ok(SubscriptionThread {
+                running,
+                thread: Some(thread),
+            }),
+            Ok(Err(error)) => {
+                let _ = thread.join();
+                Err(error)
+            }
+            // the thread panicked while connecting
+            Err(_) => match thread.join() {
+                Err(panic) => std::panic::resume_unwind(panic),
+                Ok(()) => unreachable!("connecting returned without reporting"),
+            },
+        }
+    }
+
+    /// Async stream of frames, sampled on a thread of its own like [`Subscriptions::spawn`]
+    ///
+    /// Only the newest frame is kept, a consumer that falls behind skips the
+    /// ones sampled in between. Connecting blocks the caller, as does dropping
+    /// the stream until the thread finished its last sample.
+    #[cfg(feature = "async")]
+    pub fn frame_stream<B, F>(mut self, connect: F, period: Duration) -> Result<FrameStream>
+    where
+        B: TrackingBackend,
+        F: FnOnce() -> Result<VrDeviceManager<B>> + Send + 'static,
+    {
+        let latest = Arc::new(LatestFrame::default());
+        let sender = LatestSender(latest.clone());
+        self.frame_handlers
+            .push(Box::new(move |frame| sender.replace(frame)));
+        Ok(FrameStream {
+            latest,
+            _sampling: self.spawn(connect, period)?,
+        })
+    }
+}
+
+/// Frames of OpenVR sampled every `period`, as an async stream
+#[cfg(feature = "async")]
+pub fn openvr_frame_stream(period: Duration) -> Result<FrameStream> {
+    Subscriptions::new().frame_stream(VrDeviceManager::new, period)
+}
+
+/// Newest frame not yet taken by a [`FrameStream`]
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct LatestFrame {
+    frame: std::sync::Mutex<Option<TrackedObjects>>,
+    waker: futures::task::AtomicWaker,
+    /// The sampling thread dropped its handlers
+    closed: AtomicBool,
+}
+
+/// Sampling side of a [`LatestFrame`], closes it when dropped
+#[cfg(feature = "async")]
+struct LatestSender(Arc<LatestFrame>);
+
+#[cfg(feature = "async")]
+impl LatestSender {
+    /// Replace the frame waiting to be taken, false once the stream is gone
+    fn replace(&self, frame: &TrackedObjects) -> bool {
+        if Arc::strong_count(&self.0) == 1 {
+            return false;
+        }
+        if let Ok(mut latest) = self.0.frame.lock() {
+            *latest = Some(frame.clone());
+        }
+        self.0.waker.wake();
+        true
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for LatestSender {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Release);
+        self.0.waker.wake();
+    }
+}
+
+/// Stream of frames from [`Subscriptions::frame_stream`], sampling stops when it is dropped
+#[cfg(feature = "async")]
+pub struct FrameStream {
+    latest: Arc<LatestFrame>,
+    _sampling: SubscriptionThread,
+}
+
+#[cfg(feature = "async")]
+impl futures::Stream for FrameStream {
+    type Item = TrackedObjects;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<TrackedObjects>> {
+        self.latest.waker.register(cx.waker());
+        // closed is read first, a frame sent right before closing is still taken
+        let closed = self.latest.closed.load(Ordering::Acquire);
+        let frame = match self.latest.frame.lock() {
+            Ok(mut latest) => latest.take(),
+            Err(_) => None,
+        };
+        match frame {
+            Some(frame) => std::task::Poll::Ready(Some(frame)),
+            None if closed => std::task::Poll::Ready(None),
+            None => std::task::Poll::Pending,
+        }
+    }
+}
+
+/// Sampling thread started by [`Subscriptions::spawn`], stopped when dropped
+pub struct SubscriptionThread {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SubscriptionThread {
+    /// Stop sampling and wait for the last dispatch to finish, `Err` if a handler panicked
+    pub fn stop(mut self) -> thread::Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> thread::Result<()> {
+        self.running.store(false, Ordering::Relaxed);
+        match self.thread.take() {
+            Some(thread) => thread.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SubscriptionThread {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+    use crate::tracking_messages::VrDeviceClass;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_frames_and_filtered_events() {
+        let tracked = DeviceSample::new(0, VrDeviceClass::Tracker, IDENTITY_POSE);
+        let mut lost = tracked;
+        lost.pose_valid = false;
+        let mut backend = MockBackend::new();
+        for sample in [tracked, lost, tracked].iter() {
+            backend.push_frame(vec![*sample]);
+        }
+        let mut manager = VrDeviceManager::with_backend(backend);
+        let mut subscriptions = Subscriptions::new();
+        let counted = Arc::new(AtomicUsize::new(0));
+        let counter = counted.clone();
+        subscriptions.on_frame(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let lost_events = subscriptions.events(&[DeviceEventKind::TrackingLost]);
+        let all_events = subscriptions.events(&[]);
+        drop(all_events);
+        for ts in 0..3 {
+            manager.update();
+            subscriptions.dispatch(manager.devices(), ts, Instant::now());
+        }
+        assert_eq!(counted.load(Ordering::Relaxed), 3);
+        let kinds: Vec<_> = lost_events.try_iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, vec![DeviceEventKind::TrackingLost]);
+        // the dropped receiver was unsubscribed
+        assert_eq!(subscriptions.event_handlers.len(), 1);
+
+        // the same on a thread of its own
+        let mut subscriptions = Subscriptions::new();
+        let frames = subscriptions.frames();
+        let connect = move || {
+            let mut backend = MockBackend::new();
+            backend.push_frame(vec![tracked]);
+            Ok(VrDeviceManager::with_backend(backend))
+        };
+        let thread = subscriptions
+            .spawn(connect, Duration::from_millis(1))
+            .unwrap();
+        let frame = frames.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(frame.trackers().len(), 1);
+        thread.stop().unwrap();
+    }
+
+    #[test]
+    fn test_frame_channel_is_bounded() {
+        let mut subscriptions = Subscriptions::new();
+        let frames = subscriptions.frames();
+        let devices = [VrDevice::new(0)];
+        for ts in 0..FRAME_QUEUE as u128 + 2 {
+            subscriptions.dispatch(&devices, ts, Instant::now());
+        }
+        // a full channel stays subscribed and gets frames again once read
+        assert_eq!(frames.try_iter().count(), FRAME_QUEUE);
+        subscriptions.dispatch(&devices, 100, Instant::now());
+        assert_eq!(frames.try_recv().unwrap().ts(), 100);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_frame_stream() {
+        use futures::StreamExt;
+
+        let connect = || {
+            let mut backend = MockBackend::new();
+            backend.push_frame(vec![DeviceSample::new(
+                0,
+                VrDeviceClass::HMD,
+                IDENTITY_POSE,
+            )]);
+            Ok(VrDeviceManager::with_backend(backend))
+        };
+        let mut stream = Subscriptions::new()
+            .frame_stream(connect, Duration::from_millis(1))
+            .unwrap();
+        let frames = futures::executor::block_on(async {
+            vec![stream.next().await.unwrap(), stream.next().await.unwrap()]
+        });
+        // frames sampled while the consumer was busy are skipped
+        assert!(frames[1].seq() > frames[0].seq());
+    }
+}
@@ -0,0 +1,145 @@
+//! What a session amounted to, printed on exit
+//!
+//! When the tracker stops, on Ctrl-C or an error, it prints how long it ran,
+//! how many pose frames it published, tracking quality per device and what
+//! every sink sent, so basic QA doesn't need the recording reprocessed. With
+//! `--summary` the same goes to a JSON file.
+
+use crate::quality::DeviceQuality;
+use crate::traffic::Traffic;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    /// Milliseconds since the Unix epoch at startup
+    pub started: u128,
+    pub duration_s: f32,
+    /// Pose frames published, heartbeats not counted
+    pub frames: u64,
+    pub devices: Vec<DeviceQuality>,
+    pub sinks: Vec<SinkSummary>,
+    /// Error the tracker stopped with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SinkSummary {
+    pub name: String,
+    /// Sent on the network, for sinks that do
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packets: Option<u64>,
+    /// Frames the sink's queue dropped
+    pub dropped: u64,
+}
+
+/// Summary of every sink from its drop count and, if it has one, its traffic
+pub fn sink_summaries<'a>(
+    dropped: impl Iterator<Item = (&'a str, u64)>,
+    traffic: &[(&str, &Traffic)],
+) -> Vec<SinkSummary> {
+    dropped
+        .map(|(name, dropped)| {
+            let traffic = traffic.iter().find(|(sink, _)| *sink == name);
+            SinkSummary {
+                name: name.to_owned(),
+                bytes: traffic.map(|(_, traffic)| traffic.bytes()),
+                packets: traffic.map(|(_, traffic)| traffic.packets()),
+                dropped,
+            }
+        })
+        .collect()
+}
+
+impl SessionSummary {
+    /// Write the summary for people, a line per device and sink
+    pub fn write_text(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "Session of {:.1} s, {} frames published",
+            self.duration_s, self.frames
+        )?;
+        for device in &self.devices {
+            let name = device
+                .alias
+                .as_deref()
+                .or(device.serial.as_deref())
+                .unwrap_or("unknown serial");
+            writeln!(
+                out,
+                "  device {} ({:?} {}): {:.1}% tracked, {} dropouts ({} radio), longest {:.2} s",
+                device.id,
+                device.class,
+                name,
+                device.tracked_percent,
+                device.dropouts,
+                device.radio_dropouts,
+                device.longest_dropout_s
+            )?;
+        }
+        for sink in &self.sinks {
+            write!(out, "  sink {}: {} frames dropped", sink.name, sink.dropped)?;
+            if let (Some(bytes), Some(packets)) = (sink.bytes, sink.packets) {
+                write!(out, ", {} bytes in {} packets", bytes, packets)?;
+            }
+            writeln!(out)?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(out, "  stopped on error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_sinks_and_text() {
+        let traffic = Traffic::new();
+        traffic.record(1200);
+        traffic.record(300);
+        let sinks = sink_summaries(
+            vec![("multicast", 0), ("http", 2)].into_iter(),
+            &[("multicast", &traffic)],
+        );
+        assert_eq!(sinks[0].bytes, Some(1500));
+        assert_eq!(sinks[0].packets, Some(2));
+        assert_eq!(sinks[1].bytes, None);
+        let summary = SessionSummary {
+            started: 0,
+            duration_s: 7200.0,
+            frames: 360000,
+            devices: vec![DeviceQuality {
+                id: 3,
+                serial: Some("LHR-1".into()),
+                alias: Some("chest".into()),
+                class: VrDeviceClass::Tracker,
+                tracked_percent: 99.5,
+                dropouts: 4,
+                radio_dropouts: 1,
+                longest_dropout_s: 0.5,
+                jitter_mm: None,
+                noise_floor_mm: None,
+            }],
+            sinks,
+            error: None,
+        };
+        let mut text = vec![];
+        summary.write_text(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("Session of 7200.0 s, 360000 frames published\n"));
+        assert!(text.contains("device 3 (Tracker chest): 99.5% tracked, 4 dropouts (1 radio)"));
+        assert!(text.contains("sink multicast: 0 frames dropped, 1500 bytes in 2 packets\n"));
+        assert!(text.contains("sink http: 2 frames dropped\n"));
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&summary).unwrap()).unwrap();
+        assert!(json.get("error").is_none());
+        assert_eq!(json["sinks"][1]["dropped"], 2);
+    }
+}
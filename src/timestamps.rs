@@ -0,0 +1,187 @@
+//! Clock the `ts` of frames is taken from
+//!
+//! The system wall clock, the default, is only comparable between hosts as far
+//! as their clocks happen to agree. For captures across machines `disciplined`
+//! uses the wall clock while the kernel reports it synchronized by NTP or PTP,
+//! and `ptp` reads the PTP hardware clock of a network card directly.
+//! `monotonic` counts from startup and never jumps, for single machine setups
+//! where the wall clock gets stepped.
+//!
+//! Frames name their clock in `time_source`. While a disciplined clock isn't
+//! synchronized that is `wall`, so receivers can tell when not to trust it.
+
+use crate::error::{Error, Result};
+use crate::tracking_messages::TimeSource;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Timestamps from the configured source, cheap to clone between threads
+#[derive(Clone)]
+pub struct TimestampClock {
+    source: TimeSource,
+    start: Instant,
+    #[cfg(target_os = "linux")]
+    ptp: Option<Arc<PtpClock>>,
+}
+
+impl TimestampClock {
+    /// Clock of `source`, `ptp_device` is only opened for [`TimeSource::Ptp`]
+    ///
+    /// Fails if the source isn't available on this host.
+    pub fn new(source: TimeSource, ptp_device: &Path) -> Result<Self> {
+        if source == TimeSource::Disciplined && system_clock_synced().is_none() {
+            return Err(Error::Unsupported(
+                "Can't tell whether the system clock is synchronized on this platform".to_owned(),
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        let ptp = match source {
+            TimeSource::Ptp => Some(Arc::new(PtpClock::open(ptp_device)?)),
+            _ => None,
+        };
+        #[cfg(not(target_os = "linux"))]
+        if source == TimeSource::Ptp {
+            return Err(Error::Unsupported(format!(
+                "Can't read PTP clock {}, only supported on Linux",
+                ptp_device.display()
+            )));
+        }
+        Ok(Self {
+            source,
+            start: Instant::now(),
+            #[cfg(target_os = "linux")]
+            ptp,
+        })
+    }
+
+    /// System wall clock
+    pub fn wall() -> Self {
+        Self {
+            source: TimeSource::Wall,
+            start: Instant::now(),
+            #[cfg(target_os = "linux")]
+            ptp: None,
+        }
+    }
+
+    /// Current time in milliseconds
+    pub fn now_ms(&self) -> Result<u128> {
+        Ok(self.now_us()? / 1000)
+    }
+
+    /// Current time in microseconds
+    pub fn now_us(&self) -> Result<u128> {
+        match self.source {
+            TimeSource::Wall | TimeSource::Disciplined => Ok(SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros()),
+            TimeSource::Monotonic => Ok(self.start.elapsed().as_micros()),
+            #[cfg(target_os = "linux")]
+            TimeSource::Ptp => match &self.ptp {
+                Some(ptp) => ptp.now_us(),
+                None => Err(Error::config("PTP clock not opened")),
+            },
+            #[cfg(not(target_os = "linux"))]
+            TimeSource::Ptp => Err(Error::Unsupported(
+                "PTP clocks are only supported on Linux".to_owned(),
+            )),
+        }
+    }
+
+    /// Source the clock was created with
+    pub fn requested(&self) -> TimeSource {
+        self.source
+    }
+
+    /// Source to name in frames, `Wall` while a disciplined clock isn't synchronized
+    pub fn source(&self) -> TimeSource {
+        match self.source {
+            TimeSource::Disciplined if system_clock_synced() != Some(true) => TimeSource::Wall,
+            source => source,
+        }
+    }
+}
+
+/// Whether the kernel reports the system clock synchronized, `None` if it can't tell
+pub fn system_clock_synced() -> Option<bool> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        // no modes set, only reads the kernel's clock state
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        let state = unsafe { libc::ntp_adjtime(&mut timex) };
+        if state < 0 {
+            return None;
+        }
+        Some(state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0)
+    }
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    {
+        None
+    }
+}
+
+/// PTP hardware clock, read through its character device
+#[cfg(target_os = "linux")]
+struct PtpClock {
+    device: std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl PtpClock {
+    fn open(path: &Path) -> Result<Self> {
+        let device = std::fs::File::open(path).map_err(|error| {
+            Error::config_caused(
+                format!("Failed to open PTP clock {}", path.display()),
+                error,
+            )
+        })?;
+        let clock = Self { device };
+        clock.now_us()?;
+        Ok(clock)
+    }
+
+    /// Dynamic clock id of the open device, FD_TO_CLOCKID in the kernel headers
+    fn clock_id(&self) -> libc::clockid_t {
+        use std::os::unix::io::AsRawFd;
+        ((!(self.device.as_raw_fd() as libc::clockid_t)) << 3) | 3
+    }
+
+    fn now_us(&self) -> Result<u128> {
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        if unsafe { libc::clock_gettime(self.clock_id(), &mut time) } != 0 {
+            return Err(Error::external_caused(
+                "Failed to read PTP clock",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        Ok(time.tv_sec as u128 * 1_000_000 + time.tv_nsec as u128 / 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources() {
+        let wall = TimestampClock::wall();
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(wall.now_ms().unwrap() >= before.as_millis());
+        assert_eq!(wall.source(), TimeSource::Wall);
+
+        let monotonic = TimestampClock::new(TimeSource::Monotonic, Path::new("")).unwrap();
+        assert!(monotonic.now_ms().unwrap() < 1000);
+        assert_eq!(monotonic.source(), TimeSource::Monotonic);
+
+        let missing = TimestampClock::new(TimeSource::Ptp, Path::new("/dev/no-such-ptp"));
+        assert!(missing.is_err());
+        assert_eq!("ptp".parse(), Ok(TimeSource::Ptp));
+        assert!("gps".parse::<TimeSource>().is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Per-device topic names from a template
+//!
+//! Pub-sub sinks can publish every device on a topic of its own, so a
+//! subscriber interested in one tracker doesn't have to parse and discard the
+//! rest. Templates like `vr/{alias}/pose` name the topic, with the
+//! placeholders `{name}` (alias, serial or id), `{alias}`, `{serial}`, `{id}`,
+//! `{class}` and `{role}`.
+//!
+//! Substituted values never add topic levels: `/` and the characters a sink
+//! reserves are replaced by `_`. A device without an alias, serial or role that
+//! the template asks for has no topic.
+
+use crate::tracking_messages::VrDevice;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Name,
+    Alias,
+    Serial,
+    Id,
+    Class,
+    Role,
+}
+
+/// Parsed topic template
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicTemplate {
+    parts: Vec<Part>,
+}
+
+impl FromStr for TopicTemplate {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(format!("Unmatched }} in topic {}", template));
+            }
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unmatched {{ in topic {}", template))?;
+            let part = match &rest[start + 1..start + end] {
+                "name" => Part::Name,
+                "alias" => Part::Alias,
+                "serial" => Part::Serial,
+                "id" => Part::Id,
+                "class" => Part::Class,
+                "role" => Part::Role,
+                other => {
+                    return Err(format!(
+                        "Unknown placeholder {{{}}} in topic {}, expected name, alias, serial, id, class or role",
+                        other, template
+                    ))
+                }
+            };
+            parts.push(part);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl TopicTemplate {
+    /// Topic of `device` into `out`, false if it lacks a value the template needs
+    ///
+    /// `reserved` characters of substituted values are replaced by `_`, like `/`.
+    pub fn render(&self, device: &VrDevice, reserved: &[char], out: &mut String) -> bool {
+        out.clear();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Part::Id => {
+                    let _ = write!(out, "{}", device.id());
+                    continue;
+                }
+                Part::Class => {
+                    let _ = write!(out, "{:?}", device.class());
+                    continue;
+                }
+                Part::Role => match device.role() {
+                    Some(role) => {
+                        out.push_str(role.name());
+                        continue;
+                    }
+                    None => return false,
+                },
+                Part::Name => match device.alias().or_else(|| device.serial()) {
+                    Some(name) => name,
+                    None => {
+                        let _ = write!(out, "{}", device.id());
+                        continue;
+                    }
+                },
+                Part::Alias => match device.alias() {
+                    Some(alias) => alias,
+                    None => return false,
+                },
+                Part::Serial => match device.serial() {
+                    Some(serial) => serial,
+                    None => return false,
+                },
+            };
+            out.extend(value.chars().map(|c| {
+                if c == '/' || reserved.contains(&c) {
+                    '_'
+                } else {
+                    c
+                }
+            }));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracking_messages::{DeviceRole, VrDeviceClass};
+
+    #[test]
+    fn test_render_placeholders() {
+        let template: TopicTemplate = "vr/{alias}/{class}/{id}".parse().unwrap();
+        let mut device = VrDevice::new(3);
+        device.set_class(VrDeviceClass::Tracker);
+        let mut topic = String::new();
+        assert!(!template.render(&device, &[], &mut topic));
+        device.set_alias(Some("left foot/ankle".into()));
+        assert!(template.render(&device, &[' '], &mut topic));
+        assert_eq!(topic, "vr/left_foot_ankle/Tracker/3");
+
+        let name: TopicTemplate = "/openvr/{name}/pose".parse().unwrap();
+        assert!(name.render(&VrDevice::new(4), &[], &mut topic));
+        assert_eq!(topic, "/openvr/4/pose");
+
+        let role: TopicTemplate = "vr/{role}".parse().unwrap();
+        device.set_role(Some(DeviceRole::LeftFoot));
+        assert!(role.render(&device, &[], &mut topic));
+        assert_eq!(topic, "vr/left_foot");
+
+        assert!("vr/{alias".parse::<TopicTemplate>().is_err());
+        assert!("vr/{battery}".parse::<TopicTemplate>().is_err());
+    }
+}
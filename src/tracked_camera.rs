@@ -0,0 +1,275 @@
+//! Headset front camera through the OpenVR TrackedCamera interface
+//!
+//! The camera pose is published like any other device, with the `Camera`
+//! class. Frames are optional: they are copied out on the polling thread and
+//! JPEG encoded on a thread of their own, so encoding never delays sampling.
+//! The HTTP server streams the latest frame as MJPEG on `/camera`.
+
+use crate::error::{Error, Result};
+use jpeg_encoder::{ColorType, Encoder};
+use std::convert::TryFrom;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Raw camera image as handed out by OpenVR
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawFrame {
+    pub width: usize,
+    pub height: usize,
+    pub bytes_per_pixel: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Latest encoded frame and its number, counting from 1
+pub type JpegFrame = (u64, Arc<Vec<u8>>);
+
+#[derive(Default)]
+struct StreamState {
+    raw: Option<RawFrame>,
+    jpeg: Option<JpegFrame>,
+}
+
+/// Hands raw frames to the encoder and encoded frames to HTTP clients
+///
+/// Only the latest frame is kept on both ends, slow consumers skip frames.
+#[derive(Clone, Default)]
+pub struct CameraStream {
+    state: Arc<(Mutex<StreamState>, Condvar)>,
+}
+
+impl CameraStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the frame waiting to be encoded
+    pub fn push_raw(&self, frame: RawFrame) {
+        let (state, changed) = &*self.state;
+        state.lock().unwrap().raw = Some(frame);
+        changed.notify_all();
+    }
+
+    /// Encode frames with `quality` between 1 and 100 on a background thread
+    pub fn spawn_encoder(&self, quality: u8) {
+        let stream = self.clone();
+        thread::spawn(move || {
+            let (state, changed) = &*stream.state;
+            let mut number = 0;
+            loop {
+                let raw = {
+                    let mut state = state.lock().unwrap();
+                    loop {
+                        if let Some(raw) = state.raw.take() {
+                            break raw;
+                        }
+                        state = changed.wait(state).unwrap();
+                    }
+                };
+                let encoded = match encode(&raw, quality) {
+                    Ok(encoded) => encoded,
+                    Err(error) => {
+                        eprintln!("Failed to encode camera frame: {}", error.describe());
+                        continue;
+                    }
+                };
+                number += 1;
+                state.lock().unwrap().jpeg = Some((number, Arc::new(encoded)));
+                changed.notify_all();
+            }
+        });
+    }
+
+    /// Wait for an encoded frame newer than `after`, `None` on timeout
+    pub fn wait_jpeg(&self, after: u64, timeout: Duration) -> Option<JpegFrame> {
+        let (state, changed) = &*self.state;
+        let state = state.lock().unwrap();
+        let (state, _) = changed
+            .wait_timeout_while(state, timeout, |state| match &state.jpeg {
+                Some((number, _)) => *number <= after,
+                None => true,
+            })
+            .unwrap();
+        state.jpeg.clone().filter(|(number, _)| *number > after)
+    }
+}
+
+/// JPEG of an RGB or RGBA frame, alpha is ignored
+///
+/// `quality` goes from 1 to 100 like in most encoders.
+fn encode(raw: &RawFrame, quality: u8) -> Result<Vec<u8>> {
+    let color = match raw.bytes_per_pixel {
+        3 => ColorType::Rgb,
+        4 => ColorType::Rgba,
+        other => {
+            return Err(Error::InvalidInput(format!(
+                "Can't encode frames with {} bytes per pixel",
+                other
+            )))
+        }
+    };
+    let too_large = |_| Error::InvalidInput("Frame too large for JPEG".to_owned());
+    let width = u16::try_from(raw.width).map_err(too_large)?;
+    let height = u16::try_from(raw.height).map_err(too_large)?;
+    let mut jpeg = Vec::with_capacity(raw.pixels.len() / 8);
+    Encoder::new(&mut jpeg, quality)
+        .encode(&raw.pixels, width, height, color)
+        .map_err(|error| Error::external_caused("Failed to encode JPEG", error))?;
+    Ok(jpeg)
+}
+
+/// Video streaming service of one device's camera
+pub struct TrackedCamera {
+    table: &'static openvr_sys::VR_IVRTrackedCamera_FnTable,
+    handle: openvr_sys::TrackedCameraHandle_t,
+    /// Last frame sequence copied to the stream
+    last_sequence: Option<u32>,
+}
+
+impl TrackedCamera {
+    /// Start streaming from the camera of `device`, needs an initialized OpenVR context
+    pub fn open(device: u32) -> Result<Self> {
+        // the table layout openvr_sys was generated for
+        let mut name = b"FnTable:".to_vec();
+        name.extend_from_slice(openvr_sys::IVRTrackedCamera_Version);
+        let mut error = openvr_sys::EVRInitError_VRInitError_None;
+        let table = unsafe {
+            openvr_sys::VR_GetGenericInterface(name.as_ptr() as *const _, &mut error)
+                as *const openvr_sys::VR_IVRTrackedCamera_FnTable
+        };
+        if error != openvr_sys::EVRInitError_VRInitError_None || table.is_null() {
+            return Err(Error::Interface(format!(
+                "TrackedCamera interface not available, error {}",
+                error
+            )));
+        }
+        // the function table lives as long as the OpenVR context
+        let table = unsafe { &*table };
+        let (has_camera, acquire) = match (table.HasCamera, table.AcquireVideoStreamingService) {
+            (Some(has_camera), Some(acquire))
+                if table.GetVideoStreamFrameBuffer.is_some()
+                    && table.ReleaseVideoStreamingService.is_some() =>
+            {
+                (has_camera, acquire)
+            }
+            _ => {
+                return Err(Error::Interface(
+                    "TrackedCamera interface of the runtime is missing functions".to_owned(),
+                ))
+            }
+        };
+        let mut camera = false;
+        check(unsafe { has_camera(device, &mut camera) })?;
+        if !camera {
+            return Err(Error::Interface(format!("Device {} has no camera", device)));
+        }
+        let mut handle = 0;
+        check(unsafe { acquire(device, &mut handle) })?;
+        Ok(Self {
+            table,
+            handle,
+            last_sequence: None,
+        })
+    }
+
+    /// Pose of the latest frame, also pushing the image to `stream` if it's new
+    ///
+    /// `None` until the camera delivered its first frame.
+    pub fn poll(&mut self, stream: Option<&CameraStream>) -> Option<openvr::TrackedDevicePose> {
+        let mut header: openvr_sys::CameraVideoStreamFrameHeader_t = unsafe { std::mem::zeroed() };
+        let header_size = std::mem::size_of_val(&header) as u32;
+        // checked when opening
+        let get_frame = self.table.GetVideoStreamFrameBuffer?;
+        // a null buffer only fills the header, which is cheap
+        let error = unsafe {
+            get_frame(
+                self.handle,
+                openvr_sys::EVRTrackedCameraFrameType_VRTrackedCameraFrameType_Undistorted,
+                std::ptr::null_mut(),
+                0,
+                &mut header,
+                header_size,
+            )
+        };
+        if error != openvr_sys::EVRTrackedCameraError_VRTrackedCameraError_None {
+            return None;
+        }
+        if let Some(stream) = stream {
+            if self.last_sequence != Some(header.nFrameSequence) {
+                let size = (header.nWidth * header.nHeight * header.nBytesPerPixel) as usize;
+                let mut pixels = vec![0u8; size];
+                let error = unsafe {
+                    get_frame(
+                        self.handle,
+                        openvr_sys::EVRTrackedCameraFrameType_VRTrackedCameraFrameType_Undistorted,
+                        pixels.as_mut_ptr() as *mut _,
+                        size as u32,
+                        &mut header,
+                        header_size,
+                    )
+                };
+                if error == openvr_sys::EVRTrackedCameraError_VRTrackedCameraError_None {
+                    self.last_sequence = Some(header.nFrameSequence);
+                    stream.push_raw(RawFrame {
+                        width: header.nWidth as usize,
+                        height: header.nHeight as usize,
+                        bytes_per_pixel: header.nBytesPerPixel as usize,
+                        pixels,
+                    });
+                }
+            }
+        }
+        Some(header.standingTrackedDevicePose.into())
+    }
+}
+
+impl Drop for TrackedCamera {
+    fn drop(&mut self) {
+        if let Some(release) = self.table.ReleaseVideoStreamingService {
+            unsafe {
+                release(self.handle);
+            }
+        }
+    }
+}
+
+fn check(error: openvr_sys::EVRTrackedCameraError) -> Result<()> {
+    if error != openvr_sys::EVRTrackedCameraError_VRTrackedCameraError_None {
+        return Err(Error::Interface(format!("TrackedCamera error {}", error)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_encodes_latest_frame() {
+        let stream = CameraStream::new();
+        assert!(stream.wait_jpeg(0, Duration::from_millis(1)).is_none());
+        stream.spawn_encoder(80);
+        stream.push_raw(RawFrame {
+            width: 16,
+            height: 8,
+            bytes_per_pixel: 3,
+            pixels: vec![200; 16 * 8 * 3],
+        });
+        let (number, jpeg) = stream.wait_jpeg(0, Duration::from_secs(5)).unwrap();
+        assert_eq!(number, 1);
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+        assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+        assert!(stream.wait_jpeg(1, Duration::from_millis(10)).is_none());
+
+        let mut raw = RawFrame {
+            width: 4,
+            height: 4,
+            bytes_per_pixel: 2,
+            pixels: vec![0; 4 * 4 * 2],
+        };
+        assert!(encode(&raw, 80).is_err());
+        raw.bytes_per_pixel = 4;
+        raw.pixels = vec![0; 4 * 4 * 4];
+        assert!(encode(&raw, 80).is_ok());
+    }
+}
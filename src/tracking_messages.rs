@@ -1,21 +1,449 @@
+use crate::events::DeviceEvent;
+use crate::identity::{self, SenderIdentity};
+use crate::markers::Marker;
 use crate::openvr_adaptor;
+use crate::quality::DeviceQuality;
 use nalgebra as na;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use std::usize;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackedObjects {
+    /// Frame counter, used by receivers to detect lost packets. Counted per channel
+    #[serde(default)]
+    seq: u64,
     ts: u128,
+    /// Sender of the frame, kept when the frame is reset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sender: Option<SenderIdentity>,
+    /// Clock `ts` was taken from, kept when the frame is reset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time_source: Option<TimeSource>,
+    /// Frame only contains devices that changed since the last one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+    /// Sequence number of the keyframe a partial frame builds on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyframe_seq: Option<u64>,
+    /// Liveness message sent while there are no frames to publish
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    heartbeat: bool,
+    /// Poses were predicted from the latest sample and its velocities, not sampled
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    extrapolated: bool,
+    /// Only sent for the event channel
+    #[serde(default, skip_serializing_if = "Channel::is_pose")]
+    channel: Channel,
+    /// Receivers with a name should acknowledge the message, the sender retries until they do
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    ack_requested: bool,
+    /// Device connects and disconnects, sent on the event channel
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<DeviceEvent>,
+    /// Named markers set by the operator, sent on the event channel
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    markers: Vec<Marker>,
+    /// Change in stream health, sent on a heartbeat
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<StreamStatus>,
+    /// Tracking quality of every device, sent on a periodic heartbeat
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<DeviceQuality>,
+    /// Headset display parameters, sent on a heartbeat at startup and with metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display: Option<DisplayInfo>,
+    /// Slowly changing device details, sent on the event channel at the metadata interval
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    devices: Vec<DeviceMetadata>,
+    /// Corners of the play area rectangle, sent with metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    play_area: Option<[[f32; 3]; 4]>,
     trackers: Vec<VrDevice>,
+    /// Device poses and static transforms as a tree, when configured
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    transforms: Vec<FrameTransform>,
+    /// Poses of configured device pairs relative to each other
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    relative: Vec<RelativePose>,
+}
+
+/// Logical stream a message belongs to
+///
+/// Sinks can route the two to different destinations so pose consumers
+/// don't have to parse events and the other way around.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    /// Device poses and liveness heartbeats at the frame rate
+    #[default]
+    Pose,
+    /// Status, diagnostics, metadata and device events, sent only when there is something to say
+    Event,
+}
+
+impl Channel {
+    pub fn is_pose(&self) -> bool {
+        *self == Channel::Pose
+    }
+}
+
+/// Device details that rarely change, published separately from poses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceMetadata {
+    pub id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<Arc<str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<Arc<str>>,
+    /// Derived from the serial, the same across sessions and machines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guid: Option<Arc<str>>,
+    pub class: VrDeviceClass,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dongle: Option<Arc<str>>,
+}
+
+impl From<&VrDevice> for DeviceMetadata {
+    fn from(device: &VrDevice) -> Self {
+        Self {
+            id: device.id,
+            serial: device.serial.clone(),
+            alias: device.alias.clone(),
+            guid: device.guid.clone(),
+            class: device.class,
+            battery: device.battery,
+            dongle: device.dongle.clone(),
+        }
+    }
+}
+
+/// Display parameters of the headset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisplayInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipd_m: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_rate_hz: Option<f32>,
+    /// Recommended render target size per eye in pixels
+    pub render_width: u32,
+    pub render_height: u32,
+}
+
+/// Clock the timestamps of a frame come from
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeSource {
+    /// System clock, milliseconds since the Unix epoch
+    Wall,
+    /// Milliseconds since the sender started, never jumps but only comparable within a run
+    Monotonic,
+    /// System clock while the kernel reports it synchronized by NTP or PTP
+    Disciplined,
+    /// PTP hardware clock of a network card, usually TAI rather than UTC
+    Ptp,
+}
+
+impl std::str::FromStr for TimeSource {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "wall" => Ok(TimeSource::Wall),
+            "monotonic" => Ok(TimeSource::Monotonic),
+            "disciplined" => Ok(TimeSource::Disciplined),
+            "ptp" => Ok(TimeSource::Ptp),
+            _ => Err(format!(
+                "unknown time source {}, expected wall, monotonic, disciplined or ptp",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamStatus {
+    /// Poses stopped changing, the runtime is probably hung
+    Stale,
+    /// Poses are changing again after being stale
+    Recovered,
+    /// Publishing was paused, devices are still polled
+    Paused,
+    /// Publishing resumed after a pause
+    Resumed,
 }
 
 impl TrackedObjects {
-    pub fn new(ts: u128, trackers: Vec<VrDevice>) -> Self {
-        Self { ts, trackers }
+    pub fn new(seq: u64, ts: u128, trackers: Vec<VrDevice>) -> Self {
+        Self {
+            seq,
+            ts,
+            sender: None,
+            time_source: None,
+            partial: false,
+            keyframe_seq: None,
+            heartbeat: false,
+            extrapolated: false,
+            channel: Channel::Pose,
+            ack_requested: false,
+            events: vec![],
+            markers: vec![],
+            status: None,
+            diagnostics: vec![],
+            display: None,
+            devices: vec![],
+            play_area: None,
+            trackers,
+            transforms: vec![],
+            relative: vec![],
+        }
+    }
+
+    pub fn heartbeat(seq: u64, ts: u128) -> Self {
+        let mut frame = Self::new(seq, ts, vec![]);
+        frame.set_heartbeat();
+        frame
+    }
+
+    pub fn is_heartbeat(&self) -> bool {
+        self.heartbeat
+    }
+
+    pub fn extrapolated(&self) -> bool {
+        self.extrapolated
+    }
+
+    pub fn set_extrapolated(&mut self, extrapolated: bool) {
+        self.extrapolated = extrapolated;
+    }
+
+    pub fn ack_requested(&self) -> bool {
+        self.ack_requested
+    }
+
+    pub fn set_ack_requested(&mut self, ack_requested: bool) {
+        self.ack_requested = ack_requested;
+    }
+
+    pub fn sender(&self) -> Option<&SenderIdentity> {
+        self.sender.as_ref()
+    }
+
+    pub fn set_sender(&mut self, sender: Option<SenderIdentity>) {
+        self.sender = sender;
+    }
+
+    pub fn time_source(&self) -> Option<TimeSource> {
+        self.time_source
+    }
+
+    pub fn set_time_source(&mut self, time_source: Option<TimeSource>) {
+        self.time_source = time_source;
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn set_channel(&mut self, channel: Channel) {
+        self.channel = channel;
+    }
+
+    pub fn events(&self) -> &[DeviceEvent] {
+        &self.events
+    }
+
+    pub fn set_events(&mut self, events: Vec<DeviceEvent>) {
+        self.events = events;
+    }
+
+    pub fn markers(&self) -> &[Marker] {
+        &self.markers
+    }
+
+    pub fn set_markers(&mut self, markers: Vec<Marker>) {
+        self.markers = markers;
+    }
+
+    pub fn status(&self) -> Option<StreamStatus> {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: Option<StreamStatus>) {
+        self.status = status;
+    }
+
+    pub fn diagnostics(&self) -> &[DeviceQuality] {
+        &self.diagnostics
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Vec<DeviceQuality>) {
+        self.diagnostics = diagnostics;
+    }
+
+    pub fn display(&self) -> Option<&DisplayInfo> {
+        self.display.as_ref()
+    }
+
+    pub fn set_display(&mut self, display: Option<DisplayInfo>) {
+        self.display = display;
+    }
+
+    pub fn devices(&self) -> &[DeviceMetadata] {
+        &self.devices
+    }
+
+    pub fn set_devices(&mut self, devices: Vec<DeviceMetadata>) {
+        self.devices = devices;
+    }
+
+    pub fn play_area(&self) -> Option<[[f32; 3]; 4]> {
+        self.play_area
+    }
+
+    pub fn set_play_area(&mut self, play_area: Option<[[f32; 3]; 4]>) {
+        self.play_area = play_area;
+    }
+
+    /// Mark frame as a delta on top of the keyframe with sequence number `keyframe_seq`
+    pub fn set_delta_of(&mut self, keyframe_seq: u64) {
+        self.partial = true;
+        self.keyframe_seq = Some(keyframe_seq);
+    }
+
+    /// Mark frame as a keyframe, its devices replace what receivers had
+    pub fn set_keyframe(&mut self) {
+        self.partial = false;
+        self.keyframe_seq = None;
+    }
+
+    pub fn partial(&self) -> bool {
+        self.partial
+    }
+
+    pub fn keyframe_seq(&self) -> Option<u64> {
+        self.keyframe_seq
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn ts(&self) -> u128 {
+        self.ts
+    }
+
+    pub fn set_ts(&mut self, ts: u128) {
+        self.ts = ts;
+    }
+
+    /// Shift the sequence number and the keyframe it refers to
+    pub fn offset_seq(&mut self, offset: u64) {
+        self.seq += offset;
+        if let Some(keyframe_seq) = self.keyframe_seq.as_mut() {
+            *keyframe_seq += offset;
+        }
+    }
+
+    pub fn trackers(&self) -> &[VrDevice] {
+        &self.trackers
+    }
+
+    pub fn trackers_mut(&mut self) -> &mut Vec<VrDevice> {
+        &mut self.trackers
+    }
+
+    pub fn transforms(&self) -> &[FrameTransform] {
+        &self.transforms
+    }
+
+    pub fn transforms_mut(&mut self) -> &mut Vec<FrameTransform> {
+        &mut self.transforms
+    }
+
+    pub fn relative(&self) -> &[RelativePose] {
+        &self.relative
+    }
+
+    pub fn relative_mut(&mut self) -> &mut Vec<RelativePose> {
+        &mut self.relative
+    }
+
+    /// Reuse frame for a new sequence number, keeping the tracker buffer
+    pub fn reset(&mut self, seq: u64, ts: u128) {
+        self.seq = seq;
+        self.ts = ts;
+        self.partial = false;
+        self.keyframe_seq = None;
+        self.heartbeat = false;
+        self.extrapolated = false;
+        self.channel = Channel::Pose;
+        self.ack_requested = false;
+        self.events.clear();
+        self.markers.clear();
+        self.status = None;
+        self.diagnostics.clear();
+        self.display = None;
+        self.devices.clear();
+        self.play_area = None;
+        self.transforms.clear();
+        self.relative.clear();
+    }
+
+    /// Copy frame into `other`, keeping only trackers that pass `filter`
+    ///
+    /// Reuses the tracker buffer of `other`.
+    pub fn filter_into(&self, other: &mut Self, filter: impl Fn(&VrDevice) -> bool) {
+        let mut trackers = std::mem::take(&mut other.trackers);
+        trackers.clear();
+        trackers.extend(
+            self.trackers
+                .iter()
+                .filter(|device| filter(device))
+                .cloned(),
+        );
+        *other = Self {
+            seq: self.seq,
+            ts: self.ts,
+            sender: self.sender.clone(),
+            time_source: self.time_source,
+            partial: self.partial,
+            keyframe_seq: self.keyframe_seq,
+            heartbeat: self.heartbeat,
+            extrapolated: self.extrapolated,
+            channel: self.channel,
+            ack_requested: self.ack_requested,
+            events: self.events.clone(),
+            markers: self.markers.clone(),
+            status: self.status,
+            diagnostics: self.diagnostics.clone(),
+            display: self.display.clone(),
+            devices: self.devices.clone(),
+            play_area: self.play_area,
+            transforms: self
+                .transforms
+                .iter()
+                .filter(|edge| trackers.iter().any(|device| device.id() == edge.id))
+                .cloned()
+                .collect(),
+            relative: self.relative.clone(),
+            trackers,
+        };
+    }
+
+    /// Turn frame into a heartbeat, dropping all trackers
+    pub fn set_heartbeat(&mut self) {
+        self.heartbeat = true;
+        self.trackers.clear();
+        self.transforms.clear();
+        self.relative.clear();
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum VrDeviceClass {
     Controller,
     LeftController,
@@ -24,9 +452,20 @@ pub enum VrDeviceClass {
     HMD,
     Sensor,
     Other,
+    /// Headset front camera, published with `--camera`
+    Camera,
 }
 
 impl VrDeviceClass {
+    pub fn is_controller(&self) -> bool {
+        matches!(
+            self,
+            VrDeviceClass::Controller
+                | VrDeviceClass::LeftController
+                | VrDeviceClass::RightController
+        )
+    }
+
     pub fn from_openvr_types(
         device_class: openvr::TrackedDeviceClass,
         controller: Option<openvr::TrackedControllerRole>,
@@ -50,25 +489,248 @@ impl VrDeviceClass {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Body part or use a device is assigned to
+///
+/// The headset and hand controllers get theirs from their class, trackers the
+/// role set for them in SteamVR. Variants are in the order `device_order: role` sorts by.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceRole {
+    Head,
+    LeftHand,
+    RightHand,
+    Waist,
+    Chest,
+    LeftFoot,
+    RightFoot,
+    LeftKnee,
+    RightKnee,
+    LeftAnkle,
+    RightAnkle,
+    LeftShoulder,
+    RightShoulder,
+    LeftElbow,
+    RightElbow,
+    LeftWrist,
+    RightWrist,
+    Camera,
+    Keyboard,
+}
+
+impl DeviceRole {
+    /// Role of a device of `class`, for classes that imply one
+    pub fn of_class(class: VrDeviceClass) -> Option<Self> {
+        match class {
+            VrDeviceClass::HMD => Some(DeviceRole::Head),
+            VrDeviceClass::LeftController => Some(DeviceRole::LeftHand),
+            VrDeviceClass::RightController => Some(DeviceRole::RightHand),
+            _ => None,
+        }
+    }
+
+    pub const ALL: [DeviceRole; 19] = [
+        DeviceRole::Head,
+        DeviceRole::LeftHand,
+        DeviceRole::RightHand,
+        DeviceRole::Waist,
+        DeviceRole::Chest,
+        DeviceRole::LeftFoot,
+        DeviceRole::RightFoot,
+        DeviceRole::LeftKnee,
+        DeviceRole::RightKnee,
+        DeviceRole::LeftAnkle,
+        DeviceRole::RightAnkle,
+        DeviceRole::LeftShoulder,
+        DeviceRole::RightShoulder,
+        DeviceRole::LeftElbow,
+        DeviceRole::RightElbow,
+        DeviceRole::LeftWrist,
+        DeviceRole::RightWrist,
+        DeviceRole::Camera,
+        DeviceRole::Keyboard,
+    ];
+
+    /// Name as in JSON, like `left_foot`
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceRole::Head => "head",
+            DeviceRole::LeftHand => "left_hand",
+            DeviceRole::RightHand => "right_hand",
+            DeviceRole::Waist => "waist",
+            DeviceRole::Chest => "chest",
+            DeviceRole::LeftFoot => "left_foot",
+            DeviceRole::RightFoot => "right_foot",
+            DeviceRole::LeftKnee => "left_knee",
+            DeviceRole::RightKnee => "right_knee",
+            DeviceRole::LeftAnkle => "left_ankle",
+            DeviceRole::RightAnkle => "right_ankle",
+            DeviceRole::LeftShoulder => "left_shoulder",
+            DeviceRole::RightShoulder => "right_shoulder",
+            DeviceRole::LeftElbow => "left_elbow",
+            DeviceRole::RightElbow => "right_elbow",
+            DeviceRole::LeftWrist => "left_wrist",
+            DeviceRole::RightWrist => "right_wrist",
+            DeviceRole::Camera => "camera",
+            DeviceRole::Keyboard => "keyboard",
+        }
+    }
+
+    /// Tracker role from the controller type SteamVR reports, like `vive_tracker_left_foot`
+    pub fn from_controller_type(controller_type: &str) -> Option<Self> {
+        let name = controller_type.strip_prefix("vive_tracker_")?;
+        Self::ALL.iter().copied().find(|role| role.name() == name)
+    }
+}
+
+/// Why a device is or isn't tracked
+///
+/// Tells optical problems (out of range, occluded) apart from radio dropouts (disconnected).
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackingState {
+    #[default]
+    Ok,
+    /// Connected, but the sensors can't see the base stations
+    OutOfRange,
+    Calibrating,
+    /// Only orientation is tracked
+    RotationOnly,
+    /// No radio link to the device
+    Disconnected,
+    Uninitialized,
+}
+
+impl TrackingState {
+    pub fn is_ok(&self) -> bool {
+        *self == TrackingState::Ok
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self != TrackingState::Disconnected
+    }
+}
+
+/// Origin of the tracking space poses are reported in
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Universe {
+    /// Floor level, as set up in room setup. Poses of the main stream are in this one
+    Standing,
+    /// Seated zero pose
+    Seated,
+    /// Raw lighthouse space, without room setup applied
+    Raw,
+}
+
+/// Pose of a device in another tracking universe than the main stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UniversePose {
+    pub universe: Universe,
+    pub position: na::Point3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+}
+
+/// Edge of the transform tree, the pose of `child` in the `parent` frame
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameTransform {
+    /// Device the child moves with
+    pub id: usize,
+    pub parent: Arc<str>,
+    pub child: Arc<str>,
+    pub translation: na::Vector3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+}
+
+/// Pose and velocity of device `id` in the frame of device `reference_id`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelativePose {
+    pub name: Arc<str>,
+    pub id: usize,
+    pub reference_id: usize,
+    pub translation: na::Vector3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+    /// Meters per second, in the reference frame
+    pub velocity: na::Vector3<f32>,
+    /// Radians per second, in the reference frame
+    pub angular_velocity: na::Vector3<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VrDevice {
     id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    serial: Option<Arc<str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alias: Option<Arc<str>>,
+    /// Derived from the serial, the same across sessions and machines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    guid: Option<Arc<str>>,
+    /// Sender the device came from, set when streams of several senders are merged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Arc<str>>,
     tracked: bool,
     seen: bool,
+    /// Charge between 0 and 1, for devices that report it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    battery: Option<f32>,
+    /// Only sent when not ok
+    #[serde(default, skip_serializing_if = "TrackingState::is_ok")]
+    state: TrackingState,
+    /// Wireless dongle the device is paired with, for wireless devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dongle: Option<Arc<str>>,
     position: na::Point3<f32>,
     rotation: na::UnitQuaternion<f32>,
     class: VrDeviceClass,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<DeviceRole>,
+    /// Poses in the universes listed in the config, as reported by OpenVR
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    universes: Vec<UniversePose>,
+    /// Unit vector along -Z of the rotation, where the device points
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forward: Option<na::Vector3<f32>>,
+    /// Unit vector along +Y of the rotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    up: Option<na::Vector3<f32>>,
+    /// Estimated 6x6 pose covariance, x, y, z then rotation about x, y, z
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    covariance: Option<na::Matrix6<f32>>,
+    /// Pose was outside of its workspace limits, and clamped into them if configured
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    outside_workspace: bool,
+    /// Meters per second as reported by the runtime, only used for extrapolation
+    #[serde(skip, default = "na::Vector3::zeros")]
+    velocity: na::Vector3<f32>,
+    /// Radians per second about the axes of the tracking space
+    #[serde(skip, default = "na::Vector3::zeros")]
+    angular_velocity: na::Vector3<f32>,
 }
 
 impl VrDevice {
     pub fn new(id: usize) -> Self {
         Self {
             id,
+            serial: None,
+            alias: None,
+            guid: None,
+            source: None,
             tracked: false,
             seen: false,
+            battery: None,
+            state: TrackingState::Ok,
+            dongle: None,
             position: na::Point3::new(0., 0., 0.),
             rotation: na::UnitQuaternion::identity(),
             class: VrDeviceClass::Other,
+            role: None,
+            universes: vec![],
+            forward: None,
+            up: None,
+            covariance: None,
+            outside_workspace: false,
+            velocity: na::Vector3::zeros(),
+            angular_velocity: na::Vector3::zeros(),
         }
     }
 
@@ -85,13 +747,198 @@ impl VrDevice {
         self.position = pose.to_position();
         self.rotation = pose.to_rotation();
         self.class = class;
+        // hand roles move between controllers, tracker roles are set with the serial
+        if let Some(role) = DeviceRole::of_class(class) {
+            self.role = Some(role);
+        } else if class.is_controller() {
+            self.role = None;
+        }
+    }
+
+    /// Set pose decoded from a frame. Devices in a frame have always been seen
+    pub fn set_pose(
+        &mut self,
+        tracked: bool,
+        position: na::Point3<f32>,
+        rotation: na::UnitQuaternion<f32>,
+    ) {
+        self.tracked = tracked;
+        self.seen = true;
+        self.position = position;
+        self.rotation = rotation;
+    }
+
+    /// Apply rigid transform to the pose
+    pub fn transform(&mut self, transform: &na::Isometry3<f32>) {
+        self.position = transform * self.position;
+        self.rotation = transform.rotation * self.rotation;
+        self.velocity = transform.rotation * self.velocity;
+        self.angular_velocity = transform.rotation * self.angular_velocity;
+        if self.forward.is_some() {
+            self.set_directions();
+        }
+    }
+
+    /// Publish forward and up vectors derived from the current rotation
+    pub fn set_directions(&mut self) {
+        self.forward = Some(self.rotation * -na::Vector3::z());
+        self.up = Some(self.rotation * na::Vector3::y());
+    }
+
+    pub fn forward(&self) -> Option<&na::Vector3<f32>> {
+        self.forward.as_ref()
+    }
+
+    pub fn up(&self) -> Option<&na::Vector3<f32>> {
+        self.up.as_ref()
+    }
+
+    pub fn covariance(&self) -> Option<&na::Matrix6<f32>> {
+        self.covariance.as_ref()
+    }
+
+    pub fn set_covariance(&mut self, covariance: Option<na::Matrix6<f32>>) {
+        self.covariance = covariance;
+    }
+
+    pub fn velocity(&self) -> &na::Vector3<f32> {
+        &self.velocity
+    }
+
+    pub fn angular_velocity(&self) -> &na::Vector3<f32> {
+        &self.angular_velocity
+    }
+
+    pub fn set_velocity(&mut self, velocity: na::Vector3<f32>, angular_velocity: na::Vector3<f32>) {
+        self.velocity = velocity;
+        self.angular_velocity = angular_velocity;
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
 
+    /// Name in transform trees, the alias, serial or `device_<id>`
+    pub fn frame_id(&self) -> String {
+        match self.alias().or_else(|| self.serial()) {
+            Some(name) => name.to_owned(),
+            None => format!("device_{}", self.id),
+        }
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    /// Set serial and the GUID derived from it
+    pub fn set_serial(&mut self, serial: Option<Arc<str>>) {
+        self.guid = serial
+            .as_deref()
+            .map(|serial| identity::device_guid(serial).into());
+        self.serial = serial;
+    }
+
+    pub fn guid(&self) -> Option<&str> {
+        self.guid.as_deref()
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    pub fn set_alias(&mut self, alias: Option<Arc<str>>) {
+        self.alias = alias;
+    }
+
+    pub fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn set_source(&mut self, source: Option<Arc<str>>) {
+        self.source = source;
+    }
+
+    pub fn battery(&self) -> Option<f32> {
+        self.battery
+    }
+
+    pub fn set_battery(&mut self, battery: Option<f32>) {
+        self.battery = battery;
+    }
+
+    pub fn state(&self) -> TrackingState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: TrackingState) {
+        self.state = state;
+    }
+
+    pub fn dongle(&self) -> Option<&str> {
+        self.dongle.as_deref()
+    }
+
+    pub fn set_dongle(&mut self, dongle: Option<Arc<str>>) {
+        self.dongle = dongle;
+    }
+
+    pub fn role(&self) -> Option<DeviceRole> {
+        self.role
+    }
+
+    pub fn set_role(&mut self, role: Option<DeviceRole>) {
+        self.role = role;
+    }
+
+    pub fn universes(&self) -> &[UniversePose] {
+        &self.universes
+    }
+
+    pub fn universes_mut(&mut self) -> &mut Vec<UniversePose> {
+        &mut self.universes
+    }
+
+    /// Drop details that are published with metadata instead
+    pub fn strip_metadata(&mut self) {
+        self.serial = None;
+        self.guid = None;
+        self.battery = None;
+        self.dongle = None;
+    }
+
+    pub fn set_class(&mut self, class: VrDeviceClass) {
+        self.class = class;
+    }
+
+    pub fn outside_workspace(&self) -> bool {
+        self.outside_workspace
+    }
+
+    pub fn set_outside_workspace(&mut self, outside_workspace: bool) {
+        self.outside_workspace = outside_workspace;
+    }
+
     pub fn seen(&self) -> bool {
         self.seen
     }
+
+    pub fn tracked(&self) -> bool {
+        self.tracked
+    }
+
+    pub fn position(&self) -> &na::Point3<f32> {
+        &self.position
+    }
+
+    pub fn rotation(&self) -> &na::UnitQuaternion<f32> {
+        &self.rotation
+    }
+
+    pub fn class(&self) -> VrDeviceClass {
+        self.class
+    }
 }
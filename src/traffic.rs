@@ -0,0 +1,197 @@
+//! Outgoing bandwidth of each sink
+//!
+//! Sinks count what they put on the network in a shared [`Traffic`], which
+//! the publisher turns into rates with a [`TrafficMeter`] for the log,
+//! `/metrics` and the terminal dashboard.
+
+use crate::quality::label_value;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Rates are averaged over this window
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Bytes and packets sent by a sink, readable from other threads
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    bytes: Arc<AtomicU64>,
+    packets: Arc<AtomicU64>,
+}
+
+impl Traffic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one packet or write of `bytes`
+    pub fn record(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkTraffic {
+    pub sink: String,
+    pub bytes: u64,
+    pub packets: u64,
+    pub bytes_per_s: f32,
+    pub packets_per_s: f32,
+}
+
+impl SinkTraffic {
+    /// One line summary like `multicast 12.3 kB/s 120 packets/s`
+    pub fn summary(&self) -> String {
+        format!(
+            "{} {:.1} kB/s {:.0} packets/s",
+            self.sink,
+            self.bytes_per_s / 1000.0,
+            self.packets_per_s
+        )
+    }
+}
+
+/// Turns running totals into per second rates
+#[derive(Default)]
+pub struct TrafficMeter {
+    last_update: Option<Instant>,
+    sinks: Vec<SinkTraffic>,
+}
+
+impl TrafficMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take new totals, rates are recomputed about once a second
+    pub fn update<'a>(
+        &mut self,
+        totals: impl Iterator<Item = (&'a str, &'a Traffic)>,
+        now: Instant,
+    ) {
+        let elapsed = match self.last_update {
+            Some(last_update) if now.duration_since(last_update) < RATE_WINDOW => return,
+            Some(last_update) => Some(now.duration_since(last_update).as_secs_f32()),
+            None => None,
+        };
+        self.last_update = Some(now);
+        let mut sinks = Vec::with_capacity(self.sinks.len());
+        for (name, traffic) in totals {
+            let (bytes, packets) = (traffic.bytes(), traffic.packets());
+            let previous = self.sinks.iter().find(|previous| previous.sink == name);
+            let (bytes_per_s, packets_per_s) = match (previous, elapsed) {
+                (Some(previous), Some(elapsed)) => (
+                    bytes.saturating_sub(previous.bytes) as f32 / elapsed,
+                    packets.saturating_sub(previous.packets) as f32 / elapsed,
+                ),
+                _ => (0.0, 0.0),
+            };
+            sinks.push(SinkTraffic {
+                sink: name.to_owned(),
+                bytes,
+                packets,
+                bytes_per_s,
+                packets_per_s,
+            });
+        }
+        self.sinks = sinks;
+    }
+
+    pub fn sinks(&self) -> &[SinkTraffic] {
+        &self.sinks
+    }
+
+    /// Totals and rates in the Prometheus text format
+    pub fn prometheus(&self) -> String {
+        type Metric = (
+            &'static str,
+            &'static str,
+            &'static str,
+            fn(&SinkTraffic) -> f64,
+        );
+        let metrics: [Metric; 4] = [
+            (
+                "openvr_tracker_sent_bytes_total",
+                "Bytes sent by a sink",
+                "counter",
+                |sink| sink.bytes as f64,
+            ),
+            (
+                "openvr_tracker_sent_packets_total",
+                "Packets or writes sent by a sink",
+                "counter",
+                |sink| sink.packets as f64,
+            ),
+            (
+                "openvr_tracker_sent_bytes_per_second",
+                "Bytes per second sent by a sink",
+                "gauge",
+                |sink| sink.bytes_per_s as f64,
+            ),
+            (
+                "openvr_tracker_sent_packets_per_second",
+                "Packets or writes per second sent by a sink",
+                "gauge",
+                |sink| sink.packets_per_s as f64,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, kind, value) in metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for sink in &self.sinks {
+                let _ = writeln!(
+                    out,
+                    "{}{{sink=\"{}\"}} {}",
+                    name,
+                    label_value(&sink.sink),
+                    value(sink)
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates_from_totals() {
+        let traffic = Traffic::new();
+        let mut meter = TrafficMeter::new();
+        let start = Instant::now();
+        meter.update(vec![("udp", &traffic)].into_iter(), start);
+        for _ in 0..10 {
+            traffic.record(100);
+        }
+        // too soon, rates stay
+        meter.update(
+            vec![("udp", &traffic)].into_iter(),
+            start + Duration::from_millis(500),
+        );
+        assert_eq!(meter.sinks()[0].packets, 0);
+        meter.update(
+            vec![("udp", &traffic)].into_iter(),
+            start + Duration::from_secs(2),
+        );
+        let sink = &meter.sinks()[0];
+        assert_eq!(sink.bytes, 1000);
+        assert_eq!(sink.bytes_per_s, 500.0);
+        assert_eq!(sink.packets_per_s, 5.0);
+        assert!(meter
+            .prometheus()
+            .contains("openvr_tracker_sent_bytes_total{sink=\"udp\"} 1000\n"));
+    }
+}
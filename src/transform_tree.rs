@@ -0,0 +1,167 @@
+//! Device poses as a tree of transforms, like ROS `tf`
+//!
+//! Consumers that compose frames, robot base to tracker to gripper, otherwise
+//! rebuild the hierarchy from the flat device list by hand. With
+//! `transform_tree` set every pose frame also carries `transforms`: one edge
+//! from the root frame to every tracked device, then the configured static
+//! transforms, like a tracker to the tip of the tool it is mounted on.
+//!
+//! Frames are named like in the ROS 2 MCAP layout, by alias, serial or
+//! `device_<id>`. A static transform can hang off a device or off the child of
+//! an earlier static transform, and is only published while its parent is.
+
+use crate::config::device_named;
+use crate::tracking_messages::{FrameTransform, VrDevice};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformTreeConfig {
+    /// Frame every device pose is relative to
+    pub root: Arc<str>,
+    /// Fixed transforms below devices, in order
+    pub static_transforms: Vec<StaticTransform>,
+}
+
+impl Default for TransformTreeConfig {
+    fn default() -> Self {
+        Self {
+            root: "world".into(),
+            static_transforms: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticTransform {
+    /// Serial or alias of a device, or the child of an earlier static transform
+    pub parent: String,
+    pub child: Arc<str>,
+    /// Pose of the child in the parent frame
+    pub transform: na::Isometry3<f32>,
+}
+
+impl TransformTreeConfig {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.root.trim().is_empty() {
+            problems.push("transform_tree.root is empty".to_owned());
+        }
+        let mut children = HashSet::new();
+        for transform in &self.static_transforms {
+            if transform.child.trim().is_empty() {
+                problems.push(format!(
+                    "Static transform below {} has no child name",
+                    transform.parent
+                ));
+            } else if *transform.child == *self.root || transform.child.as_ref() == transform.parent
+            {
+                problems.push(format!(
+                    "Static transform {} can't be its own parent or the root",
+                    transform.child
+                ));
+            } else if !children.insert(transform.child.clone()) {
+                problems.push(format!(
+                    "Frame {} is the child of several static transforms",
+                    transform.child
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Write the edges below the root for the tracked `devices` into `out`
+    pub fn build(
+        &self,
+        devices: &[VrDevice],
+        aliases: &HashMap<String, Arc<str>>,
+        out: &mut Vec<FrameTransform>,
+    ) {
+        out.clear();
+        // frame name of every published frame with the device it moves with
+        let mut frames: Vec<(Arc<str>, usize)> = vec![];
+        for device in devices.iter().filter(|device| device.tracked()) {
+            let child: Arc<str> = device.frame_id().into();
+            frames.push((child.clone(), device.id()));
+            out.push(FrameTransform {
+                id: device.id(),
+                parent: self.root.clone(),
+                child,
+                translation: device.position().coords,
+                rotation: *device.rotation(),
+            });
+        }
+        for transform in &self.static_transforms {
+            let parent = frames.iter().find(|(frame, id)| {
+                **frame == *transform.parent
+                    || devices.iter().any(|device| {
+                        device.id() == *id && device_named(device, &transform.parent, aliases)
+                    })
+            });
+            if let Some((parent, id)) = parent.cloned() {
+                frames.push((transform.child.clone(), id));
+                out.push(FrameTransform {
+                    id,
+                    parent,
+                    child: transform.child.clone(),
+                    translation: transform.transform.translation.vector,
+                    rotation: transform.transform.rotation,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IDENTITY_POSE;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_static_transforms_follow_their_parent() {
+        let config: TransformTreeConfig = serde_json::from_str(
+            r#"{"static_transforms": [
+                {"parent": "LHR-1", "child": "gripper", "transform": {"translation": [0, 0, -0.1], "rotation": [0, 0, 0, 1]}},
+                {"parent": "gripper", "child": "tip", "transform": {"translation": [0, 0, -0.05], "rotation": [0, 0, 0, 1]}},
+                {"parent": "LHR-2", "child": "pen", "transform": {"translation": [0, 0, 0], "rotation": [0, 0, 0, 1]}}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(config.problems().is_empty());
+        let aliases: HashMap<String, Arc<str>> = vec![("LHR-1".to_owned(), "arm".into())]
+            .into_iter()
+            .collect();
+        let mut tracker = VrDevice::new(3);
+        tracker.update(true, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        tracker.set_serial(Some("LHR-1".into()));
+        tracker.set_alias(Some("arm".into()));
+        let mut lost = VrDevice::new(4);
+        lost.update(false, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        lost.set_serial(Some("LHR-2".into()));
+
+        let mut transforms = vec![];
+        config.build(&[tracker, lost], &aliases, &mut transforms);
+        let edges: Vec<(&str, &str, usize)> = transforms
+            .iter()
+            .map(|edge| (&*edge.parent, &*edge.child, edge.id))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                ("world", "arm", 3),
+                ("arm", "gripper", 3),
+                ("gripper", "tip", 3)
+            ]
+        );
+        assert_eq!(transforms[2].translation.z, -0.05);
+
+        let mut cycle = config.clone();
+        cycle.static_transforms[1].child = "gripper".into();
+        assert_eq!(cycle.problems().len(), 1);
+    }
+}
@@ -0,0 +1,243 @@
+//! Terminal dashboard showing live device state
+//!
+//! Draws with plain ANSI escape codes, redrawing the whole screen a few times per second.
+//! With keys enabled, lines typed on stdin are read on a background thread,
+//! `p` and Enter toggles pause, `m` with an optional name sets a marker.
+
+use crate::client::FrameAssembler;
+use crate::tracking_messages::{TrackedObjects, VrDevice};
+use crate::traffic::SinkTraffic;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Commands typed on stdin
+#[derive(Debug, Default, PartialEq)]
+pub struct Typed {
+    pub pause_toggled: bool,
+    /// One per `m` line, with the name typed after it
+    pub markers: Vec<Option<String>>,
+}
+
+impl Typed {
+    fn parse(lines: impl Iterator<Item = String>) -> Self {
+        let mut typed = Self::default();
+        for line in lines {
+            if line == "p" {
+                typed.pause_toggled = !typed.pause_toggled;
+            } else if line == "m" {
+                typed.markers.push(None);
+            } else if let Some(name) = line.strip_prefix("m ") {
+                typed.markers.push(Some(name.trim().to_owned()));
+            }
+        }
+        typed
+    }
+}
+
+pub struct Dashboard {
+    assembler: FrameAssembler,
+    latest: Option<TrackedObjects>,
+    start: Instant,
+    last_draw: Option<Instant>,
+    frames: u64,
+    heartbeats: u64,
+    dropped: u64,
+    traffic: Vec<SinkTraffic>,
+    window_start: Instant,
+    window_frames: u64,
+    rate: f32,
+    paused: bool,
+    keys: Option<mpsc::Receiver<String>>,
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Self {
+            assembler: FrameAssembler::new(),
+            latest: None,
+            start: Instant::now(),
+            last_draw: None,
+            frames: 0,
+            heartbeats: 0,
+            dropped: 0,
+            traffic: vec![],
+            window_start: Instant::now(),
+            window_frames: 0,
+            rate: 0.0,
+            paused: false,
+            keys: None,
+        }
+    }
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a published frame
+    pub fn record(&mut self, frame: &TrackedObjects) {
+        if frame.is_heartbeat() {
+            self.heartbeats += 1;
+        } else {
+            self.frames += 1;
+            self.window_frames += 1;
+        }
+        if let Some(frame) = self.assembler.push(frame.clone()) {
+            self.latest = Some(frame);
+        }
+        let window = self.window_start.elapsed();
+        if window >= RATE_WINDOW {
+            self.rate = self.window_frames as f32 / window.as_secs_f32();
+            self.window_frames = 0;
+            self.window_start = Instant::now();
+        }
+    }
+
+    /// Total frames dropped by sink queues
+    pub fn set_dropped(&mut self, dropped: u64) {
+        self.dropped = dropped;
+    }
+
+    /// Outgoing rates of the sinks
+    pub fn set_traffic(&mut self, traffic: Vec<SinkTraffic>) {
+        self.traffic = traffic;
+    }
+
+    /// Read commands typed on stdin
+    pub fn enable_keys(&mut self) {
+        let (sender, keys) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line.trim().to_owned()).is_err() {
+                    return;
+                }
+            }
+        });
+        self.keys = Some(keys);
+    }
+
+    /// Commands typed since the last call
+    pub fn typed(&self) -> Typed {
+        match &self.keys {
+            Some(keys) => Typed::parse(keys.try_iter()),
+            None => Typed::default(),
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Redraw unless the screen was drawn very recently
+    pub fn draw_if_due(&mut self) -> io::Result<()> {
+        if let Some(last_draw) = self.last_draw {
+            if last_draw.elapsed() < REDRAW_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.last_draw = Some(Instant::now());
+        let mut stdout = io::stdout();
+        write!(stdout, "{}{}", CLEAR_SCREEN, self.render())?;
+        stdout.flush()
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "openvr-tracker  uptime {}s  {:.1} frames/s  {} frames  {} heartbeats  {} dropped",
+            self.start.elapsed().as_secs(),
+            self.rate,
+            self.frames,
+            self.heartbeats,
+            self.dropped
+        );
+        if self.paused {
+            let _ = writeln!(out, "PAUSED, nothing is published");
+        }
+        if self.keys.is_some() {
+            let _ = writeln!(out, "p + Enter: pause/resume");
+        }
+        if !self.traffic.is_empty() {
+            let traffic: Vec<String> = self.traffic.iter().map(SinkTraffic::summary).collect();
+            let _ = writeln!(out, "sent  {}", traffic.join("  "));
+        }
+        let _ = writeln!(out);
+        let devices = self
+            .latest
+            .as_ref()
+            .map(|frame| frame.trackers())
+            .unwrap_or_default();
+        out.push_str(&device_table(devices));
+        out
+    }
+}
+
+/// Aligned table with one row per device
+pub fn device_table(devices: &[VrDevice]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:>3}  {:<16} {:<16} {:<8} {:>8} {:>8} {:>8}  {:>7}",
+        "ID", "CLASS", "ALIAS", "TRACKED", "X", "Y", "Z", "BATTERY"
+    );
+    for device in devices {
+        let position = device.position();
+        let battery = match device.battery() {
+            Some(battery) => format!("{:.0}%", battery * 100.0),
+            None => "-".to_owned(),
+        };
+        let _ = writeln!(
+            out,
+            "{:>3}  {:<16} {:<16} {:<8} {:>8.3} {:>8.3} {:>8.3}  {:>7}",
+            device.id(),
+            format!("{:?}", device.class()),
+            device.alias().unwrap_or("-"),
+            if device.tracked() { "yes" } else { "LOST" },
+            position.x,
+            position.y,
+            position.z,
+            battery
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_render_device_row() {
+        let mut device = VrDevice::new(3);
+        device.set_class(VrDeviceClass::Tracker);
+        device.set_alias(Some("waist".into()));
+        device.set_battery(Some(0.5));
+        let mut dashboard = Dashboard::new();
+        dashboard.record(&TrackedObjects::new(0, 0, vec![device]));
+        let screen = dashboard.render();
+        let row = screen.lines().nth(3).unwrap();
+        assert!(row.contains("Tracker"));
+        assert!(row.contains("waist"));
+        assert!(row.contains("LOST"));
+        assert!(row.contains("50%"));
+    }
+
+    #[test]
+    fn test_typed_commands() {
+        let lines = ["p", "m trial 3 start", "x", "m", "p", "p"];
+        let typed = Typed::parse(lines.iter().map(|line| line.to_string()));
+        assert!(typed.pause_toggled);
+        assert_eq!(typed.markers, vec![Some("trial 3 start".to_owned()), None]);
+    }
+}
@@ -0,0 +1,373 @@
+//! Point-to-point outputs for networks that don't route multicast
+//!
+//! [`UdpSender`] sends every frame to fixed addresses, [`TcpServer`] streams
+//! newline-delimited JSON to everyone who connects. TCP clients can tailor
+//! their stream with a [handshake](crate::handshake).
+
+use crate::authentication::Authenticator;
+use crate::encryption::Cipher;
+use crate::error::{Error, Result};
+use crate::fragment::{self, Splitter};
+use crate::handshake::{self, TailoredStream};
+use crate::json_format::JsonFormat;
+use crate::multicast;
+use crate::sink::PoseSink;
+use crate::socket_options::SocketOptions;
+use crate::tracking_messages::TrackedObjects;
+use crate::traffic::Traffic;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Slow clients get dropped instead of stalling the stream
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Longest hello line a client may send, in bytes
+const MAX_HELLO: u64 = 4096;
+
+/// Sends each frame as a datagram to a list of addresses
+pub struct UdpSender {
+    socket: UdpSocket,
+    destinations: Vec<SocketAddr>,
+    format: JsonFormat,
+    buffer: Vec<u8>,
+    authentication: Option<Authenticator>,
+    encryption: Option<Cipher>,
+    sealed: Vec<u8>,
+    /// Payloads that don't fit are split into parts when set
+    max_packet_bytes: Option<usize>,
+    splitter: Splitter,
+    traffic: Traffic,
+}
+
+impl UdpSender {
+    pub fn new(destinations: Vec<SocketAddr>) -> Result<Self> {
+        let bind_address: SocketAddr = match destinations.first() {
+            Some(destination) if destination.is_ipv6() => ([0u16; 8], 0).into(),
+            _ => ([0, 0, 0, 0], 0).into(),
+        };
+        Ok(Self {
+            socket: UdpSocket::bind(bind_address).map_err(Error::Network)?,
+            destinations,
+            format: JsonFormat::default(),
+            buffer: vec![],
+            authentication: None,
+            encryption: None,
+            sealed: vec![],
+            max_packet_bytes: None,
+            splitter: Splitter::new(),
+            traffic: Traffic::new(),
+        })
+    }
+
+    pub fn set_format(&mut self, format: JsonFormat) {
+        self.format = format;
+    }
+
+    /// Apply `options` to the socket datagrams are sent from
+    pub fn set_socket_options(&self, options: &SocketOptions) -> Result<()> {
+        options.apply(&self.socket)
+    }
+
+    /// Encrypt every datagram, receivers need the same key
+    pub fn set_encryption(&mut self, encryption: Option<Cipher>) {
+        self.encryption = encryption;
+    }
+
+    /// Append an HMAC to every datagram, receivers need the same key
+    pub fn set_authentication(&mut self, authentication: Option<Authenticator>) {
+        self.authentication = authentication;
+    }
+
+    /// Split payloads into parts so no datagram is larger than `max_packet_bytes`
+    pub fn set_max_packet_bytes(&mut self, max_packet_bytes: Option<usize>) {
+        self.max_packet_bytes = max_packet_bytes;
+    }
+
+    /// Protect the payload in the buffer and send it to every destination
+    fn transmit(&mut self) -> Result<()> {
+        multicast::protect(
+            &mut self.buffer,
+            &mut self.sealed,
+            self.authentication.as_mut(),
+            self.encryption.as_mut(),
+        );
+        for destination in &self.destinations {
+            self.traffic.record(
+                self.socket
+                    .send_to(&self.buffer, destination)
+                    .map_err(Error::Network)?,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl PoseSink for UdpSender {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        self.buffer.clear();
+        self.format.write(frame, &mut self.buffer)?;
+        let part_bytes = multicast::part_bytes(
+            self.max_packet_bytes,
+            self.authentication.is_some(),
+            self.encryption.is_some(),
+        );
+        match part_bytes {
+            Some(part_bytes) if self.buffer.len() > part_bytes + fragment::HEADER_SIZE => {
+                for part in self.splitter.split(&self.buffer, part_bytes)? {
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(&part);
+                    self.transmit()?;
+                }
+                Ok(())
+            }
+            _ => self.transmit(),
+        }
+    }
+
+    fn traffic(&self) -> Option<&Traffic> {
+        Some(&self.traffic)
+    }
+}
+
+struct TcpClient {
+    stream: TcpStream,
+    /// Set when the client sent a hello
+    tailored: Option<TailoredStream>,
+}
+
+type Clients = Arc<Mutex<Vec<TcpClient>>>;
+
+/// Streams frames as one JSON document per line to every connected client
+pub struct TcpServer {
+    address: SocketAddr,
+    clients: Clients,
+    format: JsonFormat,
+    buffer: Vec<u8>,
+    /// Serialization buffer for tailored streams
+    tailored_buffer: Vec<u8>,
+    traffic: Traffic,
+}
+
+impl TcpServer {
+    /// Start listening and accepting connections on a background thread
+    pub fn bind(address: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(address).map_err(Error::Network)?;
+        let address = listener.local_addr().map_err(Error::Network)?;
+        let clients: Clients = Arc::default();
+        let accept_clients = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let clients = accept_clients.clone();
+                // waiting for the hello mustn't hold up other connections
+                thread::spawn(move || match accept(stream) {
+                    Ok(Some(client)) => clients.lock().unwrap().push(client),
+                    Ok(None) => {}
+                    Err(error) => eprintln!("TCP connection failed: {}", error),
+                });
+            }
+        });
+        Ok(Self {
+            address,
+            clients,
+            format: JsonFormat::default(),
+            buffer: vec![],
+            tailored_buffer: vec![],
+            traffic: Traffic::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn set_format(&mut self, format: JsonFormat) {
+        self.format = format;
+    }
+}
+
+/// Read the optional hello and answer it, `None` if the client was turned away
+fn accept(stream: TcpStream) -> Result<Option<TcpClient>> {
+    stream.set_nodelay(true)?;
+    let tailored = match read_hello(&stream) {
+        Ok(line) if line.is_empty() => return Ok(None),
+        Ok(line) => {
+            let (reply, tailored) = if line.ends_with(b"\n") || (line.len() as u64) < MAX_HELLO {
+                handshake::answer(&String::from_utf8_lossy(&line))
+            } else {
+                let error = format!("Hello longer than {} bytes", MAX_HELLO);
+                (handshake::ServerMessage::Error(error), None)
+            };
+            let mut reply = serde_json::to_vec(&reply)?;
+            reply.push(b'\n');
+            (&stream).write_all(&reply)?;
+            if tailored.is_none() {
+                return Ok(None);
+            }
+            tailored
+        }
+        Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => None,
+        Err(error) => return Err(error.into()),
+    };
+    stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))?;
+    Ok(Some(TcpClient { stream, tailored }))
+}
+
+/// Read up to the end of the hello line, at most [`MAX_HELLO`] bytes
+///
+/// The read gives up once [`handshake::HANDSHAKE_TIMEOUT`] has passed since it
+/// started, so a client trickling bytes can't hold the connection open.
+fn read_hello(stream: &TcpStream) -> io::Result<Vec<u8>> {
+    let deadline = Instant::now() + handshake::HANDSHAKE_TIMEOUT;
+    let mut reader = BufReader::new(stream.take(MAX_HELLO));
+    let mut line = vec![];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(line);
+        }
+        if let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+            line.extend_from_slice(&buffer[..=end]);
+            return Ok(line);
+        }
+        let read = buffer.len();
+        line.extend_from_slice(buffer);
+        reader.consume(read);
+    }
+}
+
+impl PoseSink for TcpServer {
+    fn name(&self) -> &str {
+        "tcp"
+    }
+
+    fn publish(&mut self, frame: &TrackedObjects) -> Result<()> {
+        self.buffer.clear();
+        self.format.write(frame, &mut self.buffer)?;
+        self.buffer.push(b'\n');
+        let now = Instant::now();
+        let Self {
+            clients,
+            format,
+            buffer: shared_buffer,
+            tailored_buffer,
+            traffic,
+            ..
+        } = self;
+        let mut failed = None;
+        clients.lock().unwrap().retain_mut(|client| {
+            let buffer = match client.tailored.as_mut() {
+                Some(tailored) => {
+                    tailored_buffer.clear();
+                    match tailored.write(frame, format, now, tailored_buffer) {
+                        Ok(true) => {
+                            tailored_buffer.push(b'\n');
+                            &*tailored_buffer
+                        }
+                        Ok(false) => return true,
+                        Err(error) => {
+                            failed = Some(error);
+                            return true;
+                        }
+                    }
+                }
+                None => &*shared_buffer,
+            };
+            let written = client.stream.write_all(buffer).is_ok();
+            if written {
+                traffic.record(buffer.len());
+            }
+            written
+        });
+        match failed {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn traffic(&self) -> Option<&Traffic> {
+        Some(&self.traffic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_udp_and_tcp_delivery() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut udp = UdpSender::new(vec![receiver.local_addr().unwrap()]).unwrap();
+        udp.publish(&TrackedObjects::new(3, 0, vec![])).unwrap();
+        let mut datagram = [0; 1024];
+        let len = receiver.recv(&mut datagram).unwrap();
+        assert!(String::from_utf8_lossy(&datagram[..len]).contains("\"seq\":3"));
+
+        let mut tcp = TcpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = BufReader::new(TcpStream::connect(tcp.local_addr()).unwrap());
+        while tcp.clients.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        tcp.publish(&TrackedObjects::new(4, 0, vec![])).unwrap();
+        tcp.publish(&TrackedObjects::new(5, 0, vec![])).unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        assert!(line.contains("\"seq\":4") && line.ends_with('\n'));
+        line.clear();
+        client.read_line(&mut line).unwrap();
+        assert!(line.contains("\"seq\":5"));
+
+        let mut tailored = TcpStream::connect(tcp.local_addr()).unwrap();
+        tailored
+            .write_all(b"{\"hello\": {\"format\": {\"case\": \"camel_case\"}}}\n")
+            .unwrap();
+        let mut tailored = BufReader::new(tailored);
+        line.clear();
+        tailored.read_line(&mut line).unwrap();
+        assert!(line.starts_with("{\"welcome\":{\"version\":1"));
+        while tcp.clients.lock().unwrap().len() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut frame = TrackedObjects::new(6, 0, vec![]);
+        frame.set_delta_of(5);
+        tcp.publish(&frame).unwrap();
+        line.clear();
+        tailored.read_line(&mut line).unwrap();
+        assert!(line.contains("\"keyframeSeq\":5"));
+        line.clear();
+        client.read_line(&mut line).unwrap();
+        assert!(line.contains("\"keyframe_seq\":5"));
+    }
+    #[test]
+    fn test_hello_is_limited_in_size_and_time() {
+        let tcp = TcpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut long = TcpStream::connect(tcp.local_addr()).unwrap();
+        long.write_all(&vec![b' '; MAX_HELLO as usize + 1]).unwrap();
+        let mut line = String::new();
+        BufReader::new(long).read_line(&mut line).unwrap();
+        assert!(line.starts_with("{\"error\":\"Hello longer than"));
+
+        let mut slow = TcpStream::connect(tcp.local_addr()).unwrap();
+        let start = Instant::now();
+        while tcp.clients.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < handshake::HANDSHAKE_TIMEOUT * 3);
+            let _ = slow.write_all(b" ");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! 3D view of device poses and the play area
+//!
+//! Frames arrive over the internal frame channel from [`crate::sink::ChannelSink`].
+//! The scene is projected through a slowly orbiting perspective camera and drawn
+//! as lines into a window's frame buffer.
+
+use crate::client::FrameAssembler;
+use crate::error::{Error, Result};
+use crate::tracking_messages::{TrackedObjects, VrDeviceClass};
+use minifb::{Key, Window, WindowOptions};
+use nalgebra as na;
+use std::sync::mpsc;
+use std::time::Instant;
+
+const TITLE: &str = "openvr-tracker";
+const FRAMES_PER_SECOND: usize = 30;
+
+pub const WIDTH: usize = 960;
+pub const HEIGHT: usize = 640;
+
+const AXIS_LENGTH: f32 = 0.15;
+const MARKER_SIZE: i32 = 4;
+const ORBIT_SPEED: f32 = 0.2;
+const CAMERA_DISTANCE: f32 = 4.0;
+const CAMERA_HEIGHT: f32 = 2.5;
+
+const BACKGROUND: u32 = 0x10_14_1c;
+const FLOOR: u32 = 0x50_50_50;
+const PLAY_AREA: u32 = 0x2e_a0_e0;
+const AXES: [u32; 3] = [0xe0_40_40, 0x40_e0_40, 0x40_60_ff];
+
+fn class_color(class: VrDeviceClass) -> u32 {
+    match class {
+        VrDeviceClass::HMD => 0xff_ff_ff,
+        VrDeviceClass::LeftController => 0xff_a0_20,
+        VrDeviceClass::RightController => 0x20_d0_ff,
+        VrDeviceClass::Controller => 0xd0_d0_60,
+        VrDeviceClass::Tracker => 0xff_40_c0,
+        VrDeviceClass::Sensor => 0x90_90_90,
+        VrDeviceClass::Other => 0x60_60_60,
+        VrDeviceClass::Camera => 0xa0_60_ff,
+    }
+}
+
+/// Perspective camera looking at the middle of the tracking space
+pub struct Camera {
+    view: na::Isometry3<f32>,
+    projection: na::Perspective3<f32>,
+}
+
+impl Camera {
+    /// Camera orbiting the origin, `yaw` radians around the vertical axis
+    pub fn orbit(yaw: f32) -> Self {
+        let eye = na::Point3::new(
+            CAMERA_DISTANCE * yaw.sin(),
+            CAMERA_HEIGHT,
+            CAMERA_DISTANCE * yaw.cos(),
+        );
+        let target = na::Point3::new(0.0, 1.0, 0.0);
+        Self {
+            view: na::Isometry3::look_at_rh(&eye, &target, &na::Vector3::y()),
+            projection: na::Perspective3::new(
+                WIDTH as f32 / HEIGHT as f32,
+                std::f32::consts::FRAC_PI_3,
+                0.1,
+                100.0,
+            ),
+        }
+    }
+
+    /// Pixel coordinates of a point, None if it is behind the camera
+    fn project(&self, point: &na::Point3<f32>) -> Option<(i32, i32)> {
+        let view = self.view * point;
+        if view.z >= -self.projection.znear() {
+            return None;
+        }
+        let ndc = self.projection.project_point(&view);
+        let column = (ndc.x + 1.0) * 0.5 * (WIDTH - 1) as f32;
+        let row = (1.0 - ndc.y) * 0.5 * (HEIGHT - 1) as f32;
+        Some((column.round() as i32, row.round() as i32))
+    }
+}
+
+/// Frame buffer of `WIDTH` x `HEIGHT` pixels as 0RGB
+struct Canvas {
+    pixels: Vec<u32>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Self {
+            pixels: vec![BACKGROUND; WIDTH * HEIGHT],
+        }
+    }
+
+    fn put(&mut self, (column, row): (i32, i32), color: u32) {
+        if (0..WIDTH as i32).contains(&column) && (0..HEIGHT as i32).contains(&row) {
+            self.pixels[row as usize * WIDTH + column as usize] = color;
+        }
+    }
+
+    fn line(&mut self, from: (i32, i32), to: (i32, i32), color: u32) {
+        let (mut x, mut y) = from;
+        let dx = (to.0 - x).abs();
+        let dy = -(to.1 - y).abs();
+        let step_x = if x < to.0 { 1 } else { -1 };
+        let step_y = if y < to.1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        loop {
+            self.put((x, y), color);
+            if (x, y) == to {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    fn line_3d(
+        &mut self,
+        camera: &Camera,
+        from: &na::Point3<f32>,
+        to: &na::Point3<f32>,
+        color: u32,
+    ) {
+        if let (Some(from), Some(to)) = (camera.project(from), camera.project(to)) {
+            self.line(from, to, color);
+        }
+    }
+
+    fn square(&mut self, (column, row): (i32, i32), color: u32) {
+        for y in row - MARKER_SIZE..=row + MARKER_SIZE {
+            for x in column - MARKER_SIZE..=column + MARKER_SIZE {
+                self.put((x, y), color);
+            }
+        }
+    }
+}
+
+/// Draw play area, device markers and their orientation axes into a `WIDTH` x `HEIGHT` frame buffer
+pub fn render(
+    frame: Option<&TrackedObjects>,
+    play_area: Option<[[f32; 3]; 4]>,
+    camera: &Camera,
+) -> Vec<u32> {
+    let mut canvas = Canvas::new();
+    let origin = na::Point3::origin();
+    canvas.line_3d(camera, &origin, &na::Point3::new(0.5, 0.0, 0.0), FLOOR);
+    canvas.line_3d(camera, &origin, &na::Point3::new(0.0, 0.0, 0.5), FLOOR);
+    if let Some(corners) = play_area {
+        for i in 0..4 {
+            let a = corners[i];
+            let b = corners[(i + 1) % 4];
+            canvas.line_3d(
+                camera,
+                &na::Point3::new(a[0], a[1], a[2]),
+                &na::Point3::new(b[0], b[1], b[2]),
+                PLAY_AREA,
+            );
+        }
+    }
+    let devices = frame
+        .map(|frame| frame.trackers())
+        .unwrap_or_default()
+        .iter()
+        .filter(|device| device.tracked());
+    for device in devices {
+        let position = device.position();
+        let rotation = device.rotation();
+        let axes = [na::Vector3::x(), na::Vector3::y(), na::Vector3::z()];
+        for (axis, color) in axes.iter().zip(AXES.iter()) {
+            let end = position + rotation * axis * AXIS_LENGTH;
+            canvas.line_3d(camera, position, &end, *color);
+        }
+        if let Some(pixel) = camera.project(position) {
+            canvas.square(pixel, class_color(device.class()));
+        }
+    }
+    canvas.pixels
+}
+
+/// Draw frames in a window until it is closed or the channel is
+pub fn run(frames: mpsc::Receiver<TrackedObjects>, play_area: Option<[[f32; 3]; 4]>) -> Result<()> {
+    let mut window = Window::new(TITLE, WIDTH, HEIGHT, WindowOptions::default())
+        .map_err(|error| Error::external_caused("Failed to open visualizer window", error))?;
+    window.set_target_fps(FRAMES_PER_SECOND);
+    let start = Instant::now();
+    let mut assembler = FrameAssembler::new();
+    let mut latest: Option<TrackedObjects> = None;
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        // drain the backlog so only the newest frame is drawn
+        loop {
+            match frames.try_recv() {
+                Ok(frame) => {
+                    if let Some(frame) = assembler.push(frame) {
+                        latest = Some(frame);
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        let camera = Camera::orbit(start.elapsed().as_secs_f32() * ORBIT_SPEED);
+        let pixels = render(latest.as_ref(), play_area, &camera);
+        window
+            .update_with_buffer(&pixels, WIDTH, HEIGHT)
+            .map_err(|error| Error::external_caused("Failed to draw visualizer window", error))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+    use crate::openvr_adaptor::VrDeviceManager;
+
+    #[test]
+    fn test_draws_tracked_devices_and_play_area() {
+        let mut backend = MockBackend::new();
+        let mut pose = IDENTITY_POSE;
+        pose[1][3] = 1.0;
+        backend.push_frame(vec![DeviceSample::new(0, VrDeviceClass::HMD, pose)]);
+        let mut manager = VrDeviceManager::with_backend(backend);
+        manager.update();
+        let frame = TrackedObjects::new(1, 0, manager.device_list());
+        let play_area = [
+            [-1.0, 0.0, -1.0],
+            [1.0, 0.0, -1.0],
+            [1.0, 0.0, 1.0],
+            [-1.0, 0.0, 1.0],
+        ];
+        let camera = Camera::orbit(0.0);
+
+        let view = render(Some(&frame), Some(play_area), &camera);
+        assert_eq!(view.len(), WIDTH * HEIGHT);
+        let (column, row) = camera.project(&na::Point3::new(0.0, 1.0, 0.0)).unwrap();
+        assert_eq!(
+            view[row as usize * WIDTH + column as usize],
+            class_color(VrDeviceClass::HMD)
+        );
+        assert!(view.contains(&PLAY_AREA));
+
+        let empty = render(None, None, &camera);
+        assert!(!empty.contains(&class_color(VrDeviceClass::HMD)));
+        assert!(!empty.contains(&PLAY_AREA));
+    }
+
+    #[test]
+    fn test_points_behind_camera_are_skipped() {
+        let camera = Camera::orbit(0.0);
+        assert!(camera
+            .project(&na::Point3::new(0.0, CAMERA_HEIGHT, 10.0))
+            .is_none());
+        assert!(camera.project(&na::Point3::new(0.0, 1.0, 0.0)).is_some());
+    }
+}
@@ -0,0 +1,258 @@
+//! Detection of a frozen tracking runtime
+//!
+//! A hung SteamVR keeps handing out the last poses. Real poses always carry
+//! some noise, so tracked devices that stay bit-for-bit identical mean the
+//! stream only looks alive. The runtime's frame count is compared as well,
+//! it stops advancing when the compositor hangs even while nothing is tracked.
+//!
+//! A runtime can also hang inside the call that samples it, then no samples
+//! arrive to compare at all. [`SampleDeadline`] catches that on a thread of
+//! its own.
+
+use crate::config::WatchdogConfig;
+use crate::tracking_messages::{StreamStatus, VrDevice};
+use nalgebra as na;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the deadline thread looks at the time of the last sample
+const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+pub struct StaleWatchdog {
+    poses: Vec<(usize, na::Point3<f32>, na::UnitQuaternion<f32>)>,
+    previous: Vec<(usize, na::Point3<f32>, na::UnitQuaternion<f32>)>,
+    frame_count: Option<u64>,
+    last_change: Option<Instant>,
+    /// Poses or frame count stopped changing
+    frozen: bool,
+    stale: bool,
+    /// Serials of scripted devices, whose poses say nothing about the runtime
+    ignored: Vec<String>,
+    deadline: SampleDeadline,
+}
+
+impl StaleWatchdog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leave devices with these serials out of the comparison
+    pub fn set_ignored_serials(&mut self, serials: Vec<String>) {
+        self.ignored = serials;
+    }
+
+    /// Deadline the sampling loop has to meet, shared with this watchdog
+    pub fn deadline(&self) -> SampleDeadline {
+        self.deadline.clone()
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Compare sampled devices and the runtime's frame count with the previous sample
+    ///
+    /// `frame_count` is `None` for backends that don't count frames.
+    pub fn record(
+        &mut self,
+        devices: &[VrDevice],
+        frame_count: Option<u64>,
+        config: &WatchdogConfig,
+        now: Instant,
+    ) {
+        let threshold = Duration::from_secs_f32(config.stale_after_s.max(0.0));
+        self.deadline.set_threshold(threshold);
+        self.poses.clear();
+        let ignored = &self.ignored;
+        self.poses.extend(
+            devices
+                .iter()
+                .filter(|device| device.tracked())
+                .filter(|device| !matches!(device.serial(), Some(serial) if ignored.iter().any(|ignored| ignored == serial)))
+                .map(|device| (device.id(), *device.position(), *device.rotation())),
+        );
+        // nothing tracked means no poses to judge
+        let moved = self.poses.is_empty() || self.poses != self.previous;
+        std::mem::swap(&mut self.poses, &mut self.previous);
+        let previous = std::mem::replace(&mut self.frame_count, frame_count);
+        let counted = match (frame_count, previous) {
+            (Some(count), Some(previous)) => count != previous,
+            _ => true,
+        };
+        if moved && counted {
+            self.last_change = Some(now);
+            self.frozen = false;
+            return;
+        }
+        let last_change = *self.last_change.get_or_insert(now);
+        if now.duration_since(last_change) >= threshold {
+            self.frozen = true;
+        }
+    }
+
+    /// New status when the stream goes stale or recovers
+    ///
+    /// Stale while recorded samples are frozen or the sampling loop missed its deadline,
+    /// so call this whether a sample arrived or not.
+    pub fn poll(&mut self) -> Option<StreamStatus> {
+        let stale = self.frozen || self.deadline.missed();
+        if stale == self.stale {
+            return None;
+        }
+        self.stale = stale;
+        Some(if stale {
+            StreamStatus::Stale
+        } else {
+            StreamStatus::Recovered
+        })
+    }
+}
+
+/// Time of the last sample, checked against the stale threshold by a thread of its own
+///
+/// The sampling loop calls [`beat`](Self::beat) after every sample. Clones share the deadline.
+#[derive(Clone)]
+pub struct SampleDeadline {
+    state: Arc<DeadlineState>,
+}
+
+struct DeadlineState {
+    last_beat: Mutex<Instant>,
+    threshold_ms: AtomicU64,
+    missed: AtomicBool,
+}
+
+impl Default for SampleDeadline {
+    fn default() -> Self {
+        let threshold = WatchdogConfig::default().stale_after_s;
+        Self {
+            state: Arc::new(DeadlineState {
+                last_beat: Mutex::new(Instant::now()),
+                threshold_ms: AtomicU64::new((threshold * 1000.0) as u64),
+                missed: AtomicBool::new(false),
+            }),
+        }
+    }
+}
+
+impl SampleDeadline {
+    /// A sample was taken
+    pub fn beat(&self) {
+        *self.state.last_beat.lock().unwrap() = Instant::now();
+    }
+
+    fn set_threshold(&self, threshold: Duration) {
+        self.state
+            .threshold_ms
+            .store(threshold.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Whether the last check found no sample within the threshold
+    pub fn missed(&self) -> bool {
+        self.state.missed.load(Ordering::Relaxed)
+    }
+
+    /// Compare the time of the last sample with `now`, true if that changed whether it was missed
+    fn check(&self, now: Instant) -> bool {
+        let last_beat = *self.state.last_beat.lock().unwrap();
+        let threshold = Duration::from_millis(self.state.threshold_ms.load(Ordering::Relaxed));
+        let missed = now.saturating_duration_since(last_beat) >= threshold;
+        self.state.missed.swap(missed, Ordering::Relaxed) != missed
+    }
+
+    /// Check the deadline on a thread of its own until every clone is dropped
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let state: Weak<DeadlineState> = Arc::downgrade(&self.state);
+        thread::spawn(move || loop {
+            thread::sleep(DEADLINE_CHECK_INTERVAL);
+            let deadline = match state.upgrade() {
+                Some(state) => SampleDeadline { state },
+                None => return,
+            };
+            if deadline.check(Instant::now()) {
+                if deadline.missed() {
+                    eprintln!("No sample from the tracking runtime within the stale threshold");
+                } else {
+                    eprintln!("Sampling the tracking runtime again");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceSample, MockBackend, IDENTITY_POSE};
+    use crate::openvr_adaptor::VrDeviceManager;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_frozen_poses_go_stale_and_recover() {
+        let mut backend = MockBackend::new();
+        backend.push_frame(vec![DeviceSample::new(
+            0,
+            VrDeviceClass::HMD,
+            IDENTITY_POSE,
+        )]);
+        let mut manager = VrDeviceManager::with_backend(backend);
+        let config = WatchdogConfig::default();
+        let mut watchdog = StaleWatchdog::new();
+        let start = Instant::now();
+
+        manager.update();
+        watchdog.record(manager.devices(), None, &config, start);
+        assert_eq!(watchdog.poll(), None);
+        let frozen = start + Duration::from_secs(1);
+        watchdog.record(manager.devices(), None, &config, frozen);
+        assert_eq!(watchdog.poll(), None);
+        let frozen = start + Duration::from_secs(2);
+        watchdog.record(manager.devices(), None, &config, frozen);
+        assert_eq!(watchdog.poll(), Some(StreamStatus::Stale));
+        assert_eq!(watchdog.poll(), None);
+        assert!(watchdog.is_stale());
+
+        let mut moved = manager.devices().to_vec();
+        moved[0].transform(&na::Isometry3::translation(0.0, 0.001, 0.0));
+        watchdog.record(&moved, None, &config, frozen);
+        assert_eq!(watchdog.poll(), Some(StreamStatus::Recovered));
+        assert!(!watchdog.is_stale());
+    }
+
+    #[test]
+    fn test_frozen_frame_count_goes_stale() {
+        let config = WatchdogConfig::default();
+        let mut watchdog = StaleWatchdog::new();
+        let start = Instant::now();
+
+        // nothing tracked, only the runtime's frame count tells it hung
+        watchdog.record(&[], Some(10), &config, start);
+        watchdog.record(&[], Some(11), &config, start + Duration::from_secs(1));
+        watchdog.record(&[], Some(11), &config, start + Duration::from_secs(2));
+        assert_eq!(watchdog.poll(), None);
+        watchdog.record(&[], Some(11), &config, start + Duration::from_secs(3));
+        assert_eq!(watchdog.poll(), Some(StreamStatus::Stale));
+        watchdog.record(&[], Some(12), &config, start + Duration::from_secs(4));
+        assert_eq!(watchdog.poll(), Some(StreamStatus::Recovered));
+    }
+
+    #[test]
+    fn test_missed_deadline_goes_stale_without_samples() {
+        let mut watchdog = StaleWatchdog::new();
+        let deadline = watchdog.deadline();
+        deadline.beat();
+        let beat = *deadline.state.last_beat.lock().unwrap();
+
+        assert!(!deadline.check(beat + Duration::from_secs(1)));
+        assert_eq!(watchdog.poll(), None);
+        assert!(deadline.check(beat + Duration::from_secs(2)));
+        assert_eq!(watchdog.poll(), Some(StreamStatus::Stale));
+
+        deadline.beat();
+        assert!(deadline.check(Instant::now()));
+        assert_eq!(watchdog.poll(), Some(StreamStatus::Recovered));
+    }
+}
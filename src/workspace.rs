@@ -0,0 +1,143 @@
+//! Limits on where devices may be, for consumers that drive hardware
+//!
+//! A robot mirroring a tracker should never be commanded outside of its reach
+//! because the tracker glitched. Each entry of `workspace` gives the devices
+//! it applies to a box their position has to stay in and a largest angle
+//! their rotation may have from a nominal orientation, both in the published
+//! frame after calibration and heading. Devices outside of a limit are
+//! flagged with `outside_workspace`, and with `clamp` also moved to the
+//! nearest pose inside of it: the position onto the box and the rotation
+//! towards the nominal orientation, about the same axis.
+
+use crate::config::device_named;
+use crate::tracking_messages::VrDevice;
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkspaceLimit {
+    /// Serials or aliases of the limited devices, every device if empty
+    pub devices: Vec<String>,
+    /// Lowest x, y and z the position may have
+    pub min: Option<[f32; 3]>,
+    /// Highest x, y and z the position may have
+    pub max: Option<[f32; 3]>,
+    /// Orientation the angle is measured from
+    pub rotation: na::UnitQuaternion<f32>,
+    /// Largest angle between the rotation and `rotation`
+    pub max_angle_deg: Option<f32>,
+    /// Move poses outside into the limits instead of only flagging them
+    pub clamp: bool,
+}
+
+impl Default for WorkspaceLimit {
+    fn default() -> Self {
+        Self {
+            devices: vec![],
+            min: None,
+            max: None,
+            rotation: na::UnitQuaternion::identity(),
+            max_angle_deg: None,
+            clamp: false,
+        }
+    }
+}
+
+impl WorkspaceLimit {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut corners = self.min.iter().chain(&self.max).flatten();
+        if corners.any(|value| !value.is_finite()) {
+            problems.push("workspace min and max must only contain finite values".to_owned());
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min.iter().zip(&max).any(|(min, max)| min > max) {
+                problems.push(format!("workspace min {:?} is above max {:?}", min, max));
+            }
+        }
+        if let Some(max_angle_deg) = self.max_angle_deg {
+            if !(0.0..=180.0).contains(&max_angle_deg) {
+                problems.push(format!(
+                    "workspace.max_angle_deg must be between 0 and 180, is {}",
+                    max_angle_deg
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Whether the limit is for `device`
+    pub fn applies_to(&self, device: &VrDevice, aliases: &HashMap<String, Arc<str>>) -> bool {
+        self.devices.is_empty()
+            || self
+                .devices
+                .iter()
+                .any(|name| device_named(device, name, aliases))
+    }
+
+    /// Check the pose of `device`, clamping it if configured. True when it was outside
+    pub fn limit(&self, device: &mut VrDevice) -> bool {
+        let mut position = *device.position();
+        if let Some(min) = self.min {
+            position = position.sup(&na::Point3::from(min));
+        }
+        if let Some(max) = self.max {
+            position = position.inf(&na::Point3::from(max));
+        }
+        let mut rotation = *device.rotation();
+        if let Some(max_angle_deg) = self.max_angle_deg {
+            let max_angle = max_angle_deg.to_radians();
+            let offset = self.rotation.inverse() * rotation;
+            if let Some((axis, angle)) = offset.axis_angle() {
+                if angle > max_angle {
+                    rotation =
+                        self.rotation * na::UnitQuaternion::from_axis_angle(&axis, max_angle);
+                }
+            }
+        }
+        let outside = position != *device.position() || rotation != *device.rotation();
+        if outside && self.clamp {
+            device.set_pose(device.tracked(), position, rotation);
+        }
+        outside
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IDENTITY_POSE;
+    use crate::tracking_messages::VrDeviceClass;
+
+    #[test]
+    fn test_clamp_into_box_and_cone() {
+        let limit: WorkspaceLimit = serde_json::from_str(
+            r#"{"min": [-0.5, 0, -0.5], "max": [0.5, 1, 0.5], "max_angle_deg": 30, "clamp": true}"#,
+        )
+        .unwrap();
+        assert!(limit.problems().is_empty());
+        let mut device = VrDevice::new(3);
+        device.update(true, &IDENTITY_POSE, VrDeviceClass::Tracker);
+        assert!(limit.applies_to(&device, &HashMap::new()));
+        assert!(!limit.limit(&mut device));
+
+        let rotation = na::UnitQuaternion::from_axis_angle(&na::Vector3::x_axis(), 1.0);
+        device.set_pose(true, na::Point3::new(2.0, 0.5, -1.0), rotation);
+        assert!(limit.limit(&mut device));
+        assert_eq!(*device.position(), na::Point3::new(0.5, 0.5, -0.5));
+        let (axis, angle) = device.rotation().axis_angle().unwrap();
+        assert!((axis.into_inner() - na::Vector3::x()).norm() < 1e-5);
+        assert!((angle - 30f32.to_radians()).abs() < 1e-5);
+
+        let flag_only = WorkspaceLimit {
+            clamp: false,
+            ..limit
+        };
+        device.set_pose(true, na::Point3::new(0.0, -1.0, 0.0), rotation);
+        assert!(flag_only.limit(&mut device));
+        assert_eq!(*device.position(), na::Point3::new(0.0, -1.0, 0.0));
+    }
+}